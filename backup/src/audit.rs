@@ -0,0 +1,222 @@
+//! Auditing restored files against the catalog, for positive confirmation that a restore landed intact.
+//!
+//! Each source file recorded in the catalog is looked up under the directory it was restored to, hashed again, and
+//! compared with the hash and size of the archive holding it. Files are hashed by a few threads at once, results are
+//! reported in the order of their paths.
+
+use crate::db::RecordedFile;
+use anyhow::{Context, Result};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How often progress is logged
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What the restored copy of a file is.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Finding {
+    /// Same content, the modification time may not have been restored
+    Ok {
+        mtime_differs: bool,
+    },
+    Mismatched {
+        size: u64,
+        hash: blake3::Hash,
+        mtime: Option<i64>,
+    },
+    Missing,
+    Unreadable(String),
+    /// The archive holds a torn copy, there is nothing to compare with
+    Torn,
+}
+
+/// Files of each finding, and bytes read.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AuditTotals {
+    pub ok: usize,
+    pub mismatched: usize,
+    pub missing: usize,
+    pub unreadable: usize,
+    pub torn: usize,
+    pub bytes_hashed: u64,
+}
+
+impl AuditTotals {
+    pub fn passed(&self) -> bool {
+        self.mismatched == 0 && self.missing == 0 && self.unreadable == 0
+    }
+}
+
+/// Where `recorded` is restored under `root`: its absolute path, below `root`.
+pub fn restored_path(root: &Path, recorded: &Path) -> PathBuf {
+    let relative = recorded
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect::<PathBuf>();
+    root.join(relative)
+}
+
+fn hash_file(path: &Path) -> Result<(u64, blake3::Hash)> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("unable to open {}.", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    let size = std::io::copy(&mut file, &mut hasher).with_context(|| format!("unable to read {}.", path.display()))?;
+    Ok((size, hasher.finalize()))
+}
+
+/// Compare the file restored at `restored` with what the catalog recorded. Returns the bytes read too.
+fn check(recorded: &RecordedFile, restored: &Path) -> (Finding, u64) {
+    if recorded.archive.is_torn() {
+        return (Finding::Torn, 0);
+    }
+    let metadata = match std::fs::metadata(restored) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return (Finding::Missing, 0),
+        Err(e) => return (Finding::Unreadable(e.to_string()), 0),
+    };
+    let (size, hash) = match hash_file(restored) {
+        Ok(digest) => digest,
+        Err(e) => return (Finding::Unreadable(format!("{e:#}")), 0),
+    };
+    let mtime = Some(metadata.mtime());
+    let finding = if size == recorded.archive.size as u64 && *hash.as_bytes() == recorded.archive.hash {
+        Finding::Ok {
+            mtime_differs: recorded.mtime.is_some_and(|recorded| Some(recorded) != mtime),
+        }
+    } else {
+        Finding::Mismatched { size, hash, mtime }
+    };
+    (finding, size)
+}
+
+/// Audit `files` restored under `root` with `workers` threads, and give each to `report` in the order of `files`.
+pub fn audit(
+    files: &[RecordedFile],
+    root: &Path,
+    workers: usize,
+    mut report: impl FnMut(&RecordedFile, &Path, &Finding),
+) -> AuditTotals {
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut findings = (0..files.len()).map(|_| None).collect::<Vec<_>>();
+    let mut totals = AuditTotals::default();
+    std::thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            let (next, sender) = (&next, sender.clone());
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(file) = files.get(index) else {
+                    break;
+                };
+                let restored = restored_path(root, &file.path);
+                let (finding, bytes) = check(file, &restored);
+                if sender.send((index, restored, finding, bytes)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        // 按路径顺序报告, 先完成的暂存
+        let (mut reported, mut last_progress) = (0, Instant::now());
+        for (index, restored, finding, bytes) in receiver {
+            totals.bytes_hashed += bytes;
+            findings[index] = Some((restored, finding));
+            while let Some((restored, finding)) = findings.get_mut(reported).and_then(Option::take) {
+                match finding {
+                    Finding::Ok { .. } => totals.ok += 1,
+                    Finding::Mismatched { .. } => totals.mismatched += 1,
+                    Finding::Missing => totals.missing += 1,
+                    Finding::Unreadable(_) => totals.unreadable += 1,
+                    Finding::Torn => totals.torn += 1,
+                }
+                report(&files[reported], &restored, &finding);
+                reported += 1;
+            }
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                last_progress = Instant::now();
+                tracing::info!(
+                    files = reported,
+                    total = files.len(),
+                    bytes = totals.bytes_hashed,
+                    "auditing restored files"
+                );
+            }
+        }
+    });
+    totals
+}
+
+#[cfg(test)]
+mod test {
+    use super::{audit, restored_path, AuditTotals, Finding};
+    use crate::db::{Archive, RecordedFile, ARCHIVE_TORN};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_restored_path() {
+        let root = Path::new("/restore");
+        assert_eq!(
+            restored_path(root, Path::new("/tank/a.tar")),
+            Path::new("/restore/tank/a.tar")
+        );
+        assert_eq!(
+            restored_path(root, Path::new("/../etc/passwd")),
+            Path::new("/restore/etc/passwd")
+        );
+    }
+
+    #[test]
+    fn test_audit() {
+        let root = std::env::temp_dir().join(format!("backup-audit-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("data")).unwrap();
+        let recorded = |name: &str, content: &[u8], mtime| RecordedFile {
+            path: PathBuf::from("/data").join(name),
+            mtime,
+            archive: Archive::new(0, 0, content.len() as u32, *blake3::hash(content).as_bytes()),
+        };
+        std::fs::write(root.join("data/a"), "intact").unwrap();
+        std::fs::write(root.join("data/b"), "CORRUPT").unwrap();
+        std::fs::write(root.join("data/d"), "torn").unwrap();
+        let mut torn = recorded("d", b"torn", None);
+        torn.archive = torn.archive.flag(ARCHIVE_TORN);
+        let files = vec![
+            recorded("a", b"intact", Some(0)),
+            recorded("b", b"corrupt", None),
+            recorded("c", b"missing", None),
+            torn,
+        ];
+
+        let mut findings = Vec::new();
+        let totals = audit(&files, &root, 3, |file, restored, finding| {
+            assert_eq!(restored, root.join(file.path.strip_prefix("/").unwrap()));
+            findings.push(match finding {
+                Finding::Ok { mtime_differs } => format!("ok {mtime_differs}"),
+                Finding::Mismatched { size, hash, .. } => {
+                    assert_eq!(*hash, blake3::hash(b"CORRUPT"));
+                    format!("mismatched {size}")
+                }
+                Finding::Missing => "missing".to_string(),
+                Finding::Unreadable(_) => "unreadable".to_string(),
+                Finding::Torn => "torn".to_string(),
+            });
+        });
+        // 恢复的文件修改时间不是 0
+        assert_eq!(findings, ["ok true", "mismatched 7", "missing", "torn"]);
+        assert_eq!(
+            totals,
+            AuditTotals {
+                ok: 1,
+                mismatched: 1,
+                missing: 1,
+                unreadable: 0,
+                torn: 1,
+                bytes_hashed: 13,
+            }
+        );
+        assert!(!totals.passed());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,30 +1,95 @@
 use anyhow::{Context, Result};
 use rusqlite::Connection;
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
-const DEFAULT_DATABASE_PATH: &str = "backup.db";
+pub const DEFAULT_DATABASE_PATH: &str = "backup.db";
+/// Flag of an archive whose source changed while it was read, the hash is of a torn copy
+pub const ARCHIVE_TORN: u32 = 1;
+/// Schema changes since the template, applied in order. `user_version` counts those applied.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE archive ADD COLUMN compression_ratio REAL;",
+    "ALTER TABLE archive ADD COLUMN copy_of INTEGER REFERENCES archive(id);",
+    "CREATE TABLE key (
+        id INTEGER NOT NULL PRIMARY KEY,
+        ts INTEGER NOT NULL,
+        description TEXT NOT NULL UNIQUE,
+        fingerprint TEXT NOT NULL UNIQUE
+    );
+    ALTER TABLE tape ADD COLUMN key_id INTEGER REFERENCES key(id);
+    ALTER TABLE archive ADD COLUMN key_id INTEGER REFERENCES key(id);",
+    "CREATE TABLE verification (
+        id INTEGER NOT NULL PRIMARY KEY,
+        archive_id INTEGER NOT NULL REFERENCES archive(id),
+        ts INTEGER NOT NULL,
+        ok INTEGER NOT NULL,
+        sampled INTEGER NOT NULL
+    );",
+    "ALTER TABLE file ADD COLUMN mtime INTEGER;",
+];
 
 #[derive(Debug)]
 pub struct Archive {
     /// Unique archive id
-    id: u32,
+    pub id: u32,
     /// Tape id, refer to `id` in table `tape`
-    tape: u8,
+    pub tape: u8,
     /// Reported file number on the tape
-    tape_file_index: u32,
+    pub tape_file_index: u32,
     /// Archive size, in bytes
-    size: u32,
+    pub size: u32,
     /// 32-byte blake3-hashed value
-    hash: [u8; 32],
+    pub hash: [u8; 32],
     /// The time when the file archived
-    ts: u64,
-    /// Flag, reserved
-    flag: u32,
+    pub ts: u64,
+    /// Flag, such as `ARCHIVE_TORN`
+    pub flag: u32,
+    /// Bytes received by the drive for each byte written to the medium, `None` if the drive does not tell
+    pub compression_ratio: Option<f64>,
+    /// Id of the archive this one mirrors on another tape
+    pub copy_of: Option<u32>,
+    /// Key it is encrypted with, if not the key of its tape
+    pub key_id: Option<u32>,
+}
+
+impl Archive {
+    /// An archive written now as file `tape_file_index` of `tape`, its id is given when appended.
+    pub fn new(tape: u8, tape_file_index: u32, size: u32, hash: [u8; 32]) -> Self {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        Self {
+            id: 0,
+            tape,
+            tape_file_index,
+            size,
+            hash,
+            ts,
+            flag: 0,
+            compression_ratio: None,
+            copy_of: None,
+            key_id: None,
+        }
+    }
+
+    pub fn flag(mut self, flag: u32) -> Self {
+        self.flag = flag;
+        self
+    }
+
+    pub fn compression_ratio(mut self, ratio: Option<f64>) -> Self {
+        self.compression_ratio = ratio;
+        self
+    }
+
+    pub fn is_torn(&self) -> bool {
+        self.flag & ARCHIVE_TORN != 0
+    }
 }
 
+/// A source file archived. Its id, and its version, the time it is appended, are given by [`Storage::append_file`].
 #[derive(Debug)]
 pub struct FileOnDisk {
-    id: u64,
     /// inode on filesystem. Note: it may conflict or be reused.
     inode: u64,
     /// file path
@@ -32,9 +97,30 @@ pub struct FileOnDisk {
     /// flag
     flag: u32,
     /// Archive id, refer to `id` in table `archive`
-    archive: u64,
-    /// Version, which represented by a timestamp, is when the file scanned.
-    version: u64,
+    archive: u32,
+    /// Last modification before it was archived, in seconds since epoch
+    mtime: Option<i64>,
+}
+
+impl FileOnDisk {
+    /// The source file held by `archive`.
+    pub fn new(inode: u64, path: &str, archive: u32, mtime: Option<i64>) -> Self {
+        Self {
+            inode,
+            path: path.to_string(),
+            flag: 0,
+            archive,
+            mtime,
+        }
+    }
+}
+
+/// A source file recorded in the catalog, with the archive holding it.
+#[derive(Debug)]
+pub struct RecordedFile {
+    pub path: PathBuf,
+    pub mtime: Option<i64>,
+    pub archive: Archive,
 }
 
 #[derive(Debug)]
@@ -47,6 +133,54 @@ pub struct Tape {
     description: String,
 }
 
+/// A key archives are encrypted with, by the fingerprint of its material.
+#[derive(Debug)]
+pub struct Key {
+    pub id: u32,
+    /// The time when the key was registered
+    pub ts: u64,
+    pub description: String,
+    pub fingerprint: String,
+}
+
+/// Tapes and archives encrypted with a key.
+#[derive(Debug)]
+pub struct KeyUsage {
+    pub key: Key,
+    pub tapes: u32,
+    pub archives: u32,
+}
+
+/// Archives written on a tape.
+#[derive(Debug)]
+pub struct TapeUsage {
+    pub tape: u8,
+    pub description: String,
+    pub archives: u32,
+    pub bytes: u64,
+    /// Average of the compression ratios reported, weighted by the size of the archives
+    pub compression_ratio: Option<f64>,
+}
+
+/// Rows of a table, and bytes of its pages and of those of its indexes.
+#[derive(Debug)]
+pub struct TableSize {
+    pub name: String,
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+/// Size of the database file, and of each table.
+#[derive(Debug)]
+pub struct DatabaseSize {
+    pub file_bytes: u64,
+    pub page_size: u64,
+    pub pages: u64,
+    /// Pages unused since rows were deleted, given back by VACUUM
+    pub free_pages: u64,
+    pub tables: Vec<TableSize>,
+}
+
 pub struct Storage {
     /// SQLite connection
     conn: Connection,
@@ -67,44 +201,330 @@ impl Storage {
         }
 
         let conn = Connection::open(path)?;
+        Self::migrate(&conn).with_context(|| format!("failed to upgrade the database at {}", path.display()))?;
         Ok(Self { conn })
     }
 
+    fn migrate(conn: &Connection) -> Result<()> {
+        let version: usize = conn.query_row("PRAGMA user_version;", [], |row| row.get(0))?;
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            conn.execute_batch(&format!("BEGIN; {migration} PRAGMA user_version = {}; COMMIT;", applied + 1))?;
+        }
+        Ok(())
+    }
+
     pub fn append_file(&self, file: &FileOnDisk) -> Result<()> {
         let current_time = std::time::SystemTime::now();
         let duration = current_time.duration_since(std::time::UNIX_EPOCH).unwrap();
         let ts = duration.as_secs();
 
+        // 表没有 rowid, 编号接在最后一个之后
         self.conn
             .execute(
                 "INSERT INTO file
-            (inode, path, flag, archive, version)
-            VALUES (?1, ?2, ?3, ?4, ?5);",
-                (file.inode, &file.path, &file.flag, &file.archive, ts),
+            (id, inode, path, flag, archive, version, mtime)
+            VALUES ((SELECT IFNULL(MAX(id), 0) + 1 FROM file), ?1, ?2, ?3, ?4, ?5, ?6);",
+                (file.inode, &file.path, &file.flag, &file.archive, ts, file.mtime),
             )
             .map(|_| ())
             .map_err(Into::into)
     }
 
-    pub fn append_archive(&self, archive: &Archive) -> Result<()> {
+    /// Append an archive, with the id following the last one as the table has no rowid. Returns the id.
+    pub fn append_archive(&self, archive: &Archive) -> Result<u32> {
+        self.conn.execute(
+            "INSERT INTO archive
+            (id, tape_id, tape_file_index, size, hash, ts, flag, compression_ratio, copy_of, key_id)
+            VALUES ((SELECT IFNULL(MAX(id), 0) + 1 FROM archive), ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9);",
+            (
+                archive.tape,
+                archive.tape_file_index,
+                archive.size,
+                archive.hash,
+                archive.ts,
+                archive.flag,
+                archive.compression_ratio,
+                archive.copy_of,
+                archive.key_id,
+            ),
+        )?;
+        let id = self.conn.query_row("SELECT MAX(id) FROM archive;", [], |row| row.get(0))?;
+        Ok(id)
+    }
+
+    /// Append the copies of an archive at once, the first one and its mirrors referring to it. Returns the id of the
+    /// first one.
+    pub fn append_archive_copies(&self, copies: Vec<Archive>) -> Result<u32> {
+        let mut copies = copies.into_iter();
+        let Some(first) = copies.next() else {
+            anyhow::bail!("no copy of the archive to append.");
+        };
+        // 出错时事务在丢弃时回滚
+        let transaction = self.conn.unchecked_transaction()?;
+        let id = self.append_archive(&first)?;
+        for mut mirror in copies {
+            mirror.copy_of = Some(id);
+            self.append_archive(&mirror)?;
+        }
+        transaction.commit()?;
+        Ok(id)
+    }
+
+    fn archive_of_row(row: &rusqlite::Row) -> rusqlite::Result<Archive> {
+        Ok(Archive {
+            id: row.get(0)?,
+            tape: row.get(1)?,
+            tape_file_index: row.get(2)?,
+            size: row.get(3)?,
+            hash: row.get(4)?,
+            ts: row.get(5)?,
+            flag: row.get(6)?,
+            compression_ratio: row.get(7)?,
+            copy_of: row.get(8)?,
+            key_id: row.get(9)?,
+        })
+    }
+
+    /// The last archive written as file `tape_file_index` of `tape`.
+    pub fn archive_at(&self, tape: u8, tape_file_index: u32) -> Result<Option<Archive>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, tape_id, tape_file_index, size, hash, ts, flag, compression_ratio, copy_of, key_id FROM archive
+            WHERE tape_id = ?1 AND tape_file_index = ?2 ORDER BY id DESC LIMIT 1;",
+        )?;
+        let mut rows = statement.query_map((tape, tape_file_index), Self::archive_of_row)?;
+        rows.next().transpose().map_err(Into::into)
+    }
+
+    /// All archives, in the order they were written.
+    pub fn archives(&self) -> Result<Vec<Archive>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, tape_id, tape_file_index, size, hash, ts, flag, compression_ratio, copy_of, key_id
+            FROM archive ORDER BY id;",
+        )?;
+        let archives = statement
+            .query_map([], Self::archive_of_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(archives)
+    }
+
+    /// The last version of each source file recorded, by path. With `tape`, only files with a copy on that tape.
+    pub fn recorded_files(&self, tape: Option<u8>) -> Result<Vec<RecordedFile>> {
+        let mut statement = self.conn.prepare(
+            "SELECT a.id, a.tape_id, a.tape_file_index, a.size, a.hash, a.ts, a.flag, a.compression_ratio, a.copy_of,
+                a.key_id, f.path, f.mtime
+            FROM file f JOIN archive a ON a.id = f.archive
+            WHERE f.id IN (
+                SELECT MAX(f.id) FROM file f JOIN archive a ON a.id = f.archive
+                WHERE ?1 IS NULL OR a.tape_id = ?1
+                    OR EXISTS (SELECT 1 FROM archive m WHERE m.copy_of = a.id AND m.tape_id = ?1)
+                GROUP BY f.path
+            )
+            ORDER BY f.path;",
+        )?;
+        let files = statement
+            .query_map([tape], |row| {
+                Ok(RecordedFile {
+                    path: PathBuf::from(row.get::<_, String>(10)?),
+                    mtime: row.get(11)?,
+                    archive: Self::archive_of_row(row)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    /// Archives and bytes of each tape, with the average compression ratio of the archives that have one.
+    pub fn tape_usage(&self) -> Result<Vec<TapeUsage>> {
+        let mut statement = self.conn.prepare(
+            "SELECT tape.id, CAST(tape.description AS TEXT), COUNT(archive.id), IFNULL(SUM(archive.size), 0),
+                SUM(archive.size * archive.compression_ratio)
+                    / SUM(CASE WHEN archive.compression_ratio IS NULL THEN NULL ELSE archive.size END)
+            FROM tape LEFT JOIN archive ON archive.tape_id = tape.id
+            GROUP BY tape.id ORDER BY tape.id;",
+        )?;
+        let usage = statement
+            .query_map([], |row| {
+                Ok(TapeUsage {
+                    tape: row.get(0)?,
+                    description: row.get(1)?,
+                    archives: row.get(2)?,
+                    bytes: row.get(3)?,
+                    compression_ratio: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(usage)
+    }
+
+    /// Add tape `id` unless it is known.
+    pub fn ensure_tape(&self, id: u8, description: &str) -> Result<()> {
         self.conn
             .execute(
-                "INSERT INTO archive
-            (tape, tape_file_index, size, hash, ts, flag)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
-                (
-                    archive.tape,
-                    archive.tape_file_index,
-                    archive.size,
-                    archive.hash,
-                    archive.ts,
-                    archive.flag,
-                ),
+                "INSERT OR IGNORE INTO tape
+            (id, flag, description)
+            VALUES (?1, 0, ?2);",
+                (id, description),
             )
             .map(|_| ())
             .map_err(Into::into)
     }
 
+    /// Register a key by its fingerprint, returns its id. A key already known keeps its description.
+    pub fn add_key(&self, description: &str, fingerprint: &str) -> Result<u32> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        self.conn
+            .execute(
+                "INSERT INTO key (ts, description, fingerprint) VALUES (?1, ?2, ?3)
+                ON CONFLICT (fingerprint) DO NOTHING;",
+                (ts, description, fingerprint),
+            )
+            .with_context(|| format!("unable to register the key '{description}'."))?;
+        let id = self
+            .conn
+            .query_row("SELECT id FROM key WHERE fingerprint = ?1;", [fingerprint], |row| row.get(0))?;
+        Ok(id)
+    }
+
+    /// Record that the archives of `tape` are encrypted with key `key_id`, including those already written.
+    pub fn set_tape_key(&self, tape: u8, key_id: u32) -> Result<()> {
+        let updated = self
+            .conn
+            .execute("UPDATE tape SET key_id = ?1 WHERE id = ?2;", (key_id, tape))?;
+        if updated == 0 {
+            anyhow::bail!("no tape {tape} in the catalog.");
+        }
+        Ok(())
+    }
+
+    /// Key `archive` is encrypted with, its own or the one of its tape, `None` if it is not encrypted.
+    pub fn key_of_archive(&self, archive: &Archive) -> Result<Option<Key>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, ts, description, fingerprint FROM key
+            WHERE id = IFNULL(?1, (SELECT key_id FROM tape WHERE tape.id = ?2));",
+        )?;
+        let mut rows = statement.query_map((archive.key_id, archive.tape), |row| {
+            Ok(Key {
+                id: row.get(0)?,
+                ts: row.get(1)?,
+                description: row.get(2)?,
+                fingerprint: row.get(3)?,
+            })
+        })?;
+        rows.next().transpose().map_err(Into::into)
+    }
+
+    /// All keys, with the tapes and archives encrypted with each.
+    pub fn keys(&self) -> Result<Vec<KeyUsage>> {
+        let mut statement = self.conn.prepare(
+            "SELECT key.id, key.ts, key.description, key.fingerprint,
+                (SELECT COUNT(*) FROM tape WHERE tape.key_id = key.id),
+                (SELECT COUNT(*) FROM archive JOIN tape ON tape.id = archive.tape_id
+                    WHERE IFNULL(archive.key_id, tape.key_id) = key.id)
+            FROM key ORDER BY key.id;",
+        )?;
+        let keys = statement
+            .query_map([], |row| {
+                Ok(KeyUsage {
+                    key: Key {
+                        id: row.get(0)?,
+                        ts: row.get(1)?,
+                        description: row.get(2)?,
+                        fingerprint: row.get(3)?,
+                    },
+                    tapes: row.get(4)?,
+                    archives: row.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(keys)
+    }
+
+    /// Record that `archive` was read back and matched its hash or not, `sampled` if picked by a spot check.
+    pub fn record_verification(&self, archive: u32, ok: bool, sampled: bool) -> Result<()> {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        self.conn.execute(
+            "INSERT INTO verification (archive_id, ts, ok, sampled) VALUES (?1, ?2, ?3, ?4);",
+            (archive, ts, ok, sampled),
+        )?;
+        Ok(())
+    }
+
+    /// Ids of archives verified at least once, whatever the result.
+    pub fn verified_archives(&self) -> Result<HashSet<u32>> {
+        let mut statement = self.conn.prepare("SELECT DISTINCT archive_id FROM verification;")?;
+        let ids = statement.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    /// Tapes on which a verification failed.
+    pub fn tapes_with_failures(&self) -> Result<HashSet<u8>> {
+        let mut statement = self.conn.prepare(
+            "SELECT DISTINCT archive.tape_id FROM verification JOIN archive ON archive.id = verification.archive_id
+            WHERE NOT verification.ok;",
+        )?;
+        let tapes = statement.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+        Ok(tapes)
+    }
+
+    /// Size of the database, with the rows of each table and the bytes they take from `dbstat`.
+    pub fn size(&self) -> Result<DatabaseSize> {
+        let pragma =
+            |name: &str| -> Result<u64> { Ok(self.conn.query_row(&format!("PRAGMA {name};"), [], |row| row.get(0))?) };
+        let file_bytes = match self.conn.path().filter(|path| !path.is_empty()) {
+            Some(path) => std::fs::metadata(path)?.len(),
+            None => 0,
+        };
+        let mut statement = self.conn.prepare(
+            "SELECT table_schema.name, (SELECT IFNULL(SUM(pgsize), 0) FROM dbstat
+                WHERE dbstat.name IN (SELECT name FROM sqlite_schema WHERE tbl_name = table_schema.name))
+            FROM sqlite_schema AS table_schema
+            WHERE table_schema.type = 'table' AND table_schema.name NOT LIKE 'sqlite_%'
+            ORDER BY table_schema.name;",
+        )?;
+        let tables = statement
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?
+            .map(|table| {
+                let (name, bytes) = table?;
+                let quoted = name.replace('"', "\"\"");
+                let rows = self
+                    .conn
+                    .query_row(&format!("SELECT COUNT(*) FROM \"{quoted}\";"), [], |row| row.get(0))?;
+                Ok(TableSize { name, rows, bytes })
+            })
+            .collect::<Result<_>>()?;
+        Ok(DatabaseSize {
+            file_bytes,
+            page_size: pragma("page_size")?,
+            pages: pragma("page_count")?,
+            free_pages: pragma("freelist_count")?,
+            tables,
+        })
+    }
+
+    /// Problems found by `PRAGMA integrity_check`, none if the database is sound.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        let mut statement = self.conn.prepare("PRAGMA integrity_check;")?;
+        let mut problems = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        problems.retain(|problem| problem != "ok");
+        Ok(problems)
+    }
+
+    /// Refresh the statistics of the query planner, and with `vacuum`, rewrite the file without its free pages.
+    pub fn optimize(&self, vacuum: bool) -> Result<()> {
+        self.conn.execute_batch("PRAGMA optimize; ANALYZE;")?;
+        if vacuum {
+            // VACUUM 会重写整个文件, 需要与数据库同样大小的空闲空间
+            self.conn.execute_batch("VACUUM;")?;
+        }
+        Ok(())
+    }
+
     pub fn create_tape(&self, flag: u32, description: &str) -> Result<()> {
         self.conn
             .execute(
@@ -117,3 +537,222 @@ impl Storage {
             .map_err(Into::into)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Archive, FileOnDisk, Storage, ARCHIVE_TORN};
+
+    #[test]
+    fn test_append_archive() {
+        let path = std::env::temp_dir().join(format!("backup-db-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::new(&path).unwrap();
+        storage.ensure_tape(0, "unlabeled").unwrap();
+        storage.ensure_tape(0, "unlabeled").unwrap();
+        storage.append_archive(&Archive::new(0, 1, 512, [7; 32])).unwrap();
+        storage
+            .append_archive(&Archive::new(0, 2, 1024, [8; 32]).flag(ARCHIVE_TORN))
+            .unwrap();
+
+        let rows: Vec<(u32, u32, u32)> = storage
+            .conn
+            .prepare("SELECT id, tape_file_index, size FROM archive ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows, [(1, 1, 512), (2, 2, 1024)]);
+
+        let archive = storage.archive_at(0, 2).unwrap().unwrap();
+        assert!(archive.is_torn());
+        assert_eq!(archive.hash, [8; 32]);
+        assert!(!storage.archive_at(0, 1).unwrap().unwrap().is_torn());
+        assert!(storage.archive_at(0, 3).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compression_ratio() {
+        let path = std::env::temp_dir().join(format!("backup-db-ratio-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::new(&path).unwrap();
+        storage.ensure_tape(0, "unlabeled").unwrap();
+        storage.ensure_tape(1, "offsite").unwrap();
+        storage
+            .append_archive(&Archive::new(0, 0, 1000, [0; 32]).compression_ratio(Some(2.0)))
+            .unwrap();
+        storage
+            .append_archive(&Archive::new(0, 1, 3000, [0; 32]).compression_ratio(Some(1.0)))
+            .unwrap();
+        // 驱动器不报告时为 NULL, 不计入平均值
+        storage.append_archive(&Archive::new(0, 2, 5000, [0; 32])).unwrap();
+        drop(storage);
+
+        // 再次打开时不重复迁移
+        let storage = Storage::new(&path).unwrap();
+        let ratios: Vec<_> = storage.archives().unwrap().iter().map(|a| a.compression_ratio).collect();
+        assert_eq!(ratios, [Some(2.0), Some(1.0), None]);
+        let usage = storage.tape_usage().unwrap();
+        assert_eq!((usage[0].archives, usage[0].bytes), (3, 9000));
+        assert_eq!(usage[0].compression_ratio, Some(1.25));
+        assert_eq!((usage[1].description.as_str(), usage[1].archives), ("offsite", 0));
+        assert_eq!(usage[1].compression_ratio, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_append_archive_copies() {
+        let path = std::env::temp_dir().join(format!("backup-db-copies-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::new(&path).unwrap();
+        storage.ensure_tape(0, "unlabeled").unwrap();
+        storage.ensure_tape(1, "unlabeled mirror").unwrap();
+        storage.append_archive(&Archive::new(0, 0, 512, [1; 32])).unwrap();
+        let id = storage
+            .append_archive_copies(vec![Archive::new(0, 1, 512, [2; 32]), Archive::new(1, 0, 512, [2; 32])])
+            .unwrap();
+        assert_eq!(id, 2);
+        let mirror = storage.archive_at(1, 0).unwrap().unwrap();
+        assert_eq!((mirror.id, mirror.copy_of), (3, Some(2)));
+        assert_eq!(storage.archive_at(0, 1).unwrap().unwrap().copy_of, None);
+
+        // 磁带未知时整体回滚
+        assert!(storage
+            .append_archive_copies(vec![Archive::new(0, 2, 512, [3; 32]), Archive::new(9, 0, 512, [3; 32])])
+            .is_err());
+        assert!(storage.archive_at(0, 2).unwrap().is_none());
+        assert!(storage.append_archive_copies(Vec::new()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keys() {
+        let path = std::env::temp_dir().join(format!("backup-db-keys-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::new(&path).unwrap();
+        storage.ensure_tape(0, "unlabeled").unwrap();
+        storage.ensure_tape(1, "offsite").unwrap();
+        storage.append_archive(&Archive::new(0, 0, 512, [0; 32])).unwrap();
+        storage.append_archive(&Archive::new(1, 0, 512, [0; 32])).unwrap();
+
+        let offsite = storage.add_key("offsite-2023", "ab12").unwrap();
+        // 同一密钥不重复登记
+        assert_eq!(storage.add_key("again", "ab12").unwrap(), offsite);
+        let other = storage.add_key("spare", "cd34").unwrap();
+        assert!(storage.add_key("spare", "ef56").is_err());
+
+        // 已写入的归档随磁带使用密钥, 归档自己的密钥优先
+        storage.set_tape_key(1, offsite).unwrap();
+        assert!(storage.set_tape_key(7, offsite).is_err());
+        storage
+            .append_archive(&Archive {
+                key_id: Some(other),
+                ..Archive::new(1, 1, 512, [0; 32])
+            })
+            .unwrap();
+        let archives = storage.archives().unwrap();
+        let keys: Vec<_> = archives
+            .iter()
+            .map(|a| storage.key_of_archive(a).unwrap().map(|key| key.description))
+            .collect();
+        assert_eq!(keys, [None, Some("offsite-2023".to_string()), Some("spare".to_string())]);
+
+        let usage: Vec<_> = storage
+            .keys()
+            .unwrap()
+            .into_iter()
+            .map(|usage| (usage.key.description, usage.tapes, usage.archives))
+            .collect();
+        assert_eq!(usage, [("offsite-2023".to_string(), 1, 1), ("spare".to_string(), 0, 1)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_verifications() {
+        let path = std::env::temp_dir().join(format!("backup-db-verify-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::new(&path).unwrap();
+        storage.ensure_tape(0, "unlabeled").unwrap();
+        storage.ensure_tape(1, "offsite").unwrap();
+        let first = storage.append_archive(&Archive::new(0, 0, 512, [0; 32])).unwrap();
+        let second = storage.append_archive(&Archive::new(1, 0, 512, [0; 32])).unwrap();
+        storage.append_archive(&Archive::new(1, 1, 512, [0; 32])).unwrap();
+        assert!(storage.verified_archives().unwrap().is_empty());
+
+        storage.record_verification(first, true, false).unwrap();
+        storage.record_verification(second, false, true).unwrap();
+        storage.record_verification(second, true, true).unwrap();
+        assert_eq!(storage.verified_archives().unwrap(), [first, second].into());
+        // 失败过一次即标记磁带
+        assert_eq!(storage.tapes_with_failures().unwrap(), [1].into());
+        let sampled: u32 = storage
+            .conn
+            .query_row("SELECT COUNT(*) FROM verification WHERE sampled;", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(sampled, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recorded_files() {
+        let path = std::env::temp_dir().join(format!("backup-db-files-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::new(&path).unwrap();
+        storage.ensure_tape(0, "unlabeled").unwrap();
+        storage.ensure_tape(1, "unlabeled mirror").unwrap();
+        let first = storage
+            .append_archive_copies(vec![Archive::new(0, 0, 3, [1; 32]), Archive::new(1, 0, 3, [1; 32])])
+            .unwrap();
+        let second = storage.append_archive(&Archive::new(0, 1, 5, [2; 32])).unwrap();
+        let third = storage.append_archive(&Archive::new(0, 2, 7, [3; 32])).unwrap();
+        storage.append_file(&FileOnDisk::new(1, "/data/a", first, Some(100))).unwrap();
+        storage.append_file(&FileOnDisk::new(2, "/data/b", second, None)).unwrap();
+        // 再次备份的 b 取代之前的版本
+        storage.append_file(&FileOnDisk::new(3, "/data/b", third, Some(300))).unwrap();
+
+        let files = storage.recorded_files(None).unwrap();
+        let summary = files
+            .iter()
+            .map(|file| (file.path.to_str().unwrap(), file.mtime, file.archive.size))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, [("/data/a", Some(100), 3), ("/data/b", Some(300), 7)]);
+        assert_eq!(files[0].archive.hash, [1; 32]);
+        // 镜像磁带上只有 a 的副本
+        let mirrored = storage.recorded_files(Some(1)).unwrap();
+        assert_eq!(mirrored.len(), 1);
+        assert_eq!(mirrored[0].archive.id, first);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let path = std::env::temp_dir().join(format!("backup-db-maintain-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = Storage::new(&path).unwrap();
+        storage.ensure_tape(0, "unlabeled").unwrap();
+        for i in 0..2000 {
+            storage.append_archive(&Archive::new(0, i, 512, [0; 32])).unwrap();
+        }
+        storage.conn.execute("DELETE FROM archive WHERE id > 10;", []).unwrap();
+        assert!(storage.integrity_check().unwrap().is_empty());
+
+        let before = storage.size().unwrap();
+        assert_eq!(before.file_bytes, before.pages * before.page_size);
+        assert!(before.free_pages > 0);
+        let archive = before.tables.iter().find(|table| table.name == "archive").unwrap();
+        assert_eq!(archive.rows, 10);
+        assert!(archive.bytes > 0);
+        assert_eq!(before.tables.iter().find(|table| table.name == "tape").unwrap().rows, 1);
+
+        storage.optimize(false).unwrap();
+        assert_eq!(storage.size().unwrap().pages, before.pages);
+        storage.optimize(true).unwrap();
+        let after = storage.size().unwrap();
+        assert_eq!(after.free_pages, 0);
+        assert!(after.file_bytes < before.file_bytes);
+        // ANALYZE 建立的 sqlite_stat1 不列出
+        assert!(after.tables.iter().all(|table| !table.name.starts_with("sqlite_")));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+//! Stopping a backup on SIGINT or SIGTERM without leaving an unterminated archive on the tape.
+//!
+//! The first signal asks the job to stop: no new archive is started, the one being written is finished, closed with
+//! its filemark and committed. A second signal aborts before the next block, leaving that archive uncommitted.
+
+use anyhow::Result;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::SigId;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// What the job is asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Run,
+    /// Finish the archive being written, and start no other
+    Finish,
+    /// Stop now
+    Abort,
+}
+
+/// Cancellation token of a job, shared by the reader and the writer, and set by signals once installed.
+#[derive(Clone, Default)]
+pub struct Interrupt {
+    signals: Arc<AtomicUsize>,
+    handlers: Arc<Vec<SigId>>,
+}
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count SIGINT and SIGTERM received from now on, until the last clone is dropped.
+    pub fn install(&mut self) -> Result<()> {
+        let mut handlers = Vec::new();
+        for signal in [SIGINT, SIGTERM] {
+            let signals = self.signals.clone();
+            // 处理函数只做原子加法, 可在信号上下文中执行
+            let id = unsafe {
+                signal_hook::low_level::register(signal, move || {
+                    signals.fetch_add(1, Ordering::SeqCst);
+                })
+            }?;
+            handlers.push(id);
+        }
+        self.handlers = Arc::new(handlers);
+        Ok(())
+    }
+
+    pub fn request(&self) -> Request {
+        match self.signals.load(Ordering::SeqCst) {
+            0 => Request::Run,
+            1 => Request::Finish,
+            _ => Request::Abort,
+        }
+    }
+}
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        if let Some(handlers) = Arc::get_mut(&mut self.handlers) {
+            for id in handlers.drain(..) {
+                signal_hook::low_level::unregister(id);
+            }
+        }
+    }
+}
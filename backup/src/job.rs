@@ -0,0 +1,653 @@
+//! The writer of a backup job: archives received from the reader are written to the tape, each closed with a filemark,
+//! and committed to the catalog.
+//!
+//! With a mirror, every block is written to both tapes one after the other, the drives buffer them. Each copy keeps
+//! its own file numbers and counters, and a failure, such as running out of tape, is its own.
+
+use crate::db::{Archive, FileOnDisk, Storage, ARCHIVE_TORN};
+use crate::interrupt::{Interrupt, Request};
+use crate::journal::{JobState, Journal};
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tape::device::CompressionCounters;
+
+/// Tape ids of the archives in the catalog, tapes are not labeled yet
+pub const UNLABELED_TAPE: u8 = 0;
+pub const UNLABELED_MIRROR_TAPE: u8 = 1;
+
+/// What the reader sends to the writer.
+#[derive(Debug)]
+pub enum Message {
+    Block(Vec<u8>),
+    /// The archive is complete, with the source file it holds if any
+    EndOfArchive(Option<Source>),
+    /// Close the archive without committing it, its source changed and is read again
+    Discard,
+}
+
+/// A source file archived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub path: PathBuf,
+    /// It changed while read, and was not read again
+    pub torn: bool,
+    pub inode: u64,
+    /// Last modification before it was read, in seconds since epoch
+    pub mtime: Option<i64>,
+}
+
+/// Where archives are written, the tape.
+pub trait ArchiveSink {
+    /// Tape file the next archive starts
+    fn file_number(&mut self) -> Result<u32>;
+    fn write_block(&mut self, block: &[u8]) -> Result<()>;
+    fn write_filemark(&mut self) -> Result<()>;
+    /// Compression counters of the drive, `None` if it does not report them
+    fn compression_counters(&mut self) -> Result<Option<CompressionCounters>> {
+        Ok(None)
+    }
+}
+
+/// A tape the archives are written to, the primary one or a mirror.
+pub struct Copy<'a> {
+    /// Device, for messages
+    pub name: String,
+    /// Tape id in the catalog
+    pub tape: u8,
+    sink: &'a mut dyn ArchiveSink,
+    /// A failure stops this copy only, as long as another one is written
+    best_effort: bool,
+    error: Option<String>,
+    bytes: u64,
+    /// Time spent writing
+    busy: Duration,
+}
+
+impl<'a> Copy<'a> {
+    pub fn new(name: impl Into<String>, tape: u8, sink: &'a mut dyn ArchiveSink) -> Self {
+        Self {
+            name: name.into(),
+            tape,
+            sink,
+            best_effort: false,
+            error: None,
+            bytes: 0,
+            busy: Duration::ZERO,
+        }
+    }
+
+    pub fn best_effort(mut self, best_effort: bool) -> Self {
+        self.best_effort = best_effort;
+        self
+    }
+}
+
+/// How a copy went.
+#[derive(Debug)]
+pub struct CopyReport {
+    pub name: String,
+    pub bytes: u64,
+    pub busy: Duration,
+    /// Why the copy stopped before the end of the job
+    pub error: Option<String>,
+}
+
+impl CopyReport {
+    /// Bytes written per second of writing.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.busy.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Where a copy of an archive is on tape.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub tape: u8,
+    pub file: u32,
+    pub compression_ratio: Option<f64>,
+}
+
+/// An archive closed with its filemark, on the tapes of its placements, the primary one first.
+#[derive(Debug)]
+pub struct WrittenArchive {
+    pub placements: Vec<Placement>,
+    pub bytes: u64,
+    pub hash: blake3::Hash,
+    pub torn: bool,
+    /// Source file it holds, recorded so restored files can be audited
+    pub source: Option<Source>,
+}
+
+/// Where written archives are recorded, the database.
+pub trait Catalog {
+    fn commit(&mut self, archive: &WrittenArchive) -> Result<()>;
+}
+
+impl Catalog for Storage {
+    fn commit(&mut self, archive: &WrittenArchive) -> Result<()> {
+        let size = u32::try_from(archive.bytes).context("the archive is too large for the catalog.")?;
+        let flag = if archive.torn { ARCHIVE_TORN } else { 0 };
+        let mut rows = Vec::new();
+        for placement in &archive.placements {
+            let description = match placement.tape {
+                UNLABELED_TAPE => "unlabeled",
+                _ => "unlabeled mirror",
+            };
+            self.ensure_tape(placement.tape, description)?;
+            rows.push(
+                Archive::new(placement.tape, placement.file, size, *archive.hash.as_bytes())
+                    .flag(flag)
+                    .compression_ratio(placement.compression_ratio),
+            );
+        }
+        let id = self.append_archive_copies(rows)?;
+        if let Some(source) = &archive.source {
+            // 记录绝对路径, 恢复后按此在恢复目录下查找
+            let path = std::path::absolute(&source.path).unwrap_or_else(|_| source.path.clone());
+            self.append_file(&FileOnDisk::new(source.inode, &path.to_string_lossy(), id, source.mtime))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct JobReport {
+    pub committed: u32,
+    pub bytes: u64,
+    /// `Completed`, `InterruptedCleanly` or `Aborted`
+    pub state: JobState,
+    /// Sources that changed while archived, to back up again
+    pub torn: Vec<PathBuf>,
+    pub copies: Vec<CopyReport>,
+}
+
+/// Archive being written.
+struct Pending {
+    /// File and counters before the first block on each copy, `None` for copies stopped before the archive
+    starts: Vec<Option<(u32, Option<CompressionCounters>)>>,
+    bytes: u64,
+    hasher: blake3::Hasher,
+}
+
+/// Run `operation` on each copy still written. A failed copy is stopped if best effort, else the job fails.
+fn on_copies<T>(
+    copies: &mut [Copy],
+    mut operation: impl FnMut(&mut dyn ArchiveSink) -> Result<T>,
+) -> Result<Vec<Option<T>>> {
+    let mut results = Vec::with_capacity(copies.len());
+    for copy in copies.iter_mut() {
+        if copy.error.is_some() {
+            results.push(None);
+            continue;
+        }
+        let start = Instant::now();
+        let result = operation(&mut *copy.sink);
+        copy.busy += start.elapsed();
+        match result {
+            Ok(value) => results.push(Some(value)),
+            Err(e) if copy.best_effort => {
+                tracing::error!(device = copy.name, "{e:#}, this copy is stopped");
+                copy.error = Some(format!("{e:#}"));
+                results.push(None);
+            }
+            Err(e) => return Err(e.context(format!("writing to {} failed", copy.name))),
+        }
+    }
+    if copies.iter().all(|copy| copy.error.is_some()) {
+        bail!(
+            "every copy failed, the last on {}.",
+            copies.last().map_or("", |copy| &copy.name)
+        );
+    }
+    Ok(results)
+}
+
+/// Write the archives of `messages` to every copy, committing each one once its filemarks are written. A request to
+/// finish stops the job after the archive being written, an abort before the next block.
+pub fn write_copies<C: Catalog>(
+    copies: &mut [Copy],
+    catalog: &mut C,
+    journal: &Journal,
+    interrupt: &Interrupt,
+    messages: impl IntoIterator<Item = Message>,
+) -> Result<JobReport> {
+    let mut report = JobReport {
+        committed: 0,
+        bytes: 0,
+        state: JobState::Completed,
+        torn: Vec::new(),
+        copies: Vec::new(),
+    };
+    let mut pending: Option<Pending> = None;
+    journal.record(JobState::Running, 0, None)?;
+
+    for message in messages {
+        match interrupt.request() {
+            Request::Abort => {
+                let file = pending
+                    .as_ref()
+                    .and_then(|archive| archive.starts.iter().flatten().next())
+                    .map(|start| start.0);
+                journal.record(JobState::Aborted, report.committed, file)?;
+                tracing::warn!(uncommitted = file, "aborted by a second signal");
+                report.state = JobState::Aborted;
+                break;
+            }
+            // 不再开始新的归档
+            Request::Finish if pending.is_none() => {
+                report.state = JobState::InterruptedCleanly;
+                break;
+            }
+            _ => {}
+        }
+        match message {
+            Message::Block(block) => {
+                let archive = match &mut pending {
+                    Some(archive) => archive,
+                    None => {
+                        let starts = on_copies(copies, |sink| Ok((sink.file_number()?, sink.compression_counters()?)))?;
+                        let file = starts.iter().flatten().next().map(|start| start.0);
+                        journal.record(JobState::Running, report.committed, file)?;
+                        pending.insert(Pending {
+                            starts,
+                            bytes: 0,
+                            hasher: blake3::Hasher::new(),
+                        })
+                    }
+                };
+                let written = on_copies(copies, |sink| sink.write_block(&block))?;
+                for (copy, written) in copies.iter_mut().zip(written) {
+                    if written.is_some() {
+                        copy.bytes += block.len() as u64;
+                    }
+                }
+                archive.bytes += block.len() as u64;
+                archive.hasher.update(&block);
+                report.bytes += block.len() as u64;
+            }
+            Message::Discard => {
+                // 以文件标记结束被丢弃的磁带文件, 不提交
+                if let Some(archive) = pending.take() {
+                    on_copies(copies, |sink| sink.write_filemark())?;
+                    journal.record(JobState::Running, report.committed, None)?;
+                    let file = archive.starts.iter().flatten().next().map(|start| start.0);
+                    tracing::warn!(file, "archive discarded, its source is read again");
+                }
+            }
+            Message::EndOfArchive(source) => {
+                // 空归档不占用磁带文件
+                let Some(archive) = pending.take() else {
+                    continue;
+                };
+                // 文件标记使缓冲的数据写入介质, 之后计数才完整
+                let ends = on_copies(copies, |sink| {
+                    sink.write_filemark()?;
+                    sink.compression_counters()
+                })?;
+                let placements: Vec<Placement> = copies
+                    .iter()
+                    .zip(archive.starts)
+                    .zip(ends)
+                    .filter_map(|((copy, start), end)| {
+                        // 归档开始后停止的副本不完整
+                        let (file, before) = start?;
+                        let after = end?;
+                        Some(Placement {
+                            tape: copy.tape,
+                            file,
+                            compression_ratio: before.zip(after).and_then(|(before, after)| after.ratio_since(&before)),
+                        })
+                    })
+                    .collect();
+                let torn = source.as_ref().is_some_and(|source| source.torn);
+                let written = WrittenArchive {
+                    placements,
+                    bytes: archive.bytes,
+                    hash: archive.hasher.finalize(),
+                    torn,
+                    source,
+                };
+                let file = written.placements.first().map(|placement| placement.file);
+                if written.placements.is_empty() {
+                    bail!("no copy of the archive was written completely.");
+                }
+                catalog
+                    .commit(&written)
+                    .with_context(|| format!("unable to commit the archive of tape file {}.", file.unwrap_or(0)))?;
+                report.committed += 1;
+                journal.record(JobState::Running, report.committed, None)?;
+                tracing::info!(
+                    file,
+                    copies = written.placements.len(),
+                    bytes = written.bytes,
+                    torn,
+                    compression_ratio = written.placements[0].compression_ratio,
+                    "archive committed"
+                );
+                if let Some(source) = written.source.filter(|source| source.torn) {
+                    report.torn.push(source.path);
+                }
+                // 读取端收到请求后不再发送, 通道可能就此关闭
+                if interrupt.request() != Request::Run {
+                    report.state = JobState::InterruptedCleanly;
+                    break;
+                }
+            }
+        }
+    }
+    report.copies = copies
+        .iter()
+        .map(|copy| CopyReport {
+            name: copy.name.clone(),
+            bytes: copy.bytes,
+            busy: copy.busy,
+            error: copy.error.clone(),
+        })
+        .collect();
+    if report.state == JobState::Aborted {
+        return Ok(report);
+    }
+    if let Some(archive) = pending {
+        let file = archive.starts.iter().flatten().next().map_or(0, |start| start.0);
+        bail!("the reader stopped in the middle of the archive of tape file {file}.");
+    }
+    journal.record(report.state, report.committed, None)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_copies, ArchiveSink, Catalog, Copy, JobReport, Message, Source, WrittenArchive, UNLABELED_TAPE};
+    use crate::interrupt::Interrupt;
+    use crate::journal::{JobState, Journal};
+    use anyhow::{bail, Result};
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use tape::device::CompressionCounters;
+
+    /// Records written, `None` for a filemark. Signals are raised when the given numbers of blocks are written.
+    #[derive(Default)]
+    struct FakeTape {
+        records: Vec<Option<Vec<u8>>>,
+        blocks: usize,
+        signals: Vec<(usize, i32)>,
+        /// Report counters of a drive halving what it writes, at filemarks
+        compressing: bool,
+        /// Blocks written before the end of the tape
+        capacity: Option<usize>,
+    }
+
+    impl ArchiveSink for FakeTape {
+        fn file_number(&mut self) -> Result<u32> {
+            Ok(self.records.iter().filter(|record| record.is_none()).count() as u32)
+        }
+
+        fn write_block(&mut self, block: &[u8]) -> Result<()> {
+            if self.capacity == Some(self.blocks) {
+                bail!("end of tape");
+            }
+            self.records.push(Some(block.to_vec()));
+            self.blocks += 1;
+            for (_, signal) in self.signals.iter().filter(|(after, _)| *after == self.blocks) {
+                signal_hook::low_level::raise(*signal)?;
+            }
+            Ok(())
+        }
+
+        fn write_filemark(&mut self) -> Result<()> {
+            self.records.push(None);
+            Ok(())
+        }
+
+        fn compression_counters(&mut self) -> Result<Option<CompressionCounters>> {
+            let bytes: usize = self.records.iter().flatten().map(Vec::len).sum();
+            let flushed = match self.records.iter().rposition(Option::is_none) {
+                Some(mark) => self.records[..mark].iter().flatten().map(Vec::len).sum(),
+                None => 0,
+            };
+            Ok(self.compressing.then_some(CompressionCounters {
+                bytes_from_host: bytes as u64,
+                bytes_to_medium: flushed as u64 / 2,
+            }))
+        }
+    }
+
+    /// Compression ratios committed
+    #[derive(Default)]
+    struct Ratios(Vec<Option<f64>>);
+
+    impl Catalog for Ratios {
+        fn commit(&mut self, archive: &WrittenArchive) -> Result<()> {
+            self.0.push(archive.placements[0].compression_ratio);
+            Ok(())
+        }
+    }
+
+    impl Catalog for Vec<u32> {
+        fn commit(&mut self, archive: &WrittenArchive) -> Result<()> {
+            self.push(archive.placements[0].file);
+            Ok(())
+        }
+    }
+
+    /// Write to a single tape.
+    fn write_archives(
+        tape: &mut FakeTape,
+        catalog: &mut impl Catalog,
+        journal: &Journal,
+        interrupt: &Interrupt,
+        messages: Vec<Message>,
+    ) -> Result<JobReport> {
+        write_copies(
+            &mut [Copy::new("sa0", UNLABELED_TAPE, tape)],
+            catalog,
+            journal,
+            interrupt,
+            messages,
+        )
+    }
+
+    /// Messages of archives of `blocks[i]` blocks of 4 bytes.
+    fn archives(blocks: &[usize]) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for (i, count) in blocks.iter().enumerate() {
+            messages.extend((0..*count).map(|_| Message::Block(vec![i as u8; 4])));
+            messages.push(Message::EndOfArchive(None));
+        }
+        messages
+    }
+
+    fn temp_journal(name: &str) -> Journal {
+        Journal::of_database(&std::env::temp_dir().join(format!("backup-job-{}-{name}.db", std::process::id())))
+    }
+
+    fn read(journal: &Journal) -> String {
+        let text = std::fs::read_to_string(journal.path()).unwrap();
+        std::fs::remove_file(journal.path()).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_write_archives() {
+        let (mut tape, mut catalog, journal) = (FakeTape::default(), Vec::new(), temp_journal("complete"));
+        let report = write_archives(&mut tape, &mut catalog, &journal, &Interrupt::new(), archives(&[2, 0, 1])).unwrap();
+        assert_eq!((report.committed, report.bytes, report.state), (2, 12, JobState::Completed));
+        assert_eq!(catalog, [0, 1]);
+        let marks: Vec<bool> = tape.records.iter().map(Option::is_none).collect();
+        assert_eq!(marks, [false, false, true, false, true]);
+        assert_eq!(journal.state().unwrap(), Some(JobState::Completed));
+        assert_eq!(read(&journal), "state = completed\narchives committed = 2\n");
+        assert_eq!(journal.state().unwrap(), None);
+    }
+
+    #[test]
+    fn test_compression_ratio() {
+        let journal = temp_journal("ratio");
+        let mut catalog = Ratios::default();
+        let mut tape = FakeTape {
+            compressing: true,
+            ..Default::default()
+        };
+        write_archives(&mut tape, &mut catalog, &journal, &Interrupt::new(), archives(&[2, 3])).unwrap();
+        assert_eq!(catalog.0, [Some(2.0), Some(2.0)]);
+
+        // 驱动器不报告计数时不猜测
+        let mut catalog = Ratios::default();
+        write_archives(
+            &mut FakeTape::default(),
+            &mut catalog,
+            &journal,
+            &Interrupt::new(),
+            archives(&[2]),
+        )
+        .unwrap();
+        assert_eq!(catalog.0, [None]);
+        read(&journal);
+    }
+
+    #[test]
+    fn test_torn_sources() {
+        let source = |path: &str, torn| {
+            Some(Source {
+                path: path.into(),
+                torn,
+                inode: 0,
+                mtime: None,
+            })
+        };
+        let messages = vec![
+            Message::Block(vec![1; 4]),
+            Message::Discard,
+            Message::Block(vec![2; 4]),
+            Message::EndOfArchive(source("a", false)),
+            Message::Block(vec![3; 4]),
+            Message::EndOfArchive(source("b", true)),
+        ];
+        let (mut tape, mut catalog, journal) = (FakeTape::default(), Vec::new(), temp_journal("torn"));
+        let report = write_archives(&mut tape, &mut catalog, &journal, &Interrupt::new(), messages).unwrap();
+        // 被丢弃的磁带文件 0 以文件标记结束, 但不提交
+        assert_eq!(catalog, [1, 2]);
+        let marks: Vec<bool> = tape.records.iter().map(Option::is_none).collect();
+        assert_eq!(marks, [false, true, false, true, false, true]);
+        assert_eq!(report.torn, [std::path::PathBuf::from("b")]);
+        assert_eq!(read(&journal), "state = completed\narchives committed = 2\n");
+    }
+
+    #[test]
+    fn test_signals() {
+        // 第一个信号: 写完当前归档后停止
+        let mut interrupt = Interrupt::new();
+        interrupt.install().unwrap();
+        let mut tape = FakeTape {
+            signals: vec![(1, SIGTERM)],
+            ..Default::default()
+        };
+        let (mut catalog, journal) = (Vec::new(), temp_journal("finish"));
+        let report = write_archives(&mut tape, &mut catalog, &journal, &interrupt, archives(&[3, 2])).unwrap();
+        assert_eq!(report.state, JobState::InterruptedCleanly);
+        assert_eq!(catalog, [0]);
+        assert_eq!(tape.records.len(), 4);
+        assert_eq!(tape.records.last(), Some(&None));
+        assert_eq!(read(&journal), "state = interrupted cleanly\narchives committed = 1\n");
+        drop(interrupt);
+
+        // 第二个信号: 立即中止, 当前归档未提交
+        let mut interrupt = Interrupt::new();
+        interrupt.install().unwrap();
+        let mut tape = FakeTape {
+            signals: vec![(3, SIGINT), (4, SIGINT)],
+            ..Default::default()
+        };
+        let (mut catalog, journal) = (Vec::new(), temp_journal("abort"));
+        let report = write_archives(&mut tape, &mut catalog, &journal, &interrupt, archives(&[2, 5])).unwrap();
+        assert_eq!((report.committed, report.state), (1, JobState::Aborted));
+        assert_eq!(catalog, [0]);
+        // 第二个归档写了两块, 没有文件标记
+        assert_eq!(tape.records.len(), 5);
+        assert_eq!(tape.records.last(), Some(&Some(vec![1; 4])));
+        assert_eq!(journal.state().unwrap(), Some(JobState::Aborted));
+        assert_eq!(
+            read(&journal),
+            "state = aborted\narchives committed = 1\nuncommitted archive = tape file 1\n"
+        );
+    }
+
+    /// Tape files of each copy committed
+    #[derive(Default)]
+    struct Placements(Vec<Vec<(u8, u32)>>);
+
+    impl Catalog for Placements {
+        fn commit(&mut self, archive: &WrittenArchive) -> Result<()> {
+            self.0.push(archive.placements.iter().map(|p| (p.tape, p.file)).collect());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mirror() {
+        let (mut primary, mut mirror, journal) = (FakeTape::default(), FakeTape::default(), temp_journal("mirror"));
+        let mut catalog = Placements::default();
+        let mut copies = [Copy::new("sa0", 0, &mut primary), Copy::new("sa1", 1, &mut mirror)];
+        let report = write_copies(&mut copies, &mut catalog, &journal, &Interrupt::new(), archives(&[2, 1])).unwrap();
+        assert_eq!(report.committed, 2);
+        assert_eq!(catalog.0, [[(0, 0), (1, 0)], [(0, 1), (1, 1)]]);
+        assert_eq!(primary.records, mirror.records);
+        let bytes: Vec<u64> = report.copies.iter().map(|copy| copy.bytes).collect();
+        assert_eq!(bytes, [12, 12]);
+
+        // 镜像磁带写满时作业失败
+        let mut primary = FakeTape::default();
+        let mut mirror = FakeTape {
+            capacity: Some(2),
+            ..Default::default()
+        };
+        let mut copies = [Copy::new("sa0", 0, &mut primary), Copy::new("sa1", 1, &mut mirror)];
+        let result = write_copies(
+            &mut copies,
+            &mut Placements::default(),
+            &journal,
+            &Interrupt::new(),
+            archives(&[2, 1]),
+        );
+        assert!(format!("{:#}", result.unwrap_err()).contains("sa1"));
+        read(&journal);
+    }
+
+    #[test]
+    fn test_mirror_best_effort() {
+        let journal = temp_journal("best-effort");
+        let mut primary = FakeTape::default();
+        let mut mirror = FakeTape {
+            capacity: Some(3),
+            ..Default::default()
+        };
+        let mut catalog = Placements::default();
+        let mut copies = [
+            Copy::new("sa0", 0, &mut primary),
+            Copy::new("sa1", 1, &mut mirror).best_effort(true),
+        ];
+        let report = write_copies(&mut copies, &mut catalog, &journal, &Interrupt::new(), archives(&[2, 2, 1])).unwrap();
+        // 镜像在第二个归档中写满, 之后只写主磁带
+        assert_eq!(report.committed, 3);
+        assert_eq!(catalog.0, [vec![(0, 0), (1, 0)], vec![(0, 1)], vec![(0, 2)]]);
+        assert_eq!(report.copies[1].bytes, 12);
+        assert!(report.copies[1].error.as_ref().unwrap().contains("end of tape"));
+        assert!(report.copies[0].error.is_none());
+
+        // 所有副本都失败时作业失败
+        let mut primary = FakeTape {
+            capacity: Some(1),
+            ..Default::default()
+        };
+        let mut copies = [Copy::new("sa0", 0, &mut primary).best_effort(true)];
+        assert!(write_copies(
+            &mut copies,
+            &mut Placements::default(),
+            &journal,
+            &Interrupt::new(),
+            archives(&[2])
+        )
+        .is_err());
+        read(&journal);
+    }
+}
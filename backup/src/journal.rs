@@ -0,0 +1,83 @@
+//! Journal of the last backup job, a small text file next to the database telling how the job ended.
+//!
+//! It is rewritten before each archive is started and after it is committed, so a job killed at any point leaves the
+//! archive it was writing marked uncommitted.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// State of a job, as written in the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Completed,
+    /// Stopped by a signal after committing the archive being written
+    InterruptedCleanly,
+    /// Stopped by a second signal, in the middle of an archive
+    Aborted,
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::InterruptedCleanly => "interrupted cleanly",
+            JobState::Aborted => "aborted",
+        })
+    }
+}
+
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    /// Journal of the database at `database`, with the `journal` extension.
+    pub fn of_database(database: &Path) -> Self {
+        Self {
+            path: database.with_extension("journal"),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// State of the last job, `None` if no job was recorded.
+    pub fn state(&self) -> Result<Option<JobState>> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("unable to read the journal {}.", self.path.display())),
+        };
+        let states = [
+            JobState::Running,
+            JobState::Completed,
+            JobState::InterruptedCleanly,
+            JobState::Aborted,
+        ];
+        let state = text
+            .lines()
+            .find_map(|line| line.strip_prefix("state = "))
+            .and_then(|name| states.into_iter().find(|state| state.to_string() == name));
+        match state {
+            Some(state) => Ok(Some(state)),
+            None => anyhow::bail!("no job state in the journal {}.", self.path.display()),
+        }
+    }
+
+    /// Record the state of the job, the archives committed so far, and the tape file of the archive being written.
+    pub fn record(&self, state: JobState, committed: u32, uncommitted: Option<u32>) -> Result<()> {
+        let mut text = format!("state = {state}\narchives committed = {committed}\n");
+        if let Some(file) = uncommitted {
+            text += &format!("uncommitted archive = tape file {file}\n");
+        }
+        // 先写临时文件再改名, 中途被杀也不会留下半个日志
+        let temporary = self.path.with_extension("journal.tmp");
+        std::fs::write(&temporary, text)
+            .and_then(|_| std::fs::rename(&temporary, &self.path))
+            .with_context(|| format!("unable to write the journal {}.", self.path.display()))
+    }
+}
@@ -0,0 +1,85 @@
+//! Keys of encrypted archives, known to the catalog by a fingerprint of their material, never the material itself.
+//!
+//! An archive records the key it was encrypted with, or inherits the key of its tape. Restoring or verifying it needs
+//! the keyfile with the matching fingerprint.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/// Context of the key derivation giving fingerprints, fixed so they stay comparable
+const FINGERPRINT_CONTEXT: &str = "nas-toolbox backup 2023 key fingerprint";
+/// Hex digits of a fingerprint shown in messages
+const SHORT_FINGERPRINT: usize = 8;
+
+/// Fingerprint of key material, in hex.
+pub fn fingerprint(material: &[u8]) -> String {
+    blake3::Hash::from(blake3::derive_key(FINGERPRINT_CONTEXT, material))
+        .to_hex()
+        .to_string()
+}
+
+/// Fingerprint of the keyfile at `path`, its whole content being the key.
+pub fn fingerprint_of_file(path: &Path) -> Result<String> {
+    let material = std::fs::read(path).with_context(|| format!("unable to read the keyfile {}.", path.display()))?;
+    if material.is_empty() {
+        anyhow::bail!("the keyfile {} is empty.", path.display());
+    }
+    Ok(fingerprint(&material))
+}
+
+/// The beginning of a fingerprint, enough to tell keys apart.
+pub fn short(fingerprint: &str) -> String {
+    match fingerprint.get(..SHORT_FINGERPRINT) {
+        Some(prefix) if fingerprint.len() > SHORT_FINGERPRINT => format!("{prefix}…"),
+        _ => fingerprint.to_string(),
+    }
+}
+
+/// An archive is encrypted with a key whose keyfile was not given, or another one was.
+#[derive(Debug)]
+pub struct KeyRequiredError {
+    pub archive: u32,
+    pub description: String,
+    pub fingerprint: String,
+}
+
+impl std::fmt::Display for KeyRequiredError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "archive {} requires key '{}' (fingerprint {}).",
+            self.archive,
+            self.description,
+            short(&self.fingerprint)
+        )
+    }
+}
+
+impl std::error::Error for KeyRequiredError {}
+
+#[cfg(test)]
+mod test {
+    use super::{fingerprint, short, KeyRequiredError};
+
+    #[test]
+    fn test_fingerprint() {
+        let a = fingerprint(b"offsite key");
+        assert_eq!(a.len(), 64);
+        assert_eq!(a, fingerprint(b"offsite key"));
+        assert_ne!(a, fingerprint(b"onsite key"));
+        // 指纹不是密钥本身的哈希
+        assert_ne!(a, blake3::hash(b"offsite key").to_hex().as_str());
+        assert_eq!(short("ab12cd34ef"), "ab12cd34…");
+        assert_eq!(short("ab12"), "ab12");
+
+        let e = KeyRequiredError {
+            archive: 88,
+            description: "offsite-2023".to_string(),
+            fingerprint: "ab12cd34ef56".to_string(),
+        };
+        assert_eq!(
+            e.to_string(),
+            "archive 88 requires key 'offsite-2023' (fingerprint ab12cd34…)."
+        );
+    }
+}
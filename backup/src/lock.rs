@@ -0,0 +1,83 @@
+//! Advisory lock of the catalog, a `lock` file next to the database, and the lock of the cartridge in the drive.
+//!
+//! Backup jobs and maintenance take the catalog lock exclusively, so a scheduled maintenance never rewrites the
+//! database under a job. The lock is released when the process exits, even if it is killed.
+
+use anyhow::{Context, Result};
+use std::fs::{File, TryLockError};
+use std::path::{Path, PathBuf};
+use tape::TapeDevice;
+
+/// The lock is held by another process.
+#[derive(Debug)]
+pub struct LockHeldError {
+    pub path: PathBuf,
+}
+
+impl std::fmt::Display for LockHeldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is held by another backup process.", self.path.display())
+    }
+}
+
+impl std::error::Error for LockHeldError {}
+
+/// Held until dropped.
+#[derive(Debug)]
+pub struct CatalogLock {
+    _file: File,
+}
+
+impl CatalogLock {
+    /// Take the lock of the database at `database`, without waiting for it.
+    pub fn exclusive(database: &Path) -> Result<Self> {
+        let path = database.with_extension("lock");
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("unable to open the lock {}.", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(LockHeldError { path }.into()),
+            Err(TryLockError::Error(e)) => Err(e).with_context(|| format!("unable to lock {}.", path.display())),
+        }
+    }
+}
+
+/// The cartridge locked in the drive, so nobody ejects it during a job. Removal is allowed again when dropped, also on
+/// early returns and panics.
+pub struct MediumLock<'a> {
+    tape: &'a TapeDevice,
+}
+
+impl<'a> MediumLock<'a> {
+    pub fn prevent(tape: &'a TapeDevice) -> Result<Self> {
+        tape.prevent_removal()?;
+        Ok(Self { tape })
+    }
+}
+
+impl Drop for MediumLock<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.tape.allow_removal() {
+            tracing::warn!("{e:#}, the cartridge may stay locked in {}", self.tape.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CatalogLock, LockHeldError};
+
+    #[test]
+    fn test_exclusive() {
+        let database = std::env::temp_dir().join(format!("backup-lock-{}.db", std::process::id()));
+        let lock = CatalogLock::exclusive(&database).unwrap();
+        assert!(CatalogLock::exclusive(&database).unwrap_err().is::<LockHeldError>());
+        drop(lock);
+        drop(CatalogLock::exclusive(&database).unwrap());
+        std::fs::remove_file(database.with_extension("lock")).unwrap();
+    }
+}
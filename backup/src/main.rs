@@ -1,42 +1,1238 @@
+mod audit;
 mod db;
+mod interrupt;
+mod job;
+mod journal;
+mod key;
+mod lock;
+mod sample;
+mod source;
 
-use anyhow::{Context, Result};
-use std::io::{Read, Seek, Write};
-use std::os::fd::FromRawFd;
+use anyhow::{bail, Context, Result};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use common::completion::{self, CompletionsArg};
+use common::config::Config;
+use common::exit::{self, ErrorKind, EXIT_INTERRUPTED};
+use common::logging::{self, LogArg};
+#[cfg(feature = "metrics")]
+use common::metrics::{Metrics, MetricsServer};
+use common::since::{self, Since, TimeField};
+use common::throttle::{self, Throttle};
+use std::io::{IsTerminal, Read, Seek, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tape::device::{CloseBehavior, CompareOutcome, CompressionCounters, DeviceVariant, TapeError};
 use tape::{LocationBuilder, TapeDevice};
 
-fn main() -> Result<()> {
-    let tape = TapeDevice::open("/dev/nsa0")?;
-    tape.rewind().expect("unable to rewind the tape.");
+use audit::Finding;
+use db::{Archive, DatabaseSize, Storage, DEFAULT_DATABASE_PATH};
+use interrupt::{Interrupt, Request};
+use job::{ArchiveSink, Copy, Message, UNLABELED_MIRROR_TAPE, UNLABELED_TAPE};
+use journal::{JobState, Journal};
+use key::KeyRequiredError;
+use lock::{CatalogLock, LockHeldError, MediumLock};
 
-    let fd = tape.fd();
-    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
-    let mut buffer = [0u8; 512];
+/// Size of the blocks written
+const BLOCK_SIZE: usize = 512;
+/// Blocks read ahead of the tape writer, which keeps streaming them when the readers are throttled
+const BUFFERED_BLOCKS: usize = 64;
 
-    for v in 0..8 {
-        for i in 0..512 {
-            buffer[i] = v;
+#[derive(Parser)]
+#[command(author, version, about)]
+#[command(
+    after_help = "Examples:\n  backup -f /dev/nsa1 /tank/export/photos.tar /tank/export/mail.tar\n  backup -f /dev/nsa1\n  backup --log-format json --log-file /var/log/backup.log\n  backup verify --file 3 --against archive.tar\n  backup -f /dev/nsa0 --mirror-device /dev/nsa1 /tank/export/photos.tar\n  backup list\n\nOn SIGINT or SIGTERM, the archive being written is finished and committed, then backup exits with code 6. A second signal aborts before the next block, the archive is marked uncommitted in the journal next to the database.\n\nShell completion: source <(backup completions bash), also zsh and fish."
+)]
+struct Cli {
+    /// Files to back up, one archive each. Without any, a test pattern is written and read back
+    #[arg(value_name = "FILE")]
+    sources: Vec<PathBuf>,
+    /// Tape device, /dev/nsa0 if not configured in the [backup] or [tape] section. Use a no-rewind node
+    #[arg(short = 'f', long)]
+    device: Option<String>,
+    /// Write every archive to the tape in DEVICE too, in the same pass. Each drive reaches its end of tape on its own
+    #[arg(long, value_name = "DEVICE")]
+    mirror_device: Option<String>,
+    /// Go on with the other tape when one copy fails, instead of failing the job
+    #[arg(long, default_value_t = false, requires = "mirror_device")]
+    mirror_best_effort: bool,
+    /// Wait up to SECS for a tape to be loaded and the drive ready. On a terminal, ask for a tape when none is loaded
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    wait_ready: u64,
+    #[command(flatten)]
+    log: LogArg,
+    /// Limit the read rate of the sources to MB/s, and lower the priority of the readers. The tape writer is not
+    /// limited. Also set by max_read_mbps in [backup] of nas-toolbox.toml
+    #[arg(long, value_name = "MB/s")]
+    read_limit: Option<u32>,
+    /// Read a file changed while archived again, up to N times, before flagging its archive torn. 0 if not set by
+    /// torn_retries in [backup] of nas-toolbox.toml
+    #[arg(long, value_name = "N")]
+    torn_retries: Option<u32>,
+    /// Only back up files changed since TIME, an RFC 3339 time or a duration ago such as 7d or 12h
+    #[arg(long, value_name = "TIME", value_parser = since::parse_since)]
+    since: Option<SystemTime>,
+    /// Time of files compared with --since
+    #[arg(long, value_enum, default_value_t = TimeField::Mtime, requires = "since")]
+    since_field: TimeField,
+    /// Write archives in the order of their paths instead of the order given, so that two runs over the same files
+    /// fill tapes the same way
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+    /// Print errors as a JSON object on stderr
+    #[arg(long, default_value_t = false)]
+    json: bool,
+    /// Serve progress to Prometheus on ADDR, such as 127.0.0.1:9184, until the backup ends
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<String>,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Args)]
+struct VerifyArg {
+    /// Locate to the beginning of file N first, instead of verifying from the current position
+    #[arg(long, value_name = "N")]
+    file: Option<u64>,
+    /// Compare the tape file with PATH, without extracting it. Without it, only check the file is readable
+    #[arg(long, value_name = "PATH")]
+    against: Option<PathBuf>,
+    /// Keyfile of an encrypted archive, checked against the key recorded in the catalog
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+    /// Verify a random sample of the archives of the catalog instead, such as 5%, favoring those never verified
+    #[arg(long, value_name = "PERCENT", value_parser = sample::parse_percent, conflicts_with_all = ["file", "against"])]
+    sample: Option<f64>,
+    /// Seed of the sample, the same seed picks the same archives. Random if not given, and logged
+    #[arg(long, value_name = "N", requires = "sample")]
+    seed: Option<u64>,
+    /// Tape in the drive, picks on other tapes are listed to verify later with the same seed
+    #[arg(long, value_name = "ID", requires = "sample")]
+    tape: Option<u8>,
+}
+
+#[derive(Args)]
+struct KeysArg {
+    #[command(subcommand)]
+    command: Option<KeysCommand>,
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Register the key of a keyfile by its fingerprint, the key itself is not stored
+    #[command(after_help = "Examples:\n  backup keys add offsite-2023 --key-file /root/offsite-2023.key --tape 1")]
+    Add {
+        /// Name of the key, shown when it is required
+        description: String,
+        #[arg(long, value_name = "PATH")]
+        key_file: PathBuf,
+        /// Tapes already encrypted with the key, their archives require it from now on
+        #[arg(long, value_name = "ID")]
+        tape: Vec<u8>,
+    },
+}
+
+#[derive(Args)]
+struct AuditArg {
+    /// Directory files were restored to, with their absolute paths below it
+    #[arg(long, value_name = "DIR")]
+    dir: PathBuf,
+    /// Only files with a copy on this tape, as when only it was restored
+    #[arg(long, value_name = "ID")]
+    tape: Option<u8>,
+    /// Files hashed at once
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    jobs: u64,
+}
+
+#[derive(Args)]
+struct DbArg {
+    #[command(subcommand)]
+    command: DbCommand,
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Check the integrity of the catalog, refresh its query statistics, and report its size before and after
+    #[command(after_help = "Examples:\n  backup db maintain\n  backup db maintain --vacuum")]
+    Maintain {
+        /// Also rewrite the database file without its free pages, which takes as much free space as the file
+        #[arg(long, default_value_t = false)]
+        vacuum: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Verify a file of the tape instead of writing a backup
+    #[command(after_help = "Examples:\n  backup verify --file 3 --against archive.tar\n  backup verify")]
+    Verify(VerifyArg),
+    /// List the archives of the catalog, with the compression ratio reported by the drive
+    List,
+    /// List the tapes of the catalog, with their average compression ratio
+    Tapes,
+    /// List the keys of encrypted tapes, or register one
+    #[command(
+        after_help = "Examples:\n  backup keys\n  backup keys add offsite-2023 --key-file /root/offsite-2023.key --tape 1"
+    )]
+    Keys(KeysArg),
+    /// Maintain the catalog, never while a backup job runs
+    #[command(after_help = "Examples:\n  backup db maintain --vacuum")]
+    Db(DbArg),
+    /// Hash files restored under a directory again, and compare them with the archives of the catalog. Exits with 13
+    /// if any is mismatched, missing or unreadable
+    #[command(after_help = "Examples:\n  backup audit --dir /tank/restore\n  backup audit --dir /tank/restore --tape 1")]
+    Audit(AuditArg),
+    /// Print the completion script of a shell
+    #[command(hide = true)]
+    Completions(CompletionsArg),
+}
+
+/// A block read back differs from the one written.
+#[derive(Debug)]
+struct VerifyError {
+    block: u8,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "block {} read back differs from the one written.", self.block)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// A tape file differs from the local file it is compared with.
+#[derive(Debug)]
+struct MismatchError(CompareOutcome);
+
+impl std::fmt::Display for MismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the tape file {}.", self.0)
+    }
+}
+
+impl std::error::Error for MismatchError {}
+
+/// A tape file differs from the hash recorded in the catalog when it was written.
+#[derive(Debug)]
+struct CatalogMismatchError {
+    file: u32,
+}
+
+impl std::fmt::Display for CatalogMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tape file {} differs from the hash recorded when it was written.",
+            self.file
+        )
+    }
+}
+
+impl std::error::Error for CatalogMismatchError {}
+
+/// Archives picked by a sampled verification failed on these tapes.
+#[derive(Debug)]
+struct SampleFailedError {
+    tapes: Vec<u8>,
+}
+
+impl std::fmt::Display for SampleFailedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tapes = self.tapes.iter().map(u8::to_string).collect::<Vec<_>>().join(", ");
+        write!(f, "sampled archives failed on tapes {tapes}, verify them in full.")
+    }
+}
+
+impl std::error::Error for SampleFailedError {}
+
+/// Restored files differ from the catalog, or could not be found or read.
+#[derive(Debug)]
+struct AuditFailedError {
+    mismatched: usize,
+    missing: usize,
+    unreadable: usize,
+}
+
+impl std::fmt::Display for AuditFailedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} restored files mismatched, {} missing, {} unreadable.",
+            self.mismatched, self.missing, self.unreadable
+        )
+    }
+}
+
+impl std::error::Error for AuditFailedError {}
+
+fn error_kind(e: &anyhow::Error) -> ErrorKind {
+    if e.is::<VerifyError>()
+        || e.is::<MismatchError>()
+        || e.is::<CatalogMismatchError>()
+        || e.is::<SampleFailedError>()
+        || e.is::<AuditFailedError>()
+    {
+        return ErrorKind::VerificationFailed;
+    }
+    if e.is::<KeyRequiredError>() {
+        return ErrorKind::Usage;
+    }
+    if e.is::<LockHeldError>() {
+        return ErrorKind::LockHeld;
+    }
+    exit::error_kind(e)
+}
+
+fn main() -> ExitCode {
+    let cli: Cli = match exit::parse_args() {
+        Ok(cli) => cli,
+        Err(code) => return code,
+    };
+    if let Some(Commands::Completions(arg)) = &cli.command {
+        completion::print_completions(arg.shell, Cli::command(), &["device"]);
+        return ExitCode::SUCCESS;
+    }
+    let machine = exit::machine_errors(cli.json);
+    if let Err(e) = logging::init(&cli.log, "info") {
+        return exit::report(ErrorKind::Usage, &format!("{e:#}"), machine);
+    }
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => return exit::report(ErrorKind::Config, &format!("{e:#}"), machine),
+    };
+    match run(cli, config) {
+        Ok(code) => code,
+        Err(e) => exit::report(error_kind(&e), &format!("{e:#}"), machine),
+    }
+}
+
+/// The tape, as written by the backup job.
+struct TapeSink<'a> {
+    tape: &'a TapeDevice,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+    #[cfg(feature = "metrics")]
+    start: Instant,
+    #[cfg(feature = "metrics")]
+    written: u64,
+}
+
+/// Tape file the drive is in, `None` if the driver lost track of it.
+fn current_file(tape: &TapeDevice) -> Result<Option<u32>> {
+    Ok(u32::try_from(tape.status()?.file_no as i32).ok())
+}
+
+impl ArchiveSink for TapeSink<'_> {
+    fn file_number(&mut self) -> Result<u32> {
+        current_file(self.tape)?.context("the driver lost track of the file number.")
+    }
+
+    fn write_block(&mut self, block: &[u8]) -> Result<()> {
+        // 查询位置需要一次 SCSI 命令, 只在记录每块的日志时查询
+        let pos = tracing::enabled!(tracing::Level::TRACE)
+            .then(|| self.tape.read_scsi_pos())
+            .transpose()?;
+        let count = self.tape.write(block).context("unable to write a block.")?;
+        tracing::trace!(pos, count, "block written");
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.add_bytes_processed(count as u64);
+            self.written += count as u64;
+            metrics.set_throughput((self.written as f64 / self.start.elapsed().as_secs_f64()) as u64);
+            // 每块查询一次扩展状态, 仅在开启指标时
+            if let Some(status) = self.tape.status_ex()? {
+                metrics.set_early_warning(status.eop == 1);
+            }
         }
-        let pos = tape.read_scsi_pos()?;
-        println!("pos = {pos}");
-        let count = file.write(&buffer).with_context(|| format!("when write {v}"))?;
-        println!("count = {count}");
+        Ok(())
+    }
 
-        if v % 2 == 0 {
-            tape.write_eof(1).with_context(|| format!("write eof"))?;
+    fn write_filemark(&mut self) -> Result<()> {
+        self.tape.write_eof(1).context("unable to write the filemark.")
+    }
+
+    fn compression_counters(&mut self) -> Result<Option<CompressionCounters>> {
+        self.tape.compression_counters()
+    }
+}
+
+/// A ratio with two decimals, `-` if unknown.
+fn format_ratio(ratio: Option<f64>) -> String {
+    ratio.map_or_else(|| "-".to_string(), |ratio| format!("{ratio:.2}"))
+}
+
+/// Date and time in UTC, to the minute.
+fn format_time(ts: u64) -> String {
+    match time::OffsetDateTime::from_unix_timestamp(ts as i64) {
+        Ok(time) => format!("{} {:02}:{:02}", time.date(), time.hour(), time.minute()),
+        Err(_) => ts.to_string(),
+    }
+}
+
+/// Flags of an archive, as listed.
+fn flags(archive: &Archive) -> String {
+    let mut flags = Vec::new();
+    if archive.is_torn() {
+        flags.push("torn".to_string());
+    }
+    if let Some(id) = archive.copy_of {
+        flags.push(format!("copy of {id}"));
+    }
+    if let Some(id) = archive.key_id {
+        flags.push(format!("key {id}"));
+    }
+    flags.join(", ")
+}
+
+/// Print the archives of the catalog.
+fn list(storage: &Storage) -> Result<()> {
+    println!(
+        "{:>6} {:>4} {:>6} {:>12} {:<16} {:>6}  FLAGS",
+        "ID", "TAPE", "FILE", "BYTES", "WRITTEN (UTC)", "RATIO"
+    );
+    for archive in storage.archives()? {
+        println!(
+            "{:>6} {:>4} {:>6} {:>12} {:<16} {:>6}  {}",
+            archive.id,
+            archive.tape,
+            archive.tape_file_index,
+            archive.size,
+            format_time(archive.ts),
+            format_ratio(archive.compression_ratio),
+            flags(&archive)
+        );
+    }
+    Ok(())
+}
+
+/// Print the tapes of the catalog.
+fn tapes(storage: &Storage) -> Result<()> {
+    println!("{:>4} {:>8} {:>14} {:>6}  DESCRIPTION", "TAPE", "ARCHIVES", "BYTES", "RATIO");
+    for usage in storage.tape_usage()? {
+        println!(
+            "{:>4} {:>8} {:>14} {:>6}  {}",
+            usage.tape,
+            usage.archives,
+            usage.bytes,
+            format_ratio(usage.compression_ratio),
+            usage.description
+        );
+    }
+    Ok(())
+}
+
+/// Print the keys of the catalog, or register one.
+fn keys(storage: &Storage, arg: KeysArg) -> Result<()> {
+    if let Some(KeysCommand::Add {
+        description,
+        key_file,
+        tape,
+    }) = arg.command
+    {
+        let fingerprint = key::fingerprint_of_file(&key_file)?;
+        let id = storage.add_key(&description, &fingerprint)?;
+        for tape in tape {
+            storage.set_tape_key(tape, id)?;
         }
+        tracing::info!(id, fingerprint = key::short(&fingerprint), "key registered");
+        return Ok(());
+    }
+    println!(
+        "{:>4} {:<16} {:<11} {:>5} {:>8}  DESCRIPTION",
+        "KEY", "CREATED (UTC)", "FINGERPRINT", "TAPES", "ARCHIVES"
+    );
+    for usage in storage.keys()? {
+        println!(
+            "{:>4} {:<16} {:<11} {:>5} {:>8}  {}",
+            usage.key.id,
+            format_time(usage.key.ts),
+            key::short(&usage.key.fingerprint),
+            usage.tapes,
+            usage.archives,
+            usage.key.description
+        );
     }
+    Ok(())
+}
+
+/// Print the size of the catalog.
+fn print_size(label: &str, size: &DatabaseSize) {
+    println!(
+        "{label}: {} bytes, {} pages of {} bytes, {} free",
+        size.file_bytes, size.pages, size.page_size, size.free_pages
+    );
+    println!("  {:<16} {:>10} {:>14}", "TABLE", "ROWS", "BYTES");
+    for table in &size.tables {
+        println!("  {:<16} {:>10} {:>14}", table.name, table.rows, table.bytes);
+    }
+}
+
+/// Unix time `secs` for the audit report, `-` if unknown.
+fn format_mtime(secs: Option<i64>) -> String {
+    secs.and_then(|secs| u64::try_from(secs).ok())
+        .map_or_else(|| "-".to_string(), format_time)
+}
 
+/// Audit files restored under `arg.dir` against the catalog, printing a line for each.
+fn audit_restored(storage: &Storage, arg: AuditArg) -> Result<()> {
+    let _span = tracing::info_span!("audit", dir = %arg.dir.display()).entered();
+    if !arg.dir.is_dir() {
+        bail!("{} is not a directory.", arg.dir.display());
+    }
+    let files = storage.recorded_files(arg.tape)?;
+    if files.is_empty() {
+        bail!("no source file recorded in the catalog, nothing to audit.");
+    }
+    let mut stdout = std::io::stdout().lock();
+    let totals = audit::audit(&files, &arg.dir, arg.jobs as usize, |recorded, restored, finding| {
+        let path = restored.display();
+        let line = match finding {
+            Finding::Ok { mtime_differs: false } => format!("OK         {path}"),
+            Finding::Ok { mtime_differs: true } => format!("OK         {path} (modification time not restored)"),
+            // 给出两边的哈希与修改时间, 以区分损坏与目录过时
+            Finding::Mismatched { size, hash, mtime } => format!(
+                "MISMATCH   {path}\n  expected {} bytes, blake3 {}, modified {}\n  found    {size} bytes, blake3 {hash}, modified {}",
+                recorded.archive.size,
+                blake3::Hash::from(recorded.archive.hash),
+                format_mtime(recorded.mtime),
+                format_mtime(*mtime)
+            ),
+            Finding::Missing => format!("MISSING    {path}"),
+            Finding::Unreadable(e) => format!("UNREADABLE {path}: {e}"),
+            Finding::Torn => format!("TORN       {path} (changed while backed up, nothing to compare)"),
+        };
+        // 读取端关闭时不再输出, 校验照常完成
+        let _ = writeln!(stdout, "{line}");
+    });
+    let _ = writeln!(
+        stdout,
+        "{} files: {} ok, {} mismatched, {} missing, {} unreadable, {} torn; {} bytes hashed.",
+        files.len(),
+        totals.ok,
+        totals.mismatched,
+        totals.missing,
+        totals.unreadable,
+        totals.torn,
+        totals.bytes_hashed
+    );
+    if !totals.passed() {
+        return Err(AuditFailedError {
+            mismatched: totals.mismatched,
+            missing: totals.missing,
+            unreadable: totals.unreadable,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Check and optimize the catalog at `database`, under its lock, unless the journal shows a job in progress.
+fn maintain(database: &std::path::Path, vacuum: bool) -> Result<()> {
+    let _lock = CatalogLock::exclusive(database)?;
+    let journal = Journal::of_database(database);
+    // 任务持有锁, 锁空闲而日志仍为运行中说明任务被杀死, 其归档状态待查
+    if journal.state()? == Some(JobState::Running) {
+        bail!(
+            "the journal {} shows a job in progress, which was killed if no backup runs. Run a backup to completion first.",
+            journal.path().display()
+        );
+    }
+    let storage = Storage::new(database)?;
+    let _span = tracing::info_span!("maintain").entered();
+    let before = storage.size()?;
+    print_size("before", &before);
+    let problems = storage.integrity_check()?;
+    if !problems.is_empty() {
+        for problem in &problems {
+            tracing::error!("{problem}");
+        }
+        bail!(
+            "the catalog {} failed its integrity check, it is left as is.",
+            database.display()
+        );
+    }
+    tracing::info!("integrity check passed");
+    let start = Instant::now();
+    storage.optimize(vacuum)?;
+    let after = storage.size()?;
+    print_size("after", &after);
+    tracing::info!(
+        vacuum,
+        bytes_before = before.file_bytes,
+        bytes_after = after.file_bytes,
+        "catalog maintained in {:.1} s",
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+/// Check `key_file` is the key `archive` is encrypted with, if it is.
+fn check_key(catalog: &Storage, archive: &Archive, key_file: Option<&std::path::Path>) -> Result<()> {
+    let Some(key) = catalog.key_of_archive(archive)? else {
+        return Ok(());
+    };
+    let given = key_file.map(key::fingerprint_of_file).transpose()?;
+    if given.as_deref() != Some(key.fingerprint.as_str()) {
+        return Err(KeyRequiredError {
+            archive: archive.id,
+            description: key.description,
+            fingerprint: key.fingerprint,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Compare a file of the tape with `arg.against`, or read it through and check its hash in `catalog`. Archives
+/// flagged torn have no expected hash, encrypted ones require their keyfile.
+fn verify(tape: &TapeDevice, arg: VerifyArg, catalog: Option<&Storage>) -> Result<()> {
+    if let Some(file) = arg.file {
+        tape.locate_to(&LocationBuilder::new().file(file))
+            .with_context(|| format!("unable to locate to file {file}."))?;
+    }
+    let file = match arg.file {
+        Some(file) => u32::try_from(file).ok(),
+        None => current_file(tape)?,
+    };
+    let _span = tracing::info_span!("verify").entered();
+    let archive = match (catalog, file) {
+        (Some(catalog), Some(file)) => catalog.archive_at(UNLABELED_TAPE, file)?,
+        _ => None,
+    };
+    // 读磁带之前先确认密钥
+    if let (Some(catalog), Some(archive)) = (catalog, &archive) {
+        check_key(catalog, archive, arg.key_file.as_deref())?;
+    }
+    let Some(path) = arg.against else {
+        let mut hasher = blake3::Hasher::new();
+        let report = tape.dump_file(&mut hasher)?;
+        tracing::info!(bytes = report.bytes, blocks = report.blocks, "tape file is readable");
+        match (archive, file) {
+            (Some(archive), Some(file)) if archive.is_torn() => {
+                tracing::warn!("the archive of tape file {file} is flagged torn, its expected hash is unknown")
+            }
+            (Some(archive), Some(file)) if &archive.hash != hasher.finalize().as_bytes() => {
+                if let Some(catalog) = catalog {
+                    catalog.record_verification(archive.id, false, false)?;
+                }
+                return Err(CatalogMismatchError { file }.into());
+            }
+            (Some(archive), _) => {
+                if let Some(catalog) = catalog {
+                    catalog.record_verification(archive.id, true, false)?;
+                }
+                tracing::info!("tape file matches the hash in the catalog")
+            }
+            (None, _) => tracing::info!("tape file is not in the catalog, its hash is not checked"),
+        }
+        return Ok(());
+    };
+    let other = std::fs::File::open(&path).with_context(|| format!("unable to open {}.", path.display()))?;
+    match tape.compare_file(std::io::BufReader::new(other))? {
+        CompareOutcome::Identical { bytes } => {
+            tracing::info!(bytes, "tape file matches {}", path.display());
+            Ok(())
+        }
+        outcome => Err(anyhow::Error::new(MismatchError(outcome)).context(format!("compared with {}", path.display()))),
+    }
+}
+
+/// Archives of a tape, and how many of them a sampled verification picked and checked.
+#[derive(Default)]
+struct TapeCoverage {
+    archives: u32,
+    picked: u32,
+    passed: u32,
+    failed: u32,
+}
+
+/// Read back `archive` from the tape in the drive, and compare it with its hash in the catalog.
+fn verify_archive(tape: &TapeDevice, archive: &Archive) -> Result<bool> {
+    tape.locate_to(&LocationBuilder::new().file(archive.tape_file_index as u64))
+        .with_context(|| format!("unable to locate to file {}.", archive.tape_file_index))?;
+    let mut hasher = blake3::Hasher::new();
+    tape.dump_file(&mut hasher)?;
+    Ok(&archive.hash == hasher.finalize().as_bytes())
+}
+
+/// Verify a sample of `percent` of the archives of `catalog`, those on the tape in the drive, and record the results.
+fn verify_sample(tape: &TapeDevice, arg: VerifyArg, percent: f64, catalog: &Storage) -> Result<()> {
+    let seed = arg.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos() as u64)
+    });
+    let loaded = arg.tape.unwrap_or(UNLABELED_TAPE);
+    let _span = tracing::info_span!("verify_sample", seed, tape = loaded).entered();
+    let archives = catalog.archives()?;
+    let picks = sample::pick(
+        &archives,
+        &catalog.verified_archives()?,
+        &catalog.tapes_with_failures()?,
+        percent,
+        seed,
+    );
+    tracing::info!(
+        seed,
+        "{} archives picked, pass --seed {seed} to pick the same ones",
+        picks.len()
+    );
+
+    let mut coverage = std::collections::BTreeMap::<u8, TapeCoverage>::new();
+    for archive in archives.iter().filter(|archive| !archive.is_torn()) {
+        coverage.entry(archive.tape).or_default().archives += 1;
+    }
+    for archive in picks {
+        let tape_coverage = coverage.entry(archive.tape).or_default();
+        tape_coverage.picked += 1;
+        if archive.tape != loaded {
+            continue;
+        }
+        if let Err(e) = check_key(catalog, archive, arg.key_file.as_deref()) {
+            tracing::warn!(archive = archive.id, "{e:#}, skipped");
+            continue;
+        }
+        // 定位或读取失败同样记为校验失败
+        let ok = verify_archive(tape, archive).unwrap_or_else(|e| {
+            tracing::error!(archive = archive.id, file = archive.tape_file_index, "{e:#}");
+            false
+        });
+        catalog.record_verification(archive.id, ok, true)?;
+        if ok {
+            tape_coverage.passed += 1;
+        } else {
+            tracing::error!(archive = archive.id, file = archive.tape_file_index, "sampled archive failed");
+            tape_coverage.failed += 1;
+        }
+    }
+
+    println!(
+        "{:>4} {:>8} {:>7} {:>7} {:>7} {:>9}",
+        "TAPE", "ARCHIVES", "PICKED", "PASSED", "FAILED", "COVERAGE"
+    );
+    for (id, tape_coverage) in &coverage {
+        let checked = tape_coverage.passed + tape_coverage.failed;
+        println!(
+            "{id:>4} {:>8} {:>7} {:>7} {:>7} {:>8.1}%",
+            tape_coverage.archives,
+            tape_coverage.picked,
+            tape_coverage.passed,
+            tape_coverage.failed,
+            checked as f64 * 100.0 / tape_coverage.archives.max(1) as f64
+        );
+        if *id != loaded && tape_coverage.picked > 0 {
+            tracing::info!(
+                "{} picks on tape {id} are left, load it and run backup verify --sample {percent}% --seed {seed} --tape {id}",
+                tape_coverage.picked
+            );
+        }
+    }
+    let failed = coverage
+        .iter()
+        .filter(|(_, tape_coverage)| tape_coverage.failed > 0)
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        return Err(SampleFailedError { tapes: failed }.into());
+    }
+    Ok(())
+}
+
+/// Open `device`, warning if it rewinds when closed, and wait up to `wait` for its tape. On a terminal, the operator
+/// is asked to insert one when none is loaded.
+fn open_tape(device: &str, wait: Duration) -> Result<TapeDevice> {
+    // 备份由多个文件组成, 关闭时回卷会使下一个文件覆盖前一个
+    if let Some(variant) = DeviceVariant::parse(device).filter(|v| v.behavior != CloseBehavior::NoRewind) {
+        let suggested = DeviceVariant {
+            behavior: CloseBehavior::NoRewind,
+            ..variant
+        };
+        tracing::warn!(
+            "{device} rewinds the tape when closed ({:?}), files of a backup may be overwritten. Use {suggested} instead.",
+            variant.behavior
+        );
+    }
+    // 不阻塞地打开, 没有磁带时由 wait_ready 超时, 而非一直挂起
+    let tape = TapeDevice::open_nonblocking(device)?;
+    loop {
+        let e = match tape.wait_ready(wait) {
+            Ok(_) => return Ok(tape),
+            Err(e) => e,
+        };
+        let no_tape = matches!(e.downcast_ref::<TapeError>(), Some(TapeError::NotReady { .. }));
+        if !no_tape || !std::io::stdin().is_terminal() {
+            return Err(e);
+        }
+        eprint!("No tape is loaded in {device}. Insert one and press Enter, or Ctrl-C to quit: ");
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Err(e);
+        }
+    }
+}
+
+fn run(cli: Cli, config: Config) -> Result<ExitCode> {
+    let database = config.backup.database.unwrap_or_else(|| PathBuf::from(DEFAULT_DATABASE_PATH));
+    if let Some(Commands::List | Commands::Tapes | Commands::Keys(_) | Commands::Db(_) | Commands::Audit(_)) = cli.command {
+        if !database.exists() {
+            bail!("no catalog at {}, nothing was backed up yet.", database.display());
+        }
+        if let Some(Commands::Db(DbArg {
+            command: DbCommand::Maintain { vacuum },
+        })) = cli.command
+        {
+            maintain(&database, vacuum)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        let storage = Storage::new(&database)?;
+        match cli.command {
+            Some(Commands::List) => list(&storage)?,
+            Some(Commands::Keys(arg)) => keys(&storage, arg)?,
+            Some(Commands::Audit(arg)) => audit_restored(&storage, arg)?,
+            _ => tapes(&storage)?,
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    let device = cli.device.or(config.backup.device).or(config.tape.device);
+    let device = device.unwrap_or_else(|| {
+        DeviceVariant {
+            unit: 0,
+            behavior: CloseBehavior::NoRewind,
+            mode: None,
+        }
+        .path()
+    });
+    let wait = Duration::from_secs(cli.wait_ready);
+    let tape = open_tape(&device, wait)?;
+    if let Some(Commands::Verify(arg)) = cli.command {
+        // 校验不创建数据库
+        let catalog = database.exists().then(|| Storage::new(&database)).transpose()?;
+        if let Some(percent) = arg.sample {
+            let Some(catalog) = catalog else {
+                bail!("no catalog at {}, nothing to sample.", database.display());
+            };
+            return verify_sample(&tape, arg, percent, &catalog).map(|_| ExitCode::SUCCESS);
+        }
+        return verify(&tape, arg, catalog.as_ref()).map(|_| ExitCode::SUCCESS);
+    }
+    let _lock = CatalogLock::exclusive(&database)?;
+    let mut storage = Storage::new(&database)?;
+    let journal = Journal::of_database(&database);
+    tape.rewind().context("unable to rewind the tape.")?;
+    let mirror = match &cli.mirror_device {
+        Some(device) => {
+            let mirror = open_tape(device, wait)?;
+            mirror.rewind().context("unable to rewind the mirror tape.")?;
+            Some(mirror)
+        }
+        None => None,
+    };
+    // 作业期间锁住磁带, 以免有人按下弹出键; 无法锁住时照常备份
+    let _medium_locks = std::iter::once(&tape)
+        .chain(mirror.as_ref())
+        .filter_map(|tape| {
+            MediumLock::prevent(tape)
+                .map_err(|e| tracing::warn!("{e:#}, the cartridge can be ejected during the job"))
+                .ok()
+        })
+        .collect::<Vec<_>>();
+
+    let mut buffer = [0u8; BLOCK_SIZE];
+    let read_limit = cli.read_limit.or(config.backup.max_read_mbps);
+    let torn_retries = cli.torn_retries.or(config.backup.torn_retries).unwrap_or(0);
+    let mut sources = cli.sources;
+    if cli.deterministic {
+        sources.sort();
+    }
+    let pattern = sources.is_empty();
+    let sources = match cli.since {
+        Some(cutoff) => {
+            let since = Since {
+                cutoff,
+                field: cli.since_field,
+            };
+            // 无法 stat 的文件留给读取时报错
+            let (sources, unchanged): (Vec<_>, Vec<_>) = sources
+                .into_iter()
+                .partition(|path| std::fs::metadata(path).map_or(true, |metadata| since.includes(&metadata)));
+            for path in &unchanged {
+                tracing::info!(path = %path.display(), "unchanged since --since, skipped");
+            }
+            sources
+        }
+        None => sources,
+    };
+
+    #[cfg(feature = "metrics")]
+    let (metrics, _server) = match &cli.metrics_listen {
+        Some(addr) => {
+            let metrics = Arc::new(Metrics::new("backup"));
+            let server = MetricsServer::start(addr, metrics.clone())?;
+            tracing::info!(addr = %server.local_addr(), "serving metrics");
+            metrics.set_phase("write");
+            (Some(metrics), Some(server))
+        }
+        None => (None, None),
+    };
+    #[cfg(feature = "metrics")]
+    let start = Instant::now();
+
+    let throttle = match read_limit {
+        Some(mbps) => Throttle::unlimited().max_read_mbps(mbps),
+        None => Throttle::unlimited(),
+    };
+    let mut interrupt = Interrupt::new();
+    interrupt.install()?;
+    let (sender, receiver) = mpsc::sync_channel(BUFFERED_BLOCKS);
+    let reader = {
+        let throttle = throttle.clone();
+        let interrupt = interrupt.clone();
+        std::thread::spawn(move || {
+            if read_limit.is_some() && !throttle::lower_thread_priority() {
+                tracing::debug!("unable to lower the priority of the reader");
+            }
+            let start = Instant::now();
+            let mut send = |message| sender.send(message).is_ok();
+            for path in &sources {
+                if interrupt.request() != Request::Run {
+                    break;
+                }
+                match source::send_file(path, BLOCK_SIZE, torn_retries, &throttle, &mut send) {
+                    Ok(true) => {}
+                    // 写入端出错或停止时通道已关闭
+                    Ok(false) => break,
+                    Err(e) => tracing::error!("{e:#}, the file is skipped"),
+                }
+            }
+            // 没有源文件时写入测试数据: 五个归档, 块内容为其序号
+            let archives = if pattern {
+                vec![0..1u8, 1..3, 3..5, 5..7, 7..8]
+            } else {
+                Vec::new()
+            };
+            'archives: for blocks in archives {
+                if interrupt.request() != Request::Run {
+                    break;
+                }
+                for v in blocks {
+                    let block = vec![v; BLOCK_SIZE];
+                    throttle.consume(block.len());
+                    if !send(Message::Block(block)) {
+                        break 'archives;
+                    }
+                }
+                if !send(Message::EndOfArchive(None)) {
+                    break;
+                }
+            }
+            start.elapsed()
+        })
+    };
+
+    let write_span = tracing::info_span!("tape_write").entered();
+    let mut sink = TapeSink {
+        tape: &tape,
+        #[cfg(feature = "metrics")]
+        metrics: metrics.clone(),
+        #[cfg(feature = "metrics")]
+        start,
+        #[cfg(feature = "metrics")]
+        written: 0,
+    };
+    let mut mirror_sink = mirror.as_ref().map(|tape| TapeSink {
+        tape,
+        // 指标只统计主磁带
+        #[cfg(feature = "metrics")]
+        metrics: None,
+        #[cfg(feature = "metrics")]
+        start,
+        #[cfg(feature = "metrics")]
+        written: 0,
+    });
+    let mut copies = vec![Copy::new(&device, UNLABELED_TAPE, &mut sink).best_effort(cli.mirror_best_effort)];
+    if let (Some(sink), Some(device)) = (mirror_sink.as_mut(), &cli.mirror_device) {
+        copies.push(Copy::new(device.as_str(), UNLABELED_MIRROR_TAPE, sink).best_effort(cli.mirror_best_effort));
+    }
+    let report = job::write_copies(&mut copies, &mut storage, &journal, &interrupt, receiver)?;
+    drop(copies);
+    drop(write_span);
+    for copy in &report.copies {
+        tracing::info!(
+            device = copy.name,
+            bytes = copy.bytes,
+            bytes_per_second = copy.throughput() as u64,
+            "{} bytes written at {:.1} MB/s",
+            copy.bytes,
+            copy.throughput() / (1024.0 * 1024.0)
+        );
+        if let Some(error) = &copy.error {
+            tracing::warn!(device = copy.name, "the copy stopped early: {error}");
+        }
+    }
+    for path in &report.torn {
+        tracing::warn!(path = %path.display(), "changed while archived, back it up again");
+    }
+    tracing::info!(
+        committed = report.committed,
+        bytes = report.bytes,
+        torn = report.torn.len(),
+        "{} archives committed, {} of them torn",
+        report.committed,
+        report.torn.len()
+    );
+    match report.state {
+        JobState::InterruptedCleanly => {
+            tracing::warn!(
+                committed = report.committed,
+                "interrupted, stopped after committing the archive being written"
+            );
+            return Ok(ExitCode::from(EXIT_INTERRUPTED));
+        }
+        JobState::Aborted => bail!(
+            "aborted by a second signal, the archive being written is not committed, see {}.",
+            journal.path().display()
+        ),
+        _ => {}
+    }
+    let read_time = reader.join().expect("the reader does not panic.");
+    let average = throttle.bytes_read() as f64 / read_time.as_secs_f64().max(f64::EPSILON);
+    tracing::info!(
+        read_limit_mbps = read_limit,
+        average_read_bytes_per_second = average as u64,
+        "{} bytes read at {:.1} MB/s on average, {}",
+        throttle.bytes_read(),
+        average / (1024.0 * 1024.0),
+        match read_limit {
+            Some(mbps) => format!("limited to {mbps} MB/s"),
+            None => "without limit".to_string(),
+        }
+    );
+
+    if !pattern {
+        return Ok(ExitCode::SUCCESS);
+    }
     tape.rewind()?;
-    for _ in 0..8 {
+    let _span = tracing::info_span!("verify").entered();
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &metrics {
+        metrics.set_phase("verify");
+    }
+    let mut expected = 0u8;
+    // 8 个数据块, 以及每个归档后的 5 个文件标记
+    for _ in 0..13 {
         for i in 0..512 {
             buffer[i] = 0;
         }
-        let pos = tape.read_scsi_pos()?;
-        println!("pos = {pos}");
+        let pos = tracing::enabled!(tracing::Level::TRACE)
+            .then(|| tape.read_scsi_pos())
+            .transpose()?;
 
-        let actual_read = file.read(&mut buffer)?;
-        println!("({}) {:?}", actual_read, &buffer[..actual_read]);
+        let actual_read = (&tape).read(&mut buffer)?;
+        tracing::trace!(pos, count = actual_read, "block read: {:?}", &buffer[..actual_read]);
+        if actual_read == 0 {
+            // 读到文件标记
+            continue;
+        }
+        if actual_read != buffer.len() || buffer.iter().any(|b| *b != expected) {
+            return Err(VerifyError { block: expected }.into());
+        }
+        expected += 1;
+    }
+    if expected != 8 {
+        return Err(VerifyError { block: expected }.into());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        error_kind, format_ratio, format_time, AuditFailedError, CatalogMismatchError, Cli, Commands, DbArg, DbCommand,
+        KeyRequiredError, KeysArg, KeysCommand, LockHeldError, MismatchError, SampleFailedError, VerifyError,
+    };
+    use clap::Parser;
+    use common::exit::ErrorKind;
+    use common::since::TimeField;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+    use tape::device::CompareOutcome;
+
+    #[test]
+    fn test_error_kinds() {
+        let e = anyhow::Error::new(VerifyError { block: 3 }).context("verify failed.");
+        assert_eq!(error_kind(&e), ErrorKind::VerificationFailed);
+        assert_eq!(error_kind(&e).exit_code(), 13);
+
+        let e = tape::TapeDevice::open("/dev/no-such-tape").err().unwrap();
+        assert_eq!(error_kind(&e).exit_code(), 10);
+        assert_eq!(error_kind(&anyhow::anyhow!("unexpected")).exit_code(), 1);
+
+        let e = anyhow::Error::new(MismatchError(CompareOutcome::Differs { offset: 7 })).context("compared with a.tar");
+        assert_eq!(error_kind(&e), ErrorKind::VerificationFailed);
+        assert_eq!(format!("{e:#}"), "compared with a.tar: the tape file differs at byte 7.");
+        assert_eq!(error_kind(&CatalogMismatchError { file: 2 }.into()).exit_code(), 13);
+    }
+
+    #[test]
+    fn test_read_limit() {
+        let cli = Cli::try_parse_from(["backup", "--read-limit", "40"]).unwrap();
+        assert_eq!(cli.read_limit, Some(40));
+        assert!(Cli::try_parse_from(["backup", "--read-limit", "fast"]).is_err());
+    }
+
+    #[test]
+    fn test_verify_args() {
+        let cli = Cli::try_parse_from(["backup", "verify", "--file", "3", "--against", "a.tar"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Commands::Verify(arg)) if arg.file == Some(3) && arg.against.as_deref().is_some_and(|path| path.ends_with("a.tar"))
+        ));
+        let cli = Cli::try_parse_from(["backup", "verify"]).unwrap();
+        assert!(matches!(cli.command, Some(Commands::Verify(arg)) if arg.against.is_none()));
+        let cli = Cli::try_parse_from(["backup", "verify", "--sample", "5%", "--seed", "7", "--tape", "1"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Commands::Verify(arg)) if arg.sample == Some(5.0) && arg.seed == Some(7) && arg.tape == Some(1)
+        ));
+        assert!(Cli::try_parse_from(["backup", "verify", "--sample", "5%", "--file", "3"]).is_err());
+        assert!(Cli::try_parse_from(["backup", "verify", "--seed", "7"]).is_err());
+        assert_eq!(error_kind(&SampleFailedError { tapes: vec![1, 4] }.into()).exit_code(), 13);
+        assert!(Cli::try_parse_from(["backup"]).unwrap().command.is_none());
+
+        let cli = Cli::try_parse_from(["backup", "--torn-retries", "2", "a.tar", "b.tar"]).unwrap();
+        assert_eq!(cli.sources, [PathBuf::from("a.tar"), PathBuf::from("b.tar")]);
+        assert_eq!(cli.torn_retries, Some(2));
+        assert!(cli.command.is_none());
+        assert!(!cli.deterministic);
+        assert!(
+            Cli::try_parse_from(["backup", "--deterministic", "b.tar", "a.tar"])
+                .unwrap()
+                .deterministic
+        );
+    }
+
+    #[test]
+    fn test_mirror_args() {
+        let cli = Cli::try_parse_from(["backup", "-f", "/dev/nsa0", "--mirror-device", "/dev/nsa1", "a.tar"]).unwrap();
+        assert_eq!(cli.mirror_device.as_deref(), Some("/dev/nsa1"));
+        assert!(!cli.mirror_best_effort);
+        let cli = Cli::try_parse_from(["backup", "--mirror-device", "/dev/nsa1", "--mirror-best-effort"]).unwrap();
+        assert!(cli.mirror_best_effort);
+        // 没有镜像时无意义
+        assert!(Cli::try_parse_from(["backup", "--mirror-best-effort"]).is_err());
+    }
+
+    #[test]
+    fn test_wait_ready_args() {
+        assert_eq!(Cli::try_parse_from(["backup"]).unwrap().wait_ready, 60);
+        assert_eq!(Cli::try_parse_from(["backup", "--wait-ready", "0"]).unwrap().wait_ready, 0);
+        assert!(Cli::try_parse_from(["backup", "--wait-ready", "soon"]).is_err());
+    }
+
+    #[test]
+    fn test_since_args() {
+        let cli = Cli::try_parse_from(["backup", "--since", "7d", "a.tar"]).unwrap();
+        let age = cli.since.unwrap().elapsed().unwrap().as_secs();
+        assert!((7 * 86400..7 * 86400 + 60).contains(&age));
+        assert_eq!(cli.since_field, TimeField::Mtime);
+        let cli = Cli::try_parse_from(["backup", "--since", "2023-08-01T00:00:00Z", "--since-field", "ctime"]).unwrap();
+        assert_eq!(cli.since, Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_690_848_000)));
+        assert_eq!(cli.since_field, TimeField::Ctime);
+        assert!(Cli::try_parse_from(["backup", "--since", "last week"]).is_err());
+        assert!(Cli::try_parse_from(["backup", "--since-field", "ctime"]).is_err());
+    }
+
+    #[test]
+    fn test_catalog_listing() {
+        assert!(matches!(
+            Cli::try_parse_from(["backup", "list"]).unwrap().command,
+            Some(Commands::List)
+        ));
+        assert!(matches!(
+            Cli::try_parse_from(["backup", "tapes"]).unwrap().command,
+            Some(Commands::Tapes)
+        ));
+        assert_eq!(format_ratio(Some(1.8751)), "1.88");
+        assert_eq!(format_ratio(None), "-");
+        assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13");
+    }
+
+    #[test]
+    fn test_keys_args() {
+        assert!(matches!(
+            Cli::try_parse_from(["backup", "keys"]).unwrap().command,
+            Some(Commands::Keys(KeysArg { command: None }))
+        ));
+        let cli = Cli::try_parse_from([
+            "backup",
+            "keys",
+            "add",
+            "offsite",
+            "--key-file",
+            "k",
+            "--tape",
+            "1",
+            "--tape",
+            "2",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Commands::Keys(KeysArg { command: Some(KeysCommand::Add { description, tape, .. }) }))
+                if description == "offsite" && tape == [1, 2]
+        ));
+        assert!(Cli::try_parse_from(["backup", "keys", "add", "offsite"]).is_err());
+
+        let e = anyhow::Error::new(KeyRequiredError {
+            archive: 88,
+            description: "offsite-2023".to_string(),
+            fingerprint: "ab12cd34ef".to_string(),
+        });
+        assert_eq!(error_kind(&e), ErrorKind::Usage);
+    }
+
+    #[test]
+    fn test_db_args() {
+        assert!(matches!(
+            Cli::try_parse_from(["backup", "db", "maintain"]).unwrap().command,
+            Some(Commands::Db(DbArg {
+                command: DbCommand::Maintain { vacuum: false }
+            }))
+        ));
+        assert!(matches!(
+            Cli::try_parse_from(["backup", "db", "maintain", "--vacuum"]).unwrap().command,
+            Some(Commands::Db(DbArg {
+                command: DbCommand::Maintain { vacuum: true }
+            }))
+        ));
+        assert!(Cli::try_parse_from(["backup", "db"]).is_err());
+        let e = anyhow::Error::new(LockHeldError {
+            path: PathBuf::from("backup.lock"),
+        });
+        assert_eq!(error_kind(&e).exit_code(), 14);
+    }
+
+    #[test]
+    fn test_audit_args() {
+        let cli = Cli::try_parse_from(["backup", "audit", "--dir", "/tank/restore", "--tape", "1"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Commands::Audit(arg)) if arg.dir.as_path() == std::path::Path::new("/tank/restore") && arg.tape == Some(1) && arg.jobs == 4
+        ));
+        assert!(Cli::try_parse_from(["backup", "audit"]).is_err());
+        assert!(Cli::try_parse_from(["backup", "audit", "--dir", "/tank/restore", "--jobs", "0"]).is_err());
+        let e = AuditFailedError {
+            mismatched: 1,
+            missing: 2,
+            unreadable: 0,
+        };
+        assert_eq!(e.to_string(), "1 restored files mismatched, 2 missing, 0 unreadable.");
+        assert_eq!(error_kind(&e.into()).exit_code(), 13);
     }
-    Ok(())
 }
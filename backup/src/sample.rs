@@ -0,0 +1,131 @@
+//! Random samples of the catalog for spot checks, when verifying every archive takes too long.
+//!
+//! Archives never verified, and those on tapes where a verification failed before, are more likely to be picked. A
+//! sample only depends on the catalog and the seed, so a run can be repeated with the same seed.
+
+use crate::db::Archive;
+use std::collections::HashSet;
+
+/// How much more likely an archive never verified is picked
+const NEVER_VERIFIED_WEIGHT: f64 = 4.0;
+/// How much more likely an archive on a tape with a failed verification is picked
+const FAILED_TAPE_WEIGHT: f64 = 4.0;
+
+/// Parse a percentage such as `5%` or `0.5`, above 0 and up to 100.
+pub fn parse_percent(value: &str) -> Result<f64, String> {
+    let percent = value
+        .strip_suffix('%')
+        .unwrap_or(value)
+        .parse::<f64>()
+        .map_err(|_| format!("expect a percentage such as 5%, got {value}"))?;
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(format!("expect a percentage above 0 and up to 100, got {value}"));
+    }
+    Ok(percent)
+}
+
+/// SplitMix64, the same numbers for a seed on every platform.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in (0, 1].
+    fn next_f64(&mut self) -> f64 {
+        ((self.next() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Pick `percent` of the archives with a known hash, at least one, ordered by tape and file so the drive seeks
+/// forward only. `verified` are the ids of archives verified before, `failed_tapes` the tapes with a failed one.
+pub fn pick<'a>(
+    archives: &'a [Archive],
+    verified: &HashSet<u32>,
+    failed_tapes: &HashSet<u8>,
+    percent: f64,
+    seed: u64,
+) -> Vec<&'a Archive> {
+    let mut rng = SplitMix64(seed);
+    // 加权无放回抽样 (Efraimidis-Spirakis): 键为 u^(1/w), 取最大的若干个
+    let mut keyed = archives
+        .iter()
+        .filter(|archive| !archive.is_torn())
+        .map(|archive| {
+            let mut weight = 1.0;
+            if !verified.contains(&archive.id) {
+                weight *= NEVER_VERIFIED_WEIGHT;
+            }
+            if failed_tapes.contains(&archive.tape) {
+                weight *= FAILED_TAPE_WEIGHT;
+            }
+            (rng.next_f64().powf(1.0 / weight), archive)
+        })
+        .collect::<Vec<_>>();
+    let count = (keyed.len() as f64 * percent / 100.0).ceil() as usize;
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut picks = keyed.into_iter().take(count).map(|(_, archive)| archive).collect::<Vec<_>>();
+    picks.sort_by_key(|archive| (archive.tape, archive.tape_file_index));
+    picks
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_percent, pick};
+    use crate::db::{Archive, ARCHIVE_TORN};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("5%"), Ok(5.0));
+        assert_eq!(parse_percent("0.5"), Ok(0.5));
+        assert_eq!(parse_percent("100%"), Ok(100.0));
+        for invalid in ["0%", "101%", "-5%", "five", "%", "NaN"] {
+            assert!(parse_percent(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_pick() {
+        let archives = (0..400u32)
+            .map(|i| Archive {
+                id: i + 1,
+                ..Archive::new((i % 4) as u8, i / 4, 512, [0; 32])
+            })
+            .collect::<Vec<_>>();
+        // 前 200 个校验过, 磁带 3 曾经校验失败
+        let verified = (1..=200).collect::<HashSet<u32>>();
+        let failed_tapes = HashSet::from([3]);
+
+        let picks = pick(&archives, &verified, &failed_tapes, 10.0, 42);
+        assert_eq!(picks.len(), 40);
+        let ids = picks.iter().map(|archive| archive.id).collect::<Vec<_>>();
+        let again = pick(&archives, &verified, &failed_tapes, 10.0, 42);
+        assert_eq!(again.iter().map(|archive| archive.id).collect::<Vec<_>>(), ids);
+        let other = pick(&archives, &verified, &failed_tapes, 10.0, 43);
+        assert_ne!(other.iter().map(|archive| archive.id).collect::<Vec<_>>(), ids);
+        assert!(picks
+            .windows(2)
+            .all(|pair| (pair[0].tape, pair[0].tape_file_index) < (pair[1].tape, pair[1].tape_file_index)));
+
+        let never_verified = picks.iter().filter(|archive| archive.id > 200).count();
+        assert!(never_verified > 25, "{never_verified}");
+        let on_failed_tape = picks.iter().filter(|archive| archive.tape == 3).count();
+        assert!(on_failed_tape > 15, "{on_failed_tape}");
+
+        // 损坏的归档没有预期哈希, 不参与抽样; 至少抽一个
+        let archives = vec![
+            Archive::new(0, 0, 512, [0; 32]).flag(ARCHIVE_TORN),
+            Archive::new(0, 1, 512, [0; 32]),
+        ];
+        let picks = pick(&archives, &HashSet::new(), &HashSet::new(), 0.1, 1);
+        assert_eq!(picks.len(), 1);
+        assert_eq!(picks[0].tape_file_index, 1);
+        assert!(pick(&archives[..1], &HashSet::new(), &HashSet::new(), 100.0, 1).is_empty());
+    }
+}
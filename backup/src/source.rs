@@ -0,0 +1,160 @@
+//! Reading source files into archives. A file is looked at before and after it is read, a change of its size or
+//! modification time means the archive holds a torn copy.
+
+use crate::job::{Message, Source};
+use anyhow::{Context, Result};
+use common::throttle::Throttle;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// What tells a file changed.
+#[derive(Debug, PartialEq, Eq)]
+struct Snapshot {
+    size: u64,
+    modified: Option<SystemTime>,
+    /// A file replaced by another one has another inode
+    inode: u64,
+}
+
+impl Snapshot {
+    fn of(path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path).with_context(|| format!("unable to stat {}.", path.display()))?;
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            inode: metadata.ino(),
+        })
+    }
+}
+
+/// Send the blocks of `path`, the last one possibly shorter. Returns false if `send` was refused.
+fn send_blocks(path: &Path, block_size: usize, throttle: &Throttle, send: &mut impl FnMut(Message) -> bool) -> Result<bool> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("unable to open {}.", path.display()))?;
+    loop {
+        let mut block = Vec::with_capacity(block_size);
+        let count = (&mut file)
+            .take(block_size as u64)
+            .read_to_end(&mut block)
+            .with_context(|| format!("unable to read {}.", path.display()))?;
+        if count == 0 {
+            return Ok(true);
+        }
+        throttle.consume(count);
+        if !send(Message::Block(block)) {
+            return Ok(false);
+        }
+    }
+}
+
+/// Send `path` as an archive of blocks of `block_size` bytes. If the file changed while read, the archive is
+/// discarded and the file read again, up to `retries` times, then it is sent flagged torn. Returns false if `send`
+/// was refused, as when the writer stopped.
+pub fn send_file(
+    path: &Path,
+    block_size: usize,
+    retries: u32,
+    throttle: &Throttle,
+    send: &mut impl FnMut(Message) -> bool,
+) -> Result<bool> {
+    let mut attempt = 0;
+    loop {
+        let before = Snapshot::of(path)?;
+        match send_blocks(path, block_size, throttle, send) {
+            Ok(true) => {}
+            Ok(false) => return Ok(false),
+            Err(e) => {
+                // 已发送的块不构成完整的归档
+                send(Message::Discard);
+                return Err(e);
+            }
+        }
+        let torn = Snapshot::of(path)? != before;
+        if torn && attempt < retries {
+            attempt += 1;
+            tracing::warn!(path = %path.display(), attempt, "changed while read, reading it again");
+            if !send(Message::Discard) {
+                return Ok(false);
+            }
+            continue;
+        }
+        if torn {
+            tracing::warn!(path = %path.display(), "changed while read, the archive is flagged torn");
+        }
+        let source = Source {
+            path: path.to_path_buf(),
+            torn,
+            inode: before.inode,
+            mtime: before
+                .modified
+                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as i64),
+        };
+        return Ok(send(Message::EndOfArchive(Some(source))));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::send_file;
+    use crate::job::{Message, Source};
+    use common::throttle::Throttle;
+    use std::io::Write;
+
+    /// Kinds of the messages sent, `B` for a block of that many bytes
+    fn kinds(messages: &[Message]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| match message {
+                Message::Block(block) => format!("B{}", block.len()),
+                Message::Discard => "discard".to_string(),
+                Message::EndOfArchive(Some(Source { torn: true, .. })) => "end torn".to_string(),
+                Message::EndOfArchive(_) => "end".to_string(),
+            })
+            .collect()
+    }
+
+    /// Send the file, appending to it while the first block is sent
+    fn send_growing(path: &std::path::Path, retries: u32) -> Vec<Message> {
+        std::fs::write(path, [7u8; 10]).unwrap();
+        let mut messages = Vec::new();
+        let mut send = |message: Message| {
+            if messages.is_empty() {
+                let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+                file.write_all(&[8u8; 2]).unwrap();
+            }
+            messages.push(message);
+            true
+        };
+        assert!(send_file(path, 4, retries, &Throttle::unlimited(), &mut send).unwrap());
+        messages
+    }
+
+    #[test]
+    fn test_send_file() {
+        let dir = std::env::temp_dir().join(format!("backup-source-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a");
+
+        std::fs::write(&path, [7u8; 10]).unwrap();
+        let mut messages = Vec::new();
+        let mut send = |message| {
+            messages.push(message);
+            true
+        };
+        assert!(send_file(&path, 4, 2, &Throttle::unlimited(), &mut send).unwrap());
+        assert_eq!(kinds(&messages), ["B4", "B4", "B2", "end"]);
+
+        // 读取期间文件增长: 重读一次, 或标记为不完整
+        assert_eq!(
+            kinds(&send_growing(&path, 1)),
+            ["B4", "B4", "B4", "discard", "B4", "B4", "B4", "end"]
+        );
+        assert_eq!(kinds(&send_growing(&path, 0)), ["B4", "B4", "B4", "end torn"]);
+
+        // 写入端停止
+        assert!(!send_file(&path, 4, 0, &Throttle::unlimited(), &mut |_| false).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Shell completion scripts, printed by the hidden `completions <shell>` subcommand of each binary.
+//!
+//! Scripts are static, so values of device options are the tape devices present when the script is generated. Load
+//! it from the shell startup file, such as `source <(tape completions bash)`, to pick up drives attached since.
+
+use clap::builder::PossibleValuesParser;
+use clap::{Args, Command};
+use clap_complete::Shell;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Args)]
+pub struct CompletionsArg {
+    /// Shell to generate the script for
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
+
+/// Tape device nodes under `dir`: `sa*`, and the non-rewinding `nsa*` and eject-on-close `esa*`.
+pub fn tape_devices(dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut devices: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| {
+            let unit = ["sa", "nsa", "esa"].iter().find_map(|prefix| name.strip_prefix(prefix));
+            // 只保留 sa0、nsa0.1 这样的设备节点, 不包括 sa0.ctl
+            unit.is_some_and(|unit| unit.starts_with(|c: char| c.is_ascii_digit()) && !unit.ends_with(".ctl"))
+        })
+        .map(|name| dir.join(name).to_string_lossy().into_owned())
+        .collect();
+    devices.sort();
+    devices
+}
+
+/// Write the completion script of `command`, completing arguments `device_args` with `devices`.
+pub fn write_completions(shell: Shell, mut command: Command, device_args: &[&str], devices: &[String], out: &mut dyn Write) {
+    if !devices.is_empty() {
+        for name in device_args {
+            // 仅影响生成的脚本, 解析时仍接受任意路径
+            command = command.mut_arg(*name, |arg| {
+                arg.value_parser(PossibleValuesParser::new(devices.iter().cloned()))
+            });
+        }
+    }
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, out);
+}
+
+/// Print the completion script of `command` to stdout, completing `device_args` with tape devices under /dev.
+pub fn print_completions(shell: Shell, command: Command, device_args: &[&str]) {
+    let devices = tape_devices(Path::new("/dev"));
+    write_completions(shell, command, device_args, &devices, &mut std::io::stdout());
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tape_devices, write_completions};
+    use clap::{Arg, Command};
+    use clap_complete::Shell;
+
+    #[test]
+    fn test_tape_devices() {
+        let dir = std::env::temp_dir().join(format!("completion-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["nsa0", "sa0", "sa0.ctl", "esa1", "sa1.0", "sata", "null"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let devices = tape_devices(&dir);
+        let names: Vec<_> = devices.iter().map(|path| path.rsplit('/').next().unwrap()).collect();
+        assert_eq!(names, ["esa1", "nsa0", "sa0", "sa1.0"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(tape_devices(std::path::Path::new("/no/such/dir")).is_empty());
+    }
+
+    #[test]
+    fn test_write_completions() {
+        let command = Command::new("tape")
+            .arg(Arg::new("device").short('f').long("device"))
+            .subcommand(Command::new("rewind"))
+            .subcommand(Command::new("completions").hide(true));
+        let devices = vec!["/dev/nsa0".to_string(), "/dev/nsa1".to_string()];
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let mut script = Vec::new();
+            write_completions(shell, command.clone(), &["device"], &devices, &mut script);
+            let script = String::from_utf8(script).unwrap();
+            assert!(script.contains("rewind"), "{shell}: {script}");
+            assert!(script.contains("/dev/nsa1"), "{shell}: {script}");
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Configuration shared by the tools, loaded from `/usr/local/etc/nas-toolbox.toml` and
+//! `~/.config/nas-toolbox.toml`.
+//!
+//! Values are taken in this order: command line flags, environment variables such as `NAS_TOOLBOX_TAPE_DEVICE`, the
+//! user file, then the system file. Flags are applied by each binary, so every field here is optional.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// System wide configuration file
+pub const SYSTEM_CONFIG: &str = "/usr/local/etc/nas-toolbox.toml";
+/// Prefix of environment variables overriding configuration files
+pub const ENV_PREFIX: &str = "NAS_TOOLBOX_";
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub tape: TapeConfig,
+    pub backup: BackupConfig,
+    pub d2fn: D2fnConfig,
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TapeConfig {
+    /// Tape device, such as /dev/nsa0
+    pub device: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    /// Tape device written by backups, the one of `[tape]` if not set
+    pub device: Option<String>,
+    /// Path of the backup database
+    pub database: Option<PathBuf>,
+    /// Read rate limit of the sources in MB/s
+    pub max_read_mbps: Option<u32>,
+    /// Times a source changed while archived is read again, before its archive is flagged torn
+    pub torn_retries: Option<u32>,
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct D2fnConfig {
+    /// Threads reading directories
+    pub walk_threads: Option<usize>,
+    /// Names of files and directories to skip
+    pub prune: Option<Vec<String>>,
+    /// Read rate limit in MB/s
+    pub max_read_mbps: Option<u32>,
+}
+
+/// Path of the configuration file of the current user, if `HOME` is set.
+pub fn user_config() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".config/nas-toolbox.toml"))
+}
+
+/// Parse the value of environment variable `name` into `field`, if set.
+fn override_with<T: FromStr>(field: &mut Option<T>, name: &str, var: &impl Fn(&str) -> Option<String>) -> Result<()>
+where
+    T::Err: std::fmt::Display,
+{
+    let name = format!("{ENV_PREFIX}{name}");
+    if let Some(value) = var(&name) {
+        let value = value.parse().map_err(|e| anyhow::anyhow!("invalid {name}={value}: {e}"))?;
+        *field = Some(value);
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Parse a configuration file. Errors of types and unknown keys tell the line and column.
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Load a configuration file, or `None` if it does not exist.
+    pub fn from_file(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}.", path.display())),
+        };
+        let config = Self::parse(&text).with_context(|| format!("invalid configuration {}.", path.display()))?;
+        Ok(Some(config))
+    }
+
+    /// Merge `other` into `self`, values set in `other` take precedence.
+    pub fn merge(self, other: Config) -> Config {
+        Config {
+            tape: TapeConfig {
+                device: other.tape.device.or(self.tape.device),
+            },
+            backup: BackupConfig {
+                device: other.backup.device.or(self.backup.device),
+                database: other.backup.database.or(self.backup.database),
+                max_read_mbps: other.backup.max_read_mbps.or(self.backup.max_read_mbps),
+                torn_retries: other.backup.torn_retries.or(self.backup.torn_retries),
+            },
+            d2fn: D2fnConfig {
+                walk_threads: other.d2fn.walk_threads.or(self.d2fn.walk_threads),
+                prune: other.d2fn.prune.or(self.d2fn.prune),
+                max_read_mbps: other.d2fn.max_read_mbps.or(self.d2fn.max_read_mbps),
+            },
+        }
+    }
+
+    /// Override values with environment variables looked up by `var`, named `NAS_TOOLBOX_<SECTION>_<KEY>`. Names to
+    /// prune are separated by commas.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        override_with(&mut self.tape.device, "TAPE_DEVICE", &var)?;
+        override_with(&mut self.backup.device, "BACKUP_DEVICE", &var)?;
+        override_with(&mut self.backup.database, "BACKUP_DATABASE", &var)?;
+        override_with(&mut self.backup.max_read_mbps, "BACKUP_MAX_READ_MBPS", &var)?;
+        override_with(&mut self.backup.torn_retries, "BACKUP_TORN_RETRIES", &var)?;
+        override_with(&mut self.d2fn.walk_threads, "D2FN_WALK_THREADS", &var)?;
+        override_with(&mut self.d2fn.max_read_mbps, "D2FN_MAX_READ_MBPS", &var)?;
+        if let Some(value) = var(&format!("{ENV_PREFIX}D2FN_PRUNE")) {
+            self.d2fn.prune = Some(value.split(',').map(str::to_string).collect());
+        }
+        Ok(())
+    }
+
+    /// Load the system file, the user file, and environment variables.
+    pub fn load() -> Result<Self> {
+        let mut config = Self::from_file(Path::new(SYSTEM_CONFIG))?.unwrap_or_default();
+        if let Some(path) = user_config() {
+            if let Some(user) = Self::from_file(&path)? {
+                config = config.merge(user);
+            }
+        }
+        config.apply_env(|name| std::env::var(name).ok())?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Config;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("[tape]\ndevice = \"/dev/nsa1\"\n\n[d2fn]\nprune = [\"@eaDir\"]\n").unwrap();
+        assert_eq!(config.tape.device.as_deref(), Some("/dev/nsa1"));
+        assert_eq!(config.d2fn.prune, Some(vec!["@eaDir".to_string()]));
+        assert_eq!(config.backup.database, None);
+
+        let e = Config::parse("[tape]\ndevice = \"/dev/nsa1\"\nblock = 1\n").unwrap_err();
+        let message = format!("{e:#}");
+        assert!(message.contains("line 3, column 1"), "{message}");
+        assert!(message.contains("unknown field `block`"), "{message}");
+
+        let e = Config::parse("[d2fn]\nwalk_threads = \"many\"\n").unwrap_err();
+        assert!(format!("{e:#}").contains("line 2, column 16"), "{e:#}");
+        assert!(Config::parse("[changer]\n").is_err());
+    }
+
+    #[test]
+    fn test_precedence() {
+        let system = Config::parse("[tape]\ndevice = \"/dev/nsa0\"\n[backup]\ndatabase = \"/var/db/backup.db\"\n").unwrap();
+        let user = Config::parse("[tape]\ndevice = \"/dev/nsa1\"\n").unwrap();
+        let mut config = system.merge(user);
+        assert_eq!(config.tape.device.as_deref(), Some("/dev/nsa1"));
+        assert_eq!(config.backup.database, Some(PathBuf::from("/var/db/backup.db")));
+
+        let vars = HashMap::from([
+            ("NAS_TOOLBOX_TAPE_DEVICE", "/dev/nsa2"),
+            ("NAS_TOOLBOX_D2FN_PRUNE", "@eaDir,.snapshot"),
+            ("NAS_TOOLBOX_D2FN_WALK_THREADS", "4"),
+            ("NAS_TOOLBOX_BACKUP_MAX_READ_MBPS", "40"),
+            ("NAS_TOOLBOX_BACKUP_TORN_RETRIES", "2"),
+        ]);
+        config.apply_env(|name| vars.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.tape.device.as_deref(), Some("/dev/nsa2"));
+        assert_eq!(config.d2fn.walk_threads, Some(4));
+        assert_eq!(config.backup.max_read_mbps, Some(40));
+        assert_eq!(config.backup.torn_retries, Some(2));
+        assert_eq!(config.d2fn.prune.unwrap(), ["@eaDir", ".snapshot"]);
+
+        let mut config = Config::default();
+        let e = config
+            .apply_env(|name| (name == "NAS_TOOLBOX_D2FN_WALK_THREADS").then(|| "many".to_string()))
+            .unwrap_err();
+        assert!(e.to_string().contains("NAS_TOOLBOX_D2FN_WALK_THREADS"));
+    }
+}
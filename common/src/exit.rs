@@ -0,0 +1,195 @@
+//! Exit codes shared by the binaries, so cron wrappers tell failures apart without parsing messages.
+//!
+//! | code | meaning                                      |
+//! |------|----------------------------------------------|
+//! | 0    | success                                      |
+//! | 1    | any other failure                            |
+//! | 2    | invalid arguments or configuration           |
+//! | 3    | nothing to do, such as no duplicates found   |
+//! | 4    | the drive does not support or report it      |
+//! | 5    | stopped at the end of data, by `tape dump`   |
+//! | 6    | interrupted, the archive being written ended |
+//! | 10   | device absent, or no tape loaded             |
+//! | 11   | wrong or unlabeled tape                      |
+//! | 12   | out of tape                                  |
+//! | 13   | data read back, or files checked, differ     |
+//! | 14   | device or lock held by another process       |
+//!
+//! With `--json`, or `NAS_TOOLBOX_MACHINE_ERRORS` set, the error is also printed to stderr as a single JSON object
+//! `{"ok": false, "error": {"kind": "out_of_tape", "code": 12, "message": "..."}}`.
+
+use clap::Parser;
+use serde::Serialize;
+use std::process::ExitCode;
+
+/// Exit code when the command succeeded but had nothing to do.
+pub const EXIT_NOTHING_TO_DO: u8 = 3;
+/// Exit code when a read succeeded but stopped at the end of data instead of a filemark.
+pub const EXIT_END_OF_DATA: u8 = 5;
+/// Exit code when a signal stopped the job after it finished and committed the archive being written.
+pub const EXIT_INTERRUPTED: u8 = 6;
+/// Environment variable asking for JSON errors, unless empty or `0`
+pub const MACHINE_ERRORS_ENV: &str = "NAS_TOOLBOX_MACHINE_ERRORS";
+
+/// Kind of a failure. Names and codes are a contract with scripts, do not change them.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Failure,
+    Usage,
+    Config,
+    Unsupported,
+    DeviceNotReady,
+    WrongTape,
+    OutOfTape,
+    VerificationFailed,
+    LockHeld,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Failure => 1,
+            ErrorKind::Usage | ErrorKind::Config => 2,
+            ErrorKind::Unsupported => 4,
+            ErrorKind::DeviceNotReady => 10,
+            ErrorKind::WrongTape => 11,
+            ErrorKind::OutOfTape => 12,
+            ErrorKind::VerificationFailed => 13,
+            ErrorKind::LockHeld => 14,
+        }
+    }
+}
+
+/// Kind of a failed tape operation, by its errno.
+#[cfg(feature = "tape")]
+pub fn tape_error_kind(error: &tape::device::TapeError) -> ErrorKind {
+    if error.is_unsupported() {
+        ErrorKind::Unsupported
+    } else if error.is_not_ready() {
+        ErrorKind::DeviceNotReady
+    } else if error.is_busy() {
+        ErrorKind::LockHeld
+    } else if error.is_end_of_medium() {
+        ErrorKind::OutOfTape
+    } else {
+        ErrorKind::Failure
+    }
+}
+
+/// Kind of an error of the library crates, `Failure` if it has no type known here. Binaries check their own error
+/// types before.
+pub fn error_kind(error: &anyhow::Error) -> ErrorKind {
+    #[cfg(feature = "tape")]
+    if let Some(error) = error.downcast_ref::<tape::device::TapeError>() {
+        return tape_error_kind(error);
+    }
+    #[cfg(not(feature = "tape"))]
+    let _ = error;
+    ErrorKind::Failure
+}
+
+/// Whether errors are printed as JSON: `json` is the `--json` flag of the binary.
+pub fn machine_errors(json: bool) -> bool {
+    json || std::env::var_os(MACHINE_ERRORS_ENV).is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+#[derive(Serialize)]
+struct MachineError<'a> {
+    ok: bool,
+    error: MachineErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct MachineErrorBody<'a> {
+    kind: ErrorKind,
+    code: u8,
+    message: &'a str,
+}
+
+fn to_json(kind: ErrorKind, message: &str) -> String {
+    let error = MachineError {
+        ok: false,
+        error: MachineErrorBody {
+            kind,
+            code: kind.exit_code(),
+            message,
+        },
+    };
+    serde_json::to_string(&error).expect("error is always serializable.")
+}
+
+/// Print the error to stderr, as JSON if `machine`, and return the exit code of its kind.
+pub fn report(kind: ErrorKind, message: &str, machine: bool) -> ExitCode {
+    if machine {
+        eprintln!("{}", to_json(kind, message));
+    } else {
+        eprintln!("error: {message}");
+    }
+    ExitCode::from(kind.exit_code())
+}
+
+/// Parse the command line. Help and version are printed by clap, so are invalid arguments unless JSON errors are
+/// asked, then they are reported as `Usage`.
+pub fn parse_args<P: Parser>() -> Result<P, ExitCode> {
+    match P::try_parse() {
+        Ok(args) => Ok(args),
+        Err(e) if !e.use_stderr() || !machine_errors(std::env::args().any(|arg| arg == "--json")) => e.exit(),
+        Err(e) => {
+            // 仅保留错误说明, 去掉其后的用法提示
+            let rendered = e.to_string();
+            let lines = rendered.lines().take_while(|line| !line.is_empty()).map(str::trim);
+            let message = lines.collect::<Vec<_>>().join(" ");
+            Err(report(ErrorKind::Usage, message.trim_start_matches("error: "), true))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{error_kind, to_json, ErrorKind};
+    use serde_json::json;
+
+    #[test]
+    fn test_exit_codes() {
+        let codes = [
+            (ErrorKind::Failure, 1),
+            (ErrorKind::Usage, 2),
+            (ErrorKind::Config, 2),
+            (ErrorKind::Unsupported, 4),
+            (ErrorKind::DeviceNotReady, 10),
+            (ErrorKind::WrongTape, 11),
+            (ErrorKind::OutOfTape, 12),
+            (ErrorKind::VerificationFailed, 13),
+            (ErrorKind::LockHeld, 14),
+        ];
+        for (kind, code) in codes {
+            assert_eq!(kind.exit_code(), code, "{kind:?}");
+        }
+        assert_eq!(error_kind(&anyhow::anyhow!("unknown")), ErrorKind::Failure);
+    }
+
+    #[test]
+    fn test_json() {
+        let value: serde_json::Value = serde_json::from_str(&to_json(ErrorKind::OutOfTape, "no space")).unwrap();
+        assert_eq!(
+            value,
+            json!({"ok": false, "error": {"kind": "out_of_tape", "code": 12, "message": "no space"}})
+        );
+    }
+
+    #[cfg(feature = "tape")]
+    #[test]
+    fn test_tape_errors() {
+        let e = tape::TapeDevice::open("/dev/no-such-tape").err().unwrap();
+        assert_eq!(error_kind(&e), ErrorKind::DeviceNotReady);
+        let e = e.context("failed to start the backup.");
+        assert_eq!(error_kind(&e), ErrorKind::DeviceNotReady);
+
+        let e = anyhow::Error::new(tape::device::TapeError::Unsupported {
+            device: "/dev/nsa0".to_string(),
+            capability: "setmarks",
+        });
+        assert_eq!(error_kind(&e).exit_code(), 4);
+    }
+}
@@ -0,0 +1,11 @@
+//! Code shared by the tape, backup and d2fn binaries.
+
+pub mod completion;
+pub mod config;
+pub mod exit;
+#[cfg(feature = "logging")]
+pub mod logging;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod since;
+pub mod throttle;
@@ -0,0 +1,66 @@
+//! Diagnostics through `tracing`, written to stderr or to a log file for runs from cron.
+//!
+//! Library crates only emit events, each binary installs the subscriber once in `main`.
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    /// Plain text, one event per line
+    Text,
+    /// JSON, one event per line
+    Json,
+}
+
+#[derive(Args)]
+pub struct LogArg {
+    /// Least level of events logged: error, warn, info, debug or trace. warn by default, info for backup. RUST_LOG
+    /// takes precedence if set
+    #[arg(long, global = true, value_name = "LEVEL")]
+    log_level: Option<String>,
+    /// Append events to FILE instead of printing them to stderr
+    #[arg(long, global = true, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+    /// Format of events logged
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+/// Install the subscriber of the process, logging events from `default_level` unless `--log-level` is given.
+pub fn init(arg: &LogArg, default_level: &str) -> Result<()> {
+    let level = arg.log_level.as_deref().unwrap_or(default_level);
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) => EnvFilter::try_new(directives).with_context(|| format!("invalid {}.", EnvFilter::DEFAULT_ENV))?,
+        Err(_) => EnvFilter::try_new(level).with_context(|| format!("invalid log level {level}."))?,
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_target(false);
+    match &arg.log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open {}.", path.display()))?;
+            let builder = builder.with_ansi(false).with_writer(Mutex::new(file));
+            match arg.log_format {
+                LogFormat::Text => builder.init(),
+                LogFormat::Json => builder.json().init(),
+            }
+        }
+        None => {
+            let builder = builder
+                .with_ansi(std::io::stderr().is_terminal())
+                .with_writer(std::io::stderr);
+            match arg.log_format {
+                LogFormat::Text => builder.init(),
+                LogFormat::Json => builder.json().init(),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,279 @@
+//! Progress of a running job in the Prometheus text format, served by `--metrics-listen`.
+//!
+//! The responder is a thread answering `GET /metrics` one connection at a time, which is enough for a scraper every few
+//! seconds. Binaries only create [`Metrics`] when the flag is given, so nothing is counted otherwise.
+
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the responder checks if it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Gauges and counters of a job, updated by the job and read by the responder.
+pub struct Metrics {
+    /// Binary exporting, as the `tool` label
+    tool: &'static str,
+    phase: Mutex<&'static str>,
+    bytes_processed: AtomicU64,
+    /// Bytes per second
+    throughput: AtomicU64,
+    early_warning: AtomicBool,
+    files_scanned: AtomicU64,
+    duplicates: AtomicU64,
+    errors: AtomicU64,
+    /// Unix time, 0 if no error occurred
+    last_error: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(tool: &'static str) -> Self {
+        Self {
+            tool,
+            phase: Mutex::new("start"),
+            bytes_processed: AtomicU64::new(0),
+            throughput: AtomicU64::new(0),
+            early_warning: AtomicBool::new(false),
+            files_scanned: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            last_error: AtomicU64::new(0),
+        }
+    }
+
+    /// Set the phase of the job, such as `discover` or `write`.
+    pub fn set_phase(&self, phase: &'static str) {
+        *self.phase.lock().unwrap() = phase;
+    }
+
+    /// Set bytes read or written since the job started.
+    pub fn set_bytes_processed(&self, bytes: u64) {
+        self.bytes_processed.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_processed(&self, bytes: u64) {
+        self.bytes_processed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Set the current throughput in bytes per second.
+    pub fn set_throughput(&self, bytes_per_second: u64) {
+        self.throughput.store(bytes_per_second, Ordering::Relaxed);
+    }
+
+    /// Set whether the tape is past its early warning mark.
+    pub fn set_early_warning(&self, past: bool) {
+        self.early_warning.store(past, Ordering::Relaxed);
+    }
+
+    /// Set files scanned, the count never goes back.
+    pub fn set_files_scanned(&self, files: u64) {
+        self.files_scanned.fetch_max(files, Ordering::Relaxed);
+    }
+
+    /// Set duplicate files found, the count never goes back.
+    pub fn set_duplicates(&self, files: u64) {
+        self.duplicates.fetch_max(files, Ordering::Relaxed);
+    }
+
+    /// Count `count` errors occurred now.
+    pub fn record_errors(&self, count: u64) {
+        if count == 0 {
+            return;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        self.errors.fetch_add(count, Ordering::Relaxed);
+        self.last_error.store(now, Ordering::Relaxed);
+    }
+
+    /// Metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let tool = self.tool;
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP nas_toolbox_{name} {help}");
+            let _ = writeln!(out, "# TYPE nas_toolbox_{name} {kind}");
+            let _ = writeln!(out, "nas_toolbox_{name}{{tool=\"{tool}\"}} {value}");
+        };
+        metric(
+            "processed_bytes_total",
+            "counter",
+            "Bytes read or written by the job.",
+            self.bytes_processed.load(Ordering::Relaxed),
+        );
+        metric(
+            "throughput_bytes_per_second",
+            "gauge",
+            "Current read or write rate.",
+            self.throughput.load(Ordering::Relaxed),
+        );
+        metric(
+            "tape_early_warning",
+            "gauge",
+            "1 if the tape is past its early warning mark.",
+            self.early_warning.load(Ordering::Relaxed) as u64,
+        );
+        metric(
+            "scanned_files_total",
+            "counter",
+            "Files scanned.",
+            self.files_scanned.load(Ordering::Relaxed),
+        );
+        metric(
+            "duplicate_files",
+            "gauge",
+            "Duplicate files found.",
+            self.duplicates.load(Ordering::Relaxed),
+        );
+        metric(
+            "errors_total",
+            "counter",
+            "Errors met by the job.",
+            self.errors.load(Ordering::Relaxed),
+        );
+        metric(
+            "last_error_timestamp_seconds",
+            "gauge",
+            "Unix time of the last error, 0 if none.",
+            self.last_error.load(Ordering::Relaxed),
+        );
+
+        let phase = *self.phase.lock().unwrap();
+        let _ = writeln!(out, "# HELP nas_toolbox_phase Phase the job is in.");
+        let _ = writeln!(out, "# TYPE nas_toolbox_phase gauge");
+        let _ = writeln!(out, "nas_toolbox_phase{{tool=\"{tool}\",phase=\"{phase}\"}} 1");
+        out
+    }
+}
+
+/// The responder, stopped and joined when dropped.
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    // 在 BSD 上, 接受的连接继承监听端口的非阻塞状态
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut request = [0u8; 1024];
+    let count = stream.read(&mut request)?;
+    let line = request[..count].split(|b| *b == b'\n').next().unwrap_or_default();
+
+    let (status, body) = if line.starts_with(b"GET /metrics ") || line.starts_with(b"GET / ") {
+        ("200 OK", metrics.render())
+    } else {
+        ("404 Not Found", "not found\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+impl MetricsServer {
+    /// Listen on `addr`, such as `127.0.0.1:9184`, and serve `metrics` in a thread.
+    pub fn start(addr: &str, metrics: Arc<Metrics>) -> Result<Self> {
+        let listener = TcpListener::bind(addr).with_context(|| format!("unable to listen on {addr}."))?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let stopped = stop.clone();
+        let thread = std::thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        // 单个抓取失败不影响任务
+                        let _ = respond(stream, &metrics);
+                    }
+                    // 没有连接时返回 WouldBlock
+                    Err(_) => std::thread::sleep(POLL_INTERVAL),
+                }
+            }
+        });
+        Ok(Self {
+            local_addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Metrics, MetricsServer};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new("d2fn");
+        metrics.set_phase("discover");
+        metrics.set_files_scanned(10);
+        metrics.set_files_scanned(3);
+        metrics.add_bytes_processed(512);
+        metrics.record_errors(2);
+
+        let text = metrics.render();
+        assert!(
+            text.contains("nas_toolbox_phase{tool=\"d2fn\",phase=\"discover\"} 1\n"),
+            "{text}"
+        );
+        assert!(text.contains("nas_toolbox_scanned_files_total{tool=\"d2fn\"} 10\n"), "{text}");
+        assert!(
+            text.contains("nas_toolbox_processed_bytes_total{tool=\"d2fn\"} 512\n"),
+            "{text}"
+        );
+        assert!(text.contains("nas_toolbox_errors_total{tool=\"d2fn\"} 2\n"), "{text}");
+        assert!(text.contains("# TYPE nas_toolbox_tape_early_warning gauge\n"), "{text}");
+        assert!(!text.contains("last_error_timestamp_seconds{tool=\"d2fn\"} 0\n"), "{text}");
+    }
+
+    #[test]
+    fn test_server() {
+        let metrics = Arc::new(Metrics::new("backup"));
+        let server = MetricsServer::start("127.0.0.1:0", metrics.clone()).unwrap();
+        let addr = server.local_addr();
+
+        metrics.set_early_warning(true);
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(
+            response.contains("nas_toolbox_tape_early_warning{tool=\"backup\"} 1\n"),
+            "{response}"
+        );
+        assert!(get(addr, "/other").starts_with("HTTP/1.1 404"));
+
+        drop(server);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+//! Cutoff times of incremental runs, given as `--since` either as an RFC 3339 timestamp or relative to now.
+
+use clap::ValueEnum;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Which time of a file is compared with the cutoff.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TimeField {
+    /// Last modification of the content
+    #[default]
+    Mtime,
+    /// Last change of the inode, also set by renames, chmod and hardlinks
+    Ctime,
+}
+
+impl TimeField {
+    pub fn of(self, metadata: &Metadata) -> SystemTime {
+        let (secs, nsecs) = match self {
+            TimeField::Mtime => (metadata.mtime(), metadata.mtime_nsec()),
+            TimeField::Ctime => (metadata.ctime(), metadata.ctime_nsec()),
+        };
+        let nanos = Duration::from_nanos(nsecs as u64);
+        if secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos
+        } else {
+            UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos
+        }
+    }
+}
+
+/// A file changed before the cutoff, by `field`, is skipped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Since {
+    pub cutoff: SystemTime,
+    pub field: TimeField,
+}
+
+impl Since {
+    /// Whether the file changed at or after the cutoff.
+    pub fn includes(&self, metadata: &Metadata) -> bool {
+        self.field.of(metadata) >= self.cutoff
+    }
+}
+
+/// Parse `value` relative to `now`: an RFC 3339 timestamp such as `2023-08-01T00:00:00+08:00`, or a count of
+/// seconds, minutes, hours, days or weeks ago, such as `90m` or `7d`.
+pub fn parse_since_at(value: &str, now: SystemTime) -> Result<SystemTime, String> {
+    if let Ok(time) = OffsetDateTime::parse(value, &Rfc3339) {
+        return Ok(time.into());
+    }
+    let unit_start = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (count, unit) = value.split_at(unit_start);
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => {
+            return Err(format!(
+                "expect an RFC 3339 time, or a duration such as 7d or 12h, not {value}"
+            ))
+        }
+    };
+    let count = count
+        .parse::<u64>()
+        .map_err(|_| format!("expect a count before the unit of {value}"))?;
+    count
+        .checked_mul(unit)
+        .and_then(|secs| now.checked_sub(Duration::from_secs(secs)))
+        .ok_or_else(|| format!("{value} is too far in the past"))
+}
+
+/// Parse a `--since` argument, see [`parse_since_at`].
+pub fn parse_since(value: &str) -> Result<SystemTime, String> {
+    parse_since_at(value, SystemTime::now())
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_since_at;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_parse_since() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let ago = |secs| Ok(now - Duration::from_secs(secs));
+        assert_eq!(parse_since_at("30s", now), ago(30));
+        assert_eq!(parse_since_at("90m", now), ago(90 * 60));
+        assert_eq!(parse_since_at("12h", now), ago(12 * 3600));
+        assert_eq!(parse_since_at("7d", now), ago(7 * 86400));
+        assert_eq!(parse_since_at("2w", now), ago(14 * 86400));
+        assert_eq!(parse_since_at("0d", now), Ok(now));
+        assert_eq!(
+            parse_since_at("2023-08-01T08:00:00+08:00", now),
+            Ok(UNIX_EPOCH + Duration::from_secs(1_690_848_000))
+        );
+        assert_eq!(
+            parse_since_at("2023-08-01T00:00:00.5Z", now),
+            Ok(UNIX_EPOCH + Duration::from_millis(1_690_848_000_500))
+        );
+
+        for invalid in ["", "7", "d", "7y", "-7d", "7 d", "1.5h", "2023-08-01"] {
+            assert!(parse_since_at(invalid, now).is_err(), "{invalid}");
+        }
+        assert!(parse_since_at("99999999999999w", SystemTime::now()).is_err());
+    }
+}
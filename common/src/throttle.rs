@@ -0,0 +1,201 @@
+//! Limit the read rate of a scan or a backup, so that the NAS stays responsive for other users.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Period over which the effective read rate is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+
+struct Bucket {
+    /// Bytes per second, `None` for unlimited.
+    limit: Option<u64>,
+    /// Available bytes, could be negative when a reader is in debt.
+    tokens: f64,
+    last_refill: Instant,
+
+    window_start: Instant,
+    window_bytes: u64,
+    /// Read rate measured in the last complete window, in bytes per second.
+    rate: u64,
+    /// Bytes read since the throttle was created
+    total_bytes: u64,
+}
+
+/// A token bucket over bytes read. Cloned handles share the same bucket, so the aggregated rate of all threads is
+/// what's bounded.
+#[derive(Clone)]
+pub struct Throttle {
+    bucket: Arc<Mutex<Bucket>>,
+    idle: Option<Duration>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+impl Throttle {
+    /// Conservative preset for spinning disks: 20MB/s, and a short break between files.
+    pub const NICE_READ_MBPS: u32 = 20;
+    pub const NICE_IDLE: Duration = Duration::from_millis(10);
+
+    pub fn unlimited() -> Self {
+        let now = Instant::now();
+        let bucket = Bucket {
+            limit: None,
+            tokens: 0.0,
+            last_refill: now,
+            window_start: now,
+            window_bytes: 0,
+            rate: 0,
+            total_bytes: 0,
+        };
+        Self {
+            bucket: Arc::new(Mutex::new(bucket)),
+            idle: None,
+        }
+    }
+
+    /// Limit read rate to `mbps` MB (1024 * 1024 bytes) per second.
+    pub fn max_read_mbps(self, mbps: u32) -> Self {
+        {
+            let mut bucket = self.bucket.lock().unwrap();
+            let limit = mbps as u64 * 1024 * 1024;
+            bucket.limit = Some(limit);
+            bucket.tokens = limit as f64;
+        }
+        self
+    }
+
+    /// Sleep for `duration` between files and between directories.
+    pub fn idle_between_files(mut self, duration: Duration) -> Self {
+        self.idle = Some(duration);
+        self
+    }
+
+    /// Account `bytes` just read, and block until the rate falls under the limit.
+    pub fn consume(&self, bytes: usize) {
+        let debt = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+
+            if now.duration_since(bucket.window_start) >= RATE_WINDOW {
+                let elapsed = now.duration_since(bucket.window_start).as_secs_f64();
+                bucket.rate = (bucket.window_bytes as f64 / elapsed) as u64;
+                bucket.window_start = now;
+                bucket.window_bytes = 0;
+            }
+            bucket.window_bytes += bytes as u64;
+            bucket.total_bytes += bytes as u64;
+
+            let Some(limit) = bucket.limit else {
+                return;
+            };
+            // 令牌桶容量为一秒的读取量
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * limit as f64;
+            bucket.tokens = (bucket.tokens + refill).min(limit as f64) - bytes as f64;
+            bucket.last_refill = now;
+
+            if bucket.tokens < 0.0 {
+                Some(Duration::from_secs_f64(-bucket.tokens / limit as f64))
+            } else {
+                None
+            }
+        };
+        // 在锁外等待, 其他线程会看到同样的欠额而等待更久.
+        if let Some(debt) = debt {
+            std::thread::sleep(debt);
+        }
+    }
+
+    /// Take a break, if configured.
+    pub fn idle(&self) {
+        if let Some(duration) = self.idle {
+            std::thread::sleep(duration);
+        }
+    }
+
+    /// Whether [`Throttle::idle`] takes a break.
+    pub fn idles(&self) -> bool {
+        self.idle.is_some()
+    }
+
+    /// Whether the read rate is limited.
+    pub fn is_limited(&self) -> bool {
+        self.bucket.lock().unwrap().limit.is_some()
+    }
+
+    /// Effective read rate in bytes per second, measured over the last few seconds.
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Bytes read in total.
+    pub fn bytes_read(&self) -> u64 {
+        self.bucket.lock().unwrap().total_bytes
+    }
+}
+
+/// Lower the priority of the calling thread, so that its reads give way to other users of the pool. Only readers call
+/// it, a tape writer keeps its priority to stream what is buffered. Returns whether the platform allowed it.
+pub fn lower_thread_priority() -> bool {
+    #[cfg(target_os = "freebsd")]
+    {
+        // FreeBSD 没有 I/O 优先级, 改为空闲调度类, 仅在 CPU 空闲时运行
+        let mut rtprio = libc::rtprio {
+            type_: libc::RTP_PRIO_IDLE,
+            prio: 0,
+        };
+        unsafe { libc::rtprio_thread(libc::RTP_SET, 0, &mut rtprio) == 0 }
+    }
+    #[cfg(target_os = "linux")]
+    {
+        // 当前线程设为尽力而为类的最低级别
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_BEST_EFFORT_LOWEST: libc::c_int = (2 << 13) | 7;
+        unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_BEST_EFFORT_LOWEST) == 0 }
+    }
+    #[cfg(not(any(target_os = "freebsd", target_os = "linux")))]
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::Throttle;
+    use std::time::Instant;
+
+    #[test]
+    fn test_rate_is_bounded() {
+        let throttle = Throttle::unlimited().max_read_mbps(8);
+        let instant = Instant::now();
+
+        // 初始令牌为一秒的量, 之后的 4MB 需要约半秒.
+        let handles = (0..4)
+            .map(|_| {
+                let throttle = throttle.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..3 {
+                        throttle.consume(1024 * 1024);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(instant.elapsed().as_millis() >= 450);
+        assert_eq!(throttle.bytes_read(), 12 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let throttle = Throttle::unlimited();
+        let instant = Instant::now();
+
+        for _ in 0..100 {
+            throttle.consume(1024 * 1024 * 1024);
+        }
+        assert!(instant.elapsed().as_millis() < 100);
+    }
+}
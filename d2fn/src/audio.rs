@@ -0,0 +1,355 @@
+//! Hash only the audio frames of MP3, FLAC and Ogg files, so that copies with different tags are still found.
+//!
+//! The parsers only look for where frame data begins and ends: ID3v2 headers, APE and ID3v1 trailers, FLAC metadata
+//! blocks (Vorbis comments included), and Ogg header pages are skipped. Files which can not be parsed are hashed as a
+//! whole, and thus only match exact copies.
+
+use anyhow::{bail, Result};
+use blake3::Hash;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::duplicate::{File, InodeKey};
+use common::throttle::Throttle;
+
+const AUDIO_EXT: [&str; 4] = ["mp3", "flac", "ogg", "opus"];
+
+pub fn is_audio(path: &Path) -> bool {
+    audio_ext(path).is_some()
+}
+
+fn audio_ext(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    AUDIO_EXT.iter().find(|&&e| e == ext).copied()
+}
+
+/// Size of the ID3v2 tag at the beginning, 0 if there is none.
+fn id3v2_size<R: Read + Seek>(reader: &mut R, start: u64) -> Result<u64> {
+    let mut header = [0u8; 10];
+    reader.seek(SeekFrom::Start(start))?;
+    if reader.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        return Ok(0);
+    }
+    // 长度为 syncsafe 整数, 每字节仅用低 7 位
+    let size = header[6..10].iter().fold(0u64, |acc, &b| (acc << 7) | (b & 0x7f) as u64);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Ok(10 + size + footer)
+}
+
+/// Where trailing tags begin: APEv2 and ID3v1, in any order.
+fn trailer_start<R: Read + Seek>(reader: &mut R, mut end: u64) -> Result<u64> {
+    loop {
+        if end >= 128 {
+            let mut tag = [0u8; 3];
+            reader.seek(SeekFrom::Start(end - 128))?;
+            reader.read_exact(&mut tag)?;
+            if &tag == b"TAG" {
+                end -= 128;
+                continue;
+            }
+        }
+        if end >= 32 {
+            let mut footer = [0u8; 32];
+            reader.seek(SeekFrom::Start(end - 32))?;
+            reader.read_exact(&mut footer)?;
+            if &footer[..8] == b"APETAGEX" {
+                // 标签长度包含 footer, 不含可选的 header
+                let size = u32::from_le_bytes(footer[12..16].try_into().unwrap()) as u64;
+                let flags = u32::from_le_bytes(footer[20..24].try_into().unwrap());
+                let header = if flags & 0x8000_0000 != 0 { 32 } else { 0 };
+                if size + header > end {
+                    bail!("bad APE tag size.");
+                }
+                end -= size + header;
+                continue;
+            }
+        }
+        return Ok(end);
+    }
+}
+
+fn mp3_frames<R: Read + Seek>(reader: &mut R, len: u64) -> Result<Vec<Range<u64>>> {
+    let mut start = 0;
+    loop {
+        let size = id3v2_size(reader, start)?;
+        if size == 0 {
+            break;
+        }
+        start += size;
+    }
+    let end = trailer_start(reader, len)?;
+
+    let mut sync = [0u8; 2];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut sync)?;
+    if sync[0] != 0xff || sync[1] & 0xe0 != 0xe0 || start >= end {
+        bail!("no MPEG frame at {start}.");
+    }
+    Ok(std::iter::once(start..end).collect())
+}
+
+fn flac_frames<R: Read + Seek>(reader: &mut R, len: u64) -> Result<Vec<Range<u64>>> {
+    let mut start = id3v2_size(reader, 0)?;
+    let mut magic = [0u8; 4];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut magic)?;
+    if &magic != b"fLaC" {
+        bail!("not a FLAC stream.");
+    }
+    start += 4;
+
+    // 元数据块: 1 字节 (最后一块标志 + 类型) 与 24 位长度
+    loop {
+        let mut header = [0u8; 4];
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(&mut header)?;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        start += 4 + size;
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+        if start >= len {
+            bail!("metadata block runs past the end.");
+        }
+    }
+    let end = trailer_start(reader, len)?;
+
+    let mut sync = [0u8; 2];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut sync)?;
+    if sync[0] != 0xff || sync[1] & 0xfe != 0xf8 || start >= end {
+        bail!("no FLAC frame at {start}.");
+    }
+    Ok(std::iter::once(start..end).collect())
+}
+
+/// Payload of Ogg pages after the headers. Header pages (identification, comments, setup) have granule position 0,
+/// and audio always starts on a fresh page. Page headers are left out as their sequence numbers and checksums change
+/// with the comment length.
+fn ogg_frames<R: Read + Seek>(reader: &mut R, len: u64) -> Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::new();
+    let mut position = 0;
+
+    while position < len {
+        let mut header = [0u8; 27];
+        reader.seek(SeekFrom::Start(position))?;
+        reader.read_exact(&mut header)?;
+        if &header[..4] != b"OggS" {
+            bail!("no Ogg page at {position}.");
+        }
+        let granule = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let mut segments = vec![0u8; header[26] as usize];
+        reader.read_exact(&mut segments)?;
+
+        let data_start = position + 27 + segments.len() as u64;
+        let data_end = data_start + segments.iter().map(|&s| s as u64).sum::<u64>();
+        if data_end > len {
+            bail!("truncated Ogg page at {position}.");
+        }
+        if granule != 0 && data_end > data_start {
+            ranges.push(data_start..data_end);
+        }
+        position = data_end;
+    }
+    if ranges.is_empty() {
+        bail!("no audio page.");
+    }
+    Ok(ranges)
+}
+
+/// Byte ranges holding audio frames, or an error if the file does not look like what its extension says.
+pub fn frame_ranges<R: Read + Seek>(reader: &mut R, len: u64, ext: &str) -> Result<Vec<Range<u64>>> {
+    match ext {
+        "mp3" => mp3_frames(reader, len),
+        "flac" => flac_frames(reader, len),
+        "ogg" | "opus" => ogg_frames(reader, len),
+        _ => bail!("unsupported audio format {ext}."),
+    }
+}
+
+pub struct AudioHash {
+    pub hash: Hash,
+    /// The file could not be parsed, and was hashed as a whole.
+    pub whole_file: bool,
+}
+
+/// Hash the audio frames of a file, or the whole file if the format is not recognized.
+pub fn audio_hash(path: &Path, throttle: &Throttle) -> Result<AudioHash> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let ext = audio_ext(path).unwrap_or_default();
+
+    let (ranges, whole_file) = match frame_ranges(&mut file, len, ext) {
+        Ok(ranges) => (ranges, false),
+        Err(_) => (std::iter::once(0..len).collect(), true),
+    };
+
+    const CHUNK_SIZE: usize = 1024 * 1024;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut hasher = blake3::Hasher::new();
+    for range in ranges {
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut remaining = range.end - range.start;
+        while remaining > 0 {
+            let want = remaining.min(CHUNK_SIZE as u64) as usize;
+            file.read_exact(&mut buffer[..want])?;
+            throttle.consume(want);
+            hasher.update(&buffer[..want]);
+            remaining -= want as u64;
+        }
+    }
+    throttle.idle();
+    Ok(AudioHash {
+        hash: hasher.finalize(),
+        whole_file,
+    })
+}
+
+pub struct AudioEntry {
+    pub path: PathBuf,
+    pub ino: u64,
+    pub size: u64,
+}
+
+/// Group audio files by the hash of their frames.
+#[derive(Default)]
+pub struct AudioIndex {
+    inode_set: HashSet<InodeKey>,
+    by_hash: HashMap<Hash, Vec<AudioEntry>>,
+    /// Files not parsed as audio, compared as whole files
+    pub fallback: Vec<PathBuf>,
+    /// Files which could not be read
+    pub failed: Vec<PathBuf>,
+}
+
+impl AudioIndex {
+    pub fn add(&mut self, file: &File, throttle: &Throttle) {
+        if !is_audio(&file.path) || !self.inode_set.insert((file.metadata.dev, file.metadata.ino)) {
+            return;
+        }
+
+        match audio_hash(&file.path, throttle) {
+            Ok(audio) => {
+                if audio.whole_file {
+                    self.fallback.push(file.path.clone());
+                }
+                self.by_hash.entry(audio.hash).or_default().push(AudioEntry {
+                    path: file.path.clone(),
+                    ino: file.metadata.ino,
+                    size: file.metadata.size,
+                });
+            }
+            Err(_) => self.failed.push(file.path.clone()),
+        }
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &Vec<AudioEntry>> {
+        self.by_hash.values().filter(|group| group.len() > 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::frame_ranges;
+    use std::io::Cursor;
+
+    fn frames_of(data: &[u8], ext: &str) -> anyhow::Result<Vec<u8>> {
+        let ranges = frame_ranges(&mut Cursor::new(data), data.len() as u64, ext)?;
+        Ok(ranges
+            .into_iter()
+            .flat_map(|r| data[r.start as usize..r.end as usize].to_vec())
+            .collect())
+    }
+
+    fn id3v2(text: &str) -> Vec<u8> {
+        let size = text.len() as u32;
+        let syncsafe = [
+            (size >> 21) as u8 & 0x7f,
+            (size >> 14) as u8 & 0x7f,
+            (size >> 7) as u8 & 0x7f,
+            size as u8 & 0x7f,
+        ];
+        let mut tag = b"ID3\x04\x00\x00".to_vec();
+        tag.extend(syncsafe);
+        tag.extend(text.as_bytes());
+        tag
+    }
+
+    fn id3v1(title: &str) -> Vec<u8> {
+        let mut tag = b"TAG".to_vec();
+        tag.extend(title.as_bytes());
+        tag.resize(128, 0);
+        tag
+    }
+
+    const MPEG_FRAMES: &[u8] = b"\xff\xfb\x90\x00frame data\xff\xfb\x90\x00more frames";
+
+    #[test]
+    fn test_mp3_tags_skipped() {
+        let a = [id3v2("title A"), MPEG_FRAMES.to_vec()].concat();
+        let b = [id3v2("a much longer title B"), MPEG_FRAMES.to_vec(), id3v1("B")].concat();
+
+        assert_eq!(frames_of(&a, "mp3").unwrap(), MPEG_FRAMES);
+        assert_eq!(frames_of(&b, "mp3").unwrap(), MPEG_FRAMES);
+        assert!(frames_of(b"not an mp3 file at all", "mp3").is_err());
+    }
+
+    fn flac(comment: &str) -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        // STREAMINFO, 34 字节
+        data.extend([0x00, 0, 0, 34]);
+        data.extend([7u8; 34]);
+        // VORBIS_COMMENT, 最后一块
+        let len = comment.len() as u32;
+        data.extend([0x84, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        data.extend(comment.as_bytes());
+        data.extend(b"\xff\xf8flac frames");
+        data
+    }
+
+    #[test]
+    fn test_flac_metadata_skipped() {
+        let a = flac("ARTIST=a");
+        let b = flac("ARTIST=someone else");
+
+        assert_eq!(frames_of(&a, "flac").unwrap(), b"\xff\xf8flac frames");
+        assert_eq!(frames_of(&a, "flac").unwrap(), frames_of(&b, "flac").unwrap());
+        assert!(frames_of(&a[..20], "flac").is_err());
+    }
+
+    fn ogg_page(granule: u64, sequence: u32, data: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00\x00".to_vec();
+        page.extend(granule.to_le_bytes());
+        page.extend(1u32.to_le_bytes());
+        page.extend(sequence.to_le_bytes());
+        page.extend([0u8; 4]);
+        page.push(1);
+        page.push(data.len() as u8);
+        page.extend(data);
+        page
+    }
+
+    #[test]
+    fn test_ogg_header_pages_skipped() {
+        let a = [
+            ogg_page(0, 0, b"\x01vorbis"),
+            ogg_page(0, 1, b"\x03vorbis comment A"),
+            ogg_page(1024, 2, b"audio"),
+        ]
+        .concat();
+        let b = [
+            ogg_page(0, 0, b"\x01vorbis"),
+            ogg_page(0, 1, b"\x03vorbis"),
+            ogg_page(0, 2, b"comment B, split over another page"),
+            ogg_page(1024, 3, b"audio"),
+        ]
+        .concat();
+
+        assert_eq!(frames_of(&a, "ogg").unwrap(), b"audio");
+        assert_eq!(frames_of(&b, "ogg").unwrap(), b"audio");
+        assert!(frames_of(&a[..a.len() - 2], "ogg").is_err());
+    }
+}
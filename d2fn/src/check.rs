@@ -0,0 +1,234 @@
+//! Re-check files listed in an inventory, e.g. after restoring from tape or a scrub.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::hash::{checksum_file_throttled, CachePolicy, CompareMode, Digest, HashAlgorithm, HashKey};
+use crate::inventory::InventoryReader;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileStatus {
+    Ok,
+    Missing,
+    /// Content differs from the other files of its group
+    Changed,
+    Unreadable(String),
+}
+
+pub enum CheckEvent<'a> {
+    /// A large file in progress.
+    Hashing {
+        path: &'a Path,
+        done: u64,
+        total: u64,
+        /// Bytes hashed before this file
+        bytes_hashed: u64,
+    },
+    Checked(&'a Path, &'a FileStatus),
+}
+
+#[derive(Default, Debug)]
+pub struct CheckStats {
+    pub files_checked: usize,
+    pub missing: usize,
+    pub changed: usize,
+    pub unreadable: usize,
+    /// Groups which could not be decoded
+    pub bad_groups: usize,
+    pub bytes_hashed: u64,
+}
+
+impl CheckStats {
+    pub fn failed(&self) -> usize {
+        self.missing + self.changed + self.unreadable + self.bad_groups
+    }
+}
+
+/// The digest shared by most files, the earliest one on a tie.
+pub(crate) fn majority(digests: &[Option<Digest>]) -> Option<Digest> {
+    let mut counts: HashMap<Digest, (usize, usize)> = HashMap::new();
+    for (i, digest) in digests.iter().enumerate() {
+        if let Some(digest) = digest {
+            counts.entry(*digest).or_insert((0, i)).0 += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(_, (count, first))| (*count, std::cmp::Reverse(*first)))
+        .map(|(digest, _)| digest)
+}
+
+/// Hash every file in `inventory` and report each of them to `report`. Files are compared with the hash recorded for
+/// their group, and with their recorded size. Groups without a hash, as in inventories before version 3, are compared
+/// with each other: the content most of them share is taken as the expected one. Groups of similar files are only
+/// checked for presence. Relative paths are anchored to `roots` if given, see [`InventoryReader::anchor_to`].
+pub fn verify_inventory<P: AsRef<Path>>(
+    inventory: P,
+    key: Option<&HashKey>,
+    roots: &[PathBuf],
+    cache: CachePolicy,
+    mut report: impl FnMut(CheckEvent),
+) -> Result<CheckStats> {
+    let _span = tracing::info_span!("check").entered();
+    let reader = InventoryReader::open(inventory)?.read_only_sequential().anchor_to(roots)?;
+    reader.check_key(key)?;
+
+    let mut stats = CheckStats::default();
+    for group in reader {
+        let group = match group {
+            Ok(g) => g,
+            Err(e) => {
+                tracing::error!("unable to read duplicate group: {e}");
+                stats.bad_groups += 1;
+                continue;
+            }
+        };
+        let paths = group.files.iter().map(|f| PathBuf::from(&f.path)).collect::<Vec<_>>();
+        let mode = match group.hash.and_then(|h| h.prefix) {
+            Some(prefix) => CompareMode::Part(prefix as usize),
+            None => CompareMode::Full,
+        };
+
+        let mut statuses = Vec::with_capacity(paths.len());
+        let mut digests = Vec::with_capacity(paths.len());
+        for (path, file) in paths.iter().zip(&group.files) {
+            let (status, digest) = match std::fs::metadata(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (FileStatus::Missing, None),
+                Err(e) => (FileStatus::Unreadable(e.to_string()), None),
+                Ok(_) if group.similar => (FileStatus::Ok, None),
+                Ok(metadata) if file.size.is_some_and(|size| size != metadata.len()) => (FileStatus::Changed, None),
+                Ok(_) => {
+                    let bytes_hashed = stats.bytes_hashed;
+                    let mut progress = |done, total| {
+                        report(CheckEvent::Hashing {
+                            path,
+                            done,
+                            total,
+                            bytes_hashed,
+                        })
+                    };
+                    let checksum =
+                        checksum_file_throttled(path, mode, HashAlgorithm::Blake3, key, cache, None, Some(&mut progress));
+                    match checksum {
+                        Ok(checksum) => {
+                            stats.bytes_hashed += checksum.bytes_hashed;
+                            (FileStatus::Ok, Some(checksum.hash))
+                        }
+                        Err(e) => (FileStatus::Unreadable(format!("{e:#}")), None),
+                    }
+                }
+            };
+            statuses.push(status);
+            digests.push(digest);
+        }
+
+        let expected = match group.hash {
+            Some(hash) => Some(Digest::Blake3(blake3::Hash::from(hash.blake3))),
+            None => majority(&digests),
+        };
+        for ((path, mut status), digest) in paths.iter().zip(statuses).zip(digests) {
+            if digest.is_some() && digest != expected {
+                status = FileStatus::Changed;
+            }
+            match status {
+                FileStatus::Ok => {}
+                FileStatus::Missing => stats.missing += 1,
+                FileStatus::Changed => stats.changed += 1,
+                FileStatus::Unreadable(_) => stats.unreadable += 1,
+            }
+            stats.files_checked += 1;
+            report(CheckEvent::Checked(path, &status));
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{verify_inventory, CheckEvent, FileStatus};
+    use crate::hash::CachePolicy;
+    use crate::inventory::{DuplicateFile, DuplicateGroup, GroupHash, InventoryWriter};
+
+    #[test]
+    fn test_verify_inventory() {
+        let dir = std::env::temp_dir().join(format!("d2fn-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = ["a", "b", "c", "d"].map(|name| dir.join(name));
+        for path in &paths[..3] {
+            std::fs::write(path, "same content").unwrap();
+        }
+        let group = DuplicateGroup {
+            files: paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| DuplicateFile::new(i as u64, path))
+                .collect(),
+            similar: false,
+            hash: None,
+            whole: false,
+        };
+        let inventory = dir.join("inventory");
+        InventoryWriter::create(&inventory)
+            .unwrap()
+            .export(std::iter::once(group))
+            .unwrap();
+        // c 在清单生成后被改写, d 已被删除.
+        std::fs::write(&paths[2], "other content").unwrap();
+
+        let mut checked = Vec::new();
+        let stats = verify_inventory(&inventory, None, &[], CachePolicy::DontNeed, |event| {
+            if let CheckEvent::Checked(path, status) = event {
+                checked.push((path.file_name().unwrap().to_owned(), *status == FileStatus::Ok));
+            }
+        })
+        .unwrap();
+        assert_eq!((stats.files_checked, stats.changed, stats.missing), (4, 1, 1));
+        assert_eq!(stats.failed(), 2);
+        assert_eq!(checked.iter().filter(|(_, ok)| *ok).count(), 2);
+        assert!(!checked[2].1 && !checked[3].1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_recorded_hash() {
+        let dir = std::env::temp_dir().join(format!("d2fn-check-hash-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = ["a", "b", "c"].map(|name| dir.join(name));
+        for path in &paths {
+            std::fs::write(path, "original").unwrap();
+        }
+        let files = paths.iter().map(|path| {
+            let metadata = crate::metadata::convert_metadata(std::fs::metadata(path).unwrap());
+            DuplicateFile::scanned(path, &metadata)
+        });
+        let group = DuplicateGroup {
+            files: files.collect(),
+            similar: false,
+            hash: Some(GroupHash {
+                blake3: *blake3::hash(b"orig").as_bytes(),
+                prefix: Some(4),
+            }),
+            whole: false,
+        };
+        let inventory = dir.join("inventory");
+        InventoryWriter::create(&inventory)
+            .unwrap()
+            .export(std::iter::once(group))
+            .unwrap();
+        // 多数文件被改写, 仍以记录的哈希为准; 大小变化的文件无需再读.
+        std::fs::write(&paths[1], "modified").unwrap();
+        std::fs::write(&paths[2], "grown larger").unwrap();
+
+        let mut changed = Vec::new();
+        let stats = verify_inventory(&inventory, None, &[], CachePolicy::Keep, |event| {
+            if let CheckEvent::Checked(path, FileStatus::Changed) = event {
+                changed.push(path.to_path_buf());
+            }
+        })
+        .unwrap();
+        assert_eq!(changed, paths[1..]);
+        assert_eq!(stats.bytes_hashed, 8);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
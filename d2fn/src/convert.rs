@@ -0,0 +1,240 @@
+//! Convert inventories to and from JSON Lines, for scripts.
+//!
+//! The first line holds the key id if hashes are keyed, each following line is a group.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use crate::inventory::{DuplicateFile, DuplicateGroup, ExportSummary, GroupHash, InventoryReader, InventoryWriter};
+
+#[derive(Serialize, Deserialize)]
+struct JsonFile {
+    ino: u64,
+    /// Lossy if the path is not valid UTF-8, `path_bytes` is given then.
+    path: String,
+    /// Base64 of the path, only for paths which are not valid UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path_bytes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dev: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime_nsec: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonGroup {
+    #[serde(default)]
+    similar: bool,
+    /// Hex of the blake3 hash files share
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    /// Length of the prefix hashed, if files are not hashed as a whole
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prefix: Option<u64>,
+    /// Files are identical as a whole, see [`DuplicateGroup::whole`]
+    #[serde(default)]
+    whole: bool,
+    files: Vec<JsonFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonHeader {
+    key_id: u64,
+}
+
+impl JsonFile {
+    fn from_inventory(file: DuplicateFile) -> Self {
+        let path = PathBuf::from(file.path);
+        let bytes = path.as_os_str().as_bytes();
+        let path_bytes = std::str::from_utf8(bytes)
+            .is_err()
+            .then(|| base64::engine::general_purpose::STANDARD.encode(bytes));
+        Self {
+            ino: file.ino,
+            path: path.to_string_lossy().to_string(),
+            path_bytes,
+            dev: file.dev,
+            size: file.size,
+            mtime: file.mtime,
+            mtime_nsec: file.mtime_nsec,
+        }
+    }
+
+    fn into_inventory(self) -> Result<DuplicateFile> {
+        let path = match self.path_bytes {
+            Some(bytes) => {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(bytes)
+                    .with_context(|| format!("invalid path_bytes of {}.", self.path))?;
+                PathBuf::from(std::ffi::OsString::from_vec(bytes))
+            }
+            None => PathBuf::from(self.path),
+        };
+        let mut file = DuplicateFile::new(self.ino, Path::new(&path));
+        file.dev = self.dev;
+        file.size = self.size;
+        file.mtime = self.mtime;
+        file.mtime_nsec = self.mtime_nsec;
+        Ok(file)
+    }
+}
+
+impl JsonGroup {
+    fn from_inventory(group: DuplicateGroup) -> Self {
+        Self {
+            similar: group.similar,
+            hash: group.hash.map(|h| blake3::Hash::from(h.blake3).to_hex().to_string()),
+            prefix: group.hash.and_then(|h| h.prefix),
+            whole: group.whole,
+            files: group.files.into_iter().map(JsonFile::from_inventory).collect(),
+        }
+    }
+
+    fn into_inventory(self) -> Result<DuplicateGroup> {
+        let hash = match self.hash {
+            Some(hex) => Some(GroupHash {
+                blake3: *blake3::Hash::from_hex(&hex)
+                    .with_context(|| format!("invalid hash {hex}."))?
+                    .as_bytes(),
+                prefix: self.prefix,
+            }),
+            None => None,
+        };
+        let files = self.files.into_iter().map(JsonFile::into_inventory).collect::<Result<_>>()?;
+        Ok(DuplicateGroup {
+            files,
+            similar: self.similar,
+            whole: self.whole || hash.is_some_and(|h| h.prefix.is_none()),
+            hash,
+        })
+    }
+}
+
+/// Write groups of `reader` to `writer` as JSON Lines, and return the count of groups.
+pub fn inventory_to_json<W: Write>(reader: InventoryReader, mut writer: W) -> Result<usize> {
+    if let Some(key_id) = reader.key_id() {
+        serde_json::to_writer(&mut writer, &JsonHeader { key_id })?;
+        writeln!(writer)?;
+    }
+    let mut count = 0;
+    for group in reader.read_only_sequential() {
+        serde_json::to_writer(&mut writer, &JsonGroup::from_inventory(group?))?;
+        writeln!(writer)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Read JSON Lines written by [`inventory_to_json`] and export them to `writer`. Empty lines are skipped, and so are
+/// invalid ones unless `abort_on_error` is set.
+pub fn inventory_from_json<R: BufRead, W: Write>(
+    reader: R,
+    mut writer: InventoryWriter<W>,
+    abort_on_error: bool,
+) -> Result<ExportSummary> {
+    let mut lines = reader.lines().enumerate().peekable();
+    // 首行可能记录了密钥 id
+    if let Some((_, Ok(line))) = lines.peek() {
+        if let Ok(header) = serde_json::from_str::<JsonHeader>(line) {
+            writer = writer.key_id(header.key_id);
+            lines.next();
+        }
+    }
+
+    // 读取失败时不再继续, 无效的行则交给 export_results 处理
+    let mut error = None;
+    let groups = lines
+        .map_while(|(i, line)| match line {
+            Ok(line) => Some((i, line)),
+            Err(e) => {
+                error = Some(anyhow::Error::from(e).context(format!("unable to read line {}.", i + 1)));
+                None
+            }
+        })
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str::<JsonGroup>(&line)
+                .map_err(anyhow::Error::from)
+                .and_then(JsonGroup::into_inventory)
+                .with_context(|| format!("invalid group at line {}.", i + 1))
+        });
+    let summary = writer.export_results(groups, abort_on_error)?;
+    match error {
+        Some(e) => Err(e),
+        None => Ok(summary),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{inventory_from_json, inventory_to_json};
+    use crate::hash::HashKey;
+    use crate::inventory::{DuplicateFile, DuplicateGroup, GroupHash, InventoryReader, InventoryWriter};
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    /// A path in the temporary directory, unique to this process.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("d2fn-convert-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (path, converted) = (&temp_path("json"), &temp_path("json-back"));
+        // 非 UTF-8 文件名
+        let name = Path::new(OsStr::from_bytes(b"caf\xe9.txt"));
+        let group = DuplicateGroup {
+            files: vec![DuplicateFile::new(1, name), DuplicateFile::new(2, Path::new("b.txt"))],
+            similar: false,
+            hash: Some(GroupHash {
+                blake3: [3; 32],
+                prefix: Some(1024),
+            }),
+            whole: false,
+        };
+        let key = HashKey::from([1u8; 32]);
+        InventoryWriter::create(path)
+            .unwrap()
+            .hash_key(Some(&key))
+            .export(std::iter::once(group))
+            .unwrap();
+
+        let mut json = Vec::new();
+        assert_eq!(inventory_to_json(InventoryReader::open(path).unwrap(), &mut json).unwrap(), 1);
+        let text = String::from_utf8(json.clone()).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.contains("\"path_bytes\":\"Y2Fm6S50eHQ=\""));
+
+        let writer = InventoryWriter::create(converted).unwrap();
+        assert_eq!(inventory_from_json(json.as_slice(), writer, true).unwrap().written, 1);
+        let mut reader = InventoryReader::open(converted).unwrap();
+        assert_eq!(reader.key_id(), Some(key.id()));
+        let group = reader.next().unwrap().unwrap();
+        assert_eq!(PathBuf::from(&group.files[0].path).as_os_str().as_bytes(), b"caf\xe9.txt");
+        assert_eq!(group.hash.unwrap().prefix, Some(1024));
+
+        let writer = InventoryWriter::create(converted).unwrap();
+        assert!(inventory_from_json(&b"{\"files\": 1}\n"[..], writer, true).is_err());
+
+        // 跳过无效的行, 其余组照常写入
+        json.extend_from_slice(b"{\"files\": 1}\n");
+        let writer = InventoryWriter::create(converted).unwrap();
+        let summary = inventory_from_json(json.as_slice(), writer, false).unwrap();
+        assert_eq!(summary.written, 1);
+        assert_eq!(summary.failed.len(), 1);
+        assert!(format!("{}", summary.failed[0].1).contains("line 3"));
+        assert_eq!(InventoryReader::open(converted).unwrap().total(), Some(1));
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(converted).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+//! Compare two inventories, to see which duplicates are resolved since the older scan.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::inventory::{DuplicateGroup, FileKey, GroupHash, InventoryReader, InventoryWriter};
+
+#[derive(Serialize, Default, Debug, PartialEq, Eq)]
+pub struct Category {
+    pub groups: usize,
+    /// Bytes taken by extra copies, by sizes recorded or found on disk.
+    pub wasted: u64,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct InventoryDiff {
+    /// Groups of the old inventory not found in the new one.
+    pub resolved: Category,
+    /// Groups of the new inventory not found in the old one.
+    pub new: Category,
+    /// Groups of the new inventory found in the old one.
+    pub persisting: Category,
+}
+
+fn wasted(group: &DuplicateGroup) -> u64 {
+    let size = group
+        .files
+        .iter()
+        .find_map(|f| f.size)
+        .or_else(|| {
+            let path = std::path::PathBuf::from(&group.files.first()?.path);
+            std::fs::metadata(path).ok().map(|m| m.len())
+        })
+        .unwrap_or(0);
+    let files = group.files.iter().map(FileKey::of).collect::<HashSet<_>>();
+    size * (files.len() as u64).saturating_sub(1)
+}
+
+/// Compare groups of `new` with groups of `old`. A group is found in the other inventory if both share the hash, or
+/// else a file. Hashes are only compared if both inventories were hashed with the same key.
+///
+/// Persisting groups are written to an inventory at `persisting` if given, to be applied again.
+pub fn diff<P: AsRef<Path>>(old: P, new: P, persisting: Option<&Path>) -> Result<InventoryDiff> {
+    let old = InventoryReader::open(old)?.read_only_sequential();
+    let new = InventoryReader::open(new)?.read_only_sequential();
+    let compare_hash = old.key_id() == new.key_id();
+    if !compare_hash {
+        tracing::warn!("inventories are hashed with different keys, groups are matched by files only.");
+    }
+    let key_id = new.key_id().unwrap_or(0);
+
+    let mut old_groups = Vec::new();
+    let mut hashes: HashMap<GroupHash, usize> = HashMap::new();
+    let mut files: HashMap<(bool, FileKey), usize> = HashMap::new();
+    for (i, group) in old.enumerate() {
+        let group = group?;
+        if let Some(hash) = group.hash.filter(|_| compare_hash) {
+            hashes.insert(hash, i);
+        }
+        for file in &group.files {
+            files.insert((group.similar, FileKey::of(file)), i);
+        }
+        old_groups.push(wasted(&group));
+    }
+
+    let mut result = InventoryDiff::default();
+    let mut found = vec![false; old_groups.len()];
+    let mut persisting_groups = Vec::new();
+    for group in new {
+        let group = group?;
+        let matched = group
+            .hash
+            .and_then(|hash| hashes.get(&hash))
+            .or_else(|| {
+                group
+                    .files
+                    .iter()
+                    .find_map(|file| files.get(&(group.similar, FileKey::of(file))))
+            })
+            .copied();
+        let category = match matched {
+            Some(i) => {
+                found[i] = true;
+                &mut result.persisting
+            }
+            None => &mut result.new,
+        };
+        category.groups += 1;
+        category.wasted += wasted(&group);
+        if matched.is_some() && persisting.is_some() {
+            persisting_groups.push(group);
+        }
+    }
+    for (wasted, _) in old_groups.into_iter().zip(found).filter(|(_, found)| !found) {
+        result.resolved.groups += 1;
+        result.resolved.wasted += wasted;
+    }
+
+    if let Some(path) = persisting {
+        InventoryWriter::create(path)?
+            .key_id(key_id)
+            .export(persisting_groups.into_iter())?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff, Category};
+    use crate::inventory::{DuplicateFile, DuplicateGroup, GroupHash, InventoryReader, InventoryWriter};
+    use std::path::{Path, PathBuf};
+
+    /// A path in the temporary directory, unique to this process.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("d2fn-diff-{}-{name}", std::process::id()))
+    }
+
+    fn group(hash: u8, inodes: &[u64]) -> DuplicateGroup {
+        let files = inodes.iter().map(|&ino| {
+            let mut file = DuplicateFile::new(ino, Path::new(&format!("file{ino}")));
+            file.dev = Some(1);
+            file.size = Some(100);
+            file
+        });
+        DuplicateGroup {
+            files: files.collect(),
+            similar: false,
+            hash: Some(GroupHash {
+                blake3: [hash; 32],
+                prefix: None,
+            }),
+            whole: true,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let (old, new, persisting) = (&temp_path("diff-old"), &temp_path("diff-new"), &temp_path("diff-persisting"));
+        let groups = vec![group(1, &[1, 2]), group(2, &[3, 4, 5]), group(3, &[6, 7])];
+        InventoryWriter::create(old).unwrap().export(groups.into_iter()).unwrap();
+        // 组 1 不变, 组 2 的内容被改写但仍含 inode 3, 组 3 已处理, 组 4 是新出现的.
+        let groups = vec![group(1, &[1, 2]), group(9, &[3, 4]), group(4, &[8, 9])];
+        InventoryWriter::create(new).unwrap().export(groups.into_iter()).unwrap();
+
+        let result = diff(old, new, Some(persisting)).unwrap();
+        assert_eq!(result.resolved, Category { groups: 1, wasted: 100 });
+        assert_eq!(result.persisting, Category { groups: 2, wasted: 200 });
+        assert_eq!(result.new, Category { groups: 1, wasted: 100 });
+
+        let reader = InventoryReader::open(persisting).unwrap();
+        assert_eq!(reader.total(), Some(2));
+        for path in [old, new, persisting] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
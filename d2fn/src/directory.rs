@@ -0,0 +1,541 @@
+//! Find directories whose whole content is duplicated, so that one `rm -r` replaces thousands of file actions.
+//!
+//! Each directory gets a Merkle-style digest over its children's names and content: files are identified by the
+//! hash of the duplicate group they belong to, or hashed on demand if they are not in any group or their group only
+//! shares a prefix, and sub-directories by their own digests. Hidden items count too, though the scan skips them.
+//! To avoid reading files in vain, content digests are only computed for directories whose shape (names and sizes
+//! of everything inside) matches another one.
+
+use anyhow::{Context, Result};
+use blake3::Hash;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::hash::{checksum_file_throttled, CachePolicy, CompareMode, HashAlgorithm};
+use common::throttle::Throttle;
+
+/// Directory pairs differing in more files than this are not reported as near matches.
+pub const NEAR_MATCH_LIMIT: usize = 3;
+
+/// Directories with identical content.
+pub struct DirectoryGroup {
+    pub dirs: Vec<PathBuf>,
+    /// Total size of files in one of the directories.
+    pub size: u64,
+    pub file_count: usize,
+}
+
+impl DirectoryGroup {
+    /// Bytes freed by keeping only one of the directories.
+    pub fn saved_bytes(&self) -> u64 {
+        self.size * (self.dirs.len() as u64).saturating_sub(1)
+    }
+}
+
+/// Two directories which would be duplicates, if not for a few files.
+pub struct NearMatch {
+    pub left: PathBuf,
+    pub right: PathBuf,
+    /// Relative paths present on one side only, or with different sizes.
+    pub differences: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+pub struct DirectoryReport {
+    /// The largest saving first. Groups nested in a reported group are omitted.
+    pub groups: Vec<DirectoryGroup>,
+    pub near_matches: Vec<NearMatch>,
+}
+
+enum EntryKind {
+    File(u64),
+    Dir,
+    Symlink(PathBuf),
+}
+
+struct Entry {
+    name: OsString,
+    path: PathBuf,
+    kind: EntryKind,
+}
+
+#[derive(Clone, Copy)]
+struct Shape {
+    digest: Hash,
+    size: u64,
+    file_count: usize,
+}
+
+pub struct DirectoryMatcher<'a> {
+    /// Directories are never considered above these.
+    roots: Vec<PathBuf>,
+    /// Member of a duplicate group -> the hash identifying the group, `None` if the group is not known to be identical
+    known: HashMap<PathBuf, Option<Hash>>,
+    /// Directory pairs which hold copies of the same file, checked for near matches
+    neighbours: BTreeSet<(PathBuf, PathBuf)>,
+    throttle: &'a Throttle,
+    cache: CachePolicy,
+
+    file_hashes: HashMap<PathBuf, Hash>,
+    shapes: HashMap<PathBuf, Shape>,
+    digests: HashMap<PathBuf, Hash>,
+}
+
+/// Children of `dir` sorted by name. Hidden items are listed too, a directory is only a copy if they are the same.
+fn list(dir: &Path) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for item in std::fs::read_dir(dir).with_context(|| format!("unable to read {}", dir.display()))? {
+        let item = item?;
+        let name = item.file_name();
+        let path = item.path();
+        let file_type = item.file_type()?;
+        let kind = if file_type.is_symlink() {
+            EntryKind::Symlink(std::fs::read_link(&path)?)
+        } else if file_type.is_dir() {
+            EntryKind::Dir
+        } else {
+            EntryKind::File(item.metadata()?.len())
+        };
+        entries.push(Entry { name, path, kind });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Relative path -> size of every file below `dir`, to spot differences between two directories.
+fn listing(dir: &Path) -> Result<BTreeMap<PathBuf, u64>> {
+    fn visit(root: &Path, dir: &Path, result: &mut BTreeMap<PathBuf, u64>) -> Result<()> {
+        for entry in list(dir)? {
+            match entry.kind {
+                EntryKind::Dir => visit(root, &entry.path, result)?,
+                EntryKind::File(size) => {
+                    let relative = entry.path.strip_prefix(root).unwrap_or(&entry.path).to_path_buf();
+                    result.insert(relative, size);
+                }
+                EntryKind::Symlink(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    let mut result = BTreeMap::new();
+    visit(dir, dir, &mut result)?;
+    Ok(result)
+}
+
+impl<'a> DirectoryMatcher<'a> {
+    pub fn new(throttle: &'a Throttle, cache: CachePolicy) -> Self {
+        Self {
+            roots: Vec::new(),
+            known: HashMap::new(),
+            neighbours: BTreeSet::new(),
+            throttle,
+            cache,
+            file_hashes: HashMap::new(),
+            shapes: HashMap::new(),
+            digests: HashMap::new(),
+        }
+    }
+
+    pub fn add_root<P: AsRef<Path>>(&mut self, root: P) {
+        self.roots.push(root.as_ref().to_path_buf());
+    }
+
+    fn within_roots(&self, dir: &Path) -> bool {
+        self.roots.iter().any(|root| dir.starts_with(root))
+    }
+
+    /// Register a duplicate group, `id` has to be different for each group. It is `None` if files of the group are only
+    /// known to share a prefix, they are hashed as a whole when their directories are compared.
+    pub fn add_group(&mut self, id: Option<Hash>, paths: &[&Path]) {
+        for path in paths {
+            self.known.insert(path.to_path_buf(), id);
+        }
+
+        let parents = paths.iter().filter_map(|p| p.parent()).collect::<BTreeSet<_>>();
+        for (i, left) in parents.iter().enumerate() {
+            for right in parents.iter().skip(i + 1) {
+                self.add_neighbours(left, right);
+            }
+        }
+    }
+
+    /// Record the pair, and their parents as long as the directory names agree: a/photos/2020 and b/photos/2020
+    /// also make a/photos and b/photos, and then a and b, neighbours.
+    fn add_neighbours(&mut self, left: &Path, right: &Path) {
+        let (mut left, mut right) = (left, right);
+        loop {
+            if left.starts_with(right) || right.starts_with(left) {
+                break;
+            }
+            if !self.within_roots(left) || !self.within_roots(right) {
+                break;
+            }
+            self.neighbours.insert((left.to_path_buf(), right.to_path_buf()));
+            if left.file_name() != right.file_name() {
+                break;
+            }
+            match (left.parent(), right.parent()) {
+                (Some(l), Some(r)) => (left, right) = (l, r),
+                _ => break,
+            }
+        }
+    }
+
+    /// Digest over names and sizes only, no file is read.
+    fn shape(&mut self, dir: &Path) -> Result<Shape> {
+        if let Some(shape) = self.shapes.get(dir) {
+            return Ok(*shape);
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        let (mut size, mut file_count) = (0, 0);
+        for entry in list(dir)? {
+            hasher.update(entry.name.as_bytes());
+            match &entry.kind {
+                EntryKind::File(len) => {
+                    hasher.update(b"\0f");
+                    hasher.update(&len.to_le_bytes());
+                    size += len;
+                    file_count += 1;
+                }
+                EntryKind::Dir => {
+                    let shape = self.shape(&entry.path)?;
+                    hasher.update(b"\0d");
+                    hasher.update(shape.digest.as_bytes());
+                    size += shape.size;
+                    file_count += shape.file_count;
+                }
+                EntryKind::Symlink(target) => {
+                    hasher.update(b"\0l");
+                    hasher.update(target.as_os_str().as_bytes());
+                }
+            }
+            hasher.update(b"\0");
+        }
+
+        let shape = Shape {
+            digest: hasher.finalize(),
+            size,
+            file_count,
+        };
+        self.shapes.insert(dir.to_path_buf(), shape);
+        Ok(shape)
+    }
+
+    fn file_hash(&mut self, path: &Path) -> Result<Hash> {
+        if let Some(id) = self
+            .known
+            .get(path)
+            .copied()
+            .flatten()
+            .or_else(|| self.file_hashes.get(path).copied())
+        {
+            return Ok(id);
+        }
+        // 不在任何重复组中, 或者组内只比较了前缀的文件, 需要时再计算完整哈希
+        let checksum = checksum_file_throttled(
+            path,
+            CompareMode::Full,
+            HashAlgorithm::Blake3,
+            None,
+            self.cache,
+            Some(self.throttle),
+            None,
+        )
+        .with_context(|| format!("read {}", path.display()))?;
+        let hash = checksum.hash.blake3().expect("blake3 requested");
+        self.file_hashes.insert(path.to_path_buf(), hash);
+        Ok(hash)
+    }
+
+    /// Merkle-style digest over names and content.
+    fn digest(&mut self, dir: &Path) -> Result<Hash> {
+        if let Some(digest) = self.digests.get(dir) {
+            return Ok(*digest);
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        for entry in list(dir)? {
+            hasher.update(entry.name.as_bytes());
+            match &entry.kind {
+                EntryKind::File(_) => {
+                    hasher.update(b"\0f");
+                    hasher.update(self.file_hash(&entry.path)?.as_bytes());
+                }
+                EntryKind::Dir => {
+                    hasher.update(b"\0d");
+                    hasher.update(self.digest(&entry.path)?.as_bytes());
+                }
+                EntryKind::Symlink(target) => {
+                    hasher.update(b"\0l");
+                    hasher.update(target.as_os_str().as_bytes());
+                }
+            }
+            hasher.update(b"\0");
+        }
+
+        let digest = hasher.finalize();
+        self.digests.insert(dir.to_path_buf(), digest);
+        Ok(digest)
+    }
+
+    /// Directories holding at least one member of a duplicate group, and their parents under the roots.
+    fn candidates(&self) -> BTreeSet<PathBuf> {
+        let mut candidates = BTreeSet::new();
+        for path in self.known.keys() {
+            for dir in path.ancestors().skip(1) {
+                if !self.within_roots(dir) || !candidates.insert(dir.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+        candidates
+    }
+
+    pub fn find(mut self) -> Result<DirectoryReport> {
+        let mut by_shape: HashMap<Hash, Vec<PathBuf>> = HashMap::new();
+        for dir in self.candidates() {
+            match self.shape(&dir) {
+                Ok(shape) if shape.file_count > 0 => by_shape.entry(shape.digest).or_default().push(dir),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("{e:#}"),
+            }
+        }
+
+        let mut groups = Vec::new();
+        for dirs in by_shape.into_values().filter(|dirs| dirs.len() > 1) {
+            let mut by_digest: HashMap<Hash, Vec<PathBuf>> = HashMap::new();
+            for dir in dirs {
+                match self.digest(&dir) {
+                    Ok(digest) => by_digest.entry(digest).or_default().push(dir),
+                    Err(e) => tracing::warn!("{e:#}"),
+                }
+            }
+            for dirs in by_digest.into_values().filter(|dirs| dirs.len() > 1) {
+                let shape = self.shapes[&dirs[0]];
+                groups.push(DirectoryGroup {
+                    dirs,
+                    size: shape.size,
+                    file_count: shape.file_count,
+                });
+            }
+        }
+
+        // 父目录已经整体重复时, 其中的子目录不再单独报告
+        groups.sort_by_key(|g| std::cmp::Reverse(g.saved_bytes()));
+        let mut reported: Vec<PathBuf> = Vec::new();
+        groups.retain(|group| {
+            let nested = group
+                .dirs
+                .iter()
+                .all(|dir| reported.iter().any(|r| dir.starts_with(r) && dir != r));
+            if !nested {
+                reported.extend(group.dirs.iter().cloned());
+            }
+            !nested
+        });
+
+        let mut near_matches = Vec::new();
+        let covered = |dir: &Path| reported.iter().any(|r| dir.starts_with(r));
+        let neighbours = std::mem::take(&mut self.neighbours);
+        for (left, right) in neighbours {
+            if covered(&left) && covered(&right) {
+                continue;
+            }
+            let (Ok(left_files), Ok(right_files)) = (listing(&left), listing(&right)) else {
+                continue;
+            };
+
+            let mut differences = left_files
+                .iter()
+                .filter(|(path, size)| right_files.get(*path) != Some(size))
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>();
+            differences.extend(right_files.keys().filter(|path| !left_files.contains_key(*path)).cloned());
+
+            // 差异文件须少于一半, 否则两个目录只是碰巧有几个共同的文件
+            let total = left_files.len().max(right_files.len());
+            if !differences.is_empty() && differences.len() <= NEAR_MATCH_LIMIT && differences.len() * 2 < total {
+                differences.sort();
+                near_matches.push(NearMatch {
+                    left,
+                    right,
+                    differences,
+                });
+            }
+        }
+
+        Ok(DirectoryReport { groups, near_matches })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DirectoryMatcher;
+    use crate::hash::CachePolicy;
+    use common::throttle::Throttle;
+    use std::path::{Path, PathBuf};
+
+    fn create_tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("d2fn-dir-{}-{name}", std::process::id()));
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    fn matcher_of<'a>(root: &Path, throttle: &'a Throttle, groups: &[&[&str]]) -> DirectoryMatcher<'a> {
+        let mut matcher = DirectoryMatcher::new(throttle, CachePolicy::Keep);
+        matcher.add_root(root);
+        for (i, group) in groups.iter().enumerate() {
+            let paths = group.iter().map(|p| root.join(p)).collect::<Vec<_>>();
+            let paths = paths.iter().map(|p| p.as_path()).collect::<Vec<_>>();
+            matcher.add_group(Some(blake3::hash(&i.to_le_bytes())), &paths);
+        }
+        matcher
+    }
+
+    #[test]
+    fn test_copied_tree() {
+        let root = create_tree(
+            "copied",
+            &[
+                ("photos/a.jpg", "aaaa"),
+                ("photos/2020/b.jpg", "bbbb"),
+                ("photos/2020/note.txt", "not scanned"),
+                ("photos (1)/a.jpg", "aaaa"),
+                ("photos (1)/2020/b.jpg", "bbbb"),
+                ("photos (1)/2020/note.txt", "not scanned"),
+                ("other/a.jpg", "aaaa"),
+            ],
+        );
+        let throttle = Throttle::unlimited();
+        let matcher = matcher_of(
+            &root,
+            &throttle,
+            &[
+                &["photos/a.jpg", "photos (1)/a.jpg", "other/a.jpg"],
+                &["photos/2020/b.jpg", "photos (1)/2020/b.jpg"],
+            ],
+        );
+
+        let report = matcher.find().unwrap();
+        // photos/2020 与 photos (1)/2020 包含在上一级的重复目录中, 不单独报告
+        assert_eq!(report.groups.len(), 1);
+        let mut dirs = report.groups[0].dirs.clone();
+        dirs.sort();
+        assert_eq!(dirs, vec![root.join("photos"), root.join("photos (1)")]);
+        assert_eq!(report.groups[0].file_count, 3);
+        assert_eq!(report.groups[0].size, 4 + 4 + 11);
+        assert!(report.near_matches.is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_same_shape_different_content() {
+        let root = create_tree(
+            "shape",
+            &[
+                ("a/x.jpg", "xxxx"),
+                ("a/y.txt", "1111"),
+                ("b/x.jpg", "xxxx"),
+                ("b/y.txt", "2222"),
+            ],
+        );
+        let throttle = Throttle::unlimited();
+        let matcher = matcher_of(&root, &throttle, &[&["a/x.jpg", "b/x.jpg"]]);
+
+        let report = matcher.find().unwrap();
+        assert!(report.groups.is_empty());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_hidden_files() {
+        let root = create_tree(
+            "hidden",
+            &[
+                ("a/x.jpg", "xxxx"),
+                ("a/.notes", "only here"),
+                ("b/x.jpg", "xxxx"),
+                ("c/x.jpg", "xxxx"),
+                ("c/.cache/y", "yyyy"),
+                ("d/x.jpg", "xxxx"),
+                ("d/.cache/y", "yyyy"),
+            ],
+        );
+        let throttle = Throttle::unlimited();
+        let matcher = matcher_of(&root, &throttle, &[&["a/x.jpg", "b/x.jpg", "c/x.jpg", "d/x.jpg"]]);
+
+        // 仅隐藏文件不同的目录不是副本
+        let report = matcher.find().unwrap();
+        assert_eq!(report.groups.len(), 1);
+        let mut dirs = report.groups[0].dirs.clone();
+        dirs.sort();
+        assert_eq!(dirs, vec![root.join("c"), root.join("d")]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_prefix_group() {
+        let root = create_tree(
+            "prefix",
+            &[
+                ("a/x.bin", "same prefix, then 1"),
+                ("b/x.bin", "same prefix, then 2"),
+                ("c/x.bin", "same prefix, then 1"),
+            ],
+        );
+        let throttle = Throttle::unlimited();
+        let mut matcher = DirectoryMatcher::new(&throttle, CachePolicy::Keep);
+        matcher.add_root(&root);
+        let paths = ["a/x.bin", "b/x.bin", "c/x.bin"].map(|p| root.join(p));
+        matcher.add_group(None, &paths.iter().map(|p| p.as_path()).collect::<Vec<_>>());
+
+        // 只有前缀相同的组, 文件按完整内容比较
+        let report = matcher.find().unwrap();
+        assert_eq!(report.groups.len(), 1);
+        let mut dirs = report.groups[0].dirs.clone();
+        dirs.sort();
+        assert_eq!(dirs, vec![root.join("a"), root.join("c")]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_near_match() {
+        let root = create_tree(
+            "near",
+            &[
+                ("photos/a.jpg", "aaaa"),
+                ("photos/b.jpg", "bbbb"),
+                ("photos/c.jpg", "cccc"),
+                ("photos-backup/a.jpg", "aaaa"),
+                ("photos-backup/b.jpg", "bbbb"),
+                ("photos-backup/c.jpg", "cccc"),
+                ("photos-backup/d.jpg", "dddd"),
+            ],
+        );
+        let throttle = Throttle::unlimited();
+        let matcher = matcher_of(
+            &root,
+            &throttle,
+            &[
+                &["photos/a.jpg", "photos-backup/a.jpg"],
+                &["photos/b.jpg", "photos-backup/b.jpg"],
+                &["photos/c.jpg", "photos-backup/c.jpg"],
+            ],
+        );
+
+        let report = matcher.find().unwrap();
+        assert!(report.groups.is_empty());
+        assert_eq!(report.near_matches.len(), 1);
+        assert_eq!(report.near_matches[0].differences, vec![PathBuf::from("d.jpg")]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
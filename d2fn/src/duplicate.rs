@@ -1,16 +1,31 @@
 use anyhow::{bail, Context, Result};
 
-use blake3::Hash;
 use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::DirEntry;
+use std::fs::{DirEntry, FileType};
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::hash::{checksum_file, CompareMode};
-use crate::metadata::{convert_metadata, FileMetadata};
-use filewalker::FileWalker;
+#[cfg(feature = "audio")]
+use crate::audio::{AudioEntry, AudioIndex};
+use crate::directory::DirectoryMatcher;
+use crate::hash::{
+    checksum_file_throttled, CachePolicy, Checksum, CompareMode, CompareSize, Digest, HashAlgorithm, HashKey,
+};
+use crate::ignore_file::IgnoreRules;
+use crate::inventory::{DuplicateFile, DuplicateGroup, GroupHash, InventoryWriter};
+use crate::metadata::{convert_metadata, FileMetadata, WasteMetric};
+use crate::parallel_walk::{DirOrder, ParallelWalker, Prune, SymlinkPolicy, WalkError, WalkItem};
+#[cfg(feature = "similar-images")]
+use crate::similar::{Fingerprint, SimilarImages};
+use crate::status_file::{StatusFile, Totals};
+use common::since::Since;
+use common::throttle::Throttle;
 
 const DEFAULT_EXT_FILTER: [&str; 44] = [
     "pdf", "mdx", "epub", "djvu", "xps", // Document
@@ -43,9 +58,47 @@ impl TryFrom<DirEntry> for File {
     }
 }
 
+impl File {
+    /// Whether the file on disk no longer matches the recorded metadata, or is gone.
+    pub fn is_stale(&self) -> bool {
+        match std::fs::metadata(&self.path) {
+            Ok(metadata) => self.metadata.is_changed(&convert_metadata(metadata)),
+            Err(_) => true,
+        }
+    }
+
+    /// Bytes the file takes on disk, its apparent size if the file system does not count blocks.
+    pub fn disk_usage(&self) -> u64 {
+        self.metadata.disk_usage()
+    }
+
+    /// Calculate checksum, or return `None` if the file changed since it was scanned.
+    fn checksum_unchanged(
+        &self,
+        mode: CompareMode,
+        algorithm: HashAlgorithm,
+        key: Option<&HashKey>,
+        cache: CachePolicy,
+        throttle: &Throttle,
+        progress: Option<&mut dyn FnMut(u64, u64)>,
+    ) -> Result<Option<Checksum>> {
+        if self.is_stale() {
+            return Ok(None);
+        }
+        let hash = checksum_file_throttled(&self.path, mode, algorithm, key, cache, Some(throttle), progress);
+        // 计算哈希期间文件可能被改写, 此时的哈希值不可信. 读取失败也可能源于此.
+        if self.is_stale() {
+            return Ok(None);
+        }
+        hash.map(Some)
+    }
+}
+
 type FileExtension = u32;
 type FileSize = u64;
 type RecordIndex = usize;
+/// (dev, ino)
+pub type InodeKey = (u64, u64);
 
 pub trait ScanFilter {
     fn filter(&self, file: &File) -> bool;
@@ -115,40 +168,219 @@ fn ext_hash(path: &Path) -> FileExtension {
 
 enum PreviousScanned {
     Index(RecordIndex),
-    Hash(HashSet<blake3::Hash>),
+    Hash(HashSet<Digest>),
+}
+
+/// How to decide that a file under the scan path has no copy in the reference tree.
+///
+/// Files whose (ext, size) matches no reference file are unique for sure. Proving the rest unique requires hashing
+/// every one of them, together with the reference files they share (ext, size) with.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UniqueCheck {
+    /// Hash every candidate sharing (ext, size) with a reference file.
+    Hash,
+    /// Trust (ext, size) mismatch alone, files sharing (ext, size) with a reference file are assumed to be copies.
+    Quick,
 }
 
 #[derive(Eq, PartialEq, Hash)]
 struct ClassifyingKey(FileExtension, FileSize);
 
 pub struct Duplicate<'a, F: ScanFilter> {
-    path: PathBuf,
+    /// Directories to scan, see [`Duplicate::add_root`].
+    roots: Vec<PathBuf>,
+    /// Reference tree in cross-tree mode, see [`Duplicate::reference_root`].
+    reference: Option<PathBuf>,
+    /// Records before this index come from the reference tree. Set once the reference tree is indexed.
+    reference_end: Option<RecordIndex>,
+    /// Collect files with no copy in the reference tree, see [`Duplicate::collect_unique`].
+    unique_check: Option<UniqueCheck>,
+    unique: Vec<RecordIndex>,
 
     records: Vec<File>,
-    inode_set: HashSet<u64>,
+    inode_set: HashSet<InodeKey>,
+    /// Files with more than one link, and the paths observed for each of them.
+    hardlinks: HashMap<InodeKey, HardlinkGroup>,
     /// (.pdf, 2MB) -> {a.pdf, b.pdf, c.pdf}
     /// (.pdf, 30M) -> {q.pdf, l.pdf}
     /// (.mp4, 400M) -> (1.mp4)
     set: HashMap<ClassifyingKey, PreviousScanned>,
     /// file hash -> [2, 4, ...]
-    hash2files: HashMap<blake3::Hash, Vec<RecordIndex>>,
-    full_hash2files: HashMap<blake3::Hash, Vec<RecordIndex>>,
+    hash2files: HashMap<Digest, Vec<RecordIndex>>,
+    /// Records whose partial hash in `hash2files` already covers the whole file, see [`Checksum`].
+    whole_hashed: HashSet<RecordIndex>,
+    /// Groups split by `verify()`, keyed by the blake3 hash of the whole file
+    full_hash2files: HashMap<Digest, Vec<RecordIndex>>,
+    /// Groups of `hash2files` whose files `verify()` found identical as a whole
+    confirmed: HashSet<Digest>,
+    /// Hash function of the candidate stage, see [`Duplicate::candidate_hash`].
+    algorithm: HashAlgorithm,
+    /// Given to the last `discover()`.
+    compare_size: Option<CompareSize>,
+    /// Key of blake3 hashes, see [`Duplicate::hash_key`].
+    key: Option<HashKey>,
+    /// See [`Duplicate::cache_policy`].
+    cache: CachePolicy,
+    /// See [`Duplicate::min_group_waste`].
+    min_group_waste: u64,
+    /// See [`Duplicate::waste_metric`].
+    waste_metric: WasteMetric,
 
     filter: F,
+    /// Skip files matched by `.d2fnignore` files
+    respect_ignore_files: bool,
+    /// See [`Duplicate::max_depth`].
+    max_depth: Option<usize>,
+    /// Count of directories at `max_depth`, whose content is skipped
+    pruned_dirs: usize,
+    /// Abort on the first unreadable entry, see [`Duplicate::strict`].
+    strict: bool,
+    walk_errors: Vec<WalkError>,
+    /// Order results by path, see [`Duplicate::deterministic`].
+    deterministic: bool,
+    /// Walker threads, see [`Duplicate::parallel_walk`].
+    walk_threads: Option<usize>,
+    /// See [`Duplicate::prune_if`].
+    prune: Option<Arc<Prune>>,
+    pruned_entries: usize,
+    /// Canonical prefixes, see [`Duplicate::exclude_paths`].
+    excluded_paths: Vec<PathBuf>,
+    /// See [`Duplicate::modified_since`].
+    since: Option<Since>,
+    /// See [`Duplicate::follow_symlinks`].
+    follow_symlinks: bool,
+    /// Symbolic links not followed
+    skipped_symlinks: usize,
+    throttle: Throttle,
+    /// Fingerprints of images, see [`Duplicate::find_similar_images`].
+    #[cfg(feature = "similar-images")]
+    similar: Option<SimilarImages>,
+    /// Hashes of audio frames, see [`Duplicate::compare_audio_content`].
+    #[cfg(feature = "audio")]
+    audio: Option<AudioIndex>,
+
+    /// See [`Duplicate::limit_files`].
+    file_budget: Option<usize>,
+    /// See [`Duplicate::limit_bytes`].
+    byte_budget: Option<u64>,
+    /// Total size of files indexed
+    bytes_indexed: u64,
+    /// A budget ran out before the walk finished
+    partial: bool,
 
     status_channel: Option<Sender<StatusReport>>,
     status_report_step: usize,
     status: StatusReport,
+    /// See [`Duplicate::status_file`].
+    status_file: Option<StatusFile>,
 
     _marker: std::marker::PhantomData<&'a ()>,
 }
 
+/// Bytes a group takes beyond its first copy, by both metrics.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupWaste {
+    pub apparent: u64,
+    pub allocated: u64,
+}
+
+impl GroupWaste {
+    pub fn by(self, metric: WasteMetric) -> u64 {
+        match metric {
+            WasteMetric::Apparent => self.apparent,
+            WasteMetric::Allocated => self.allocated,
+        }
+    }
+}
+
+/// Waste of a group beyond its first copy. In cross-tree mode the first one is the reference file.
+pub fn group_waste(files: &[&File]) -> GroupWaste {
+    files.iter().skip(1).fold(GroupWaste::default(), |waste, file| GroupWaste {
+        apparent: waste.apparent + file.metadata.size,
+        allocated: waste.allocated + file.disk_usage(),
+    })
+}
+
+/// Paths observed during the scan which point to the same inode. They are already deduplicated on disk.
+pub struct HardlinkGroup {
+    pub paths: Vec<PathBuf>,
+    pub size: u64,
+    pub link_count: u64,
+}
+
+impl HardlinkGroup {
+    /// Bytes saved by these links, compared to keeping a copy for each observed path.
+    pub fn saved_bytes(&self) -> u64 {
+        self.size * (self.paths.len() as u64).saturating_sub(1)
+    }
+}
+
+/// A duplicate group in cross-tree mode: `redundant` files are copies of `reference`, which lives in the reference
+/// tree and should be kept.
+pub struct CrossGroup<'a> {
+    pub reference: &'a File,
+    pub redundant: Vec<&'a File>,
+}
+
+impl<'a> CrossGroup<'a> {
+    /// Flatten into a plain group, the reference file comes first.
+    pub fn into_files(self) -> Vec<&'a File> {
+        let mut files = Vec::with_capacity(self.redundant.len() + 1);
+        files.push(self.reference);
+        files.extend(self.redundant);
+        files
+    }
+}
+
+/// How far a scan went, see [`Duplicate::limit_files`] and [`Duplicate::limit_bytes`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanExtent {
+    /// A budget ran out, files were left out
+    pub partial: bool,
+    pub files: usize,
+    /// Total size of the files indexed
+    pub bytes_indexed: u64,
+    pub bytes_hashed: u64,
+    /// Bytes freed by removing every duplicate found so far
+    pub reclaimable_bytes: u64,
+}
+
+/// What [`Duplicate::verify`] found.
+#[derive(Default, Debug)]
+pub struct VerifyStats {
+    /// Groups compared by full content
+    pub groups_checked: usize,
+    /// Groups whose members are identical
+    pub groups_confirmed: usize,
+    /// Groups whose members only share the leading part, and were split by full content
+    pub groups_split: usize,
+    pub files_rehashed: usize,
+    pub bytes_rehashed: u64,
+    /// Bytes freed by removing every duplicate left after verification
+    pub reclaimable_bytes: u64,
+}
+
 #[derive(Default)]
 pub struct StatusReport {
     pub scanned: usize,
     pub duplicated: usize,
+    /// Files skipped because of `.d2fnignore`, those in ignored directories are not counted
+    pub ignored: usize,
+    /// Subtrees skipped because of [`Duplicate::exclude_paths`]
+    pub excluded: usize,
+    /// Files changed or removed after being scanned, and thus dropped from result
+    pub stale_files: usize,
+    /// Effective read rate, in bytes per second
+    pub read_rate: u64,
+    /// Bytes read in total
+    #[cfg(feature = "metrics")]
+    pub bytes_read: u64,
 
     pub last_file: String,
+    /// File being hashed in `verify()`, set only while a large file is in progress
+    pub hashing_current_file: Option<String>,
+    /// Bytes hashed and size of `hashing_current_file`
+    pub hashing_progress: (u64, u64),
 }
 
 impl<'a> Duplicate<'a, NoFilter> {
@@ -158,16 +390,52 @@ impl<'a> Duplicate<'a, NoFilter> {
         let path = path.as_ref().to_path_buf();
 
         Duplicate {
-            path,
+            roots: vec![path],
+            reference: None,
+            reference_end: None,
+            unique_check: None,
+            unique: Vec::new(),
             records: Vec::with_capacity(Self::DEFAULT_SIZE),
             inode_set: HashSet::with_capacity(Self::DEFAULT_SIZE),
+            hardlinks: HashMap::new(),
             set: HashMap::with_capacity(Self::DEFAULT_SIZE),
             hash2files: HashMap::with_capacity(Self::DEFAULT_SIZE),
             full_hash2files: HashMap::new(),
+            confirmed: HashSet::new(),
+            whole_hashed: HashSet::new(),
+            algorithm: HashAlgorithm::Blake3,
+            compare_size: None,
+            key: None,
+            cache: CachePolicy::Keep,
+            min_group_waste: 0,
+            waste_metric: WasteMetric::Apparent,
             filter: NoFilter,
+            respect_ignore_files: true,
+            max_depth: None,
+            pruned_dirs: 0,
+            strict: false,
+            walk_errors: Vec::new(),
+            deterministic: false,
+            walk_threads: None,
+            prune: None,
+            pruned_entries: 0,
+            excluded_paths: Vec::new(),
+            since: None,
+            follow_symlinks: false,
+            skipped_symlinks: 0,
+            throttle: Throttle::unlimited(),
+            #[cfg(feature = "similar-images")]
+            similar: None,
+            #[cfg(feature = "audio")]
+            audio: None,
+            file_budget: None,
+            byte_budget: None,
+            bytes_indexed: 0,
+            partial: false,
             status_channel: None,
             status_report_step: usize::MAX,
             status: Default::default(),
+            status_file: None,
             _marker: Default::default(),
         }
     }
@@ -176,28 +444,293 @@ impl<'a> Duplicate<'a, NoFilter> {
 impl<'a, F: ScanFilter> Duplicate<'a, F> {
     pub fn custom_filter<G: ScanFilter>(self, filter: G) -> Duplicate<'a, G> {
         let Duplicate {
-            path,
+            roots,
+            reference,
+            reference_end,
+            unique_check,
+            unique,
             records,
             inode_set,
+            hardlinks,
             set,
             hash2files,
+            whole_hashed,
+            algorithm,
+            key,
+            cache,
+            min_group_waste,
+            waste_metric,
+            file_budget,
+            byte_budget,
+            respect_ignore_files,
+            max_depth,
+            pruned_dirs,
+            strict,
+            walk_errors,
+            deterministic,
+            walk_threads,
+            prune,
+            pruned_entries,
+            excluded_paths,
+            since,
+            follow_symlinks,
+            skipped_symlinks,
+            throttle,
+            #[cfg(feature = "similar-images")]
+            similar,
+            #[cfg(feature = "audio")]
+            audio,
+            status_file,
             ..
         } = self;
         Duplicate {
-            path,
+            roots,
+            reference,
+            reference_end,
+            unique_check,
+            unique,
             records,
             inode_set,
+            hardlinks,
             set,
             hash2files,
             filter,
+            respect_ignore_files,
+            max_depth,
+            pruned_dirs,
+            strict,
+            walk_errors,
+            deterministic,
+            walk_threads,
+            prune,
+            pruned_entries,
+            excluded_paths,
+            since,
+            follow_symlinks,
+            skipped_symlinks,
+            throttle,
+            #[cfg(feature = "similar-images")]
+            similar,
+            #[cfg(feature = "audio")]
+            audio,
             full_hash2files: HashMap::new(),
+            confirmed: HashSet::new(),
+            whole_hashed,
+            algorithm,
+            compare_size: None,
+            key,
+            cache,
+            min_group_waste,
+            waste_metric,
+            file_budget,
+            byte_budget,
+            bytes_indexed: 0,
+            partial: false,
             status_channel: None,
             status_report_step: 0,
             status: Default::default(),
+            status_file,
             _marker: Default::default(),
         }
     }
 
+    /// Scan another directory. Duplicates are searched across all roots, as if they were one tree.
+    pub fn add_root<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.roots.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Enable cross-tree mode: files under `path` are indexed first, and then only files under the scan path which
+    /// duplicate something in the reference tree are reported. Pairs inside the same tree are ignored.
+    pub fn reference_root<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.reference = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// In cross-tree mode, also collect files under the scan path which have no copy in the reference tree.
+    /// See [`UniqueCheck`] for the cost.
+    pub fn collect_unique(mut self, check: UniqueCheck) -> Self {
+        self.unique_check = Some(check);
+        self
+    }
+
+    /// Whether to read `.d2fnignore` files while scanning, enabled by default. Ignored directories are not read at all:
+    /// the scan uses the parallel walker, on one thread unless [`Duplicate::parallel_walk`] is set.
+    pub fn respect_ignore_files(mut self, enable: bool) -> Self {
+        self.respect_ignore_files = enable;
+        self
+    }
+
+    /// Hash function to compare candidates sharing extension and size, blake3 by default. `verify()` always uses
+    /// blake3.
+    pub fn candidate_hash(mut self, algorithm: HashAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Hash with keyed blake3, so that hashes in the result can not be matched against hashes of known files.
+    pub fn hash_key(mut self, key: HashKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Leave out of the results groups wasting less than `bytes`, that is what the group takes beyond one copy.
+    /// They are still counted, see [`Duplicate::suppressed_groups`].
+    pub fn min_group_waste(mut self, bytes: u64) -> Self {
+        self.min_group_waste = bytes;
+        self
+    }
+
+    /// Measure the waste of groups for [`Duplicate::min_group_waste`] by `metric`, the apparent size by default.
+    pub fn waste_metric(mut self, metric: WasteMetric) -> Self {
+        self.waste_metric = metric;
+        self
+    }
+
+    /// Whether to keep hashed files in the page cache, see [`CachePolicy`].
+    pub fn cache_policy(mut self, cache: CachePolicy) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Key given by [`Duplicate::hash_key`].
+    pub fn key(&self) -> Option<&HashKey> {
+        self.key.as_ref()
+    }
+
+    /// Directories scanned, followed by the reference tree in cross-tree mode.
+    pub fn roots(&self) -> Vec<&Path> {
+        self.roots.iter().chain(self.reference.iter()).map(PathBuf::as_path).collect()
+    }
+
+    /// Only index files at most `depth` levels below each root, 0 being files directly in the root. Deeper directories
+    /// are not read at all: the scan uses the parallel walker, on one thread unless [`Duplicate::parallel_walk`] is set.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Abort the scan on the first entry which can not be read. By default such entries are skipped and collected,
+    /// see [`Duplicate::walk_errors`].
+    pub fn strict(mut self, enable: bool) -> Self {
+        self.strict = enable;
+        self
+    }
+
+    /// Walk directories sorted by name on one thread, and order groups, and files in each group, by path, so that
+    /// scans of the same tree give the same result. Overrides [`Duplicate::parallel_walk`].
+    pub fn deterministic(mut self, enable: bool) -> Self {
+        self.deterministic = enable;
+        self
+    }
+
+    /// Stop the walk once `files` files are indexed, for a quick sample of a large tree. See [`Duplicate::extent`].
+    pub fn limit_files(mut self, files: usize) -> Self {
+        self.file_budget = Some(files);
+        self
+    }
+
+    /// Stop the walk once `bytes` are read to hash files. The file being hashed is finished first.
+    pub fn limit_bytes(mut self, bytes: u64) -> Self {
+        self.byte_budget = Some(bytes);
+        self
+    }
+
+    /// Read directories on `threads` threads, for wide trees on network file systems. Files are hashed on the
+    /// calling thread as before.
+    pub fn parallel_walk(mut self, threads: usize) -> Self {
+        self.walk_threads = Some(threads);
+        self
+    }
+
+    /// Skip files and directories for which `prune` returns true, see [`Prune`]. With the parallel walker, pruned
+    /// directories are not read at all; otherwise they are still walked, and their files dropped.
+    pub fn prune_if(mut self, prune: impl Fn(&Path, &FileType, usize) -> bool + Send + Sync + 'static) -> Self {
+        self.prune = Some(Arc::new(prune));
+        self
+    }
+
+    /// Never descend into directories under `prefixes`, such as snapshots or application data. Prefixes are
+    /// canonicalized, so a symlinked spelling matches too. Excluded subtrees are not read at all: the scan uses the
+    /// parallel walker, on one thread unless [`Duplicate::parallel_walk`] is set.
+    pub fn exclude_paths<I: IntoIterator<Item = PathBuf>>(mut self, prefixes: I) -> Self {
+        for prefix in prefixes {
+            let canonical = std::fs::canonicalize(&prefix).unwrap_or_else(|_| {
+                // 不存在的路径无法解析链接, 只做规范化
+                std::path::absolute(&prefix).unwrap_or(prefix).components().collect()
+            });
+            self.excluded_paths.push(canonical);
+        }
+        // 重叠的前缀只保留最上层的
+        self.excluded_paths.sort();
+        self.excluded_paths.dedup_by(|nested, outer| nested.starts_with(outer));
+        self
+    }
+
+    /// Excluded prefixes below `root`, spelled as the walker sees them. `None` if `root` itself is excluded.
+    fn excluded_under(&self, root: &Path) -> Option<Vec<PathBuf>> {
+        let canonical_root = std::fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+        if self.excluded_paths.iter().any(|prefix| canonical_root.starts_with(prefix)) {
+            return None;
+        }
+        let under = self
+            .excluded_paths
+            .iter()
+            .filter_map(|prefix| prefix.strip_prefix(&canonical_root).ok())
+            .map(|relative| root.join(relative))
+            .collect();
+        Some(under)
+    }
+
+    /// Index only files under the roots changed at or after the cutoff of `since`, for reports of what changed since
+    /// a scan. Every directory is still walked, and the reference tree is indexed as a whole.
+    pub fn modified_since(mut self, since: Since) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Follow symbolic links, skipped by default. Files reached through a link are indexed unless they were seen by
+    /// another path. Links to directories are only entered by the parallel walker, see [`Duplicate::parallel_walk`].
+    pub fn follow_symlinks(mut self, enable: bool) -> Self {
+        self.follow_symlinks = enable;
+        self
+    }
+
+    /// Limit the aggregated read rate while hashing, in MB per second.
+    pub fn max_read_mbps(mut self, mbps: u32) -> Self {
+        self.throttle = self.throttle.max_read_mbps(mbps);
+        self
+    }
+
+    /// Sleep between hashed files and between directories, to leave some I/O for other users.
+    pub fn idle_between_files(mut self, duration: Duration) -> Self {
+        self.throttle = self.throttle.idle_between_files(duration);
+        self
+    }
+
+    /// Also fingerprint images, and group those within `threshold` bits of Hamming distance.
+    #[cfg(feature = "similar-images")]
+    pub fn find_similar_images(mut self, threshold: u32) -> Self {
+        self.similar = Some(SimilarImages::new(threshold));
+        self
+    }
+
+    /// Also group audio files by their frames only, so that copies with different tags are found.
+    #[cfg(feature = "audio")]
+    pub fn compare_audio_content(mut self) -> Self {
+        self.audio = Some(AudioIndex::default());
+        self
+    }
+
+    pub fn is_cross_mode(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    /// Whether `path` is in the reference tree, which is kept as it is.
+    pub fn in_reference(&self, path: &Path) -> bool {
+        self.reference.as_ref().is_some_and(|reference| path.starts_with(reference))
+    }
+
     pub fn enable_status_channel(&mut self, step: usize) -> Receiver<StatusReport> {
         assert!(step > 0);
 
@@ -208,6 +741,42 @@ impl<'a, F: ScanFilter> Duplicate<'a, F> {
         rx
     }
 
+    /// Rewrite a JSON document of the progress at `path`, at most once per `interval` while the scan goes on. See
+    /// [`Duplicate::finish_status_file`] for the last one.
+    pub fn status_file(mut self, path: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.status_file = Some(StatusFile::new(path.into(), interval));
+        self
+    }
+
+    /// Write the status file a last time, with `"state": "done"` and the results. Call it once the scan completed.
+    pub fn finish_status_file(&mut self) -> Result<()> {
+        let Some(mut status_file) = self.status_file.take() else {
+            return Ok(());
+        };
+        let groups: Vec<usize> = self.groups().map(Vec::len).filter(|&len| len > 1).collect();
+        let totals = Totals {
+            files: self.records.len(),
+            groups: groups.len(),
+            duplicates: groups.iter().map(|len| len - 1).sum(),
+            reclaimable_bytes: self.reclaimable_bytes(),
+            walk_errors: self.walk_errors.len(),
+        };
+        let status = StatusReport {
+            read_rate: self.throttle.rate(),
+            ..self.status_snapshot()
+        };
+        status_file.finish(&status, self.throttle.bytes_read(), &totals)
+    }
+
+    /// Counters of `status`, without the file in progress.
+    fn status_snapshot(&self) -> StatusReport {
+        StatusReport {
+            last_file: String::new(),
+            hashing_current_file: None,
+            ..self.status
+        }
+    }
+
     fn append_record(&mut self, file: File) -> RecordIndex {
         let index = self.records.len();
         self.records.push(file);
@@ -215,49 +784,123 @@ impl<'a, F: ScanFilter> Duplicate<'a, F> {
         index
     }
 
-    fn push(&mut self, file: File, compare_size: usize) -> Result<()> {
-        let ino = file.metadata.ino;
+    fn push(&mut self, file: File, compare_size: CompareSize) -> Result<()> {
+        let inode = (file.metadata.dev, file.metadata.ino);
         let path = file.path.clone();
         let extension = ext_hash(&file.path);
         let size = file.metadata.size;
 
-        if self.inode_set.contains(&ino) {
+        if file.metadata.link_count > 1 {
+            // 记录硬链接文件的每个路径, 以便单独报告已经去重的文件
+            self.hardlinks
+                .entry(inode)
+                .or_insert_with(|| HardlinkGroup {
+                    paths: Vec::new(),
+                    size,
+                    link_count: file.metadata.link_count,
+                })
+                .paths
+                .push(path.clone());
+        }
+        if self.inode_set.contains(&inode) {
             // 忽略已经记录过的文件
             return Ok(());
         }
         // 先记一个 ino
         // 如果当前文件之前（t时刻）去重过, 那么它只会被添加进来一次, 且, 自那次去重后新产生的、与它重复的文件会被识别到.
         // 如果没去重过也不影响, 未去重时他们的 ino 不同.
-        self.inode_set.insert(ino);
+        self.inode_set.insert(inode);
+
+        let key = ClassifyingKey(extension, size);
+        // 跨目录模式下, 参考目录已经索引完毕. 此时只需找出与参考目录中文件重复的文件,
+        // 不会与参考目录中任何文件重复的 (ext, size) 组合无需记录.
+        let against_reference = self.reference_end.is_some();
+        if against_reference && !self.set.contains_key(&key) {
+            if self.unique_check.is_some() {
+                let index = self.append_record(file);
+                self.unique.push(index);
+            }
+            return Ok(());
+        }
+        if against_reference && self.unique_check == Some(UniqueCheck::Quick) {
+            // 快速模式下, 与参考目录中文件扩展名、大小相同的文件被视为副本, 不再计算哈希
+            return Ok(());
+        }
 
         // 将当前文件信息存起, 便于后续比对.
         let index = self.append_record(file);
-        let key = ClassifyingKey(extension, size);
         if let Some(previous_result) = self.set.get_mut(&key) {
             // 存在与当前文件相同扩展名和大小的文件，且 inode 不同.
             // 需要通过哈希值进行最终的判断
-            let hash = checksum_file(path, CompareMode::Part(compare_size))?;
+            let mode = CompareMode::Part(compare_size.length(size));
+            let checksum = self.records[index].checksum_unchanged(
+                mode,
+                self.algorithm,
+                self.key.as_ref(),
+                self.cache,
+                &self.throttle,
+                None,
+            )?;
+            if checksum.is_some_and(|c| c.covered_whole_file) {
+                self.whole_hashed.insert(index);
+            }
+            let hash = checksum.map(|c| c.hash);
             // 这里使用了 PreviousScanned 结构. 由于估计存在大量非重复文件, 对于第一次出现满足某个 (ext, size)
             // 组合的文件只记录其下标, 等到第二次遇到该组合时再计算其哈希值, 以减少计算量
             if let PreviousScanned::Index(previous_index) = previous_result {
-                let previous_file = &self.records[*previous_index];
-                let previous_hash = checksum_file(&previous_file.path, CompareMode::Part(compare_size))?;
+                let i = *previous_index;
+                let previous_file = &self.records[i];
+                // 两个文件用同一长度计算哈希, 否则无法比较. 取较小的文件, 虽然同组文件大小总是相等.
+                let mode = CompareMode::Part(compare_size.length(size.min(previous_file.metadata.size)));
+                let previous_checksum = previous_file.checksum_unchanged(
+                    mode,
+                    self.algorithm,
+                    self.key.as_ref(),
+                    self.cache,
+                    &self.throttle,
+                    None,
+                )?;
 
                 let mut set_of_file_hash_in_ext_size = HashSet::new();
-                set_of_file_hash_in_ext_size.insert(previous_hash);
-
-                let i = *previous_index;
+                if let Some(Checksum {
+                    hash: previous_hash,
+                    covered_whole_file,
+                    ..
+                }) = previous_checksum
+                {
+                    if covered_whole_file {
+                        self.whole_hashed.insert(i);
+                    }
+                    set_of_file_hash_in_ext_size.insert(previous_hash);
+                    // 把之前扫描中遇到的这个文件, 它的哈希值不存在于 hash2files 中, 可以加进去
+                    // 这可能导致最终结果里 hash2files 出现一些 value.len() == 1 的键值对, 滤去即可
+                    self.hash2files.insert(previous_hash, vec![i]);
+                } else {
+                    // 之前的文件在扫描后被修改了, 不再参与比较
+                    self.status.stale_files += 1;
+                }
                 *previous_result = PreviousScanned::Hash(set_of_file_hash_in_ext_size);
-
-                // 把之前扫描中遇到的这个文件, 它的哈希值不存在于 hash2files 中, 可以加进去
-                // 这可能导致最终结果里 hash2files 出现一些 value.len() == 1 的键值对, 滤去即可
-                self.hash2files.insert(previous_hash, vec![i]);
             }
 
+            let Some(hash) = hash else {
+                self.status.stale_files += 1;
+                return Ok(());
+            };
+
             // 现在 PreviousScanned 一定记录了一个哈希值的集合
             // 如果当前文件是重复出现的, 即 hash 出现重复, 那么 set 和 hash2files 中已经存在这个哈希值了, 需要在 hash2files 登记一下
             // 如果当前文件第一次出现, 需要将 hash 添加到 set 中, 并在 hash2files 中记录 （后面没有机会记录了）
             if let PreviousScanned::Hash(set) = previous_result {
+                if against_reference {
+                    // 只与参考目录中的文件比对, 不登记新的哈希值, 以免目标目录内部的文件互相匹配
+                    if let Some(duplicate_file_list) = self.hash2files.get_mut(&hash) {
+                        duplicate_file_list.push(index);
+                        self.status.duplicated += 1;
+                    } else if self.unique_check.is_some() {
+                        self.unique.push(index);
+                    }
+                    return Ok(());
+                }
                 // 依上述分析, 直接添加
                 set.insert(hash);
                 // 在 hash2files 里记录一下
@@ -286,72 +929,697 @@ impl<'a, F: ScanFilter> Duplicate<'a, F> {
         result
     }
 
+    fn is_reference(&self, index: RecordIndex) -> bool {
+        matches!(self.reference_end, Some(end) if index < end)
+    }
+
+    /// Length of the prefix hashed for files of group `v`, `None` if they are hashed as a whole.
+    fn hashed_prefix(&self, v: &[RecordIndex]) -> Option<u64> {
+        if v.iter().all(|i| self.whole_hashed.contains(i)) {
+            return None;
+        }
+        // 同组文件大小相同
+        let size = self.records[v[0]].metadata.size;
+        self.compare_size.map(|c| c.length(size) as u64)
+    }
+
+    /// Groups along with the digest shared, and the length hashed if not whole files.
+    fn digest_groups(&'a self) -> impl Iterator<Item = (&'a Digest, Option<u64>, &'a Vec<RecordIndex>)> {
+        let partial = self.hash2files.iter().map(|(digest, v)| (digest, self.hashed_prefix(v), v));
+        let full = self.full_hash2files.iter().map(|(digest, v)| (digest, None, v));
+        let mut groups = partial.chain(full).filter(|(_, _, v)| v.len() > 1).collect::<Vec<_>>();
+        if self.deterministic {
+            // 组内已按路径排序, 以第一个文件为准
+            groups.sort_by_key(|(_, _, v)| &self.records[v[0]].path);
+        }
+        groups.into_iter()
+    }
+
+    fn groups(&'a self) -> impl Iterator<Item = &'a Vec<RecordIndex>> {
+        self.digest_groups().map(|(_, _, v)| v)
+    }
+
+    fn cross_group(&'a self, v: &[RecordIndex]) -> Option<CrossGroup<'a>> {
+        let reference = v.iter().find(|&&i| self.is_reference(i))?;
+        let redundant = v
+            .iter()
+            .filter(|&&i| !self.is_reference(i))
+            .map(|&i| &self.records[i])
+            .collect::<Vec<_>>();
+
+        if redundant.is_empty() {
+            return None;
+        }
+        Some(CrossGroup {
+            reference: &self.records[*reference],
+            redundant,
+        })
+    }
+
+    /// Duplicate groups. In cross-tree mode, each group starts with a file in the reference tree, followed by its
+    /// copies under the scan path.
     pub fn result(&'a self) -> impl Iterator<Item = Vec<&'a File>> {
-        let group_set1 = self
+        self.result_with_digest().map(|(_, _, files)| files)
+    }
+
+    /// Same as [`Duplicate::result`], along with the digest files of each group share, and the length of the prefix
+    /// it covers if files are not hashed as a whole.
+    pub fn result_with_digest(&'a self) -> impl Iterator<Item = (&'a Digest, Option<u64>, Vec<&'a File>)> {
+        self.all_results()
+            .filter(|(_, _, files)| group_waste(files).by(self.waste_metric) >= self.min_group_waste)
+    }
+
+    /// Groups, including those below [`Duplicate::min_group_waste`].
+    fn all_results(&'a self) -> impl Iterator<Item = (&'a Digest, Option<u64>, Vec<&'a File>)> {
+        let cross_mode = self.is_cross_mode();
+
+        self.digest_groups().filter_map(move |(digest, prefix, record_vec)| {
+            let files = if cross_mode {
+                self.cross_group(record_vec).map(CrossGroup::into_files)?
+            } else {
+                self.map_record_vec(record_vec)
+            };
+            Some((digest, prefix, files))
+        })
+    }
+
+    /// Count of groups left out by [`Duplicate::min_group_waste`], and the bytes they waste in total.
+    pub fn suppressed_groups(&'a self) -> (usize, u64) {
+        if self.min_group_waste == 0 {
+            return (0, 0);
+        }
+        self.all_results()
+            .map(|(_, _, files)| group_waste(&files).by(self.waste_metric))
+            .filter(|&waste| waste < self.min_group_waste)
+            .fold((0, 0), |(count, bytes), waste| (count + 1, bytes + waste))
+    }
+
+    /// How far the scan went, and whether a budget stopped it.
+    pub fn extent(&self) -> ScanExtent {
+        ScanExtent {
+            partial: self.partial,
+            files: self.status.scanned,
+            bytes_indexed: self.bytes_indexed,
+            bytes_hashed: self.throttle.bytes_read(),
+            reclaimable_bytes: self.reclaimable_bytes(),
+        }
+    }
+
+    /// Whether [`Duplicate::limit_files`] or [`Duplicate::limit_bytes`] ran out.
+    fn budget_spent(&self) -> bool {
+        self.file_budget.is_some_and(|files| self.status.scanned >= files)
+            || self.byte_budget.is_some_and(|bytes| self.throttle.bytes_read() >= bytes)
+    }
+
+    /// Count of directories not entered because of [`Duplicate::max_depth`].
+    pub fn pruned_dir_count(&self) -> usize {
+        self.pruned_dirs
+    }
+
+    /// Count of files and directories skipped by [`Duplicate::prune_if`]. Files in a pruned directory are not counted.
+    pub fn pruned_entry_count(&self) -> usize {
+        self.pruned_entries
+    }
+
+    /// Count of subtrees skipped by [`Duplicate::exclude_paths`], roots included.
+    pub fn excluded_count(&self) -> usize {
+        self.status.excluded
+    }
+
+    /// Count of symbolic links skipped, either not followed or dangling.
+    pub fn skipped_symlink_count(&self) -> usize {
+        self.skipped_symlinks
+    }
+
+    /// Entries skipped because they could not be read.
+    pub fn walk_errors(&self) -> &[WalkError] {
+        &self.walk_errors
+    }
+
+    /// Count of files changed or removed after being scanned. They are excluded from result.
+    pub fn stale_count(&self) -> usize {
+        self.status.stale_files
+    }
+
+    /// Files already hardlinked together, with more than one path observed during the scan. No hashing involved.
+    pub fn hardlink_groups(&self) -> impl Iterator<Item = &HardlinkGroup> {
+        let mut groups = self
+            .hardlinks
+            .values()
+            .filter(|group| group.paths.len() > 1)
+            .collect::<Vec<_>>();
+        if self.deterministic {
+            groups.sort_by_key(|group| &group.paths[0]);
+        }
+        groups.into_iter()
+    }
+
+    /// Every path observed for the inode of `file`, starting with `file` itself. A group member which is already
+    /// hardlinked elsewhere expands to all of its paths, and they share one `ino`.
+    pub fn linked_paths<'b>(&'b self, file: &'b File) -> Vec<&'b Path> {
+        let inode = (file.metadata.dev, file.metadata.ino);
+        let mut paths = vec![file.path.as_path()];
+        if let Some(group) = self.hardlinks.get(&inode) {
+            paths.extend(group.paths.iter().map(PathBuf::as_path).filter(|&p| p != file.path));
+        }
+        paths
+    }
+
+    /// Files under the scan path with no copy in the reference tree. Yields nothing unless
+    /// [`Duplicate::collect_unique`] is set.
+    pub fn unique_files(&'a self) -> impl Iterator<Item = &'a File> {
+        let enabled = self.unique_check.is_some();
+        // verify() 可能将组拆分, 拆分后不再包含参考目录文件的, 同样没有副本.
+        let orphans = self
             .hash2files
-            .iter()
-            .filter(|(_, v)| v.len() > 1)
-            .map(|(_, record_vec)| self.map_record_vec(record_vec));
+            .values()
+            .chain(self.full_hash2files.values())
+            .filter(move |v| enabled && !v.iter().any(|&i| self.is_reference(i)))
+            .flatten();
 
-        let group_set2 = self
-            .full_hash2files
-            .iter()
-            .filter(|(_, v)| v.len() > 1)
-            .map(|(_, record_vec)| self.map_record_vec(record_vec));
+        self.unique.iter().chain(orphans).map(|&i| &self.records[i])
+    }
+
+    /// Groups of similar images, leaving out those whose members are all identical. Empty unless
+    /// [`Duplicate::find_similar_images`] is set.
+    #[cfg(feature = "similar-images")]
+    pub fn similar_groups(&'a self) -> Vec<Vec<&'a Fingerprint>> {
+        let Some(similar) = &self.similar else {
+            return Vec::new();
+        };
+        let exact = self.exact_group_ids();
+
+        similar
+            .groups()
+            .into_iter()
+            .filter(|group| !Self::all_identical(&exact, group.iter().map(|f| f.path.as_path())))
+            .collect()
+    }
+
+    /// Groups of audio files with the same frames, leaving out those whose members are all identical. Empty unless
+    /// [`Duplicate::compare_audio_content`] is set.
+    #[cfg(feature = "audio")]
+    pub fn audio_groups(&'a self) -> Vec<&'a Vec<AudioEntry>> {
+        let Some(audio) = &self.audio else {
+            return Vec::new();
+        };
+        let exact = self.exact_group_ids();
 
-        group_set1.chain(group_set2)
+        audio
+            .groups()
+            .filter(|group| !Self::all_identical(&exact, group.iter().map(|f| f.path.as_path())))
+            .collect()
     }
 
-    pub fn discover(&mut self, compare_size: usize) -> Result<()> {
-        let walker = FileWalker::open(&self.path)
-            .with_context(|| format!("failed to read start directory: {}", self.path.display()))?
-            .file_only(true)
+    /// Audio files not parsed, and compared as whole files.
+    #[cfg(feature = "audio")]
+    pub fn audio_fallbacks(&self) -> &[PathBuf] {
+        self.audio.as_ref().map(|a| a.fallback.as_slice()).unwrap_or_default()
+    }
+
+    /// Member of a duplicate group -> index of the group.
+    #[cfg(any(feature = "similar-images", feature = "audio"))]
+    fn exact_group_ids(&'a self) -> HashMap<&'a Path, usize> {
+        self.groups()
+            .enumerate()
+            .flat_map(|(id, v)| v.iter().map(move |&i| (self.records[i].path.as_path(), id)))
+            .collect()
+    }
+
+    /// Whether all `paths` are in the same duplicate group, that is, they are identical.
+    #[cfg(any(feature = "similar-images", feature = "audio"))]
+    fn all_identical<'p>(exact: &HashMap<&Path, usize>, mut paths: impl Iterator<Item = &'p Path>) -> bool {
+        let Some(first) = paths.next().and_then(|p| exact.get(p)) else {
+            return false;
+        };
+        paths.all(|p| exact.get(p) == Some(first))
+    }
+
+    /// Images which failed to decode, and thus have no fingerprint.
+    #[cfg(feature = "similar-images")]
+    pub fn undecodable_images(&self) -> &[PathBuf] {
+        self.similar.as_ref().map(|s| s.failed.as_slice()).unwrap_or_default()
+    }
+
+    /// Prepare a search for duplicated directories, seeded with the duplicate groups found.
+    pub fn directory_matcher(&self) -> DirectoryMatcher<'_> {
+        let mut matcher = DirectoryMatcher::new(&self.throttle, self.cache);
+        for root in self.roots.iter().chain(self.reference.iter()) {
+            matcher.add_root(root);
+        }
+        for (digest, prefix, v) in self.digest_groups() {
+            let paths = v.iter().map(|&i| self.records[i].path.as_path()).collect::<Vec<_>>();
+            // 未经 verify 确认、只比较了前缀的组, 文件由目录比较时完整计算哈希.
+            // 目录摘要基于 blake3, 其他算法的值仅作为组标识
+            let whole = prefix.is_none() || self.confirmed.contains(digest);
+            let id = whole.then(|| digest.blake3().unwrap_or_else(|| blake3::hash(digest.as_bytes())));
+            matcher.add_group(id, &paths);
+        }
+        matcher
+    }
+
+    /// Duplicate groups in cross-tree mode. Yields nothing if no reference tree is set.
+    pub fn cross_result(&'a self) -> impl Iterator<Item = CrossGroup<'a>> {
+        self.groups()
+            .filter_map(|record_vec| self.cross_group(record_vec))
+            .filter(|group| {
+                let waste = group
+                    .redundant
+                    .iter()
+                    .map(|file| file.metadata.size_by(self.waste_metric))
+                    .sum::<u64>();
+                waste >= self.min_group_waste
+            })
+    }
+
+    /// Groups as inventory records, hardlinked paths included. Paths are relative to `roots`.
+    fn inventory_groups(&'a self, roots: &'a [&'a Path]) -> impl Iterator<Item = DuplicateGroup> + 'a {
+        let groups = self.result_with_digest().map(move |(digest, prefix, group)| {
+            let files = group
+                .iter()
+                .flat_map(|&file_ref| {
+                    // 同一 inode 的路径共用 ino, apply 据此跳过已是硬链接的文件.
+                    self.linked_paths(file_ref)
+                        .into_iter()
+                        .map(|path| DuplicateFile::scanned(path, &file_ref.metadata).relative_to(roots))
+                })
+                .collect::<Vec<_>>();
+            // 用 xxh3 比较的组没有可持久化的哈希, 是否比较过整个文件另行记录.
+            let hash = digest.blake3().map(|hash| GroupHash {
+                blake3: *hash.as_bytes(),
+                prefix,
+            });
+
+            DuplicateGroup {
+                files,
+                similar: false,
+                hash,
+                whole: prefix.is_none() || self.confirmed.contains(digest),
+            }
+        });
+        #[cfg(feature = "similar-images")]
+        let groups = groups.chain(self.similar_groups().into_iter().map(move |group| {
+            let files = group
+                .iter()
+                .map(|fingerprint| DuplicateFile::new(fingerprint.ino, &fingerprint.path).relative_to(roots))
+                .collect::<Vec<_>>();
+
+            DuplicateGroup {
+                files,
+                similar: true,
+                hash: None,
+                whole: false,
+            }
+        }));
+        // 标签不同的音频文件并不相同, 替换会丢失标签
+        #[cfg(feature = "audio")]
+        let groups = groups.chain(self.audio_groups().into_iter().map(move |group| {
+            let files = group
+                .iter()
+                .map(|entry| DuplicateFile::new(entry.ino, &entry.path).relative_to(roots))
+                .collect::<Vec<_>>();
+
+            DuplicateGroup {
+                files,
+                similar: true,
+                hash: None,
+                whole: false,
+            }
+        }));
+        groups
+    }
+
+    /// Write the groups found to `writer`, along with the hash key and the roots scanned, and return the count of
+    /// groups written. Groups are converted as they are written.
+    pub fn write_to<W: Write>(&'a self, writer: InventoryWriter<W>) -> Result<u32> {
+        // 路径按扫描目录记录为相对路径, 目录挂载位置改变后仍可使用.
+        let roots = self.roots();
+        let current_dir = std::env::current_dir()?;
+        let absolute_roots = roots.iter().map(|root| current_dir.join(root)).collect();
+        let mut writer = writer.hash_key(self.key()).scan_roots(absolute_roots);
+        let summary = writer.export_results(self.inventory_groups(&roots).map(Ok), true)?;
+        Ok(summary.written)
+    }
+
+    /// Index files under the reference tree and every root. Files sharing extension and size are compared by the
+    /// hash of their first `compare_size` bytes, see [`CompareSize`].
+    pub fn discover(&mut self, compare_size: impl Into<CompareSize>) -> Result<()> {
+        let _span = tracing::info_span!("scan").entered();
+        let compare_size = compare_size.into();
+        self.compare_size = Some(compare_size);
+        if let Some(status_file) = &mut self.status_file {
+            status_file.set_phase("discover");
+        }
+        if let Some(reference) = self.reference.clone() {
+            self.walk(&reference, compare_size)?;
+            self.reference_end = Some(self.records.len());
+        }
+        for root in self.roots.clone() {
+            self.walk(&root, compare_size)?;
+        }
+        self.sort_by_path();
+        tracing::info!(files = self.records.len(), errors = self.walk_errors.len(), "scan finished");
+        Ok(())
+    }
+
+    /// Sort files in each group by path if [`Duplicate::deterministic`] is set.
+    fn sort_by_path(&mut self) {
+        if !self.deterministic {
+            return;
+        }
+        let records = &self.records;
+        for v in self.hash2files.values_mut().chain(self.full_hash2files.values_mut()) {
+            v.sort_by(|&a, &b| records[a].path.cmp(&records[b].path));
+        }
+        self.unique.sort_by(|&a, &b| records[a].path.cmp(&records[b].path));
+        for group in self.hardlinks.values_mut() {
+            group.paths.sort();
+        }
+    }
+
+    fn walk(&mut self, root: &Path, compare_size: CompareSize) -> Result<()> {
+        let _span = tracing::info_span!("walk", root = %root.display()).entered();
+        if self.budget_spent() {
+            self.partial = true;
+            return Ok(());
+        }
+        let Some(excluded) = self.excluded_under(root) else {
+            tracing::info!("the root is excluded, skipped");
+            self.status.excluded += 1;
+            return Ok(());
+        };
+        // 参照目录的文件都要索引, 新文件才能与之比较
+        let since = self.since.filter(|_| self.reference.as_deref() != Some(root));
+        let ignore_rules = self
+            .respect_ignore_files
+            .then(|| Arc::new(Mutex::new(IgnoreRules::new(root))));
+        let parallel_pruned = Arc::new(AtomicUsize::new(0));
+        let parallel_excluded = Arc::new(AtomicUsize::new(0));
+        let parallel_too_deep = Arc::new(AtomicUsize::new(0));
+        // 不跟随的链接交给下面统一跳过并计数. 未设置线程数时不跟随指向目录的链接
+        let policy = if self.follow_symlinks && self.walk_threads.is_some() {
+            SymlinkPolicy::Follow
+        } else {
+            SymlinkPolicy::Yield
+        };
+        let mut walker = ParallelWalker::open(root)
+            .with_context(|| format!("failed to read start directory: {}", root.display()))?
+            .threads(self.walk_threads.filter(|_| !self.deterministic).unwrap_or(1))
+            .yield_dirs(if self.throttle.idles() {
+                DirOrder::PreOrder
+            } else {
+                DirOrder::None
+            })
             .filter_hidden_items(true)
-            .flatten();
+            .symlinks(policy)
+            .sorted(self.deterministic);
+        if let Some(since) = since {
+            walker = walker.modified_since(since);
+        }
+        if let Some(prune) = self.prune.clone() {
+            let counter = parallel_pruned.clone();
+            walker = walker.prune_if(move |path, file_type, depth| {
+                let pruned = prune(path, file_type, depth);
+                if pruned {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                pruned
+            });
+        }
+        if let Some(max_depth) = self.max_depth {
+            // 不进入限制深度处的目录, 其中的文件都比限制更深
+            let counter = parallel_too_deep.clone();
+            walker = walker.prune_if(move |_, file_type, depth| {
+                let too_deep = depth >= max_depth && file_type.is_dir();
+                if too_deep {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                too_deep
+            });
+        }
+        if let Some(rules) = ignore_rules.clone() {
+            walker =
+                walker.prune_if(move |path, file_type, _| file_type.is_dir() && rules.lock().unwrap().is_dir_ignored(path));
+        }
+        if !excluded.is_empty() {
+            let counter = parallel_excluded.clone();
+            walker = walker.prune_if(move |path, _, _| {
+                let hit = excluded.iter().any(|prefix| path == prefix);
+                if hit {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                hit
+            });
+        }
 
         for item in walker {
-            if let Ok(file) = File::try_from(item) {
-                let path = file.path.clone();
-                self.status.scanned += 1;
-                // 报告当前扫描进度
-                if self.status_channel.is_some() && self.status.scanned % self.status_report_step == 0 {
-                    if let Some(channel) = &self.status_channel {
-                        let path = path.to_string_lossy().to_string();
-                        let report = StatusReport {
-                            last_file: path,
-                            ..self.status
-                        };
-                        let _ = channel.send(report);
-                    }
+            let mut item = match item {
+                Ok(WalkItem::File(item)) => item,
+                Ok(WalkItem::Dir(dir)) => {
+                    // 并行遍历时各目录的文件交错到达, 需要暂停时以目录本身为准, 每个目录暂停一下.
+                    tracing::trace!(dir = %dir.display(), "entering");
+                    self.throttle.idle();
+                    continue;
                 }
-
-                if !self.filter.filter(&file) {
+                Err(error) => {
+                    self.skip_entry(error)?;
                     continue;
                 }
-
-                if let Err(e) = self.push(file, compare_size) {
-                    eprintln!("unable to add {}: {}", path.display(), e);
+            };
+            // 预算用完时停止遍历, 丢弃遍历器即停止其线程
+            if self.budget_spent() {
+                self.partial = true;
+                break;
+            }
+            let item_path = item.path();
+            // 没有跟随的符号链接, 其大小是目标路径的长度, 读取时却会跟随到目标, 只能跳过.
+            if item.file_type().is_ok_and(|t| t.is_symlink()) {
+                // 遍历器未跟随链接时, 指向文件的在这里跟随.
+                if self.follow_symlinks && std::fs::metadata(&item_path).is_ok_and(|m| m.is_file()) {
+                    item = item.follow();
+                } else {
+                    self.skipped_symlinks += 1;
+                    continue;
+                }
+            }
+            if let Some(rules) = &ignore_rules {
+                if rules.lock().unwrap().is_ignored(&item_path) {
+                    self.status.ignored += 1;
+                    continue;
+                }
+            }
+            let metadata = match item.metadata() {
+                // 遍历器按链接本身过滤, 这里跟随的链接按目标再过滤一次
+                Ok(metadata) if item.via_link() && since.is_some_and(|since| !since.includes(&metadata)) => {
+                    continue;
+                }
+                Ok(metadata) => convert_metadata(metadata),
+                Err(error) => {
+                    // 文件在列出之后被删除, 或者没有权限
+                    self.skip_entry(WalkError {
+                        path: item_path,
+                        error,
+                        subtree_skipped: false,
+                    })?;
+                    continue;
                 }
             };
+            if metadata.size == 0 {
+                continue;
+            }
+            if item.via_link() && self.inode_set.contains(&(metadata.dev, metadata.ino)) {
+                // 经由链接再次遇到的文件, 不是硬链接, 不必记录这条路径.
+                continue;
+            }
+            let file = File {
+                path: item_path,
+                metadata,
+            };
+            let path = file.path.clone();
+            self.status.scanned += 1;
+            self.bytes_indexed += file.metadata.size;
+            // 报告当前扫描进度
+            if self.status_channel.is_some() && self.status.scanned % self.status_report_step == 0 {
+                if let Some(channel) = &self.status_channel {
+                    let path = path.to_string_lossy().to_string();
+                    let report = StatusReport {
+                        last_file: path,
+                        read_rate: self.throttle.rate(),
+                        #[cfg(feature = "metrics")]
+                        bytes_read: self.throttle.bytes_read(),
+                        hashing_current_file: None,
+                        ..self.status
+                    };
+                    let _ = channel.send(report);
+                }
+            }
+            if self.status_file.as_ref().is_some_and(StatusFile::due) {
+                let report = StatusReport {
+                    last_file: path.to_string_lossy().to_string(),
+                    read_rate: self.throttle.rate(),
+                    hashing_current_file: None,
+                    ..self.status_snapshot()
+                };
+                if let Some(status_file) = &mut self.status_file {
+                    status_file.update(&report, self.throttle.bytes_read(), None);
+                }
+            }
+
+            if !self.filter.filter(&file) {
+                continue;
+            }
+            #[cfg(feature = "similar-images")]
+            if let Some(similar) = &mut self.similar {
+                similar.add(&file, &self.throttle);
+            }
+            #[cfg(feature = "audio")]
+            if let Some(audio) = &mut self.audio {
+                audio.add(&file, &self.throttle);
+            }
+
+            if let Err(e) = self.push(file, compare_size) {
+                tracing::warn!(path = %path.display(), "unable to add file: {e}");
+            }
+        }
+        self.pruned_entries += parallel_pruned.load(Ordering::Relaxed);
+        self.status.excluded += parallel_excluded.load(Ordering::Relaxed);
+        self.pruned_dirs += parallel_too_deep.load(Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn skip_entry(&mut self, error: WalkError) -> Result<()> {
+        if self.strict {
+            bail!("failed to read {error}");
         }
+        tracing::debug!("skipped: {error}");
+        self.walk_errors.push(error);
         Ok(())
     }
 
-    pub fn verify(&mut self) -> Result<usize> {
-        let mut conflict_count = 0usize;
+    /// Bytes freed if every reported duplicate is removed. In cross-tree mode only redundant copies count.
+    fn reclaimable_bytes(&self) -> u64 {
+        let cross_mode = self.is_cross_mode();
+
+        self.hash2files
+            .values()
+            .chain(self.full_hash2files.values())
+            .filter(|v| v.len() > 1)
+            .map(|v| {
+                // 稀疏文件的表观大小会夸大可节省的空间
+                let metadata = &self.records[v[0]].metadata;
+                let size = if metadata.is_sparse() {
+                    metadata.allocated_bytes()
+                } else {
+                    metadata.size
+                };
+                let copies = if cross_mode {
+                    match v.iter().filter(|&&i| !self.is_reference(i)).count() {
+                        count if count < v.len() => count,
+                        _ => 0,
+                    }
+                } else {
+                    v.len() - 1
+                };
+                size * copies as u64
+            })
+            .sum()
+    }
+
+    /// Compare full content of files in each group, and split groups whose members only share the leading part.
+    /// Groups left with less than two files are dropped.
+    pub fn verify(&mut self) -> Result<VerifyStats> {
+        let _span = tracing::info_span!("verify").entered();
+        let mut stats = VerifyStats::default();
+        let reference_end = self.reference_end;
+        let is_reference = |i: RecordIndex| matches!(reference_end, Some(end) if i < end);
+        let mut emptied = Vec::new();
+        // 拆分后仅剩一个文件的组, 跨目录模式下这些文件可能没有副本
+        let mut dissolved = Vec::new();
+        // 按组估计完成的比例
+        let groups_total = self.hash2files.values().filter(|v| v.len() > 1).count();
+        let mut groups_done = 0;
+        let counters = self.status_snapshot();
+        if let Some(status_file) = &mut self.status_file {
+            status_file.set_phase("verify");
+            status_file.update(&counters, self.throttle.bytes_read(), Some(0.0));
+        }
 
-        for (_, vec) in self.hash2files.iter_mut() {
+        for (partial_checksum, vec) in self.hash2files.iter_mut() {
             if vec.len() == 1 {
                 continue;
             }
+            let fraction = groups_done as f64 / groups_total.max(1) as f64;
+            groups_done += 1;
+            if let Some(status_file) = &mut self.status_file {
+                status_file.update(&counters, self.throttle.bytes_read(), Some(fraction));
+            }
+            // 跨目录模式下, 仅由参考目录中的文件组成的组不会被报告, 无需验证.
+            if reference_end.is_some() && vec.iter().all(|&i| is_reference(i)) {
+                continue;
+            }
+            stats.groups_checked += 1;
 
             // vec 是一个文件下标集合, 现在需要找到对应的 File 结构, 并计算其文件哈希值.
             // 按计算结果, 验证文件是否重复.
-            let mut full_checksum_map: HashMap<Hash, Vec<RecordIndex>> = HashMap::new();
+            let mut full_checksum_map: HashMap<Digest, Vec<RecordIndex>> = HashMap::new();
+            let mut stale_files = Vec::new();
             for i in vec.iter() {
                 let file = &self.records[*i];
-                let full_checksum =
-                    checksum_file(&file.path, CompareMode::Full).with_context(|| format!("read {}", file.path.display()))?;
+                // 部分哈希已经覆盖整个文件, 无需再读一遍, 只需确认文件未被修改. 验证只认 blake3.
+                let whole_hash = partial_checksum.blake3().filter(|_| self.whole_hashed.contains(i));
+                let full_checksum = if let Some(hash) = whole_hash {
+                    if file.is_stale() {
+                        stale_files.push(*i);
+                        continue;
+                    }
+                    Digest::Blake3(hash)
+                } else {
+                    // 大文件需要很久, 定期报告进度
+                    let channel = self.status_channel.as_ref();
+                    let status_file = &mut self.status_file;
+                    let throttle = &self.throttle;
+                    let watched = channel.is_some() || status_file.is_some();
+                    let mut report = |done, total| {
+                        if let Some(channel) = channel {
+                            let _ = channel.send(StatusReport {
+                                read_rate: throttle.rate(),
+                                #[cfg(feature = "metrics")]
+                                bytes_read: throttle.bytes_read(),
+                                hashing_current_file: Some(file.path.to_string_lossy().to_string()),
+                                hashing_progress: (done, total),
+                                ..Default::default()
+                            });
+                        }
+                        if let Some(status_file) = status_file.as_mut().filter(|f| f.due()) {
+                            let report = StatusReport {
+                                read_rate: throttle.rate(),
+                                hashing_current_file: Some(file.path.to_string_lossy().to_string()),
+                                hashing_progress: (done, total),
+                                last_file: String::new(),
+                                ..counters
+                            };
+                            status_file.update(&report, throttle.bytes_read(), Some(fraction));
+                        }
+                    };
+                    let progress = watched.then_some(&mut report as &mut dyn FnMut(u64, u64));
+                    let full_checksum = file
+                        .checksum_unchanged(
+                            CompareMode::Full,
+                            HashAlgorithm::Blake3,
+                            self.key.as_ref(),
+                            self.cache,
+                            &self.throttle,
+                            progress,
+                        )
+                        .with_context(|| format!("read {}", file.path.display()))?;
+                    let Some(full_checksum) = full_checksum else {
+                        stale_files.push(*i);
+                        continue;
+                    };
+                    stats.files_rehashed += 1;
+                    stats.bytes_rehashed += file.metadata.size;
+                    full_checksum.hash
+                };
 
                 if let Some(same_checksum_files) = full_checksum_map.get_mut(&full_checksum) {
                     same_checksum_files.push(*i);
@@ -359,22 +1627,839 @@ impl<'a, F: ScanFilter> Duplicate<'a, F> {
                     full_checksum_map.insert(full_checksum, vec![*i]);
                 }
             }
+            // 扫描后被修改的文件, 从组中移除.
+            if !stale_files.is_empty() {
+                vec.retain(|i| !stale_files.contains(i));
+                self.status.stale_files += stale_files.len();
+            }
 
             // 如果真的出现了：前 compare_size 大小相同, 但完整的文件不同的情况（针对存档文件少见）
             // 注意，这里不考虑哈希碰撞，即：默认只有部分哈希相同，完整的哈希才有可能相同.
             if full_checksum_map.len() > 1 {
                 vec.clear();
-                conflict_count += full_checksum_map.len();
+                stats.groups_split += 1;
 
                 for (full_checksum, mut array) in full_checksum_map.into_iter() {
-                    if let Some(old_array) = self.full_hash2files.get_mut(&full_checksum) {
+                    if array.len() < 2 {
+                        dissolved.append(&mut array);
+                    } else if let Some(old_array) = self.full_hash2files.get_mut(&full_checksum) {
                         old_array.append(&mut array);
                     } else {
                         self.full_hash2files.insert(full_checksum, array);
                     }
                 }
+            } else if vec.len() > 1 {
+                stats.groups_confirmed += 1;
+                self.confirmed.insert(*partial_checksum);
+            }
+            if vec.is_empty() {
+                emptied.push(*partial_checksum);
+            }
+        }
+
+        for partial_checksum in emptied {
+            self.hash2files.remove(&partial_checksum);
+        }
+        if self.unique_check.is_some() {
+            let orphans = dissolved.into_iter().filter(|&i| !is_reference(i));
+            self.unique.extend(orphans);
+        }
+        self.sort_by_path();
+        stats.reclaimable_bytes = self.reclaimable_bytes();
+        tracing::info!(
+            checked = stats.groups_checked,
+            split = stats.groups_split,
+            bytes = stats.bytes_rehashed,
+            "verify finished"
+        );
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::duplicate::{group_waste, Duplicate, File, GroupWaste, NoFilter, UniqueCheck, WalkError};
+    use crate::hash::{CompareSize, HashAlgorithm};
+    use crate::inventory::{InventoryReader, InventoryWriter};
+    use crate::metadata::{FileMetadata, WasteMetric};
+    use common::since::{Since, TimeField};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    fn create_tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("d2fn-test-{}-{name}", std::process::id()));
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    fn file_name(path: &Path) -> &str {
+        path.file_name().unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn test_cross_tree() {
+        let archive = create_tree("archive", &[("a.pdf", "same content"), ("b.pdf", "same content")]);
+        let inbox = create_tree(
+            "inbox",
+            &[
+                ("c.pdf", "same content"),
+                ("d.pdf", "diff content"),
+                ("e.pdf", "inbox copies"),
+                ("f.pdf", "inbox copies"),
+            ],
+        );
+
+        let mut duplicate = Duplicate::new(&inbox).reference_root(&archive);
+        duplicate.discover(1024).unwrap();
+
+        let groups = duplicate.cross_result().collect::<Vec<_>>();
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].reference.path.starts_with(&archive));
+        assert_eq!(groups[0].redundant.len(), 1);
+        assert_eq!(file_name(&groups[0].redundant[0].path), "c.pdf");
+        assert_eq!(duplicate.result().count(), 1);
+        assert!(duplicate.in_reference(&groups[0].reference.path));
+        assert!(!duplicate.in_reference(&groups[0].redundant[0].path));
+
+        std::fs::remove_dir_all(archive).unwrap();
+        std::fs::remove_dir_all(inbox).unwrap();
+    }
+
+    #[test]
+    fn test_modified_since() {
+        let archive = create_tree("since-archive", &[("a.pdf", "same content")]);
+        let inbox = create_tree("since-inbox", &[("b.pdf", "same content"), ("c.pdf", "same content")]);
+        let old = SystemTime::now() - Duration::from_secs(30 * 86400);
+        for path in [archive.join("a.pdf"), inbox.join("c.pdf")] {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+        }
+        let since = Since {
+            cutoff: SystemTime::now() - Duration::from_secs(86400),
+            field: TimeField::Mtime,
+        };
+
+        // 旧的 c.pdf 不索引, 参照目录中的旧文件照常索引
+        let mut duplicate = Duplicate::new(&inbox).reference_root(&archive).modified_since(since);
+        duplicate.discover(1024).unwrap();
+        let groups = duplicate.cross_result().collect::<Vec<_>>();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].redundant.len(), 1);
+        assert_eq!(file_name(&groups[0].redundant[0].path), "b.pdf");
+
+        let mut duplicate = Duplicate::new(&inbox).modified_since(since).parallel_walk(2);
+        duplicate.discover(1024).unwrap();
+        assert_eq!(duplicate.result().count(), 0);
+
+        std::fs::remove_dir_all(archive).unwrap();
+        std::fs::remove_dir_all(inbox).unwrap();
+    }
+
+    #[test]
+    fn test_hardlink_groups() {
+        let root = create_tree("hardlink", &[("a.pdf", "linked content"), ("b.pdf", "another file")]);
+        std::fs::hard_link(root.join("a.pdf"), root.join("c.pdf")).unwrap();
+
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(1024).unwrap();
+
+        let groups = duplicate.hardlink_groups().collect::<Vec<_>>();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].paths.len(), 2);
+        assert_eq!(groups[0].saved_bytes(), "linked content".len() as u64);
+        assert_eq!(duplicate.result().count(), 0);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_group_with_hardlinked_member() {
+        let root = create_tree("linked-member", &[("a.pdf", "same content"), ("b.pdf", "same content")]);
+        std::fs::hard_link(root.join("a.pdf"), root.join("c.pdf")).unwrap();
+
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(1024).unwrap();
+
+        let groups = duplicate.result().collect::<Vec<_>>();
+        assert_eq!(groups.len(), 1);
+        // 组内只有两个 inode, 展开后共三个路径.
+        assert_eq!(groups[0].len(), 2);
+        let expanded = groups[0]
+            .iter()
+            .flat_map(|&file| duplicate.linked_paths(file).into_iter().map(|p| (file.metadata.ino, p)))
+            .collect::<Vec<_>>();
+        assert_eq!(expanded.len(), 3);
+        let linked = expanded
+            .iter()
+            .filter(|(_, p)| ["a.pdf", "c.pdf"].contains(&file_name(p)))
+            .collect::<Vec<_>>();
+        assert_eq!(linked.len(), 2);
+        assert_eq!(linked[0].0, linked[1].0);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_adaptive_compare_size() {
+        // 开头 1MB 相同, 在文件 5% 处才出现差异
+        let size = 24 * 1024 * 1024;
+        let head = "h".repeat(size * 5 / 100);
+        let (a, b) = (
+            head.clone() + &"a".repeat(size - head.len()),
+            head.clone() + &"b".repeat(size - head.len()),
+        );
+        let root = create_tree("adaptive", &[("a.iso", &a), ("b.iso", &b)]);
+
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(1024 * 1024).unwrap();
+        assert_eq!(duplicate.result().count(), 1);
+
+        let adaptive = CompareSize::Adaptive {
+            min: 64 * 1024,
+            max: 256 * 1024 * 1024,
+            fraction: 0.1,
+        };
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(adaptive).unwrap();
+        assert_eq!(duplicate.result().count(), 0);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_compare_size_length() {
+        let adaptive = CompareSize::Adaptive {
+            min: 1024,
+            max: 4096,
+            fraction: 0.5,
+        };
+        assert_eq!(adaptive.length(100), 1024);
+        assert_eq!(adaptive.length(4000), 2000);
+        assert_eq!(adaptive.length(1 << 30), 4096);
+        assert_eq!(CompareSize::Fixed(10).length(1 << 30), 10);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let root = create_tree(
+            "depth",
+            &[
+                ("a.pdf", "same"),
+                ("d1/a.pdf", "same"),
+                ("d1/d2/a.pdf", "same"),
+                ("d1/d2/d3/a.pdf", "same"),
+                ("e1/e2/a.pdf", "same"),
+            ],
+        );
+        let count = |depth| {
+            let mut duplicate = Duplicate::new(&root).max_depth(depth);
+            duplicate.discover(1024).unwrap();
+            let files = duplicate.result().next().map(|g| g.len()).unwrap_or(0);
+            (files, duplicate.pruned_dir_count())
+        };
+
+        assert_eq!(count(0), (0, 2));
+        // d1/d2 与 e1/e2 未被进入
+        assert_eq!(count(1), (2, 2));
+        assert_eq!(count(2), (4, 1));
+        assert_eq!(count(3), (5, 0));
+
+        // 限制深度以下的目录不被读取, 遍历器也就见不到其中的条目
+        let deepest = Arc::new(AtomicUsize::new(0));
+        let seen = deepest.clone();
+        let mut duplicate = Duplicate::new(&root).max_depth(1).prune_if(move |_, _, depth| {
+            seen.fetch_max(depth, Ordering::Relaxed);
+            false
+        });
+        duplicate.discover(1024).unwrap();
+        assert_eq!(deepest.load(Ordering::Relaxed), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_ignored_dirs() {
+        let root = create_tree(
+            "ignored-dirs",
+            &[
+                (".d2fnignore", "cache/\n*.iso\n"),
+                ("a.pdf", "same"),
+                ("b.pdf", "same"),
+                ("c.iso", "same"),
+                ("cache/a.pdf", "same"),
+                ("cache/sub/a.pdf", "same"),
+            ],
+        );
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let paths = seen.clone();
+        let mut duplicate = Duplicate::new(&root).prune_if(move |path, _, _| {
+            paths.lock().unwrap().push(path.to_path_buf());
+            false
+        });
+        duplicate.discover(1024).unwrap();
+        assert_eq!(duplicate.result().next().map(|g| g.len()), Some(2));
+        assert_eq!(duplicate.status.ignored, 1);
+        // 被忽略的目录不被读取
+        let seen = seen.lock().unwrap();
+        assert!(seen.contains(&root.join("cache")));
+        assert!(!seen
+            .iter()
+            .any(|path| path.starts_with(root.join("cache")) && path != &root.join("cache")));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let root = create_tree(
+            "deterministic",
+            &[
+                ("b/1.pdf", "one"),
+                ("a/1.pdf", "one"),
+                ("c.pdf", "one"),
+                ("z/2.pdf", "two"),
+                ("y/2.pdf", "two"),
+            ],
+        );
+        let scan = || {
+            let mut duplicate = Duplicate::new(&root).deterministic(true);
+            duplicate.discover(1024).unwrap();
+            duplicate
+                .result()
+                .map(|group| group.iter().map(|f| f.path.clone()).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        let first = scan();
+        assert_eq!(first, scan());
+        assert_eq!(
+            first,
+            vec![
+                vec![root.join("a/1.pdf"), root.join("b/1.pdf"), root.join("c.pdf")],
+                vec![root.join("y/2.pdf"), root.join("z/2.pdf")],
+            ]
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let root = create_tree(
+            "prune",
+            &[
+                ("a.pdf", "same"),
+                ("@eaDir/a.pdf", "same"),
+                ("@eaDir/sub/a.pdf", "same"),
+                ("photos/@eaDir/a.pdf", "same"),
+                ("photos/a.pdf", "same"),
+                ("photos/skip.pdf", "same"),
+            ],
+        );
+        let scan = |threads: Option<usize>| {
+            let mut duplicate = Duplicate::new(&root).prune_if(|path, _, depth| {
+                let name = path.file_name().unwrap();
+                name == "@eaDir" || (depth == 1 && name == "skip.pdf")
+            });
+            if let Some(threads) = threads {
+                duplicate = duplicate.parallel_walk(threads);
             }
+            duplicate.discover(1024).unwrap();
+            let files = duplicate.result().next().map(|g| g.len()).unwrap_or(0);
+            (files, duplicate.pruned_entry_count())
+        };
+
+        assert_eq!(scan(None), (2, 3));
+        assert_eq!(scan(Some(2)), (2, 3));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_exclude_paths() {
+        let root = create_tree(
+            "exclude",
+            &[
+                ("a.pdf", "same"),
+                (".snapshots/daily/a.pdf", "same"),
+                ("appdata/a.pdf", "same"),
+                ("appdata/cache/a.pdf", "same"),
+                ("media/a.pdf", "same"),
+                ("media/old/a.pdf", "same"),
+            ],
+        );
+        let link = root.with_extension("link");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&root, &link).unwrap();
+        let scan = |prefixes: &[PathBuf], threads: Option<usize>| {
+            let mut duplicate = Duplicate::new(&root).exclude_paths(prefixes.to_vec());
+            if let Some(threads) = threads {
+                duplicate = duplicate.parallel_walk(threads);
+            }
+            duplicate.discover(1024).unwrap();
+            let files = duplicate.result().next().map(|g| g.len()).unwrap_or(0);
+            (files, duplicate.excluded_count())
+        };
+
+        for threads in [None, Some(2)] {
+            // 嵌套的前缀
+            assert_eq!(scan(&[root.join("media/old"), root.join("appdata/cache")], threads), (3, 2));
+            // 重叠的前缀只计一次, 经由链接的写法也能匹配
+            assert_eq!(scan(&[link.join("appdata"), root.join("appdata/cache/")], threads), (3, 1));
+            // 排除整个根目录, 或不存在的路径
+            assert_eq!(scan(std::slice::from_ref(&link), threads), (0, 1));
+            assert_eq!(scan(&[root.join("missing")], threads), (5, 0));
         }
-        Ok(conflict_count)
+
+        std::fs::remove_file(link).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_status_file() {
+        let root = create_tree("status-file", &[("a.pdf", "same"), ("b.pdf", "same"), ("c.pdf", "other")]);
+        let path = root.with_extension("json");
+        let mut duplicate = Duplicate::new(&root).status_file(&path, Duration::ZERO);
+        duplicate.discover(1024).unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            (document["state"].as_str(), document["phase"].as_str()),
+            (Some("running"), Some("discover"))
+        );
+        duplicate.verify().unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("\"phase\":\"verify\""));
+
+        duplicate.finish_status_file().unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(document["state"], "done");
+        assert_eq!(document["scanned"], 3);
+        assert_eq!(document["totals"]["files"], 3);
+        assert_eq!(document["totals"]["groups"], 1);
+        assert_eq!(document["totals"]["duplicates"], 1);
+        assert!(document["updated"].as_u64() >= document["started"].as_u64());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_min_group_waste() {
+        let big = "x".repeat(64 * 1024);
+        let root = create_tree(
+            "min-waste",
+            &[
+                ("a.pdf", "same"),
+                ("b.pdf", "same"),
+                ("c.zip", &big),
+                ("d.zip", &big),
+                ("e.zip", &big),
+            ],
+        );
+        let mut duplicate = Duplicate::new(&root).min_group_waste(32 * 1024);
+        duplicate.discover(1024).unwrap();
+        // 小组不出现在结果中, 但被计数
+        let groups = duplicate.result().collect::<Vec<_>>();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].len(), 3);
+        let (count, bytes) = duplicate.suppressed_groups();
+        assert_eq!(count, 1);
+        assert!(bytes > 0 && bytes < 32 * 1024);
+
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(1024).unwrap();
+        assert_eq!(duplicate.result().count(), 2);
+        assert_eq!(duplicate.suppressed_groups(), (0, 0));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_group_waste() {
+        let file = |ino, size, blocks| File {
+            path: PathBuf::from(format!("/data/{ino}")),
+            metadata: FileMetadata {
+                dev: 1,
+                ino,
+                link_count: 1,
+                size,
+                blocks,
+                mtime: 0,
+                mtime_nsec: 0,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+            },
+        };
+        // 压缩的文件系统上分配的块远少于表观大小; 稀疏文件亦然. 不计块数时按表观大小
+        let files = [file(1, 10 << 20, 4096), file(2, 10 << 20, 2048), file(3, 1 << 20, 0)];
+        let files = files.iter().collect::<Vec<_>>();
+        let waste = group_waste(&files);
+        assert_eq!(
+            waste,
+            GroupWaste {
+                apparent: 11 << 20,
+                allocated: 2 << 20
+            }
+        );
+        assert_eq!(waste.by(WasteMetric::Apparent), 11 << 20);
+        assert_eq!(waste.by(WasteMetric::Allocated), 2 << 20);
+        assert_eq!(group_waste(&files[..1]), GroupWaste::default());
+    }
+
+    #[test]
+    fn test_write_to() {
+        let root = create_tree(
+            "write-to",
+            &[
+                ("a.pdf", "same content"),
+                ("b/c.pdf", "same content"),
+                ("d.pdf", "diff content"),
+            ],
+        );
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(1024).unwrap();
+        let path = std::env::temp_dir().join(format!("d2fn-test-{}-write-to.inv", std::process::id()));
+        assert_eq!(duplicate.write_to(InventoryWriter::create(&path).unwrap()).unwrap(), 1);
+
+        // 读取时路径按扫描目录还原
+        let groups = InventoryReader::open(&path)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(groups.len(), 1);
+        let group = &groups[0];
+        assert!(!group.similar);
+        let digest = duplicate.result_with_digest().next().unwrap().0;
+        assert_eq!(group.hash.as_ref().unwrap().blake3, *digest.blake3().unwrap().as_bytes());
+        let mut paths = group.files.iter().map(|file| PathBuf::from(&file.path)).collect::<Vec<_>>();
+        paths.sort();
+        let root = root.canonicalize().unwrap_or(root);
+        assert_eq!(paths, [root.join("a.pdf"), root.join("b/c.pdf")]);
+        assert!(group
+            .files
+            .iter()
+            .all(|file| file.size == Some(12) && file.mtime.is_some() && file.dev.is_some()));
+        assert!(group.whole);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn test_write_audio_group() {
+        let root = create_tree("write-audio", &[]);
+        std::fs::create_dir_all(root.join("b")).unwrap();
+        // 帧相同, 标签不同
+        std::fs::write(root.join("a.mp3"), b"ID3\x04\x00\x00\x00\x00\x00\x01A\xff\xfb\x90\x00frames").unwrap();
+        std::fs::write(
+            root.join("b/c.mp3"),
+            b"ID3\x04\x00\x00\x00\x00\x00\x02BB\xff\xfb\x90\x00frames",
+        )
+        .unwrap();
+        let mut duplicate = Duplicate::new(&root).compare_audio_content();
+        duplicate.discover(1024).unwrap();
+        let path = std::env::temp_dir().join(format!("d2fn-test-{}-write-audio.inv", std::process::id()));
+        assert_eq!(duplicate.write_to(InventoryWriter::create(&path).unwrap()).unwrap(), 1);
+
+        // 路径相对于扫描目录记录, 挂载到别处后仍然可用
+        let groups = InventoryReader::open(&path)
+            .unwrap()
+            .anchor_to(&[PathBuf::from("/mnt/moved")])
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].similar);
+        let mut paths = groups[0]
+            .files
+            .iter()
+            .map(|file| PathBuf::from(&file.path))
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [PathBuf::from("/mnt/moved/a.mp3"), PathBuf::from("/mnt/moved/b/c.mp3")]
+        );
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_write_prefix_group() {
+        let root = create_tree("write-prefix", &[("a.pdf", "same content"), ("b.pdf", "same contenT")]);
+        let mut duplicate = Duplicate::new(&root).candidate_hash(HashAlgorithm::Xxh3_128);
+        duplicate.discover(4).unwrap();
+        let path = std::env::temp_dir().join(format!("d2fn-test-{}-write-prefix.inv", std::process::id()));
+        let read_back = |duplicate: &Duplicate<NoFilter>| {
+            duplicate.write_to(InventoryWriter::create(&path).unwrap()).unwrap();
+            InventoryReader::open(&path)
+                .unwrap()
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap()
+        };
+
+        // xxh3 没有可持久化的哈希, 仍应记下只比较了前缀.
+        let groups = read_back(&duplicate);
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].hash.is_none());
+        assert!(!groups[0].whole);
+
+        std::fs::write(root.join("b.pdf"), "same content").unwrap();
+        let mut duplicate = Duplicate::new(&root).candidate_hash(HashAlgorithm::Xxh3_128);
+        duplicate.discover(4).unwrap();
+        assert_eq!(duplicate.verify().unwrap().groups_confirmed, 1);
+        assert!(read_back(&duplicate)[0].whole);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_budgets() {
+        let content = "x".repeat(1024);
+        let names = (0..8).map(|i| format!("{i}.pdf")).collect::<Vec<_>>();
+        let files = names.iter().map(|name| (name.as_str(), content.as_str())).collect::<Vec<_>>();
+        let root = create_tree("budgets", &files);
+
+        let mut duplicate = Duplicate::new(&root).limit_files(3);
+        duplicate.discover(1024).unwrap();
+        let extent = duplicate.extent();
+        assert!(extent.partial);
+        assert_eq!((extent.files, extent.bytes_indexed), (3, 3 * 1024));
+        assert_eq!(extent.reclaimable_bytes, 2 * 1024);
+
+        // 第二个文件加入时两个文件都被哈希, 之后预算用完
+        let mut duplicate = Duplicate::new(&root).limit_bytes(1);
+        duplicate.discover(1024).unwrap();
+        let extent = duplicate.extent();
+        assert!(extent.partial);
+        assert_eq!((extent.files, extent.bytes_hashed), (2, 2 * 1024));
+
+        // 预算恰好够用时不算部分结果
+        let mut duplicate = Duplicate::new(&root).limit_files(8);
+        duplicate.discover(1024).unwrap();
+        assert!(!duplicate.extent().partial);
+        assert_eq!(duplicate.result().next().unwrap().len(), 8);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_walk_errors() {
+        let error = || WalkError {
+            path: PathBuf::from("/data/locked"),
+            error: std::io::Error::from(std::io::ErrorKind::PermissionDenied),
+            subtree_skipped: true,
+        };
+
+        let mut duplicate = Duplicate::new("/data");
+        duplicate.skip_entry(error()).unwrap();
+        assert_eq!(duplicate.walk_errors().len(), 1);
+        assert!(duplicate.walk_errors()[0]
+            .to_string()
+            .starts_with("/data/locked (subtree skipped): "));
+
+        let mut duplicate = Duplicate::new("/data").strict(true);
+        assert!(duplicate.skip_entry(error()).is_err());
+        assert!(duplicate.walk_errors().is_empty());
+
+        let root = create_tree("walk-errors", &[("a.pdf", "same content"), ("b.pdf", "same content")]);
+        let unreadable = create_deep_dir(&root);
+        for threads in [1, 4] {
+            let mut duplicate = Duplicate::new(&root).parallel_walk(threads);
+            duplicate.discover(1024).unwrap();
+            assert_eq!(duplicate.walk_errors().len(), 1);
+            assert_eq!(duplicate.walk_errors()[0].path, unreadable);
+            assert!(duplicate.walk_errors()[0].subtree_skipped);
+            assert_eq!(duplicate.result().next().unwrap().len(), 2);
+
+            let mut duplicate = Duplicate::new(&root).parallel_walk(threads).strict(true);
+            let e = duplicate.discover(1024).unwrap_err();
+            assert!(e.to_string().contains(&*unreadable.to_string_lossy()), "{e}");
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Nest directories below `root` until the path is longer than `PATH_MAX`. Even root cannot read the deepest one,
+    /// unlike a directory without permissions.
+    fn create_deep_dir(root: &Path) -> PathBuf {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = "d".repeat(255);
+        let c_name = CString::new(name.as_str()).unwrap();
+        let c_root = CString::new(root.as_os_str().as_bytes()).unwrap();
+        let mut path = root.to_path_buf();
+        // 路径过长, 只能逐级相对于上一级目录创建
+        unsafe {
+            let mut fd = libc::open(c_root.as_ptr(), libc::O_DIRECTORY | libc::O_RDONLY);
+            assert!(fd >= 0);
+            while path.as_os_str().len() <= libc::PATH_MAX as usize {
+                assert_eq!(libc::mkdirat(fd, c_name.as_ptr(), 0o755), 0);
+                let child = libc::openat(fd, c_name.as_ptr(), libc::O_DIRECTORY | libc::O_RDONLY);
+                assert!(child >= 0);
+                libc::close(fd);
+                fd = child;
+                path.push(&name);
+            }
+            libc::close(fd);
+        }
+        path
+    }
+
+    #[test]
+    fn test_file_truncated_before_hash() {
+        let root = create_tree("truncated", &[("a.pdf", "original content"), ("b.pdf", "original content")]);
+        let scan = |name: &str| {
+            File::try_from(
+                std::fs::read_dir(&root)
+                    .unwrap()
+                    .flatten()
+                    .find(|e| e.file_name() == name)
+                    .unwrap(),
+            )
+        };
+
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.push(scan("a.pdf").unwrap(), CompareSize::Fixed(1024)).unwrap();
+        // a.pdf 在扫描之后、计算哈希之前被截断
+        std::fs::File::options()
+            .write(true)
+            .open(root.join("a.pdf"))
+            .unwrap()
+            .set_len(3)
+            .unwrap();
+        duplicate.push(scan("b.pdf").unwrap(), CompareSize::Fixed(1024)).unwrap();
+
+        assert_eq!(duplicate.stale_count(), 1);
+        assert_eq!(duplicate.result().count(), 0);
+        assert_eq!(duplicate.verify().unwrap().groups_checked, 0);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_unique_files() {
+        let archive = create_tree("unique-archive", &[("a.pdf", "same content"), ("b.pdf", "some content")]);
+        let staging = create_tree(
+            "unique-staging",
+            &[
+                ("c.pdf", "same content"),
+                ("d.pdf", "diff content"),
+                ("e.pdf", "no size match"),
+            ],
+        );
+        let unique_names = |check| {
+            let mut duplicate = Duplicate::new(&staging).reference_root(&archive).collect_unique(check);
+            duplicate.discover(1024).unwrap();
+
+            let mut names = duplicate
+                .unique_files()
+                .map(|file| file_name(&file.path).to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        assert_eq!(unique_names(UniqueCheck::Hash), vec!["d.pdf", "e.pdf"]);
+        assert_eq!(unique_names(UniqueCheck::Quick), vec!["e.pdf"]);
+
+        std::fs::remove_dir_all(archive).unwrap();
+        std::fs::remove_dir_all(staging).unwrap();
+    }
+
+    #[test]
+    fn test_verify_splits_partial_collision() {
+        // 开头 1MB 相同, 仅凭部分哈希无法区分
+        let head = "h".repeat(1024 * 1024);
+        let (same, diff, other) = (head.clone() + "AAAA", head.clone() + "BBBB", "o".repeat(head.len() + 4));
+        let size = same.len() as u64;
+        let root = create_tree(
+            "collision",
+            &[("a.pdf", &same), ("b.pdf", &same), ("c.pdf", &diff), ("d.pdf", &other)],
+        );
+
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(head.len()).unwrap();
+        assert_eq!(duplicate.result().count(), 1);
+        assert_eq!(duplicate.result().next().unwrap().len(), 3);
+
+        let stats = duplicate.verify().unwrap();
+        assert_eq!(stats.groups_checked, 1);
+        assert_eq!(stats.groups_confirmed, 0);
+        assert_eq!(stats.groups_split, 1);
+        assert_eq!(stats.files_rehashed, 3);
+        assert_eq!(stats.bytes_rehashed, 3 * size);
+        assert_eq!(stats.reclaimable_bytes, size);
+
+        // 拆分后的空组与单文件组都被移除
+        assert!(duplicate.hash2files.values().all(|v| !v.is_empty()));
+        assert!(duplicate.full_hash2files.values().all(|v| v.len() > 1));
+        let groups = duplicate.result().collect::<Vec<_>>();
+        assert_eq!(groups.len(), 1);
+        let mut names = groups[0].iter().map(|f| file_name(&f.path)).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["a.pdf", "b.pdf"]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_verify_confirmed() {
+        let root = create_tree("confirmed", &[("a.pdf", "identical"), ("b.pdf", "identical")]);
+
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(4).unwrap();
+        let stats = duplicate.verify().unwrap();
+        assert_eq!(stats.groups_checked, 1);
+        assert_eq!(stats.groups_confirmed, 1);
+        assert_eq!(stats.groups_split, 0);
+        assert_eq!(stats.reclaimable_bytes, "identical".len() as u64);
+        // 只比较了前 4 字节, 需要重新计算完整哈希
+        assert_eq!(stats.files_rehashed, 2);
+
+        // 部分哈希已覆盖整个文件时, 不再重复读取
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(1024).unwrap();
+        let stats = duplicate.verify().unwrap();
+        assert_eq!(stats.groups_confirmed, 1);
+        assert_eq!(stats.files_rehashed, 0);
+        assert_eq!(duplicate.result().count(), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_verify_split_orphan_is_unique() {
+        let head = "h".repeat(1024 * 1024);
+        let archive = create_tree("split-archive", &[("a.pdf", &(head.clone() + "AAAA"))]);
+        let staging = create_tree("split-staging", &[("b.pdf", &(head.clone() + "BBBB"))]);
+
+        let mut duplicate = Duplicate::new(&staging)
+            .reference_root(&archive)
+            .collect_unique(UniqueCheck::Hash);
+        duplicate.discover(head.len()).unwrap();
+        assert_eq!(duplicate.unique_files().count(), 0);
+
+        let stats = duplicate.verify().unwrap();
+        assert_eq!(stats.groups_split, 1);
+        assert_eq!(stats.reclaimable_bytes, 0);
+        let unique = duplicate.unique_files().collect::<Vec<_>>();
+        assert_eq!(unique.len(), 1);
+        assert_eq!(file_name(&unique[0].path), "b.pdf");
+
+        std::fs::remove_dir_all(archive).unwrap();
+        std::fs::remove_dir_all(staging).unwrap();
     }
 }
@@ -0,0 +1,172 @@
+//! Per-directory `.d2fnignore` support.
+//!
+//! A `.d2fnignore` file holds gitignore-style patterns which apply to the directory it lives in and everything below.
+//! Patterns in deeper files take precedence over the ones closer to the scan root.
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub const IGNORE_FILE_NAME: &str = ".d2fnignore";
+
+pub struct IgnoreRules {
+    root: PathBuf,
+    /// directory -> rules loaded from its `.d2fnignore`, `None` if there is no such file.
+    cache: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl IgnoreRules {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            cache: HashMap::new(),
+        }
+    }
+
+    fn load(dir: &Path) -> Option<Gitignore> {
+        let path = dir.join(IGNORE_FILE_NAME);
+        if !path.is_file() {
+            return None;
+        }
+
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(e) = builder.add(&path) {
+            tracing::warn!("{}: {e}", path.display());
+        }
+        match builder.build() {
+            Ok(rules) => Some(rules),
+            Err(e) => {
+                tracing::warn!("unable to load {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    fn rules_of(&mut self, dir: &Path) -> Option<&Gitignore> {
+        self.cache
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| Self::load(dir))
+            .as_ref()
+    }
+
+    /// Check whether a file should be skipped, according to `.d2fnignore` files between the root and it.
+    pub fn is_ignored(&mut self, path: &Path) -> bool {
+        self.matches(path, false)
+    }
+
+    /// Check whether a directory should not be entered, according to `.d2fnignore` files between the root and it.
+    pub fn is_dir_ignored(&mut self, dir: &Path) -> bool {
+        self.matches(dir, true)
+    }
+
+    fn matches(&mut self, path: &Path, is_dir: bool) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+
+        // 自根目录向下, 列出文件所在的每一级目录, 再从最深处开始匹配, 深层规则优先.
+        let mut dirs = vec![self.root.clone()];
+        if let Some(parent) = relative.parent() {
+            let mut dir = self.root.clone();
+            for component in parent.components() {
+                dir.push(component);
+                dirs.push(dir.clone());
+            }
+        }
+
+        for dir in dirs.iter().rev() {
+            if let Some(rules) = self.rules_of(dir) {
+                let matched = rules.matched_path_or_any_parents(path, is_dir);
+                if matched.is_ignore() {
+                    return true;
+                }
+                if matched.is_whitelist() {
+                    return false;
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IgnoreRules, IGNORE_FILE_NAME};
+    use std::path::PathBuf;
+
+    fn create_tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("d2fn-ignore-{}-{name}", std::process::id()));
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn test_negation() {
+        let root = create_tree(
+            "negation",
+            &[
+                (IGNORE_FILE_NAME, "*.iso\n!keep.iso\n"),
+                ("a.iso", ""),
+                ("keep.iso", ""),
+                ("sub/b.iso", ""),
+                ("sub/c.pdf", ""),
+            ],
+        );
+        let mut rules = IgnoreRules::new(&root);
+
+        assert!(rules.is_ignored(&root.join("a.iso")));
+        assert!(!rules.is_ignored(&root.join("keep.iso")));
+        assert!(rules.is_ignored(&root.join("sub/b.iso")));
+        assert!(!rules.is_ignored(&root.join("sub/c.pdf")));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_anchored() {
+        let root = create_tree(
+            "anchored",
+            &[
+                (IGNORE_FILE_NAME, "/build\n.cache/\n"),
+                ("build/a.bin", ""),
+                ("src/build/b.bin", ""),
+                ("src/.cache/c.bin", ""),
+            ],
+        );
+        let mut rules = IgnoreRules::new(&root);
+
+        assert!(rules.is_ignored(&root.join("build/a.bin")));
+        assert!(!rules.is_ignored(&root.join("src/build/b.bin")));
+        assert!(rules.is_ignored(&root.join("src/.cache/c.bin")));
+        assert!(rules.is_dir_ignored(&root.join("build")));
+        assert!(!rules.is_dir_ignored(&root.join("src/build")));
+        assert!(rules.is_dir_ignored(&root.join("src/.cache")));
+        assert!(!rules.is_dir_ignored(&root.join("src")));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_deeper_file_overrides() {
+        let root = create_tree(
+            "precedence",
+            &[
+                (IGNORE_FILE_NAME, "*.mp4\n"),
+                ("photos/.d2fnignore", "!*.mp4\n*.jpg\n"),
+                ("a.mp4", ""),
+                ("photos/b.mp4", ""),
+                ("photos/c.jpg", ""),
+                ("d.jpg", ""),
+            ],
+        );
+        let mut rules = IgnoreRules::new(&root);
+
+        assert!(rules.is_ignored(&root.join("a.mp4")));
+        assert!(!rules.is_ignored(&root.join("photos/b.mp4")));
+        assert!(rules.is_ignored(&root.join("photos/c.jpg")));
+        assert!(!rules.is_ignored(&root.join("d.jpg")));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
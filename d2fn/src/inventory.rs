@@ -1,18 +1,89 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bincode::{Decode, Encode};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::mem::size_of;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
-pub const CURRENT_VERSION: u8 = 0x01;
+use crate::hash::HashKey;
+use crate::metadata::FileMetadata;
+
+/// Version 2 adds `key_id` to the header, version 3 adds metadata of files and the hash of groups. Since version 4,
+/// records are followed by an end marker and a footer instead of being counted in the header, so that inventories can
+/// be written to pipes. Version 5 adds a CRC32 after each record, version 6 an index of records before the end marker,
+/// and version 7 flags to the header. Version 8 records scan roots in the header, paths of files are relative to them.
+/// Since version 9, the offset in the header is where records begin, readers skip header fields they do not know.
+/// Version 10 records nanoseconds of modification times.
+pub const CURRENT_VERSION: u8 = 0x0a;
+/// First version ending with a footer.
+const FOOTER_VERSION: u8 = 0x04;
+/// First version with a CRC32 of each record, following the record.
+const CRC_VERSION: u8 = 0x05;
+/// First version with an index.
+const INDEX_VERSION: u8 = 0x06;
+/// First version with flags in the header.
+const FLAGS_VERSION: u8 = 0x07;
+/// First version with scan roots.
+const ROOTS_VERSION: u8 = 0x08;
+/// First version with a u32 offset of records in the header. Offsets written before are wrong, and ignored.
+const OFFSET_VERSION: u8 = 0x09;
+/// First version with nanoseconds of modification times.
+const NSEC_VERSION: u8 = 0x0a;
+/// Taken by the length of a record, marks the end of records.
+const END_MARKER: u32 = u32::MAX;
+/// Taken by the length of a record, followed by the count of records and the offset of each of them, as u64.
+const INDEX_MARKER: u32 = u32::MAX - 1;
+
+/// End marker, count of records, offset of the index since version 6, and blake3 of records with their lengths, CRCs
+/// excluded.
+fn footer_size(version: u8) -> usize {
+    if version >= INDEX_VERSION {
+        4 + 4 + 8 + 32
+    } else {
+        4 + 4 + 32
+    }
+}
+
+/// Header flag: everything after the header, the footer included, is a zstd stream.
+const HEADER_FLAG_ZSTD: u8 = 0x01;
+
+/// Group flag: files look alike but are not identical.
+const GROUP_FLAG_SIMILAR: u8 = 0x01;
+/// Group flag: the flag byte is followed by the blake3 hash files share.
+const GROUP_FLAG_HASH: u8 = 0x02;
+/// Group flag: the hash covers only a prefix of files, whose length follows the hash as a little-endian u64.
+const GROUP_FLAG_PREFIX: u8 = 0x04;
+/// Group flag: files are identical as a whole, hashed in full or verified, whatever the algorithm.
+const GROUP_FLAG_WHOLE: u8 = 0x08;
+
+/// Damage found when reading an inventory. Readable groups before the damage, and after a corrupt record, are still
+/// returned by [`InventoryReader`], so that they can be salvaged.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InventoryError {
+    /// The record at `index`, counting from 0, does not match its CRC or cannot be decoded.
+    CorruptRecord { index: u32 },
+    /// The file ends in the middle of a record, or without a footer; `groups` records were read before.
+    TruncatedFile { groups: u32 },
+}
+
+impl std::fmt::Display for InventoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InventoryError::CorruptRecord { index } => write!(f, "group {index} of the inventory is corrupted"),
+            InventoryError::TruncatedFile { groups } => write!(f, "inventory truncated after {groups} groups"),
+        }
+    }
+}
+
+impl std::error::Error for InventoryError {}
 
 /// bincode 中实现的对 PathBuf 的序列化、反序列化代码，会将文件名按 UTF-8 对待
 /// 这可能导致对非 UTF-8 文件名的反序列化出现错误. 因此底层使用 `Vec<u8>` 处理.
-#[derive(Encode, Decode)]
+#[derive(Encode, Decode, Clone, PartialEq, Eq, Hash)]
 pub struct D2fnPath {
     path: Vec<u8>,
 }
@@ -24,6 +95,13 @@ impl From<D2fnPath> for PathBuf {
     }
 }
 
+impl From<&D2fnPath> for PathBuf {
+    fn from(value: &D2fnPath) -> Self {
+        let os_path = OsStr::from_bytes(&value.path);
+        PathBuf::from(os_path)
+    }
+}
+
 impl From<&Path> for D2fnPath {
     fn from(value: &Path) -> Self {
         let os_path = value.as_os_str();
@@ -33,35 +111,276 @@ impl From<&Path> for D2fnPath {
     }
 }
 
-#[derive(Encode, Decode, Default)]
+#[derive(Default)]
 pub struct Header {
     version: u8,
-    offset: u8,
+    /// Where records begin, set when the header is encoded.
+    offset: u32,
     count: u32,
+    /// [`HashKey::id`] of the key files were hashed with, 0 if hashes are not keyed.
+    key_id: u64,
+    flags: u8,
+    /// Absolute paths of directories scanned, see [`DuplicateFile::root`].
+    roots: Vec<PathBuf>,
+}
+
+/// Records of an inventory, read from the file, or decompressed on the fly.
+enum RecordSource {
+    Plain(BufReader<File>),
+    Zstd {
+        decoder: zstd::Decoder<'static, BufReader<File>>,
+        /// Offset of the zstd stream in the file
+        start: u64,
+        /// Offset of the next read, as if the inventory were not compressed
+        position: u64,
+    },
+}
+
+impl Read for RecordSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            RecordSource::Plain(reader) => reader.read(buf),
+            RecordSource::Zstd { decoder, position, .. } => {
+                let n = decoder.read(buf)?;
+                *position += n as u64;
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl RecordSource {
+    /// Move to `offset` as if the inventory were not compressed. A zstd stream is decompressed again from the start to
+    /// move backward.
+    fn seek_to(&mut self, offset: u64) -> std::io::Result<()> {
+        let skip = match self {
+            RecordSource::Plain(reader) => return reader.seek(SeekFrom::Start(offset)).map(|_| ()),
+            RecordSource::Zstd {
+                decoder,
+                start,
+                position,
+            } => {
+                if offset < *position {
+                    let mut file = decoder.get_ref().get_ref().try_clone()?;
+                    file.seek(SeekFrom::Start(*start))?;
+                    *decoder = zstd::Decoder::new(file)?;
+                    *position = *start;
+                }
+                offset - *position
+            }
+        };
+        let skipped = std::io::copy(&mut self.take(skip), &mut std::io::sink())?;
+        if skipped < skip {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+/// Where records are written, compressed or not.
+enum RecordSink<'a, W: Write> {
+    Plain(&'a mut BufWriter<W>),
+    Zstd(zstd::Encoder<'static, &'a mut BufWriter<W>>),
+}
+
+impl<W: Write> Write for RecordSink<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            RecordSink::Plain(writer) => writer.write(buf),
+            RecordSink::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            RecordSink::Plain(writer) => writer.flush(),
+            RecordSink::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl<W: Write> RecordSink<'_, W> {
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            RecordSink::Plain(writer) => writer.flush(),
+            RecordSink::Zstd(encoder) => encoder.finish()?.flush(),
+        }
+    }
 }
 
-#[derive(Encode, Decode)]
+/// A file as recorded at scan time. Metadata is `None` in inventories before version 3, and for groups of similar
+/// files.
+#[derive(Encode, Decode, Clone)]
 pub struct DuplicateFile {
     pub ino: u64,
     pub path: D2fnPath,
+    pub dev: Option<u64>,
+    pub size: Option<u64>,
+    /// Last modification time, in seconds since epoch
+    pub mtime: Option<i64>,
+    /// Nanoseconds part of the last modification time, `None` in inventories before version 10
+    pub mtime_nsec: Option<i64>,
+    /// Index of the scan root `path` is relative to, `None` if `path` is not relative to a root. Paths are anchored
+    /// to their roots when read, so readers always see `None`.
+    pub root: Option<u16>,
+}
+
+impl DuplicateFile {
+    pub fn new(ino: u64, path: &Path) -> Self {
+        Self {
+            ino,
+            path: D2fnPath::from(path),
+            dev: None,
+            size: None,
+            mtime: None,
+            mtime_nsec: None,
+            root: None,
+        }
+    }
+
+    /// A file found by a scan. Hardlinked paths share `metadata`.
+    pub fn scanned(path: &Path, metadata: &FileMetadata) -> Self {
+        Self {
+            ino: metadata.ino,
+            path: D2fnPath::from(path),
+            dev: Some(metadata.dev),
+            size: Some(metadata.size),
+            mtime: Some(metadata.mtime),
+            mtime_nsec: Some(metadata.mtime_nsec),
+            root: None,
+        }
+    }
+
+    /// Store the path relative to the first of `roots` it is under, if any. See [`InventoryWriter::scan_roots`].
+    pub fn relative_to(mut self, roots: &[&Path]) -> Self {
+        let path = PathBuf::from(&self.path);
+        for (i, root) in roots.iter().enumerate() {
+            if let Some(relative) = path.strip_prefix(root).ok().filter(|p| !p.as_os_str().is_empty()) {
+                self.path = D2fnPath::from(relative);
+                self.root = Some(i as u16);
+                break;
+            }
+        }
+        self
+    }
+
+    /// Whether the file on disk differs from the recorded one, by what is recorded.
+    pub fn is_changed(&self, metadata: &std::fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        metadata.ino() != self.ino
+            || self.dev.is_some_and(|dev| dev != metadata.dev())
+            || self.size.is_some_and(|size| size != metadata.size())
+            || self.mtime.is_some_and(|mtime| mtime != metadata.mtime())
+            || self.mtime_nsec.is_some_and(|nsec| nsec != metadata.mtime_nsec())
+    }
 }
 
-#[derive(Encode, Decode)]
+/// File of inventories before version 3.
+#[derive(Decode)]
+struct LegacyFile {
+    ino: u64,
+    path: D2fnPath,
+}
+
+/// File of inventories of version 3 to 7, without root.
+#[derive(Decode)]
+struct UnrootedFile {
+    ino: u64,
+    path: D2fnPath,
+    dev: Option<u64>,
+    size: Option<u64>,
+    mtime: Option<i64>,
+}
+
+/// File of inventories of version 8 and 9, without nanoseconds of the modification time.
+#[derive(Decode)]
+struct RootedFile {
+    ino: u64,
+    path: D2fnPath,
+    dev: Option<u64>,
+    size: Option<u64>,
+    mtime: Option<i64>,
+    root: Option<u16>,
+}
+
+/// Join `relative` to `root`, refusing paths which would escape the root.
+fn anchor(root: &Path, relative: &Path) -> Result<PathBuf> {
+    let inside = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !inside {
+        bail!("path {} escapes its root {}.", relative.display(), root.display());
+    }
+    Ok(root.join(relative))
+}
+
+/// The blake3 hash shared by files of a group, keyed if the inventory has a key id.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct GroupHash {
+    pub blake3: [u8; 32],
+    /// Length of the prefix hashed, `None` if files are hashed as a whole.
+    pub prefix: Option<u64>,
+}
+
+/// A record is the bincode-encoded file list, optionally followed by a flag byte and what flags announce. Readers
+/// ignore trailing bytes in a record, so groups without flags are stored as they were before flags exist.
 pub struct DuplicateGroup {
     pub files: Vec<DuplicateFile>,
+    /// Similar, not identical, see `similar.rs`. Replacing one file with another loses data.
+    pub similar: bool,
+    /// `None` for groups of similar files, groups compared by xxh3, and inventories before version 3.
+    pub hash: Option<GroupHash>,
+    /// Files are known identical as a whole, not only by a prefix. Groups of older inventories are whole only if they
+    /// have a blake3 hash of whole files.
+    pub whole: bool,
 }
 
 pub struct InventoryReader {
-    reader: BufReader<File>,
+    reader: RecordSource,
     buffer: Vec<u8>,
 
     header: Header,
+    /// Count of records in the footer, if it was found when opening.
+    footer_count: Option<u32>,
+    /// Offset of the index in the footer, until the index is loaded.
+    footer_index: Option<u64>,
+    /// Offsets of records known so far, from the index or read one by one. Empty if sequential.
+    index: Vec<u64>,
+    sequential: bool,
+    records_start: u64,
+    /// Offset of the index or the end marker, once found.
+    records_end: Option<u64>,
+    /// Offset of the next read.
+    position: u64,
     read_count: u32,
+    /// Hash of records read so far, checked against the footer.
+    payload: blake3::Hasher,
+    /// Records not matching their CRC.
+    corrupt_count: u32,
+    /// Records were skipped by seeking, the payload hash is incomplete.
+    seeked: bool,
+    finished: bool,
 }
 
-pub struct InventoryWriter {
+pub struct InventoryWriter<W: Write = File> {
     buffer: Vec<u8>,
-    writer: BufWriter<File>,
+    writer: BufWriter<W>,
+    key_id: u64,
+    /// Key id in the header, once it is written or found in the file appended to.
+    header_key_id: Option<u64>,
+    roots: Vec<PathBuf>,
+    /// Roots of the file appended to.
+    header_roots: Vec<PathBuf>,
+    /// Records written so far and their hash, for the footer.
+    count: u32,
+    payload: blake3::Hasher,
+    /// Offsets of records written so far, for the index.
+    offsets: Vec<u64>,
+    position: u64,
+    /// zstd level, if compressed
+    compression: Option<i32>,
+    finished: bool,
 }
 
 impl InventoryReader {
@@ -71,32 +390,352 @@ impl InventoryReader {
         let mut reader = BufReader::new(file);
 
         let header = Self::read_header(&mut reader).with_context(|| "reading header.".to_string())?;
+        let mut records_start = reader.stream_position()?;
+        if header.version >= OFFSET_VERSION {
+            // 跳过较新版本写入的未知头部字段
+            if (header.offset as u64) < records_start {
+                bail!("invalid header, records begin at {} inside the header.", header.offset);
+            }
+            records_start = reader.seek(SeekFrom::Start(header.offset as u64))?;
+        }
+        // 压缩的清单 footer 也在压缩流中, 无法预先读取.
+        let (footer_count, footer_index, reader) = if header.flags & HEADER_FLAG_ZSTD != 0 {
+            let decoder = zstd::Decoder::with_buffer(reader)?;
+            let reader = RecordSource::Zstd {
+                decoder,
+                start: records_start,
+                position: records_start,
+            };
+            (None, None, reader)
+        } else if header.version >= FOOTER_VERSION {
+            let (count, index) = Self::peek_footer(&mut reader, header.version)?;
+            (count, index, RecordSource::Plain(reader))
+        } else {
+            (None, None, RecordSource::Plain(reader))
+        };
         Ok(Self {
             reader,
             buffer,
             header,
+            footer_count,
+            footer_index,
+            index: Vec::new(),
+            sequential: false,
+            records_start,
+            records_end: None,
+            position: records_start,
             read_count: 0,
+            payload: blake3::Hasher::new(),
+            corrupt_count: 0,
+            seeked: false,
+            finished: false,
         })
     }
 
-    pub fn total(&self) -> usize {
-        self.header.count as usize
+    /// Only read groups in order, without keeping their offsets, for consumers streaming large inventories.
+    pub fn read_only_sequential(mut self) -> Self {
+        self.sequential = true;
+        self.footer_index = None;
+        self.index = Vec::new();
+        self
+    }
+
+    /// Anchor relative paths to `roots` instead of the roots recorded, given in the same order, e.g. after the pool is
+    /// mounted elsewhere. Nothing changes if `roots` is empty.
+    pub fn anchor_to(mut self, roots: &[PathBuf]) -> Result<Self> {
+        if roots.is_empty() {
+            return Ok(self);
+        }
+        let recorded = &self.header.roots;
+        if recorded.len() != roots.len() {
+            let recorded = recorded.iter().map(|r| r.display().to_string()).collect::<Vec<_>>();
+            bail!(
+                "the inventory has {} scan roots [{}], but {} roots are given.",
+                recorded.len(),
+                recorded.join(", "),
+                roots.len()
+            );
+        }
+        self.header.roots = roots.to_vec();
+        Ok(self)
+    }
+
+    /// Version of the format the inventory was written in.
+    pub fn version(&self) -> u8 {
+        self.header.version
+    }
+
+    /// Absolute paths of directories scanned, or the directories given to [`InventoryReader::anchor_to`].
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.header.roots
+    }
+
+    /// Count of groups, `None` if the footer is missing, as the writer has not finished yet or was interrupted, or if
+    /// the inventory is compressed.
+    pub fn total(&self) -> Option<usize> {
+        if self.header.version >= FOOTER_VERSION {
+            self.footer_count.map(|count| count as usize)
+        } else {
+            Some(self.header.count as usize)
+        }
+    }
+
+    /// Read the count and the index offset in the footer without moving the reader, `None` if the file does not end
+    /// with a footer.
+    fn peek_footer(reader: &mut BufReader<File>, version: u8) -> Result<(Option<u32>, Option<u64>)> {
+        let position = reader.stream_position()?;
+        let length = reader.get_ref().metadata()?.len();
+        let footer_size = footer_size(version) as u64;
+        if length < position + footer_size {
+            return Ok((None, None));
+        }
+        reader.seek(SeekFrom::Start(length - footer_size))?;
+        let marker = reader.read_u32::<LittleEndian>()?;
+        let count = reader.read_u32::<LittleEndian>()?;
+        let index = if version >= INDEX_VERSION {
+            Some(reader.read_u64::<LittleEndian>()?)
+        } else {
+            None
+        };
+        reader.seek(SeekFrom::Start(position))?;
+        if marker != END_MARKER {
+            return Ok((None, None));
+        }
+        Ok((Some(count), index.filter(|&offset| offset != 0)))
+    }
+
+    /// Load offsets of all records from the index pointed to by the footer.
+    fn load_index(&mut self) -> Result<()> {
+        let (Some(offset), Some(count)) = (self.footer_index.take(), self.footer_count) else {
+            return Ok(());
+        };
+        self.reader.seek_to(offset)?;
+        if self.reader.read_u32::<LittleEndian>()? != INDEX_MARKER || self.reader.read_u32::<LittleEndian>()? != count {
+            bail!("the index of the inventory is corrupted.");
+        }
+        let mut index = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            index.push(self.reader.read_u64::<LittleEndian>()?);
+        }
+        self.index = index;
+        // 读取位置已改变, 由调用者重新定位.
+        self.seeked = true;
+        Ok(())
+    }
+
+    /// Move to the `n`-th group, counting from 0, so that it is returned next. Inventories without an index are read
+    /// up to the group the first time, offsets of groups read are kept for later seeks.
+    pub fn seek_to_group(&mut self, n: usize) -> Result<()> {
+        if self.sequential {
+            bail!("the inventory is opened for sequential reading only.");
+        }
+        if let Some(total) = self.total().filter(|&total| n >= total) {
+            bail!("no group {n}, the inventory has {total} groups.");
+        }
+        if self.index.len() <= n {
+            self.load_index()?;
+        }
+        if self.index.len() <= n {
+            // 没有索引: 从已知的最后一组开始顺序读取.
+            let (start, count) = match self.index.last() {
+                Some(&offset) => (offset, self.index.len() - 1),
+                None => (self.records_start, 0),
+            };
+            self.jump(start, count)?;
+            while self.index.len() <= n {
+                if self.read_record()?.is_none() {
+                    bail!("no group {n}, the inventory has {} groups.", self.read_count);
+                }
+                self.read_count += 1;
+            }
+        }
+        self.jump(self.index[n], n)
+    }
+
+    fn jump(&mut self, offset: u64, count: usize) -> Result<()> {
+        self.reader.seek_to(offset)?;
+        self.position = offset;
+        self.read_count = count as u32;
+        self.seeked = true;
+        self.finished = false;
+        Ok(())
+    }
+
+    /// Read the `n`-th group, counting from 0, see [`InventoryReader::seek_to_group`].
+    pub fn get_group(&mut self, n: usize) -> Result<DuplicateGroup> {
+        self.seek_to_group(n)?;
+        self.next().with_context(|| format!("no group {n}."))?
+    }
+
+    /// Id of the key hashes were derived with, `None` if not keyed.
+    pub fn key_id(&self) -> Option<u64> {
+        Some(self.header.key_id).filter(|&id| id != 0)
+    }
+
+    /// Refuse to go on unless the inventory was hashed with `key`, or both are not keyed.
+    pub fn check_key(&self, key: Option<&HashKey>) -> Result<()> {
+        match (self.key_id(), key.map(HashKey::id)) {
+            (None, None) => Ok(()),
+            (Some(expected), Some(given)) if expected == given => Ok(()),
+            (Some(_), Some(_)) => bail!("the inventory was hashed with another key."),
+            (Some(_), None) => bail!("the inventory was hashed with a key, please pass it with --key-file."),
+            (None, Some(_)) => bail!("the inventory was not hashed with a key, but --key-file is given."),
+        }
     }
 
     fn read_header<R: BufRead>(mut reader: R) -> Result<Header> {
         let version = reader.read_u8()?;
-        let offset = reader.read_u8()?;
+        let offset = if version >= OFFSET_VERSION {
+            reader.read_u32::<LittleEndian>()?
+        } else {
+            reader.read_u8()? as u32
+        };
         let count = reader.read_u32::<LittleEndian>()?;
+        // 版本 1 没有 key_id, 视作未使用密钥.
+        let key_id = match version {
+            0 => bail!("incomplete inventory, the scan writing it may have been interrupted."),
+            1 => {
+                tracing::warn!("legacy inventory of version 1, run `d2fn upgrade` on it or scan again.");
+                0
+            }
+            2..=CURRENT_VERSION => reader.read_u64::<LittleEndian>()?,
+            _ => bail!("unsupported inventory version {version}, newer than {CURRENT_VERSION}."),
+        };
+        let flags = if version >= FLAGS_VERSION { reader.read_u8()? } else { 0 };
+        let mut roots = Vec::new();
+        if version >= ROOTS_VERSION {
+            for _ in 0..reader.read_u16::<LittleEndian>()? {
+                let mut root = vec![0u8; reader.read_u16::<LittleEndian>()? as usize];
+                reader.read_exact(&mut root)?;
+                roots.push(PathBuf::from(OsString::from_vec(root)));
+            }
+        }
+
+        Ok(Header {
+            version,
+            offset,
+            count,
+            key_id,
+            flags,
+            roots,
+        })
+    }
 
-        Ok(Header { version, offset, count })
+    /// Read the next record into the buffer and return its size and whether it matches its CRC, `None` at the end
+    /// marker.
+    fn read_record(&mut self) -> Result<Option<(usize, bool)>> {
+        let start = self.position;
+        let mut size = self.reader.read_u32::<LittleEndian>()?;
+        if size == INDEX_MARKER && self.header.version >= INDEX_VERSION {
+            let count = self.reader.read_u32::<LittleEndian>()?;
+            self.reader.seek_to(start + 4 + 4 + count as u64 * 8)?;
+            size = self.reader.read_u32::<LittleEndian>()?;
+        }
+        if size == END_MARKER && self.header.version >= FOOTER_VERSION {
+            self.records_end = Some(start);
+            return Ok(None);
+        }
+        if !self.sequential && self.index.len() == self.read_count as usize {
+            self.index.push(start);
+        }
+        let record = self
+            .buffer
+            .get_mut(..size as usize)
+            .with_context(|| format!("record of {size} bytes is too large."))?;
+        self.reader.read_exact(record)?;
+        let intact = if self.header.version >= CRC_VERSION {
+            self.reader.read_u32::<LittleEndian>()? == crc32fast::hash(record)
+        } else {
+            true
+        };
+        let crc_size = if self.header.version >= CRC_VERSION { 4 } else { 0 };
+        self.position = start + 4 + size as u64 + crc_size;
+
+        self.payload.update(&size.to_le_bytes());
+        self.payload.update(record);
+        Ok(Some((size as usize, intact)))
     }
 
-    fn decode<D: Decode + Sized, R: BufRead>(mut reader: R, buf: &mut [u8]) -> Result<D> {
-        let size = reader.read_u32::<LittleEndian>()?;
+    fn check_footer(&mut self) -> Result<()> {
+        let truncated = InventoryError::TruncatedFile { groups: self.read_count };
+        let mut footer = [0u8; 4 + 8 + 32];
+        let footer = &mut footer[..footer_size(self.header.version) - 4];
+        self.reader.read_exact(footer).context(truncated)?;
+        let count = u32::from_le_bytes(footer[..4].try_into().unwrap());
+        let hash = &footer[footer.len() - 32..];
 
-        reader.read_exact(&mut buf[..size as usize])?;
-        let (data, _) = bincode::decode_from_slice(&buf[..size as usize], bincode::config::standard())?;
-        Ok(data)
+        // 损坏的记录已逐条报告过, 跳过的记录未计入哈希, 此时不再比较哈希.
+        let hash_matched = self.corrupt_count > 0 || self.seeked || hash == self.payload.finalize().as_bytes();
+        if count != self.read_count || !hash_matched {
+            bail!(
+                "the footer does not match {} groups read, the inventory is corrupted.",
+                self.read_count
+            );
+        }
+        Ok(())
+    }
+
+    /// Decode a record, and anchor paths of files to `roots`.
+    fn decode(record: &[u8], version: u8, roots: &[PathBuf]) -> Result<DuplicateGroup> {
+        let config = bincode::config::standard();
+        let (mut files, used): (Vec<DuplicateFile>, _) = if version < 3 {
+            let (files, used): (Vec<LegacyFile>, _) = bincode::decode_from_slice(record, config)?;
+            let files = files.into_iter().map(|f| DuplicateFile::new(f.ino, &PathBuf::from(f.path)));
+            (files.collect(), used)
+        } else if version < ROOTS_VERSION {
+            let (files, used): (Vec<UnrootedFile>, _) = bincode::decode_from_slice(record, config)?;
+            let files = files.into_iter().map(|f| DuplicateFile {
+                ino: f.ino,
+                path: f.path,
+                dev: f.dev,
+                size: f.size,
+                mtime: f.mtime,
+                mtime_nsec: None,
+                root: None,
+            });
+            (files.collect(), used)
+        } else if version < NSEC_VERSION {
+            let (files, used): (Vec<RootedFile>, _) = bincode::decode_from_slice(record, config)?;
+            let files = files.into_iter().map(|f| DuplicateFile {
+                ino: f.ino,
+                path: f.path,
+                dev: f.dev,
+                size: f.size,
+                mtime: f.mtime,
+                mtime_nsec: None,
+                root: f.root,
+            });
+            (files.collect(), used)
+        } else {
+            bincode::decode_from_slice(record, config)?
+        };
+        for file in &mut files {
+            if let Some(i) = file.root.take() {
+                let root = roots.get(i as usize).with_context(|| format!("no scan root {i}."))?;
+                file.path = D2fnPath::from(anchor(root, &PathBuf::from(&file.path))?.as_path());
+            }
+        }
+
+        let mut trailer = &record[used..];
+        let flags = trailer.read_u8().unwrap_or(0);
+        let hash = if flags & GROUP_FLAG_HASH != 0 {
+            let mut blake3 = [0u8; 32];
+            trailer.read_exact(&mut blake3).context("truncated group hash.")?;
+            let prefix = if flags & GROUP_FLAG_PREFIX != 0 {
+                Some(trailer.read_u64::<LittleEndian>().context("truncated group hash.")?)
+            } else {
+                None
+            };
+            Some(GroupHash { blake3, prefix })
+        } else {
+            None
+        };
+        Ok(DuplicateGroup {
+            files,
+            similar: flags & GROUP_FLAG_SIMILAR != 0,
+            hash,
+            whole: flags & GROUP_FLAG_WHOLE != 0 || hash.is_some_and(|h| h.prefix.is_none()),
+        })
     }
 }
 
@@ -104,65 +743,438 @@ impl Iterator for InventoryReader {
     type Item = Result<DuplicateGroup>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.read_count < self.header.count {
-            let result = Self::decode(&mut self.reader, &mut self.buffer);
-
-            self.read_count += 1;
-            Some(result)
-        } else {
-            None
+        if self.finished || (self.header.version < FOOTER_VERSION && self.read_count >= self.header.count) {
+            return None;
+        }
+        let index = self.read_count;
+        match self.read_record() {
+            Ok(Some((size, intact))) => {
+                self.read_count += 1;
+                if !intact {
+                    self.corrupt_count += 1;
+                    return Some(Err(InventoryError::CorruptRecord { index }.into()));
+                }
+                let group = Self::decode(&self.buffer[..size], self.header.version, &self.header.roots);
+                Some(group.context(InventoryError::CorruptRecord { index }))
+            }
+            Ok(None) => {
+                self.finished = true;
+                self.check_footer().err().map(Err)
+            }
+            // 记录的边界已无法确定, 不再继续.
+            Err(e) => {
+                self.finished = true;
+                Some(Err(anyhow!(e).context(InventoryError::TruncatedFile { groups: index })))
+            }
         }
     }
 }
 
-impl InventoryWriter {
+impl InventoryWriter<File> {
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
         let file = File::create(path)?;
-        let buffer = vec![0u8; 1024 * 1024];
-        let mut writer = BufWriter::new(file);
+        Ok(Self::new(file))
+    }
+
+    /// Open an inventory to add groups to, with [`InventoryWriter::export`]. The whole file is read and checked first;
+    /// only uncompressed inventories of the current version can be appended to.
+    pub fn append<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut reader = InventoryReader::open(path)?;
+        if reader.header.version != CURRENT_VERSION {
+            bail!(
+                "only inventories of version {CURRENT_VERSION} can be appended to, {} is of version {}.",
+                path.display(),
+                reader.header.version
+            );
+        }
+        if reader.header.flags & HEADER_FLAG_ZSTD != 0 {
+            bail!("{} is compressed, and cannot be appended to.", path.display());
+        }
+        for group in &mut reader {
+            group.with_context(|| format!("{} is damaged.", path.display()))?;
+        }
+
+        // 去掉索引和 footer, 新的记录接在其后.
+        let records_end = reader.records_end.context("the inventory has no end marker.")?;
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(records_end)?;
+        let mut writer = Self::new(file);
+        writer.writer.get_mut().seek(SeekFrom::End(0))?;
+        writer.key_id = reader.header.key_id;
+        writer.header_key_id = Some(reader.header.key_id);
+        writer.header_roots = reader.header.roots;
+        writer.count = reader.read_count;
+        writer.payload = reader.payload;
+        writer.offsets = reader.index;
+        writer.position = records_end;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> InventoryWriter<W> {
+    /// Write to `writer`, which needs not be seekable. Nothing is written until [`InventoryWriter::export`].
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+            buffer: vec![0u8; 1024 * 1024],
+            key_id: 0,
+            header_key_id: None,
+            roots: Vec::new(),
+            header_roots: Vec::new(),
+            count: 0,
+            payload: blake3::Hasher::new(),
+            offsets: Vec::new(),
+            position: 0,
+            compression: None,
+            finished: false,
+        }
+    }
+
+    /// Record the key files were hashed with, see [`HashKey`].
+    pub fn hash_key(mut self, key: Option<&HashKey>) -> Self {
+        self.key_id = key.map_or(0, HashKey::id);
+        self
+    }
+
+    /// Record absolute paths of directories scanned. Files made relative to them with [`DuplicateFile::relative_to`]
+    /// are anchored to the roots again when read, or to other directories given to [`InventoryReader::anchor_to`].
+    pub fn scan_roots(mut self, roots: Vec<PathBuf>) -> Self {
+        self.roots = roots;
+        self
+    }
+
+    /// Compress records with zstd at `level`. Compressed inventories cannot be appended to, and have to be read in
+    /// order to seek.
+    pub fn with_compression(mut self, level: i32) -> Self {
+        self.compression = Some(level);
+        self
+    }
 
-        Self::write_header(&mut writer, &Header::default())?;
-        Ok(Self { writer, buffer })
+    /// Record the key id of another inventory, 0 if not keyed.
+    pub fn key_id(mut self, key_id: u64) -> Self {
+        self.key_id = key_id;
+        self
     }
 
-    fn write_header<W: Write>(writer: &mut W, header: &Header) -> Result<()> {
+    /// Encode `header`, with the offset of records right after it.
+    fn encode_header(header: &Header) -> Result<Vec<u8>> {
+        let mut writer = Vec::new();
         writer.write_u8(header.version)?;
-        writer.write_u8(header.offset)?;
+        // 先占位, 写完头部后填入
+        writer.write_u32::<LittleEndian>(0)?;
         writer.write_u32::<LittleEndian>(header.count)?;
-        Ok(())
+        writer.write_u64::<LittleEndian>(header.key_id)?;
+        writer.write_u8(header.flags)?;
+        writer.write_u16::<LittleEndian>(header.roots.len().try_into().context("too many scan roots.")?)?;
+        for root in &header.roots {
+            let root = root.as_os_str().as_bytes();
+            writer.write_u16::<LittleEndian>(root.len().try_into().context("scan root too long.")?)?;
+            writer.write_all(root)?;
+        }
+
+        let offset = u32::try_from(writer.len())?;
+        writer[1..5].copy_from_slice(&offset.to_le_bytes());
+        Ok(writer)
     }
 
-    fn encode<D: Encode, W: Write>(val: D, writer: &mut W, buf: &mut [u8]) -> Result<()> {
-        let size = bincode::encode_into_slice(val, buf, bincode::config::standard())?;
+    /// Write `group` as a record, and return its bytes including the length.
+    fn encode<'b>(group: DuplicateGroup, writer: &mut impl Write, buf: &'b mut [u8]) -> Result<&'b [u8]> {
+        let mut size = bincode::encode_into_slice(group.files, buf, bincode::config::standard())?;
+        let mut trailer = Vec::new();
+        if let Some(hash) = group.hash {
+            trailer.extend_from_slice(&hash.blake3);
+            if let Some(prefix) = hash.prefix {
+                trailer.write_u64::<LittleEndian>(prefix)?;
+            }
+        }
+        let flags = (group.similar as u8 * GROUP_FLAG_SIMILAR)
+            | (group.hash.is_some() as u8 * GROUP_FLAG_HASH)
+            | (group.hash.is_some_and(|h| h.prefix.is_some()) as u8 * GROUP_FLAG_PREFIX)
+            | (group.whole as u8 * GROUP_FLAG_WHOLE);
+        if flags != 0 {
+            let end = size + 1 + trailer.len();
+            let slot = buf.get_mut(size..end).context("group too large.")?;
+            slot[0] = flags;
+            slot[1..].copy_from_slice(&trailer);
+            size = end;
+        }
 
         writer.write_u32::<LittleEndian>(size as u32)?;
         writer.write_all(&buf[..size])?;
-        Ok(())
+        writer.write_u32::<LittleEndian>(crc32fast::hash(&buf[..size]))?;
+        Ok(&buf[..size])
     }
 
+    /// Write the header unless appending, `groups`, and the footer. Can be called only once.
     pub fn export<T: Iterator<Item = DuplicateGroup>>(&mut self, groups: T) -> Result<()> {
-        let mut count = 0u32;
-        for group in groups {
-            count += 1;
-            Self::encode(group, &mut self.writer, &mut self.buffer)?;
+        self.export_results(groups.map(Ok), true)?;
+        Ok(())
+    }
+
+    /// Export groups which may have failed to be produced. Failed groups are skipped and returned in the summary, or
+    /// with `abort_on_error`, the first one stops the export and is returned as the error. The inventory is finished
+    /// with groups written before either way.
+    pub fn export_results<T: Iterator<Item = Result<DuplicateGroup>>>(
+        &mut self,
+        groups: T,
+        abort_on_error: bool,
+    ) -> Result<ExportSummary> {
+        if self.finished {
+            bail!("the inventory is already finished.");
+        }
+        match self.header_key_id {
+            None => {
+                let header = Header {
+                    version: CURRENT_VERSION,
+                    offset: 0,
+                    count: 0,
+                    key_id: self.key_id,
+                    flags: self.compression.map_or(0, |_| HEADER_FLAG_ZSTD),
+                    roots: self.roots.clone(),
+                };
+                let bytes = Self::encode_header(&header)?;
+                self.writer.write_all(&bytes)?;
+                self.header_key_id = Some(self.key_id);
+                self.position = bytes.len() as u64;
+            }
+            Some(key_id) if key_id != self.key_id => bail!("the inventory appended to was hashed with another key."),
+            Some(_) if self.roots != self.header_roots => bail!("the inventory appended to was scanned from other roots."),
+            Some(_) => {}
         }
 
-        let new_header = Header {
-            version: CURRENT_VERSION,
-            offset: (2 + size_of::<usize>()) as u8,
-            count,
+        // 偏移量与哈希均按未压缩的内容计算.
+        let mut sink = match self.compression {
+            Some(level) => RecordSink::Zstd(zstd::Encoder::new(&mut self.writer, level)?),
+            None => RecordSink::Plain(&mut self.writer),
         };
-        self.writer.seek(SeekFrom::Start(0))?;
-        Self::write_header(&mut self.writer, &new_header)?;
-        Ok(())
+        let mut summary = ExportSummary::default();
+        let mut aborted = None;
+        for (i, group) in groups.enumerate() {
+            let group = match group {
+                Ok(group) => group,
+                Err(e) if abort_on_error => {
+                    aborted = Some(e);
+                    break;
+                }
+                Err(e) => {
+                    summary.failed.push((i, e));
+                    continue;
+                }
+            };
+            summary.written += 1;
+            self.count += 1;
+            self.offsets.push(self.position);
+            let record = Self::encode(group, &mut sink, &mut self.buffer)?;
+            self.position += 4 + record.len() as u64 + 4;
+            self.payload.update(&(record.len() as u32).to_le_bytes());
+            self.payload.update(record);
+        }
+
+        sink.write_u32::<LittleEndian>(INDEX_MARKER)?;
+        sink.write_u32::<LittleEndian>(self.count)?;
+        for &offset in &self.offsets {
+            sink.write_u64::<LittleEndian>(offset)?;
+        }
+        sink.write_u32::<LittleEndian>(END_MARKER)?;
+        sink.write_u32::<LittleEndian>(self.count)?;
+        sink.write_u64::<LittleEndian>(self.position)?;
+        sink.write_all(self.payload.finalize().as_bytes())?;
+        sink.finish()?;
+        self.finished = true;
+        match aborted {
+            Some(e) => Err(e.context(format!("export aborted, {} groups written before.", summary.written))),
+            None => Ok(summary),
+        }
+    }
+}
+
+/// Outcome of [`InventoryWriter::export_results`].
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    /// Count of groups written
+    pub written: u32,
+    /// Position among the groups given, and error of each group skipped
+    pub failed: Vec<(usize, anyhow::Error)>,
+}
+
+/// Identity of a file across inventories: device and inode if recorded, or the path.
+#[derive(PartialEq, Eq, Hash)]
+pub enum FileKey {
+    Inode(u64, u64),
+    Path(D2fnPath),
+}
+
+impl FileKey {
+    pub fn of(file: &DuplicateFile) -> Self {
+        match file.dev {
+            Some(dev) => FileKey::Inode(dev, file.ino),
+            None => FileKey::Path(file.path.clone()),
+        }
+    }
+}
+
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
     }
+    i
+}
+
+/// Merge groups of `inputs` into a new inventory at `output`, and return the count of groups written. Groups sharing a
+/// file are merged into one if they have the same hash, so groups listing the same files are written once. A file in
+/// groups of different hashes was rewritten between the scans, it is kept in the group of the later input only. Groups
+/// of similar files are only merged with each other. Inputs of different versions or keys are rejected.
+pub fn merge<P: AsRef<Path>>(inputs: &[P], output: &Path) -> Result<usize> {
+    let mut groups = Vec::new();
+    let mut first_header: Option<(u8, u64)> = None;
+    for input in inputs {
+        let input = input.as_ref();
+        let reader = InventoryReader::open(input)
+            .with_context(|| format!("unable to open {}.", input.display()))?
+            .read_only_sequential();
+        let header = (reader.header.version, reader.header.key_id);
+        match first_header {
+            None => first_header = Some(header),
+            Some((version, _)) if version != header.0 => {
+                bail!(
+                    "{} is of version {}, other inputs are of version {version}.",
+                    input.display(),
+                    header.0
+                )
+            }
+            Some((_, key_id)) if key_id != header.1 => {
+                bail!("{} is hashed with another key than other inputs.", input.display())
+            }
+            Some(_) => {}
+        }
+        for group in reader {
+            groups.push(group.with_context(|| format!("{} is damaged.", input.display()))?);
+        }
+    }
+
+    // 哈希相同且共享文件的组合并为一组. 从后往前处理, 文件在哈希不同的组中时, 两次扫描之间已被改写,
+    // 只保留在较新的组中.
+    let hashes = groups.iter().map(|group| group.hash).collect::<Vec<_>>();
+    let mut parents = (0..groups.len()).collect::<Vec<_>>();
+    let mut owners: HashMap<(bool, FileKey), usize> = HashMap::new();
+    for (i, group) in groups.iter_mut().enumerate().rev() {
+        let mut joined = Vec::new();
+        group
+            .files
+            .retain(|file| match owners.entry((group.similar, FileKey::of(file))) {
+                Entry::Vacant(entry) => {
+                    entry.insert(i);
+                    true
+                }
+                Entry::Occupied(entry) if hashes[*entry.get()] == hashes[i] => {
+                    joined.push(*entry.get());
+                    true
+                }
+                Entry::Occupied(_) => false,
+            });
+        for owner in joined {
+            let (a, b) = (find_root(&mut parents, owner), find_root(&mut parents, i));
+            parents[b.max(a)] = a.min(b);
+        }
+    }
+
+    let mut merged: Vec<Option<DuplicateGroup>> = Vec::new();
+    merged.resize_with(groups.len(), || None);
+    let mut seen = std::collections::HashSet::new();
+    for (i, group) in groups.into_iter().enumerate() {
+        let root = find_root(&mut parents, i);
+        let files = group
+            .files
+            .into_iter()
+            .filter(|f| seen.insert((root, FileKey::of(f), f.path.clone())))
+            .collect::<Vec<_>>();
+        match &mut merged[root] {
+            Some(target) => {
+                target.files.extend(files);
+                target.whole &= group.whole;
+            }
+            None => {
+                merged[root] = Some(DuplicateGroup {
+                    files,
+                    similar: group.similar,
+                    hash: group.hash,
+                    whole: group.whole,
+                })
+            }
+        }
+    }
+
+    // 移走改写的文件后, 只剩一个文件的组不再是重复
+    let merged = merged
+        .into_iter()
+        .flatten()
+        .filter(|group| group.files.len() > 1)
+        .collect::<Vec<_>>();
+    let count = merged.len();
+    let key_id = first_header.map_or(0, |(_, key_id)| key_id);
+    let mut writer = InventoryWriter::create(output)?;
+    writer.key_id = key_id;
+    writer.export(merged.into_iter())?;
+    Ok(count)
+}
+
+/// Groups kept and lost by [`repair`].
+pub struct RepairStats {
+    pub kept: usize,
+    pub lost: usize,
+    /// The inventory ends before its footer, groups after the end are lost but not counted.
+    pub truncated: bool,
+}
+
+/// Copy intact groups of the damaged inventory `input` to a new inventory at `output`.
+pub fn repair(input: &Path, output: &Path) -> Result<RepairStats> {
+    let reader = InventoryReader::open(input)?.read_only_sequential();
+    let key_id = reader.header.key_id;
+    let mut stats = RepairStats {
+        kept: 0,
+        lost: 0,
+        truncated: false,
+    };
+
+    let mut writer = InventoryWriter::create(output)?;
+    writer.key_id = key_id;
+    let groups = reader.filter_map(|group| {
+        let e = match group {
+            Ok(group) => {
+                stats.kept += 1;
+                return Some(group);
+            }
+            Err(e) => e,
+        };
+        match e.downcast_ref::<InventoryError>() {
+            Some(InventoryError::CorruptRecord { .. }) => stats.lost += 1,
+            Some(InventoryError::TruncatedFile { .. }) => stats.truncated = true,
+            // footer 与记录不符, 记录本身均已读出.
+            None => tracing::warn!("{e:#}"),
+        }
+        None
+    });
+    writer.export(groups)?;
+    Ok(stats)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::inventory::{D2fnPath, DuplicateFile, DuplicateGroup, InventoryReader, InventoryWriter};
+    use crate::hash::HashKey;
+    use crate::inventory::{
+        D2fnPath, DuplicateFile, DuplicateGroup, GroupHash, InventoryError, InventoryReader, InventoryWriter,
+        CURRENT_VERSION,
+    };
+    use byteorder::{LittleEndian, WriteBytesExt};
     use std::path::{Path, PathBuf};
 
+    /// A path in the temporary directory, unique to this process.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("d2fn-inventory-{}-{name}", std::process::id()))
+    }
+
     fn generate_test_data() -> Vec<DuplicateGroup> {
         let file1 = "file1.txt".as_bytes().to_vec();
         let file2 = "file2.txt".as_bytes().to_vec();
@@ -176,50 +1188,498 @@ mod test {
                     DuplicateFile {
                         ino: 1,
                         path: D2fnPath { path: file1 },
+                        dev: Some(1),
+                        size: Some(10),
+                        mtime: Some(1_690_000_000),
+                        mtime_nsec: Some(0),
+                        root: None,
                     },
                     DuplicateFile {
                         ino: 2,
                         path: D2fnPath { path: file2 },
+                        dev: Some(1),
+                        size: Some(10),
+                        mtime: Some(1_690_000_000),
+                        mtime_nsec: Some(0),
+                        root: None,
                     },
                     DuplicateFile {
                         ino: 3,
                         path: D2fnPath { path: file3 },
+                        dev: Some(1),
+                        size: Some(10),
+                        mtime: Some(1_690_000_000),
+                        mtime_nsec: Some(0),
+                        root: None,
                     },
                 ],
+                similar: false,
+                hash: Some(GroupHash {
+                    blake3: [7; 32],
+                    prefix: Some(4096),
+                }),
+                whole: true,
             },
             DuplicateGroup {
                 files: vec![
                     DuplicateFile {
                         ino: 4,
                         path: D2fnPath { path: file4 },
+                        dev: None,
+                        size: None,
+                        mtime: None,
+                        mtime_nsec: None,
+                        root: None,
                     },
                     DuplicateFile {
                         ino: 5,
                         path: D2fnPath { path: file5 },
+                        dev: None,
+                        size: None,
+                        mtime: None,
+                        mtime_nsec: None,
+                        root: None,
                     },
                 ],
+                similar: true,
+                hash: None,
+                whole: false,
             },
         ]
     }
 
     #[test]
     fn test_d2fn_path() {
-        let path = Path::new("./test-file");
-        let dataset = generate_test_data();
-
+        let path = &temp_path("path");
         let mut writer = InventoryWriter::create(path).unwrap();
-        writer.export(dataset.into_iter()).unwrap();
+        writer.export(generate_test_data().into_iter()).unwrap();
+        drop(writer);
+
+        let groups = InventoryReader::open(path)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(groups.len(), 2);
+        for (i, (group, expected)) in groups.into_iter().zip(generate_test_data()).enumerate() {
+            assert_eq!(group.similar, i == 1);
+            assert_eq!(group.hash.is_some(), i == 0);
+            assert_eq!(group.whole, i == 0);
+            assert_eq!(group.files[0].size, (i == 0).then_some(10));
+            let files = |group: DuplicateGroup| {
+                group
+                    .files
+                    .into_iter()
+                    .map(|file| (file.ino, PathBuf::from(&file.path)))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(files(group), files(expected));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_key_id() {
+        let path = &temp_path("keyed");
+        let key = HashKey::from([1u8; 32]);
+        let mut writer = InventoryWriter::create(path).unwrap().hash_key(Some(&key));
+        writer.export(generate_test_data().into_iter()).unwrap();
         drop(writer);
 
         let reader = InventoryReader::open(path).unwrap();
-        println!("len(groups) = {}", reader.header.count);
-        for group in reader {
-            let group = group.unwrap();
-            for item in group.files {
-                let path = Into::<PathBuf>::into(item.path);
-                println!("({}): {}", item.ino, path.display());
+        assert_eq!(reader.key_id(), Some(key.id()));
+        assert!(reader.check_key(Some(&key)).is_ok());
+        assert!(reader.check_key(None).is_err());
+        assert!(reader.check_key(Some(&HashKey::from([2u8; 32]))).is_err());
+        assert_eq!(reader.count(), 2);
+
+        // 版本 1 的清单: 头部没有 key_id.
+        std::fs::write(path, [0x01, 10, 0, 0, 0, 0]).unwrap();
+        let reader = InventoryReader::open(path).unwrap();
+        assert_eq!(reader.key_id(), None);
+        assert!(reader.check_key(None).is_ok());
+        assert!(reader.check_key(Some(&key)).is_err());
+        assert_eq!(reader.count(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_layout() {
+        let path = &temp_path("v1");
+        // 版本 1 的写入方式: 6 字节头部, 之后是带长度前缀的记录.
+        let mut v1 = vec![0x01, 10, 2, 0, 0, 0];
+        for group in generate_test_data() {
+            // 版本 3 之前的文件只有 ino 和路径.
+            let files = group.files.into_iter().map(|f| (f.ino, f.path)).collect::<Vec<_>>();
+            let mut record = bincode::encode_to_vec(files, bincode::config::standard()).unwrap();
+            if group.similar {
+                record.push(0x01);
             }
+            v1.write_u32::<LittleEndian>(record.len() as u32).unwrap();
+            v1.extend_from_slice(&record);
+        }
+        std::fs::write(path, &v1).unwrap();
+
+        let reader = InventoryReader::open(path).unwrap();
+        assert_eq!(reader.total(), Some(2));
+        let groups = reader.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(groups[0].files.len(), 3);
+        assert!(groups[1].similar);
+        assert_eq!(PathBuf::from(&groups[0].files[2].path), Path::new("中文字符.txt"));
+        assert!(groups[0].files[0].size.is_none() && groups[0].hash.is_none());
+
+        // 写入中断时留下的占位头部, 以及未来的版本.
+        v1[0] = 0;
+        std::fs::write(path, &v1).unwrap();
+        assert!(InventoryReader::open(path).is_err());
+        v1[0] = CURRENT_VERSION + 1;
+        std::fs::write(path, &v1).unwrap();
+        assert!(InventoryReader::open(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_stream() {
+        let path = &temp_path("stream");
+        // 写入不可 seek 的输出
+        let mut stream = Vec::new();
+        InventoryWriter::new(&mut stream)
+            .export(generate_test_data().into_iter())
+            .unwrap();
+        std::fs::write(path, &stream).unwrap();
+
+        let reader = InventoryReader::open(path).unwrap();
+        assert_eq!(reader.total(), Some(2));
+        assert_eq!(reader.filter(Result::is_ok).count(), 2);
+
+        // 写入中断: 没有 footer, 已写入的组仍可读出. 截去 footer 48 字节, 两组的索引 24 字节, 以及最后一组的 CRC 和一个字节.
+        std::fs::write(path, &stream[..stream.len() - 48 - 24 - 5]).unwrap();
+        let reader = InventoryReader::open(path).unwrap();
+        assert_eq!(reader.total(), None);
+        let groups = reader.collect::<Vec<_>>();
+        assert_eq!(groups.len(), 2);
+        assert!(groups[0].is_ok());
+        let e = groups[1].as_ref().err().unwrap().downcast_ref::<InventoryError>();
+        assert_eq!(e, Some(&InventoryError::TruncatedFile { groups: 1 }));
+
+        // footer 与记录不符
+        let last = stream.len() - 1;
+        stream[last] ^= 0xff;
+        std::fs::write(path, &stream).unwrap();
+        let reader = InventoryReader::open(path).unwrap();
+        assert!(reader.last().unwrap().is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let (path, repaired) = (&temp_path("corrupt"), &temp_path("repaired"));
+        InventoryWriter::create(path)
+            .unwrap()
+            .export(generate_test_data().into_iter())
+            .unwrap();
+        // 没有扫描目录时头部 20 字节, 随后是第一条记录的长度和内容.
+        let mut data = std::fs::read(path).unwrap();
+        data[20 + 4 + 3] ^= 0x20;
+        std::fs::write(path, data).unwrap();
+
+        let groups = InventoryReader::open(path).unwrap().collect::<Vec<_>>();
+        assert_eq!(groups.len(), 2);
+        let e = groups[0].as_ref().err().unwrap().downcast_ref::<InventoryError>();
+        assert_eq!(e, Some(&InventoryError::CorruptRecord { index: 0 }));
+        assert!(groups[1].as_ref().is_ok_and(|group| group.similar));
+
+        let stats = super::repair(path, repaired).unwrap();
+        assert_eq!((stats.kept, stats.lost, stats.truncated), (1, 1, false));
+        let reader = InventoryReader::open(repaired).unwrap();
+        assert_eq!(reader.total(), Some(1));
+        assert_eq!(reader.filter(Result::is_ok).count(), 1);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(repaired).unwrap();
+    }
+
+    #[test]
+    fn test_seek_to_group() {
+        let path = &temp_path("seek");
+        let groups = (0..5).flat_map(|_| generate_test_data());
+        InventoryWriter::create(path).unwrap().export(groups).unwrap();
+
+        let mut reader = InventoryReader::open(path).unwrap();
+        assert!(reader.get_group(7).unwrap().similar);
+        assert!(!reader.get_group(2).unwrap().similar);
+        // 定位后继续顺序读取
+        assert_eq!(reader.filter(Result::is_ok).count(), 7);
+
+        // 没有索引时边读边记录
+        let mut reader = InventoryReader::open(path).unwrap();
+        reader.footer_index = None;
+        assert!(reader.get_group(5).unwrap().similar);
+        assert_eq!(reader.index.len(), 6);
+        assert!(!reader.get_group(4).unwrap().similar);
+        assert!(reader.get_group(9).unwrap().similar);
+        assert!(reader.get_group(10).is_err());
+
+        let mut reader = InventoryReader::open(path).unwrap().read_only_sequential();
+        assert!(reader.seek_to_group(0).is_err());
+        assert_eq!(reader.count(), 10);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        let (path, plain) = (&temp_path("zstd"), &temp_path("zstd-plain"));
+        let groups = || (0..100).flat_map(|_| generate_test_data());
+        InventoryWriter::create(path)
+            .unwrap()
+            .with_compression(3)
+            .export(groups())
+            .unwrap();
+        InventoryWriter::create(plain).unwrap().export(groups()).unwrap();
+        let size = |path| std::fs::metadata(path).unwrap().len();
+        assert!(size(path) * 10 < size(plain));
+
+        let reader = InventoryReader::open(path).unwrap();
+        assert_eq!(reader.total(), None);
+        assert_eq!(reader.filter(Result::is_ok).count(), 200);
+
+        // 压缩流中向前、向后定位
+        let mut reader = InventoryReader::open(path).unwrap();
+        assert!(reader.get_group(151).unwrap().similar);
+        assert!(!reader.get_group(20).unwrap().similar);
+        assert!(reader.get_group(200).is_err());
+
+        assert!(InventoryWriter::append(path).is_err());
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(plain).unwrap();
+    }
+
+    #[test]
+    fn test_export_results() {
+        let path = &temp_path("export-results");
+        let groups = || {
+            let mut groups = generate_test_data().into_iter().map(Ok).collect::<Vec<_>>();
+            groups.insert(1, Err(anyhow::anyhow!("unreadable")));
+            groups.into_iter()
+        };
+        let summary = InventoryWriter::create(path)
+            .unwrap()
+            .export_results(groups(), false)
+            .unwrap();
+        assert_eq!(summary.written, 2);
+        assert_eq!(summary.failed.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1]);
+        let reader = InventoryReader::open(path).unwrap();
+        assert_eq!(reader.total(), Some(2));
+        assert_eq!(reader.filter(Result::is_ok).count(), 2);
+
+        // 出错即停止, 已写入的组仍可读取
+        let e = InventoryWriter::create(path).unwrap().export_results(groups(), true);
+        assert!(e.is_err());
+        let reader = InventoryReader::open(path).unwrap();
+        assert_eq!(reader.total(), Some(1));
+        assert_eq!(reader.filter(Result::is_ok).count(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let path = &temp_path("header");
+        InventoryWriter::create(path)
+            .unwrap()
+            .key_id(0x0102030405060708)
+            .scan_roots(vec![PathBuf::from("/r")])
+            .export(generate_test_data().into_iter().take(1))
+            .unwrap();
+        let mut data = std::fs::read(path).unwrap();
+        let header = [
+            &[CURRENT_VERSION][..],
+            &24u32.to_le_bytes(),
+            // 流式写入时组数记录在尾部
+            &0u32.to_le_bytes(),
+            &0x0102030405060708u64.to_le_bytes(),
+            &[0],
+            &1u16.to_le_bytes(),
+            &2u16.to_le_bytes(),
+            b"/r",
+        ]
+        .concat();
+        assert_eq!(data[..24], header);
+
+        // 较新版本在头部末尾追加的字段应被跳过
+        data.splice(24..24, [0xaa; 6]);
+        data[1..5].copy_from_slice(&30u32.to_le_bytes());
+        std::fs::write(path, data).unwrap();
+        let reader = InventoryReader::open(path).unwrap().read_only_sequential();
+        assert_eq!(reader.key_id(), Some(0x0102030405060708));
+        let groups = reader.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(groups.len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_scan_roots() {
+        let path = &temp_path("roots");
+        let roots = [Path::new("/old/pool"), Path::new("/old/backup")];
+        let files = ["/old/pool/a/b.txt", "/old/backup/b.txt", "/elsewhere/c.txt"]
+            .map(|p| DuplicateFile::new(1, Path::new(p)).relative_to(&roots));
+        assert_eq!(files.iter().map(|f| f.root).collect::<Vec<_>>(), [Some(0), Some(1), None]);
+        let mut escaping = DuplicateFile::new(2, Path::new("../../etc/passwd"));
+        escaping.root = Some(0);
+        let groups = vec![
+            DuplicateGroup {
+                files: files.to_vec(),
+                similar: false,
+                hash: None,
+                whole: false,
+            },
+            DuplicateGroup {
+                files: vec![escaping],
+                similar: false,
+                hash: None,
+                whole: false,
+            },
+        ];
+        InventoryWriter::create(path)
+            .unwrap()
+            .scan_roots(roots.map(PathBuf::from).to_vec())
+            .export(groups.into_iter())
+            .unwrap();
+
+        let paths = |reader: InventoryReader| {
+            let groups = reader.collect::<Vec<_>>();
+            assert!(groups[1].is_err());
+            let group = groups.into_iter().next().unwrap().unwrap();
+            group.files.iter().map(|f| PathBuf::from(&f.path)).collect::<Vec<_>>()
+        };
+        let reader = InventoryReader::open(path).unwrap();
+        assert_eq!(
+            paths(reader),
+            ["/old/pool/a/b.txt", "/old/backup/b.txt", "/elsewhere/c.txt"].map(PathBuf::from)
+        );
+        // 挂载位置改变
+        let moved = ["/mnt/pool", "/mnt/backup"].map(PathBuf::from);
+        let reader = InventoryReader::open(path).unwrap().anchor_to(&moved).unwrap();
+        assert_eq!(
+            paths(reader),
+            ["/mnt/pool/a/b.txt", "/mnt/backup/b.txt", "/elsewhere/c.txt"].map(PathBuf::from)
+        );
+        assert!(InventoryReader::open(path).unwrap().anchor_to(&moved[..1]).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_append() {
+        let path = &temp_path("append");
+        InventoryWriter::create(path)
+            .unwrap()
+            .export(generate_test_data().into_iter())
+            .unwrap();
+        InventoryWriter::append(path)
+            .unwrap()
+            .export(generate_test_data().into_iter().take(1))
+            .unwrap();
+
+        let mut reader = InventoryReader::open(path).unwrap();
+        assert_eq!(reader.total(), Some(3));
+        assert!(!reader.get_group(2).unwrap().similar);
+        let reader = InventoryReader::open(path).unwrap();
+        assert_eq!(reader.filter(Result::is_ok).count(), 3);
+
+        // 使用其他密钥追加
+        let key = HashKey::from([1u8; 32]);
+        let mut writer = InventoryWriter::append(path).unwrap().hash_key(Some(&key));
+        assert!(writer.export(generate_test_data().into_iter()).is_err());
+
+        // 旧版本的清单不能追加
+        let mut legacy = std::fs::read(path).unwrap();
+        legacy[0] = 2;
+        std::fs::write(path, legacy).unwrap();
+        assert!(InventoryWriter::append(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let (first, second, output) = (&temp_path("merge-1"), &temp_path("merge-2"), &temp_path("merge-out"));
+        InventoryWriter::create(first)
+            .unwrap()
+            .export(generate_test_data().into_iter())
+            .unwrap();
+        // 第二次扫描: 同一组, 以及与第一组共用 inode 3 的新组.
+        let mut groups = generate_test_data();
+        let mut files = groups[0].files[1..].to_vec();
+        files[0].ino = 6;
+        files[0].path = D2fnPath::from(Path::new("file6.txt"));
+        groups.push(DuplicateGroup {
+            files,
+            similar: false,
+            hash: groups[0].hash,
+            whole: groups[0].whole,
+        });
+        InventoryWriter::create(second).unwrap().export(groups.into_iter()).unwrap();
+
+        assert_eq!(super::merge(&[first, second], output).unwrap(), 2);
+        let merged = InventoryReader::open(output)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(merged[0].files.len(), 4);
+        assert_eq!(merged[0].hash, generate_test_data()[0].hash);
+        assert_eq!(merged[1].files.len(), 2);
+
+        // 密钥不同的清单不能合并
+        let key = HashKey::from([1u8; 32]);
+        InventoryWriter::create(second)
+            .unwrap()
+            .hash_key(Some(&key))
+            .export(generate_test_data().into_iter())
+            .unwrap();
+        assert!(super::merge(&[first, second], output).is_err());
+        for path in [first, second, output] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_merge_rewritten() {
+        let (first, second, output) = (
+            &temp_path("merge-rewritten-1"),
+            &temp_path("merge-rewritten-2"),
+            &temp_path("merge-rewritten-out"),
+        );
+        let file = |ino, path: &str| DuplicateFile {
+            ino,
+            path: D2fnPath::from(Path::new(path)),
+            dev: Some(1),
+            size: Some(10),
+            mtime: Some(1_690_000_000),
+            mtime_nsec: Some(0),
+            root: None,
+        };
+        let group = |files, blake3| DuplicateGroup {
+            files,
+            similar: false,
+            hash: Some(GroupHash { blake3, prefix: None }),
+            whole: true,
+        };
+        // 两次扫描之间 /a 被改写, 与 /c 相同而不再与 /b 相同
+        InventoryWriter::create(first)
+            .unwrap()
+            .export([group(vec![file(2, "/b"), file(1, "/a")], [1; 32])].into_iter())
+            .unwrap();
+        InventoryWriter::create(second)
+            .unwrap()
+            .export([group(vec![file(1, "/a"), file(3, "/c")], [2; 32])].into_iter())
+            .unwrap();
+
+        assert_eq!(super::merge(&[first, second], output).unwrap(), 1);
+        let merged = InventoryReader::open(output)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        let paths = merged[0].files.iter().map(|f| PathBuf::from(&f.path)).collect::<Vec<_>>();
+        assert_eq!(paths, [Path::new("/a"), Path::new("/c")]);
+        assert_eq!(merged[0].hash.unwrap().blake3, [2; 32]);
+        for path in [first, second, output] {
+            std::fs::remove_file(path).unwrap();
         }
-        std::fs::remove_file("./test-file").unwrap();
     }
 }
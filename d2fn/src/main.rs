@@ -1,31 +1,110 @@
+#[cfg(feature = "audio")]
+mod audio;
+mod check;
+mod convert;
+mod diff;
+mod directory;
 mod duplicate;
 mod hash;
+mod ignore_file;
 mod inventory;
 mod metadata;
+mod parallel_walk;
+mod plan;
+mod refresh;
+mod report;
+mod review;
+#[cfg(feature = "similar-images")]
+mod similar;
+mod status_file;
+mod upgrade;
 
-use anyhow::{Context, Result};
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use anyhow::{bail, Context, Result};
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use common::completion::{self, CompletionsArg};
+use common::config::Config;
+use common::exit::{self, ErrorKind, EXIT_NOTHING_TO_DO};
+use common::logging;
+#[cfg(feature = "metrics")]
+use common::metrics::{Metrics, MetricsServer};
+use common::since::{self, Since, TimeField};
+use std::collections::HashSet;
+use std::ffi::OsString;
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::process::ExitCode;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use unicode_width::UnicodeWidthChar;
 
-use crate::duplicate::{ScanFilter, StatusReport};
-use crate::hash::CompareMode;
-use crate::inventory::{D2fnPath, DuplicateFile, DuplicateGroup, InventoryReader, InventoryWriter};
-use duplicate::{DefaultFilter, Duplicate};
+use crate::check::{CheckEvent, FileStatus};
+use crate::directory::DirectoryReport;
+use crate::duplicate::{ScanExtent, ScanFilter, StatusReport, UniqueCheck};
+use crate::hash::{CachePolicy, CompareMode, CompareSize, HashAlgorithm, HashKey};
+use crate::inventory::{InventoryReader, InventoryWriter};
+use crate::metadata::WasteMetric;
+use crate::plan::{Plan, Resolution};
+use crate::report::ReportFormat;
+use crate::review::Review;
+use common::throttle::Throttle;
+use duplicate::{group_waste, DefaultFilter, Duplicate};
 
 const DEFAULT_COMPARE_SIZE: &str = "1M";
+const DEFAULT_COMPARE_MIN: &str = "64K";
+const DEFAULT_COMPARE_MAX: &str = "256M";
 const DEFAULT_OUTPUT_FORMAT: OutputFormat = OutputFormat::Script;
+const DEFAULT_INVENTORY: &str = "inventory.d2fn";
+/// Days after which an inventory has to be refreshed before it is applied
+const DEFAULT_MAX_AGE: u64 = 7;
+#[cfg(feature = "thumbnails")]
+const DEFAULT_THUMBNAIL_LIMIT: &str = "20M";
+
+/// How a command ends, if no error occurred.
+enum Outcome {
+    Done,
+    NoDuplicates,
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(value: Outcome) -> Self {
+        match value {
+            Outcome::Done => ExitCode::SUCCESS,
+            Outcome::NoDuplicates => ExitCode::from(EXIT_NOTHING_TO_DO),
+        }
+    }
+}
+
+/// Files listed in an inventory are missing, changed or unreadable.
+#[derive(Debug)]
+struct CheckFailed(usize);
+
+impl std::fmt::Display for CheckFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} files failed the check.", self.0)
+    }
+}
+
+impl std::error::Error for CheckFailed {}
+
+fn error_kind(e: &anyhow::Error) -> ErrorKind {
+    if e.is::<CheckFailed>() {
+        return ErrorKind::VerificationFailed;
+    }
+    exit::error_kind(e)
+}
 
 #[derive(Parser)]
 #[command(name = "d2fn")]
 #[command(author = "sunnysab <i@sunnysab.cn>")]
 #[command(version = "0.1")]
 #[command(about = "DeDuplicate File on NAS")]
+#[command(after_help = "Shell completion: source <(d2fn completions bash), also zsh and fish.")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[command(flatten)]
+    log: logging::LogArg,
 }
 
 #[derive(Clone, ValueEnum)]
@@ -38,32 +117,337 @@ enum OutputFormat {
     Inventory,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum ConvertFormat {
+    /// JSON Lines, one group per line
+    Json,
+    /// Inventory, from JSON Lines
+    Inventory,
+}
+
 #[derive(Args)]
 struct ScanArg {
-    /// The directory to scan
-    path: PathBuf,
+    /// Directories to scan, duplicates are searched across all of them
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    /// Reference directory. Only report files in `paths` that already exist here
+    #[arg(long)]
+    against: Option<PathBuf>,
+    /// With --against, list files in `paths` that have no copy in the reference directory instead.
+    /// Files sharing extension and size with a reference file have to be hashed to prove that
+    #[arg(long, default_value_t = false, requires = "against")]
+    unique: bool,
+    /// With --unique, assume files sharing extension and size with a reference file are copies, without hashing
+    #[arg(long, default_value_t = false, requires = "unique")]
+    quick: bool,
     /// Verify the full content to file
     #[arg(long, default_value_t = false)]
     verify: bool,
-    /// Compare size
+    /// Also group images which look alike, even if resized or re-encoded
+    #[cfg(feature = "similar-images")]
+    #[arg(long, default_value_t = false)]
+    similar_images: bool,
+    /// Maximum Hamming distance between fingerprints of similar images, out of 64 bits
+    #[cfg(feature = "similar-images")]
+    #[arg(long, default_value_t = similar::DEFAULT_THRESHOLD, requires = "similar_images")]
+    similarity_threshold: u32,
+    /// Also group audio files with the same frames, ignoring tags
+    #[cfg(feature = "audio")]
+    #[arg(long, default_value_t = false)]
+    audio: bool,
+    /// Also find directories whose whole content is duplicated
+    #[arg(long, default_value_t = false, conflicts_with = "unique")]
+    dirs: bool,
+    /// Compare size, either fixed like "1M", or a fraction of each file like "5%"
     #[arg(long, default_value_t = DEFAULT_COMPARE_SIZE.to_string())]
     compare_size: String,
+    /// Hash function to compare candidates, before --verify which always uses blake3
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Blake3)]
+    candidate_hash: HashAlgorithm,
+    /// Derive blake3 hashes from the 32-byte key in this file, as raw bytes or hex
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+    /// Lower bound of a fractional compare size
+    #[arg(long, default_value_t = DEFAULT_COMPARE_MIN.to_string())]
+    compare_min: String,
+    /// Upper bound of a fractional compare size
+    #[arg(long, default_value_t = DEFAULT_COMPARE_MAX.to_string())]
+    compare_max: String,
     /// Output format
     #[arg(short, long, value_enum, default_value_t = DEFAULT_OUTPUT_FORMAT)]
     format: OutputFormat,
-    /// Output path
-    #[arg(short, long)]
+    /// Output path. An inventory can be written to stdout with "-"
+    #[arg(short, long = "out", visible_alias = "output")]
     output: Option<PathBuf>,
+    /// Add groups found to an existing inventory, instead of replacing it
+    #[arg(long, default_value_t = false)]
+    append: bool,
+    /// Compress the inventory with zstd, at LEVEL from 1 to 22
+    #[arg(long, value_name = "LEVEL", num_args = 0..=1, default_missing_value = "3", conflicts_with = "append", value_parser = clap::value_parser!(i32).range(1..=22))]
+    compress: Option<i32>,
+    /// Only scan files at most N directories deep below each path, 0 for files directly in it
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+    /// Abort on the first file or directory which can not be read, instead of skipping it
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+    /// Walk directories in name order on one thread, and order groups and files by path, so that two scans of the
+    /// same tree give the same output. Overrides --walk-threads
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+    /// Read directories on N threads, for wide trees on network file systems. Also set by walk_threads in [d2fn] of
+    /// nas-toolbox.toml
+    #[arg(long, value_name = "N")]
+    walk_threads: Option<usize>,
+    /// Skip files and directories named NAME, such as @eaDir or .snapshot. Can be given more than once. Replaces prune
+    /// in [d2fn] of nas-toolbox.toml
+    #[arg(long, value_name = "NAME")]
+    prune: Vec<String>,
+    /// Never descend into PATH, such as /tank/media/.snapshots. Can be given more than once. Symlinked spellings of
+    /// the same directory match too
+    #[arg(long, value_name = "PATH")]
+    exclude_path: Vec<PathBuf>,
+    /// Only report groups wasting at least SIZE beyond one copy, such as 50M. Smaller groups are counted in the
+    /// summary
+    #[arg(long, value_name = "SIZE")]
+    min_waste: Option<String>,
+    /// Measure waste for --min-waste by blocks allocated on disk rather than the apparent size, which differ on file
+    /// systems with compression and for sparse files
+    #[arg(long, default_value_t = false)]
+    by_allocated: bool,
+    /// Stop after indexing N files, and estimate the duplicates of the whole tree from this sample
+    #[arg(long, value_name = "N")]
+    sample_files: Option<usize>,
+    /// Stop after reading SIZE to hash files, such as 2G, and estimate the duplicates of the whole tree
+    #[arg(long, value_name = "SIZE")]
+    sample_bytes: Option<String>,
+    /// Only index files changed since TIME, an RFC 3339 time or a duration ago such as 7d or 12h. Files under
+    /// --against are all indexed
+    #[arg(long, value_name = "TIME", value_parser = since::parse_since)]
+    since: Option<SystemTime>,
+    /// Time of files compared with --since
+    #[arg(long, value_enum, default_value_t = TimeField::Mtime, requires = "since")]
+    since_field: TimeField,
+    /// Follow symbolic links. Links to directories are only followed with --walk-threads
+    #[arg(long, default_value_t = false)]
+    follow_symlinks: bool,
+    /// Do not read .d2fnignore files
+    #[arg(long, default_value_t = false)]
+    no_ignore_file: bool,
+    /// Rewrite a JSON document of the progress at PATH for monitors, with "state": "done" once the scan completed.
+    /// A stale "updated" timestamp means the scan hangs or was killed
+    #[arg(long, value_name = "PATH")]
+    status_file: Option<PathBuf>,
+    /// Seconds between rewrites of --status-file
+    #[arg(long, value_name = "SECS", default_value_t = 10, requires = "status_file")]
+    status_interval: u64,
+    /// Limit read rate, in MB/s. Also set by max_read_mbps in [d2fn] of nas-toolbox.toml
+    #[arg(long)]
+    max_read_mbps: Option<u32>,
+    /// Sleep between files and directories, in milliseconds
+    #[arg(long)]
+    idle_ms: Option<u64>,
+    /// Be gentle to other users of the disks, a preset of --max-read-mbps and --idle-ms
+    #[arg(long, default_value_t = false)]
+    nice: bool,
+    /// Advise the kernel to drop hashed data from the page cache, so that other workloads keep theirs
+    #[arg(long, default_value_t = false)]
+    drop_cache: bool,
+    /// Fully hash files at least this large through a memory map, on all cores
+    #[cfg(feature = "parallel-hash")]
+    #[arg(long, value_name = "SIZE")]
+    mmap_threshold: Option<String>,
+    /// Serve progress to Prometheus on ADDR, such as 127.0.0.1:9184, until the scan ends
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<String>,
+}
+
+#[derive(Args)]
+struct ReportArg {
+    /// Inventory written by scan
+    inventory: PathBuf,
+    /// Output as JSON
+    #[arg(long, default_value_t = false, conflicts_with = "csv")]
+    json: bool,
+    /// Output as CSV, one row per file
+    #[arg(long, default_value_t = false)]
+    csv: bool,
+    /// Only show the N groups wasting most space
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+    /// Rank groups by blocks allocated on disk rather than the apparent size
+    #[arg(long, default_value_t = false, conflicts_with = "html")]
+    by_allocated: bool,
+    /// Only show the N-th group, counting from 1 in the order of the inventory
+    #[arg(long, value_name = "N", conflicts_with = "top", value_parser = clap::value_parser!(u64).range(1..))]
+    group: Option<u64>,
+    /// Write a self-contained HTML page to this path, for reviewing in a browser
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["json", "csv", "top", "group"])]
+    html: Option<PathBuf>,
+    /// Show thumbnails of images in the HTML page, skipping images larger than SIZE
+    #[cfg(feature = "thumbnails")]
+    #[arg(long, value_name = "SIZE", num_args = 0..=1, default_missing_value = DEFAULT_THUMBNAIL_LIMIT, requires = "html")]
+    thumbnails: Option<String>,
 }
 
 #[derive(Args)]
 struct DedupArg {
     inventory: PathBuf,
+    /// Key file the inventory was hashed with
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+    /// Directory a scan root is mounted at now, once for each root recorded in the inventory, in the same order
+    #[arg(long = "root", value_name = "DIR")]
+    roots: Vec<PathBuf>,
+    /// Refuse inventories written more than this many days ago, 0 to accept any. See `refresh`
+    #[arg(long, value_name = "DAYS", default_value_t = DEFAULT_MAX_AGE)]
+    max_age: u64,
+}
+
+#[derive(Args)]
+struct ReviewArg {
+    inventory: PathBuf,
+    /// Where to save decisions
+    #[arg(long, default_value = "plan.d2fn")]
+    plan: PathBuf,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("source").required(true).args(["inventory", "plan"])))]
+#[command(group(ArgGroup::new("resolution").args(["hardlink", "delete"])))]
+struct ApplyArg {
+    /// Inventory written by scan, the first file of each group is kept
+    inventory: Option<PathBuf>,
+    /// Plan file saved by review
+    #[arg(long)]
+    plan: Option<PathBuf>,
+    /// Replace the other files in each group of the inventory with hardlinks
+    #[arg(long, default_value_t = false, requires = "inventory")]
+    hardlink: bool,
+    /// Remove the other files in each group of the inventory
+    #[arg(long, default_value_t = false, requires = "inventory")]
+    delete: bool,
+    /// Also apply to groups of similar, not identical files, or plans replacing such files. The files replaced are lost
+    #[arg(long, default_value_t = false)]
+    allow_lossy: bool,
+    /// Key file the inventory was hashed with
+    #[arg(long, value_name = "PATH", requires = "inventory")]
+    key_file: Option<PathBuf>,
+    /// Directory a scan root is mounted at now, once for each root recorded in the inventory, in the same order
+    #[arg(long = "root", value_name = "DIR", requires = "inventory")]
+    roots: Vec<PathBuf>,
+    /// Refuse inventories written more than this many days ago, 0 to accept any. See `refresh`
+    #[arg(long, value_name = "DAYS", default_value_t = DEFAULT_MAX_AGE, requires = "inventory")]
+    max_age: u64,
+    /// Only check the plan, do not touch any file
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct MergeArg {
+    /// Inventories to merge, of the same version and key, the latest scan last
+    #[arg(required = true, num_args = 2..)]
+    inputs: Vec<PathBuf>,
+    /// Path of the merged inventory
+    #[arg(short, long = "out")]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct ConvertArg {
+    /// Inventory, or JSON Lines written by convert
+    input: PathBuf,
+    #[arg(long, value_enum)]
+    to: ConvertFormat,
+    /// Output path, JSON is written to stdout by default
+    #[arg(short, long = "out")]
+    output: Option<PathBuf>,
+    /// Stop at the first invalid line when converting to an inventory, instead of skipping it
+    #[arg(long, default_value_t = false)]
+    abort_on_error: bool,
+}
+
+#[derive(Args)]
+struct DiffArg {
+    /// Inventory of the earlier scan
+    old: PathBuf,
+    /// Inventory of the later scan
+    new: PathBuf,
+    /// Output as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+    /// Write groups found in both inventories to a new inventory, to be applied again
+    #[arg(long, value_name = "PATH")]
+    persisting: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct RepairArg {
+    /// Damaged inventory
+    inventory: PathBuf,
+    /// Path of the repaired inventory
+    #[arg(short, long = "out")]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct CheckArg {
+    /// Inventory written by scan
+    inventory: PathBuf,
+    /// Key file the inventory was hashed with
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+    /// Advise the kernel to drop hashed data from the page cache
+    #[arg(long, default_value_t = false)]
+    drop_cache: bool,
+    /// Directory a scan root is mounted at now, once for each root recorded in the inventory, in the same order
+    #[arg(long = "root", value_name = "DIR")]
+    roots: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+struct RefreshArg {
+    /// Inventory written by scan
+    inventory: PathBuf,
+    /// Path of the refreshed inventory, the inventory is replaced by default
+    #[arg(short, long = "out")]
+    output: Option<PathBuf>,
+    /// Also hash files again, and drop those whose content changed
+    #[arg(long, default_value_t = false)]
+    rehash: bool,
+    /// Key file the inventory was hashed with
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+    /// Advise the kernel to drop hashed data from the page cache
+    #[arg(long, default_value_t = false)]
+    drop_cache: bool,
+    /// Directory a scan root is mounted at now, once for each root recorded in the inventory, in the same order
+    #[arg(long = "root", value_name = "DIR")]
+    roots: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+struct UpgradeArg {
+    /// Inventory written by an earlier version, never modified
+    input: PathBuf,
+    /// Path of the upgraded inventory
+    output: PathBuf,
+    /// Also hash files of groups recorded without a hash
+    #[arg(long, default_value_t = false)]
+    rehash: bool,
+    /// Key file the inventory was hashed with
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+    /// Advise the kernel to drop hashed data from the page cache
+    #[arg(long, default_value_t = false)]
+    drop_cache: bool,
 }
 
 #[derive(Args)]
 struct HashArg {
-    /// The file to hash
+    /// The file to hash, `-` for stdin
     file: String,
 
     /// Compare complete file content
@@ -72,13 +456,65 @@ struct HashArg {
     /// Compare size
     #[arg(long, default_value_t = DEFAULT_COMPARE_SIZE.to_string())]
     hash_size: String,
+    /// Hash function
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Blake3)]
+    algorithm: HashAlgorithm,
+    /// Derive a keyed blake3 hash from the 32-byte key in this file
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Scan(ScanArg),
+    /// Find duplicate files
+    #[command(
+        after_help = "Examples:\n  d2fn scan /mnt/photos /mnt/backup --format inventory -o photos.d2fn\n  d2fn scan /mnt/new --against /mnt/archive --unique\n  d2fn scan /mnt/share --format html -o report.html"
+    )]
+    Scan(Box<ScanArg>),
+    /// Summarize an inventory
+    #[command(after_help = "Examples:\n  d2fn report photos.d2fn --top 20\n  d2fn report photos.d2fn --html report.html")]
+    Report(ReportArg),
+    /// Hardlink duplicates listed in an inventory, same as `apply <inventory> --hardlink`
+    #[command(after_help = "Examples:\n  d2fn dedup photos.d2fn")]
     Dedup(DedupArg),
+    /// Review duplicate groups interactively
+    #[command(after_help = "Examples:\n  d2fn review photos.d2fn --plan plan.d2fn\n  d2fn apply --plan plan.d2fn")]
+    Review(ReviewArg),
+    /// Hardlink or delete duplicates, as listed in an inventory or a plan saved by review
+    #[command(after_help = "Examples:\n  d2fn apply photos.d2fn --hardlink --dry-run\n  d2fn apply --plan plan.d2fn")]
+    Apply(ApplyArg),
+    /// Merge inventories, groups sharing files are merged into one
+    #[command(after_help = "Examples:\n  d2fn merge a.d2fn b.d2fn -o all.d2fn")]
+    Merge(MergeArg),
+    /// Copy intact groups of a damaged inventory to a new one
+    #[command(after_help = "Examples:\n  d2fn repair damaged.d2fn -o repaired.d2fn")]
+    Repair(RepairArg),
+    /// Compare two inventories: groups resolved, new, and persisting since the old one
+    #[command(
+        after_help = "Examples:\n  d2fn diff last-week.d2fn today.d2fn\n  d2fn diff last-week.d2fn today.d2fn --json"
+    )]
+    Diff(DiffArg),
+    /// Convert an inventory to JSON Lines, or back
+    #[command(
+        after_help = "Examples:\n  d2fn convert photos.d2fn --to json -o photos.jsonl\n  d2fn convert photos.jsonl --to inventory -o photos.d2fn"
+    )]
+    Convert(ConvertArg),
+    /// Re-hash files listed in an inventory, and report missing, changed or unreadable ones
+    #[command(after_help = "Examples:\n  d2fn check photos.d2fn")]
+    Check(CheckArg),
+    /// Drop files which changed since the scan from an inventory, and groups left with a single file
+    #[command(
+        after_help = "Examples:\n  d2fn refresh photos.d2fn\n  d2fn refresh photos.d2fn --rehash --out photos-now.d2fn"
+    )]
+    Refresh(RefreshArg),
+    /// Rewrite an inventory of an earlier version in the current format, filling in metadata of its files
+    #[command(after_help = "Examples:\n  d2fn upgrade old.d2fn new.d2fn\n  d2fn upgrade old.d2fn new.d2fn --rehash")]
+    Upgrade(UpgradeArg),
+    #[command(after_help = "Examples:\n  d2fn hash IMG_0001.JPG --full\n  dd if=/dev/nsa0 bs=64k | d2fn hash - --full")]
     Hash(HashArg),
+    /// Print the completion script of a shell
+    #[command(hide = true)]
+    Completions(CompletionsArg),
 }
 
 fn display_duration(secs: u64) -> String {
@@ -114,30 +550,78 @@ fn display_file_size(len: u64) -> String {
     format!("{}{}", r, t[i])
 }
 
-/// Parse user input size "1G", "1GB", "1MB"... to a usize.
-fn parse_file_size(text: &str) -> usize {
-    let mut num = 0usize;
-    let mut last_i = 0usize;
-    for (i, c) in text.char_indices() {
-        if c.is_ascii_digit() {
-            num = num * 10 + (c as usize) - 48;
-        } else {
-            last_i = i;
-            break;
+/// Parse user input size "1G", "1GB", "1MB"... to a usize. A number without unit is in bytes.
+fn parse_file_size(text: &str) -> Result<usize> {
+    let digits = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (num, unit) = text.split_at(digits);
+    let num = num
+        .parse::<usize>()
+        .with_context(|| format!("invalid size {text}, expect a number such as 512, 4k, 16m or 1g."))?;
+    let unit = match unit.to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1024usize,
+        "m" | "mb" => 1024 * 1024usize,
+        "g" | "gb" => 1024 * 1024 * 1024usize,
+        _ => bail!("invalid size {text}, expect a unit of k, m or g."),
+    };
+    num.checked_mul(unit).with_context(|| format!("size {text} is too large."))
+}
+
+fn parse_compare_size(arg: &ScanArg) -> Result<CompareSize> {
+    let Some(percent) = arg.compare_size.strip_suffix('%') else {
+        return Ok(CompareSize::Fixed(parse_file_size(&arg.compare_size)?));
+    };
+    let percent = percent
+        .parse::<f64>()
+        .ok()
+        .filter(|p| *p > 0.0 && *p <= 100.0)
+        .with_context(|| format!("invalid compare size {}", arg.compare_size))?;
+    Ok(CompareSize::Adaptive {
+        min: parse_file_size(&arg.compare_min)?,
+        max: parse_file_size(&arg.compare_max)?,
+        fraction: percent / 100.0,
+    })
+}
+
+/// Describe duplicated directories, each line begins with `prefix`.
+fn write_directory_report<W: Write>(report: &DirectoryReport, mut writer: W, prefix: &str) -> Result<()> {
+    for (index, group) in report.groups.iter().enumerate() {
+        writeln!(
+            writer,
+            "{prefix}directory group {}, {} * {} ({} files), {} can be saved:",
+            index + 1,
+            group.dirs.len(),
+            display_file_size(group.size),
+            group.file_count,
+            display_file_size(group.saved_bytes())
+        )?;
+        for dir in &group.dirs {
+            writeln!(writer, "{prefix}  {}", dir.display())?;
         }
     }
+    for near_match in &report.near_matches {
+        let differences = near_match.differences.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>();
+        writeln!(
+            writer,
+            "{prefix}{} and {} differ only in: {}",
+            near_match.left.display(),
+            near_match.right.display(),
+            differences.join(", ")
+        )?;
+    }
+    Ok(())
+}
 
-    let unit = text[last_i..].to_lowercase();
-    let unit = match unit.as_str() {
-        "g" | "gb" => 1024 * 1024 * 1024usize,
-        "m" | "mb" => 1024 * 1024usize,
-        "k" | "kb" => 1024usize,
-        _ => panic!("unexpected size {unit}"),
-    };
-    num * unit
+/// Quote `path` for a POSIX shell, as a single-quoted word.
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
 }
 
-fn generate_dedup_script<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path) -> Result<()> {
+fn generate_dedup_script<F: ScanFilter>(
+    duplicate: &Duplicate<F>,
+    directories: &DirectoryReport,
+    output: &Path,
+) -> Result<()> {
     let script = std::fs::File::create(output).with_context(|| format!("failed to create {}.", output.display()))?;
     let mut buffer = BufWriter::new(script);
     writeln!(&mut buffer, "#/usr/bin/bash")?;
@@ -151,9 +635,10 @@ fn generate_dedup_script<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path)
         group += 1;
 
         let del_count = file_group.len() as u64 - 1;
+        let waste = group_waste(&file_group);
         let size = display_file_size(file_group[0].metadata.size);
-        let total_size = display_file_size(file_group[0].metadata.size * del_count);
-        let occupied = display_file_size(file_group[0].metadata.blocks * 512 * del_count);
+        let total_size = display_file_size(waste.apparent);
+        let occupied = display_file_size(waste.allocated);
         writeln!(
             &mut buffer,
             "# group {group}, {del_count} * {size} = {total_size} ({occupied} in disk) can be saved."
@@ -161,11 +646,39 @@ fn generate_dedup_script<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path)
 
         if let [first, rest @ ..] = file_group.as_slice() {
             writeln!(&mut buffer, "# Keep {}: {}", first.metadata.ino, first.path.display())?;
-            let source = first.path.display();
+            for linked in duplicate.linked_paths(first).into_iter().skip(1) {
+                writeln!(&mut buffer, "#   also linked: {}", linked.display())?;
+            }
+            let source = shell_quote(&first.path);
             for &file_to_del in rest {
-                let destination = file_to_del.path.display();
-                writeln!(&mut buffer, "# Remove {}: {}", file_to_del.metadata.ino, destination)?;
-                writeln!(&mut buffer, "ln -f '{source}' '{destination}'")?;
+                let (from, to) = (&first.metadata, &file_to_del.metadata);
+                if !duplicate.is_cross_mode() && (from.mode, from.uid, from.gid) != (to.mode, to.uid, to.gid) {
+                    // 硬链接共享 inode, 替换后权限和所有者与保留的文件一致.
+                    writeln!(
+                        &mut buffer,
+                        "# Note: owner or permissions differ ({:o} {}:{} -> {:o} {}:{}), the kept file's owner and permissions apply.",
+                        to.mode & 0o7777,
+                        to.uid,
+                        to.gid,
+                        from.mode & 0o7777,
+                        from.uid,
+                        from.gid
+                    )?;
+                }
+                // 已经硬链接的文件, 每个路径都要处理, 否则空间无法释放. 参考目录中的路径保持不变.
+                for linked in duplicate.linked_paths(file_to_del) {
+                    if duplicate.in_reference(linked) {
+                        continue;
+                    }
+                    writeln!(&mut buffer, "# Remove {}: {}", file_to_del.metadata.ino, linked.display())?;
+                    let destination = shell_quote(linked);
+                    if duplicate.is_cross_mode() {
+                        // 跨目录模式下, 参考目录中的文件被保留, 另一侧的副本直接删除.
+                        writeln!(&mut buffer, "rm -f {destination}")?;
+                    } else {
+                        writeln!(&mut buffer, "ln -f {source} {destination}")?;
+                    }
+                }
                 writeln!(&mut buffer)?;
                 dup_count += 1;
 
@@ -175,23 +688,92 @@ fn generate_dedup_script<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path)
             }
         }
 
-        total_size_across_group += file_group[0].metadata.size * del_count;
-        block_size_across_group += file_group[0].metadata.blocks * 512 * del_count;
+        total_size_across_group += waste.apparent;
+        block_size_across_group += waste.allocated;
+    }
+
+    // 相似的图片并不相同, 替换会丢失内容, 仅作记录.
+    #[cfg(feature = "similar-images")]
+    for (index, group) in duplicate.similar_groups().iter().enumerate() {
+        if index == 0 {
+            writeln!(&mut buffer, "# Similar, not identical, nothing to do:")?;
+        }
+        writeln!(&mut buffer, "# similar group {}:", index + 1)?;
+        for fingerprint in group {
+            writeln!(
+                &mut buffer,
+                "#   {:>8} {}",
+                display_file_size(fingerprint.size),
+                fingerprint.path.display()
+            )?;
+        }
+    }
+
+    #[cfg(feature = "audio")]
+    for (index, group) in duplicate.audio_groups().iter().enumerate() {
+        if index == 0 {
+            writeln!(&mut buffer, "# Same audio, different tags, nothing to do:")?;
+        }
+        writeln!(&mut buffer, "# audio group {}:", index + 1)?;
+        for entry in group.iter() {
+            writeln!(
+                &mut buffer,
+                "#   {:>8} {}",
+                display_file_size(entry.size),
+                entry.path.display()
+            )?;
+        }
+    }
+    #[cfg(feature = "audio")]
+    for (index, path) in duplicate.audio_fallbacks().iter().enumerate() {
+        if index == 0 {
+            writeln!(&mut buffer, "# Not parsed as audio, compared as whole files:")?;
+        }
+        writeln!(&mut buffer, "#   {}", path.display())?;
+    }
+
+    // 已经是硬链接的文件不计入可清理的空间, 仅作记录.
+    for (index, hardlink_group) in duplicate.hardlink_groups().enumerate() {
+        if index == 0 {
+            writeln!(&mut buffer, "# Already hardlinked, nothing to do:")?;
+        }
+        writeln!(
+            &mut buffer,
+            "# hardlink group {}, {} of {} links found, {} saved.",
+            index + 1,
+            hardlink_group.paths.len(),
+            hardlink_group.link_count,
+            display_file_size(hardlink_group.saved_bytes())
+        )?;
+        for path in &hardlink_group.paths {
+            writeln!(&mut buffer, "#   {}", path.display())?;
+        }
+    }
+
+    if !directories.groups.is_empty() || !directories.near_matches.is_empty() {
+        // 整个目录重复时, 保留其中一个, 其余 `rm -r` 即可替代上面逐个文件的操作.
+        writeln!(&mut buffer, "# Duplicate directories, keeping one of each group is enough:")?;
+        write_directory_report(directories, &mut buffer, "# ")?;
     }
 
-    println!(
+    eprintln!(
         "{} files ({} on disk) can be cleaned.",
         display_file_size(total_size_across_group),
         display_file_size(block_size_across_group)
     );
-    println!("Script has been written to {}", output.display());
-    println!("Remember to grant execute permission before you run it.");
+    eprintln!("Script has been written to {}", output.display());
+    eprintln!("Remember to grant execute permission before you run it.");
 
-    let inventory_path = Path::new("inventory.d2fn");
-    generate_inventory(duplicate, inventory_path)?;
+    let inventory_path = Path::new(DEFAULT_INVENTORY);
+    generate_inventory(duplicate, inventory_path, false, None)?;
     Ok(())
 }
 
+/// Show paths relative to the scan root they are found under.
+fn strip_root<'a>(path: &'a Path, roots: &[PathBuf]) -> &'a Path {
+    roots.iter().find_map(|root| path.strip_prefix(root).ok()).unwrap_or(path)
+}
+
 fn generate_html<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path, scan: &ScanArg) -> Result<()> {
     let mut html = std::fs::File::create(output).with_context(|| format!("failed to create {}.", output.display()))?;
     let html_template: &'static str = include_str!("../template/report.html");
@@ -208,17 +790,22 @@ fn generate_html<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path, scan: &
         index: usize,
         files: Vec<FileSummary>,
     }
+
+    #[derive(serde::Serialize)]
+    struct HardlinkSummary {
+        paths: Vec<String>,
+        saved: String,
+    }
     let mut mapped_groups = Vec::new();
     for (group_index, group) in duplicate.result().enumerate() {
         let files = group
             .into_iter()
-            .map(|file_ref| {
-                let path = file_ref.path.strip_prefix(&scan.path).unwrap_or(&file_ref.path);
-                FileSummary {
+            .flat_map(|file_ref| {
+                duplicate.linked_paths(file_ref).into_iter().map(|path| FileSummary {
                     ino: file_ref.metadata.ino,
-                    path: path.to_string_lossy().to_string(),
+                    path: strip_root(path, &scan.paths).to_string_lossy().to_string(),
                     size: display_file_size(file_ref.metadata.size),
-                }
+                })
             })
             .collect::<Vec<_>>();
         mapped_groups.push(Group {
@@ -227,10 +814,24 @@ fn generate_html<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path, scan: &
         });
     }
 
+    let hardlink_groups = duplicate
+        .hardlink_groups()
+        .map(|group| HardlinkSummary {
+            paths: group
+                .paths
+                .iter()
+                .map(|path| strip_root(path, &scan.paths).to_string_lossy().to_string())
+                .collect(),
+            saved: display_file_size(group.saved_bytes()),
+        })
+        .collect::<Vec<_>>();
+
     let mut context = tera::Context::new();
-    context.insert("path", &scan.path.to_string_lossy().to_string());
+    let roots = scan.paths.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>();
+    context.insert("path", &roots.join(", "));
     context.insert("group_count", &mapped_groups.len());
     context.insert("groups", &mapped_groups);
+    context.insert("hardlink_groups", &hardlink_groups);
     let parameter = if scan.verify {
         "快速 + 完整内容验证".to_string()
     } else {
@@ -242,57 +843,126 @@ fn generate_html<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path, scan: &
         tera::Tera::one_off(html_template, &context, false).with_context(|| "unable to render html".to_string())?;
     html.write_all(content.as_bytes())
         .with_context(|| "when write to file".to_string())?;
-    println!("Report has been written to {}.", output.display());
+    eprintln!("Report has been written to {}.", output.display());
 
-    let inventory_path = Path::new("inventory.d2fn");
-    generate_inventory(duplicate, inventory_path)?;
+    let inventory_path = Path::new(DEFAULT_INVENTORY);
+    generate_inventory(duplicate, inventory_path, false, None)?;
     Ok(())
 }
 
-fn generate_inventory<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path) -> Result<()> {
-    println!("Writing result inventory....");
+/// Write the inventory to `output`, or to stdout if it is "-". With `append`, groups are added to the inventory at
+/// `output`. Records are compressed at the zstd level `compression` if given.
+fn generate_inventory<F: ScanFilter>(
+    duplicate: &Duplicate<F>,
+    output: &Path,
+    append: bool,
+    compression: Option<i32>,
+) -> Result<()> {
+    eprintln!("Writing result inventory....");
 
-    let mut writer = InventoryWriter::create(output)?;
-    let iter = duplicate.result().map(|group| {
-        let files = group
-            .iter()
-            .map(|&file_ref| DuplicateFile {
-                ino: file_ref.metadata.ino,
-                path: D2fnPath::from(file_ref.path.as_path()),
-            })
-            .collect::<Vec<_>>();
+    if output == Path::new("-") {
+        write_inventory(duplicate, InventoryWriter::new(std::io::stdout().lock()), compression)
+    } else if append && output.exists() {
+        write_inventory(duplicate, InventoryWriter::append(output)?, None)
+    } else {
+        write_inventory(duplicate, InventoryWriter::create(output)?, compression)
+    }
+}
 
-        DuplicateGroup { files }
-    });
+fn write_inventory<F: ScanFilter, W: Write>(
+    duplicate: &Duplicate<F>,
+    mut writer: InventoryWriter<W>,
+    compression: Option<i32>,
+) -> Result<()> {
+    if let Some(level) = compression {
+        writer = writer.with_compression(level);
+    }
+    let count = duplicate.write_to(writer)?;
+    eprintln!("Inventory exported, {count} groups written.");
+    Ok(())
+}
+
+/// Space used on the file system holding `path`.
+// statvfs 的字段类型随平台不同
+#[allow(clippy::unnecessary_cast)]
+fn used_bytes(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    Some((stat.f_blocks as u64).saturating_sub(stat.f_bfree as u64) * stat.f_frsize as u64)
+}
+
+/// Extrapolate the duplicates found by a partial scan to the space used on the file system of `root`.
+fn print_estimate(extent: &ScanExtent, root: &Path) {
+    if extent.bytes_indexed == 0 {
+        return;
+    }
+    let ratio = extent.reclaimable_bytes as f64 / extent.bytes_indexed as f64;
+    eprintln!(
+        "Estimate from a partial scan: {} of the {} indexed can be reclaimed ({:.1}%).",
+        display_file_size(extent.reclaimable_bytes),
+        display_file_size(extent.bytes_indexed),
+        ratio * 100.0
+    );
+    // 样本只能发现其内部的重复, 整棵树的重复通常更多
+    if let Some(used) = used_bytes(root) {
+        eprintln!(
+            "At that rate, the {} used on the file system of {} would hold about {} of duplicates, likely more: \
+             copies outside the sample were not compared.",
+            display_file_size(used),
+            root.display(),
+            display_file_size((used as f64 * ratio) as u64)
+        );
+    }
+}
+
+fn generate_unique_list<F: ScanFilter>(duplicate: &Duplicate<F>, output: &Path) -> Result<()> {
+    let list = std::fs::File::create(output).with_context(|| format!("failed to create {}.", output.display()))?;
+    let mut buffer = BufWriter::new(list);
+
+    let (mut count, mut total_size) = (0, 0);
+    for file in duplicate.unique_files() {
+        writeln!(&mut buffer, "{}\t{}", file.metadata.size, file.path.display())?;
+        count += 1;
+        total_size += file.metadata.size;
+    }
 
-    writer.export(iter)?;
-    println!("Inventory exported.");
+    eprintln!(
+        "{count} files ({}) have no copy in the reference directory.",
+        display_file_size(total_size)
+    );
+    eprintln!("List has been written to {}", output.display());
     Ok(())
 }
 
-fn report<F: ScanFilter>(duplicate: &Duplicate<F>, arg: &ScanArg) -> Result<()> {
+fn export_results<F: ScanFilter>(duplicate: &Duplicate<F>, directories: &DirectoryReport, arg: &ScanArg) -> Result<()> {
     let path = arg.output.clone();
 
     match arg.format {
         OutputFormat::Html => {
             let path = path.unwrap_or_else(|| PathBuf::from("report.html"));
-            generate_html(duplicate, &path, arg).expect("unable to generate report page.");
+            generate_html(duplicate, &path, arg).with_context(|| "unable to generate report page.".to_string())
         }
         OutputFormat::Script => {
             let path = path.unwrap_or_else(|| PathBuf::from("dedup.sh"));
-            generate_dedup_script(duplicate, &path).expect("unable to generate script.");
+            generate_dedup_script(duplicate, directories, &path).with_context(|| "unable to generate script.".to_string())
         }
         OutputFormat::Inventory => {
-            let path = path.unwrap_or_else(|| PathBuf::from("inventory.d2fn"));
-            generate_inventory(duplicate, &path).expect("unable to generate inventory file.");
+            let path = path.unwrap_or_else(|| PathBuf::from(DEFAULT_INVENTORY));
+            generate_inventory(duplicate, &path, arg.append, arg.compress)
+                .with_context(|| "unable to generate inventory file.".to_string())
         }
     }
-    Ok(())
 }
 
 fn print_progress(status: StatusReport, width: usize) {
     let blank_line = " ".repeat(width);
-    let clear_line = || print!("\r{blank_line}\r");
+    let clear_line = || eprint!("\r{blank_line}\r");
 
     fn get_truncated_content(text: &str, mut remaining_width: usize) -> &str {
         let mut len = 0usize;
@@ -309,16 +979,140 @@ fn print_progress(status: StatusReport, width: usize) {
     }
 
     clear_line();
-    let count = format!("S {}/D {}: ", status.scanned, status.duplicated);
-    print!("{count}{}", get_truncated_content(&status.last_file, width - count.len()));
+    if let Some(file) = &status.hashing_current_file {
+        let (done, total) = status.hashing_progress;
+        let count = format!(
+            "H {}% of {} {}/s: ",
+            done * 100 / total.max(1),
+            display_file_size(total),
+            display_file_size(status.read_rate)
+        );
+        eprint!("{count}{}", get_truncated_content(file, width - count.len()));
+        std::io::stderr().flush().unwrap();
+        return;
+    }
+    let count = format!(
+        "S {}/D {}/I {} {}/s: ",
+        status.scanned,
+        status.duplicated,
+        status.ignored,
+        display_file_size(status.read_rate)
+    );
+    eprint!("{count}{}", get_truncated_content(&status.last_file, width - count.len()));
 
-    std::io::stdout().flush().unwrap();
+    std::io::stderr().flush().unwrap();
 }
 
-fn scan(arg: ScanArg) {
-    println!("Scanning on {}...", arg.path.display());
-    println!("File type filter: {:?}", DefaultFilter::ext_set());
-    let mut duplicate = Duplicate::new(&arg.path).custom_filter(DefaultFilter::new());
+fn scan(arg: ScanArg) -> Result<Outcome> {
+    // 命令行参数优先于配置文件
+    let config = Config::load()?.d2fn;
+    let (first, rest) = arg.paths.split_first().expect("at least one path is required by clap.");
+    for path in &arg.paths {
+        eprintln!("Scanning on {}...", path.display());
+    }
+    eprintln!("File type filter: {:?}", DefaultFilter::ext_set());
+    let mut duplicate = Duplicate::new(first)
+        .custom_filter(DefaultFilter::new())
+        .candidate_hash(arg.candidate_hash)
+        .respect_ignore_files(!arg.no_ignore_file)
+        .strict(arg.strict)
+        .deterministic(arg.deterministic)
+        .follow_symlinks(arg.follow_symlinks)
+        .cache_policy(cache_policy(arg.drop_cache));
+    if let Some(depth) = arg.max_depth {
+        duplicate = duplicate.max_depth(depth);
+    }
+    if let Some(path) = &arg.key_file {
+        eprintln!("Hashes are keyed.");
+        duplicate = duplicate.hash_key(HashKey::load(path)?);
+    }
+    if let Some(threads) = arg.walk_threads.or(config.walk_threads) {
+        duplicate = duplicate.parallel_walk(threads);
+    }
+    let prune = if arg.prune.is_empty() {
+        config.prune.unwrap_or_default()
+    } else {
+        arg.prune.clone()
+    };
+    if !prune.is_empty() {
+        let names = prune.iter().map(OsString::from).collect::<HashSet<_>>();
+        duplicate = duplicate.prune_if(move |path, _, _| path.file_name().is_some_and(|name| names.contains(name)));
+    }
+    if !arg.exclude_path.is_empty() {
+        duplicate = duplicate.exclude_paths(arg.exclude_path.iter().cloned());
+    }
+    if let Some(cutoff) = arg.since {
+        duplicate = duplicate.modified_since(Since {
+            cutoff,
+            field: arg.since_field,
+        });
+    }
+    if let Some(files) = arg.sample_files {
+        duplicate = duplicate.limit_files(files);
+    }
+    if let Some(size) = &arg.sample_bytes {
+        duplicate = duplicate.limit_bytes(parse_file_size(size)? as u64);
+    }
+    if let Some(size) = &arg.min_waste {
+        duplicate = duplicate.min_group_waste(parse_file_size(size)? as u64);
+    }
+    if arg.by_allocated {
+        duplicate = duplicate.waste_metric(WasteMetric::Allocated);
+    }
+    if let Some(path) = &arg.status_file {
+        duplicate = duplicate.status_file(path, Duration::from_secs(arg.status_interval));
+    }
+    for path in rest {
+        duplicate = duplicate.add_root(path);
+    }
+    if let Some(reference) = &arg.against {
+        eprintln!("Only report files that already exist in {}.", reference.display());
+        duplicate = duplicate.reference_root(reference);
+    }
+    let max_read_mbps = arg
+        .max_read_mbps
+        .or(arg.nice.then_some(Throttle::NICE_READ_MBPS))
+        .or(config.max_read_mbps);
+    let idle = arg
+        .idle_ms
+        .map(Duration::from_millis)
+        .or(arg.nice.then_some(Throttle::NICE_IDLE));
+    if let Some(mbps) = max_read_mbps {
+        eprintln!("Read rate is limited to {mbps}MB/s.");
+        duplicate = duplicate.max_read_mbps(mbps);
+    }
+    if let Some(idle) = idle {
+        duplicate = duplicate.idle_between_files(idle);
+    }
+    #[cfg(feature = "parallel-hash")]
+    if let Some(threshold) = &arg.mmap_threshold {
+        hash::set_mmap_threshold(parse_file_size(threshold)? as u64);
+    }
+    if arg.unique {
+        let check = if arg.quick { UniqueCheck::Quick } else { UniqueCheck::Hash };
+        duplicate = duplicate.collect_unique(check);
+    }
+    #[cfg(feature = "similar-images")]
+    if arg.similar_images {
+        duplicate = duplicate.find_similar_images(arg.similarity_threshold);
+    }
+    #[cfg(feature = "audio")]
+    if arg.audio {
+        duplicate = duplicate.compare_audio_content();
+    }
+
+    #[cfg(feature = "metrics")]
+    let (metrics, _server) = match &arg.metrics_listen {
+        Some(addr) => {
+            let metrics = Arc::new(Metrics::new("d2fn"));
+            let server = MetricsServer::start(addr, metrics.clone())?;
+            eprintln!("Metrics are served on http://{}/metrics.", server.local_addr());
+            (Some(metrics), Some(server))
+        }
+        None => (None, None),
+    };
+    #[cfg(feature = "metrics")]
+    let progress_metrics = metrics.clone();
 
     let rx = duplicate.enable_status_channel(30);
     std::thread::spawn(move || {
@@ -328,9 +1122,16 @@ fn scan(arg: ScanArg) {
         let (terminal_size::Width(width), _) =
             terminal_size::terminal_size().unwrap_or((terminal_size::Width(80), terminal_size::Height(25)));
 
-        println!("S = Scanned files, D = Duplicates");
+        eprintln!("S = Scanned files, D = Duplicates, I = Ignored by .d2fnignore, H = Hashing a large file");
         // 当 scan 函数结束后, channel 会关闭, 由此子线程 recv 也会关闭.
         while let Ok(status) = rx.recv() {
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &progress_metrics {
+                metrics.set_files_scanned(status.scanned as u64);
+                metrics.set_duplicates(status.duplicated as u64);
+                metrics.set_bytes_processed(status.bytes_read);
+                metrics.set_throughput(status.read_rate);
+            }
             if start.elapsed().as_millis() > delta_milli_sec {
                 print_progress(status, width as usize);
                 delta_milli_sec += 250; // 平均一秒最多刷新 4 次.
@@ -338,73 +1139,668 @@ fn scan(arg: ScanArg) {
         }
     });
 
-    let compare_size = parse_file_size(&arg.compare_size);
+    let compare_size = parse_compare_size(&arg)?;
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &metrics {
+        metrics.set_phase("discover");
+    }
     let instant = Instant::now();
-    duplicate.discover(compare_size).expect("Error occurred while discovering.");
+    duplicate
+        .discover(compare_size)
+        .with_context(|| "error occurred while discovering.".to_string())?;
     let duration = instant.elapsed();
-    println!("\nDiscovering finished, {} elapsed.", display_duration(duration.as_secs()));
+    eprintln!("\nDiscovering finished, {} elapsed.", display_duration(duration.as_secs()));
+    let extent = duplicate.extent();
+    if extent.partial {
+        eprintln!(
+            "The sample budget ran out after {} files, {} hashed. The result is partial.",
+            extent.files,
+            display_file_size(extent.bytes_hashed)
+        );
+    }
+    if duplicate.pruned_dir_count() > 0 {
+        eprintln!("{} directories below --max-depth were skipped.", duplicate.pruned_dir_count());
+    }
+    if duplicate.pruned_entry_count() > 0 {
+        eprintln!(
+            "{} files and directories matched --prune and were skipped.",
+            duplicate.pruned_entry_count()
+        );
+    }
+    if duplicate.excluded_count() > 0 {
+        eprintln!(
+            "{} directories under --exclude-path were skipped.",
+            duplicate.excluded_count()
+        );
+    }
+    if duplicate.skipped_symlink_count() > 0 {
+        eprintln!("{} symbolic links were skipped.", duplicate.skipped_symlink_count());
+    }
+    let walk_errors = duplicate.walk_errors();
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &metrics {
+        metrics.record_errors(walk_errors.len() as u64);
+    }
+    if !walk_errors.is_empty() {
+        eprintln!(
+            "{} paths could not be read and were skipped, the result may be incomplete:",
+            walk_errors.len()
+        );
+        for error in walk_errors.iter().take(10) {
+            eprintln!("  {error}");
+        }
+        if walk_errors.len() > 10 {
+            eprintln!("  ...");
+        }
+    }
+
+    let (hardlink_count, saved) = duplicate
+        .hardlink_groups()
+        .fold((0, 0), |(count, saved), group| (count + 1, saved + group.saved_bytes()));
+    if hardlink_count > 0 {
+        eprintln!(
+            "{hardlink_count} groups are already hardlinked, {} saved.",
+            display_file_size(saved)
+        );
+    }
+
+    #[cfg(feature = "similar-images")]
+    if arg.similar_images {
+        eprintln!(
+            "{} groups of similar images found, {} images could not be decoded.",
+            duplicate.similar_groups().len(),
+            duplicate.undecodable_images().len()
+        );
+    }
+
+    #[cfg(feature = "audio")]
+    if arg.audio {
+        eprintln!(
+            "{} groups of audio with different tags found, {} files were compared as a whole.",
+            duplicate.audio_groups().len(),
+            duplicate.audio_fallbacks().len()
+        );
+    }
 
     if arg.verify {
-        println!("Trying to verify duplicate list, which may take a while...");
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &metrics {
+            metrics.set_phase("verify");
+        }
+        eprintln!("Trying to verify duplicate list, which may take a while...");
         let instant = Instant::now();
-        let conflict_count = duplicate.verify().expect("Error occurred while verifying.");
+        let stats = duplicate
+            .verify()
+            .with_context(|| "error occurred while verifying.".to_string())?;
         let duration = instant.elapsed();
-        println!(
-            "{conflict_count} conflicts detected, costs {}.",
+        eprintln!(
+            "{} groups verified, {} confirmed, {} split, {} read, costs {}.",
+            stats.groups_checked,
+            stats.groups_confirmed,
+            stats.groups_split,
+            display_file_size(stats.bytes_rehashed),
             display_duration(duration.as_secs())
         );
+        eprintln!("{} can be reclaimed.", display_file_size(stats.reclaimable_bytes));
+    }
+    if duplicate.stale_count() > 0 {
+        eprintln!("{} files changed during the scan and were skipped.", duplicate.stale_count());
+    }
+    let (suppressed, wasted) = duplicate.suppressed_groups();
+    if suppressed > 0 {
+        eprintln!(
+            "Suppressed {suppressed} small groups wasting {} total{}, below --min-waste.",
+            display_file_size(wasted),
+            if arg.by_allocated { " on disk" } else { "" }
+        );
+    }
+    if extent.partial {
+        print_estimate(&duplicate.extent(), first);
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &metrics {
+        metrics.set_phase("write");
+    }
+    if arg.unique {
+        let path = arg.output.clone().unwrap_or_else(|| PathBuf::from("unique.txt"));
+        generate_unique_list(&duplicate, &path).with_context(|| "unable to generate unique file list.".to_string())?;
+        duplicate.finish_status_file()?;
+        return Ok(Outcome::Done);
+    }
+    if duplicate.is_cross_mode() {
+        let redundant_count: usize = duplicate.cross_result().map(|group| group.redundant.len()).sum();
+        eprintln!("{redundant_count} files already exist in the reference directory.");
+    }
+    let directories = if arg.dirs {
+        eprintln!("Looking for duplicate directories...");
+        let report = duplicate
+            .directory_matcher()
+            .find()
+            .with_context(|| "error occurred while comparing directories.".to_string())?;
+        write_directory_report(&report, std::io::stderr().lock(), "")?;
+        report
+    } else {
+        DirectoryReport::default()
+    };
+    export_results(&duplicate, &directories, &arg)?;
+    duplicate.finish_status_file()?;
+
+    if duplicate.result().next().is_none() {
+        eprintln!("No duplicates found.");
+        return Ok(Outcome::NoDuplicates);
+    }
+    Ok(Outcome::Done)
+}
+
+fn report(arg: ReportArg) -> Result<Outcome> {
+    if let Some(output) = &arg.html {
+        #[cfg(feature = "thumbnails")]
+        let thumbnail_limit = arg
+            .thumbnails
+            .as_deref()
+            .map(|size| parse_file_size(size).map(|size| size as u64))
+            .transpose()?;
+        #[cfg(not(feature = "thumbnails"))]
+        let thumbnail_limit = None;
+
+        let file = std::fs::File::create(output).with_context(|| format!("failed to create {}.", output.display()))?;
+        let count = report::write_html(&arg.inventory, BufWriter::new(file), thumbnail_limit)
+            .with_context(|| "unable to write html report.".to_string())?;
+        eprintln!("{count} groups written to {}.", output.display());
+        if count == 0 {
+            return Ok(Outcome::NoDuplicates);
+        }
+        return Ok(Outcome::Done);
+    }
+
+    let groups = match arg.group {
+        Some(n) => report::load_group(&arg.inventory, n as usize).map(|group| vec![group]),
+        None => {
+            let metric = if arg.by_allocated {
+                WasteMetric::Allocated
+            } else {
+                WasteMetric::Apparent
+            };
+            report::load(&arg.inventory, arg.top, metric)
+        }
+    }
+    .with_context(|| "unable to load inventory.".to_string())?;
+    let format = match (arg.json, arg.csv) {
+        (true, _) => ReportFormat::Json,
+        (_, true) => ReportFormat::Csv,
+        _ => ReportFormat::Text,
+    };
+
+    report::write(&groups, format, std::io::stdout().lock())?;
+    if groups.is_empty() {
+        return Ok(Outcome::NoDuplicates);
+    }
+    Ok(Outcome::Done)
+}
+
+/// Refuse `inventory` if it was written more than `max_age` days ago, as files may have changed since.
+fn check_age(inventory: &Path, max_age: u64) -> Result<()> {
+    if max_age == 0 {
+        return Ok(());
+    }
+    let modified = std::fs::metadata(inventory)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("unable to stat {}.", inventory.display()))?;
+    let days = modified.elapsed().unwrap_or_default().as_secs() / 86400;
+    if days >= max_age {
+        bail!(
+            "{} was written {days} days ago, run `d2fn refresh` on it first, or pass --max-age {}.",
+            inventory.display(),
+            days + 1
+        );
     }
-    report(&duplicate, &arg).expect("report failed");
+    Ok(())
 }
 
-fn dedup(arg: DedupArg) {
-    let path = &arg.inventory.as_path();
-    let reader = InventoryReader::open(path).expect("unable to open inventory.");
+fn dedup(arg: DedupArg) -> Result<Outcome> {
+    check_age(&arg.inventory, arg.max_age)?;
+    let key = arg.key_file.as_ref().map(HashKey::load).transpose()?;
+    let plan = Plan::from_inventory(&arg.inventory, Resolution::Hardlink, false, key.as_ref(), &arg.roots)
+        .with_context(|| "unable to open inventory.".to_string())?;
+    execute_plan(&plan, false)
+}
+
+fn review(arg: ReviewArg) -> Result<Outcome> {
+    let review = Review::load(&arg.inventory, arg.plan).with_context(|| "unable to load inventory.".to_string())?;
+    review.run().with_context(|| "terminal error.".to_string())?;
+    Ok(Outcome::Done)
+}
 
-    println!("{} in total..", reader.total());
-    for group in reader {
-        let mut group = match group {
-            Ok(g) => g,
-            Err(e) => {
-                eprintln!("error: when read duplicate group, {e}");
-                continue;
+fn apply(arg: ApplyArg) -> Result<Outcome> {
+    let plan = match (&arg.inventory, &arg.plan) {
+        (Some(inventory), _) => {
+            let resolution = match (arg.hardlink, arg.delete) {
+                (true, _) => Resolution::Hardlink,
+                (_, true) => Resolution::Delete,
+                _ => bail!("either --hardlink or --delete is required to apply an inventory."),
+            };
+            check_age(inventory, arg.max_age)?;
+            let key = arg.key_file.as_ref().map(HashKey::load).transpose()?;
+            Plan::from_inventory(inventory, resolution, arg.allow_lossy, key.as_ref(), &arg.roots)
+                .with_context(|| "unable to open inventory.".to_string())?
+        }
+        (None, Some(plan)) => {
+            let plan = Plan::load(plan).with_context(|| "unable to load plan.".to_string())?;
+            let lossy = plan.lossy_actions();
+            if lossy > 0 && !arg.allow_lossy {
+                bail!("the plan replaces {lossy} files with similar, not identical ones, see --allow-lossy.");
             }
-        };
+            plan
+        }
+        (None, None) => unreachable!("either inventory or plan is required by clap."),
+    };
+    execute_plan(&plan, arg.dry_run)
+}
 
-        // 牺牲一次复制, 尽量避免后续的 PathBuf::clone 以提升性能.
-        let source = group.files.swap_remove(0);
-        let src_path = Into::<PathBuf>::into(source.path);
-        for dup in group.files {
-            let destination = Into::<PathBuf>::into(dup.path);
+/// Execute every action, and fail if any of them failed.
+fn execute_plan(plan: &Plan, dry_run: bool) -> Result<Outcome> {
+    if plan.actions.is_empty() {
+        eprintln!("Nothing to do.");
+        return Ok(Outcome::NoDuplicates);
+    }
 
-            let result = std::fs::remove_file(&destination).and_then(|_| std::fs::hard_link(&src_path, &destination));
-            if let Err(e) = result {
-                eprintln!("failed on {} :{e}", dup.ino);
+    eprintln!("{} actions in total..", plan.actions.len());
+    let mut failed = 0;
+    for action in &plan.actions {
+        if let Err(e) = action.execute(dry_run) {
+            tracing::error!("failed on {}: {e}", PathBuf::from(&action.target().path).display());
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} actions failed.");
+    }
+    Ok(Outcome::Done)
+}
+
+fn merge(arg: MergeArg) -> Result<Outcome> {
+    let count = inventory::merge(&arg.inputs, &arg.output).with_context(|| "unable to merge inventories.".to_string())?;
+    eprintln!("{count} groups written to {}.", arg.output.display());
+    if count == 0 {
+        return Ok(Outcome::NoDuplicates);
+    }
+    Ok(Outcome::Done)
+}
+
+fn diff(arg: DiffArg) -> Result<Outcome> {
+    let result = diff::diff(&arg.old, &arg.new, arg.persisting.as_deref())
+        .with_context(|| "unable to compare inventories.".to_string())?;
+    if arg.json {
+        serde_json::to_writer_pretty(std::io::stdout().lock(), &result)?;
+        println!();
+    } else {
+        for (name, category) in [
+            ("Resolved", &result.resolved),
+            ("New", &result.new),
+            ("Persisting", &result.persisting),
+        ] {
+            println!(
+                "{name:<10} {:>8} groups, {}",
+                category.groups,
+                display_file_size(category.wasted)
+            );
+        }
+    }
+    if let Some(path) = &arg.persisting {
+        eprintln!(
+            "{} persisting groups written to {}.",
+            result.persisting.groups,
+            path.display()
+        );
+    }
+    Ok(Outcome::Done)
+}
+
+fn repair(arg: RepairArg) -> Result<Outcome> {
+    let stats = inventory::repair(&arg.inventory, &arg.output)
+        .with_context(|| format!("unable to repair {}.", arg.inventory.display()))?;
+    eprintln!(
+        "{} groups written to {}, {} corrupted groups dropped.",
+        stats.kept,
+        arg.output.display(),
+        stats.lost
+    );
+    if stats.truncated {
+        tracing::warn!("the inventory is truncated, groups after the end are lost.");
+    }
+    Ok(Outcome::Done)
+}
+
+fn convert(arg: ConvertArg) -> Result<Outcome> {
+    let count = match (arg.to, &arg.output) {
+        (ConvertFormat::Json, output) => {
+            let reader = InventoryReader::open(&arg.input).with_context(|| "unable to open inventory.".to_string())?;
+            match output {
+                Some(output) => {
+                    let file =
+                        std::fs::File::create(output).with_context(|| format!("failed to create {}.", output.display()))?;
+                    convert::inventory_to_json(reader, BufWriter::new(file))?
+                }
+                None => convert::inventory_to_json(reader, std::io::stdout().lock())?,
+            }
+        }
+        (ConvertFormat::Inventory, Some(output)) => {
+            let file =
+                std::fs::File::open(&arg.input).with_context(|| format!("failed to open {}.", arg.input.display()))?;
+            let writer = InventoryWriter::create(output)?;
+            let summary = convert::inventory_from_json(std::io::BufReader::new(file), writer, arg.abort_on_error)?;
+            for (_, e) in &summary.failed {
+                tracing::warn!("{e:#}");
+            }
+            if !summary.failed.is_empty() {
+                eprintln!("{} groups skipped.", summary.failed.len());
+            }
+            summary.written as usize
+        }
+        (ConvertFormat::Inventory, None) => bail!("please give the path of the inventory with --out."),
+    };
+    eprintln!("{count} groups converted.");
+    Ok(Outcome::Done)
+}
+
+fn cache_policy(drop_cache: bool) -> CachePolicy {
+    if drop_cache {
+        CachePolicy::DontNeed
+    } else {
+        CachePolicy::Keep
+    }
+}
+
+fn check(arg: CheckArg) -> Result<Outcome> {
+    let key = arg.key_file.as_ref().map(HashKey::load).transpose()?;
+    let (terminal_size::Width(width), _) =
+        terminal_size::terminal_size().unwrap_or((terminal_size::Width(80), terminal_size::Height(25)));
+    let start = Instant::now();
+    let mut last_refresh = Duration::ZERO;
+
+    let cache = cache_policy(arg.drop_cache);
+    let stats = check::verify_inventory(&arg.inventory, key.as_ref(), &arg.roots, cache, |event| match event {
+        CheckEvent::Hashing {
+            path,
+            done,
+            total,
+            bytes_hashed,
+        } => {
+            // 平均一秒最多刷新 4 次.
+            if start.elapsed() > last_refresh + Duration::from_millis(250) {
+                last_refresh = start.elapsed();
+                let status = StatusReport {
+                    read_rate: ((bytes_hashed + done) as f64 / last_refresh.as_secs_f64()) as u64,
+                    hashing_current_file: Some(path.to_string_lossy().to_string()),
+                    hashing_progress: (done, total),
+                    ..Default::default()
+                };
+                print_progress(status, width as usize);
             }
         }
+        CheckEvent::Checked(path, status) => {
+            let reason = match status {
+                FileStatus::Ok => return,
+                FileStatus::Missing => "missing".to_string(),
+                FileStatus::Changed => "changed".to_string(),
+                FileStatus::Unreadable(e) => format!("unreadable, {e}"),
+            };
+            eprintln!("\r{}: {reason}", path.display());
+        }
+    })
+    .with_context(|| "unable to check inventory.".to_string())?;
+
+    eprintln!(
+        "\n{} files checked, {} hashed in {}: {} missing, {} changed, {} unreadable.",
+        stats.files_checked,
+        display_file_size(stats.bytes_hashed),
+        display_duration(start.elapsed().as_secs()),
+        stats.missing,
+        stats.changed,
+        stats.unreadable
+    );
+    if stats.bad_groups > 0 {
+        eprintln!("{} groups could not be read.", stats.bad_groups);
+    }
+    if stats.failed() > 0 {
+        return Err(CheckFailed(stats.failed()).into());
+    }
+    Ok(Outcome::Done)
+}
+
+fn refresh(arg: RefreshArg) -> Result<Outcome> {
+    let key = arg.key_file.as_ref().map(HashKey::load).transpose()?;
+    let output = arg.output.as_ref().unwrap_or(&arg.inventory);
+    let start = Instant::now();
+    let stats = refresh::refresh_inventory(
+        &arg.inventory,
+        output,
+        key.as_ref(),
+        &arg.roots,
+        arg.rehash,
+        cache_policy(arg.drop_cache),
+    )
+    .with_context(|| "unable to refresh inventory.".to_string())?;
+
+    eprintln!(
+        "{} groups kept, {} dropped: {} files gone, {} changed, {} unreadable.",
+        stats.groups_kept, stats.groups_dropped, stats.files_gone, stats.files_changed, stats.files_unreadable
+    );
+    if arg.rehash {
+        eprintln!(
+            "{} hashed in {}.",
+            display_file_size(stats.bytes_hashed),
+            display_duration(start.elapsed().as_secs())
+        );
+    }
+    if stats.bad_groups > 0 {
+        eprintln!("{} groups could not be read.", stats.bad_groups);
+    }
+    eprintln!("Refreshed inventory written to {}.", output.display());
+    if stats.groups_kept == 0 {
+        return Ok(Outcome::NoDuplicates);
+    }
+    Ok(Outcome::Done)
+}
+
+fn upgrade(arg: UpgradeArg) -> Result<Outcome> {
+    let key = arg.key_file.as_ref().map(HashKey::load).transpose()?;
+    let stats = upgrade::upgrade_inventory(
+        &arg.input,
+        &arg.output,
+        key.as_ref(),
+        arg.rehash,
+        cache_policy(arg.drop_cache),
+        |event| match event {
+            upgrade::UpgradeEvent::Missing(path) => eprintln!("missing: {}", path.display()),
+            upgrade::UpgradeEvent::Replaced(path) => eprintln!("replaced: {}", path.display()),
+        },
+    )
+    .with_context(|| format!("unable to upgrade {}.", arg.input.display()))?;
+
+    eprintln!(
+        "{} groups, {} files upgraded from version {}: {} filled in, {} missing, {} replaced.",
+        stats.groups, stats.files, stats.version, stats.filled, stats.missing, stats.replaced
+    );
+    if arg.rehash {
+        eprintln!("{} groups hashed, {} left without a hash.", stats.hashed, stats.unhashed);
     }
+    if stats.bad_groups > 0 {
+        eprintln!("{} groups could not be read.", stats.bad_groups);
+    }
+    eprintln!("Upgraded inventory written to {}.", arg.output.display());
+    Ok(Outcome::Done)
 }
 
-fn hash(arg: HashArg) {
+fn hash(arg: HashArg) -> Result<Outcome> {
     let hash_mode = match (arg.full, arg.hash_size) {
         (true, _) => CompareMode::Full,
         (_, size_str) => {
-            let size_value = parse_file_size(&size_str);
+            let size_value = parse_file_size(&size_str)?;
             CompareMode::Part(size_value)
         }
     };
 
-    let checksum = hash::checksum_file(arg.file, hash_mode).expect("failed on hash::checksum_file.");
-    println!("{checksum}");
+    let key = arg.key_file.as_ref().map(HashKey::load).transpose()?;
+    let checksum = if arg.file == "-" {
+        hash::checksum_reader(std::io::stdin().lock(), hash_mode, arg.algorithm, key.as_ref())
+    } else {
+        hash::checksum_file(&arg.file, hash_mode, arg.algorithm, key.as_ref())
+    }
+    .with_context(|| format!("failed to hash {}", arg.file))?;
+    println!("{}", checksum.hash);
+    Ok(Outcome::Done)
 }
 
-fn main() {
-    let args = Cli::parse();
+fn main() -> ExitCode {
+    let args: Cli = match exit::parse_args() {
+        Ok(args) => args,
+        Err(code) => return code,
+    };
+    let json = matches!(&args.command, Commands::Report(arg) if arg.json)
+        || matches!(&args.command, Commands::Diff(arg) if arg.json);
+    let machine = exit::machine_errors(json);
+    if let Err(e) = logging::init(&args.log, "warn") {
+        return exit::report(ErrorKind::Usage, &format!("{e:#}"), machine);
+    }
 
-    match args.command {
-        Commands::Scan(arg) => scan(arg),
+    let result = match args.command {
+        Commands::Completions(arg) => {
+            completion::print_completions(arg.shell, Cli::command(), &[]);
+            return ExitCode::SUCCESS;
+        }
+        Commands::Scan(arg) => scan(*arg),
+        Commands::Report(arg) => report(arg),
         Commands::Dedup(arg) => dedup(arg),
+        Commands::Review(arg) => review(arg),
+        Commands::Apply(arg) => apply(arg),
+        Commands::Merge(arg) => merge(arg),
+        Commands::Repair(arg) => repair(arg),
+        Commands::Diff(arg) => diff(arg),
+        Commands::Convert(arg) => convert(arg),
+        Commands::Check(arg) => check(arg),
+        Commands::Refresh(arg) => refresh(arg),
+        Commands::Upgrade(arg) => upgrade(arg),
         Commands::Hash(arg) => hash(arg),
+    };
+    match result {
+        Ok(outcome) => {
+            eprintln!("Done.");
+            outcome.into()
+        }
+        Err(e) => exit::report(error_kind(&e), &format!("{e:#}"), machine),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        apply, check_age, error_kind, generate_dedup_script, parse_file_size, shell_quote, CheckFailed, Cli, Commands,
+    };
+    use crate::directory::DirectoryReport;
+    use crate::duplicate::Duplicate;
+    use crate::inventory::DuplicateFile;
+    use crate::metadata::convert_metadata;
+    use crate::plan::{Action, Plan};
+    use clap::Parser;
+    use common::exit::ErrorKind;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_error_kinds() {
+        let e = anyhow::Error::new(CheckFailed(2));
+        assert_eq!(error_kind(&e), ErrorKind::VerificationFailed);
+        assert_eq!(error_kind(&e).exit_code(), 13);
+        assert_eq!(e.to_string(), "2 files failed the check.");
+        assert_eq!(error_kind(&anyhow::anyhow!("unable to open inventory.")).exit_code(), 1);
+    }
+
+    #[test]
+    fn test_check_age() {
+        let path = std::env::temp_dir().join(format!("d2fn-age-{}.d2fn", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(10 * 86400))
+            .unwrap();
+        let e = check_age(&path, 7).unwrap_err();
+        assert!(e.to_string().contains("written 10 days ago"));
+        check_age(&path, 11).unwrap();
+        check_age(&path, 0).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_file_size() {
+        assert_eq!(parse_file_size("0").unwrap(), 0);
+        assert_eq!(parse_file_size("1000").unwrap(), 1000);
+        assert_eq!(parse_file_size("512b").unwrap(), 512);
+        assert_eq!(parse_file_size("4k").unwrap(), 4096);
+        assert_eq!(parse_file_size("16MB").unwrap(), 16 << 20);
+        assert_eq!(parse_file_size("1g").unwrap(), 1 << 30);
+        for invalid in ["", "k", "4t", "-1", "1.5m", "99999999999999999999g"] {
+            assert!(parse_file_size(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_script_quoting() {
+        assert_eq!(shell_quote(Path::new("it's $HOME")), r"'it'\''s $HOME'");
+
+        let root = std::env::temp_dir().join(format!("d2fn-script-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let (plain, odd) = (root.join("plain.pdf"), root.join("it's $HOME; touch x.pdf"));
+        std::fs::write(&plain, "same content").unwrap();
+        std::fs::write(&odd, "same content").unwrap();
+        let mut duplicate = Duplicate::new(&root);
+        duplicate.discover(1024).unwrap();
+        let script = root.join("dedup.sh");
+        generate_dedup_script(&duplicate, &DirectoryReport::default(), &script).unwrap();
+
+        let status = std::process::Command::new("sh")
+            .arg(&script)
+            .current_dir(&root)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            std::fs::metadata(&plain).unwrap().ino(),
+            std::fs::metadata(&odd).unwrap().ino()
+        );
+        assert!(!root.join("x.pdf").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_apply_lossy_plan() {
+        let root = std::env::temp_dir().join(format!("d2fn-apply-lossy-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let scanned = |name: &str, content: &str| {
+            let path = root.join(name);
+            std::fs::write(&path, content).unwrap();
+            DuplicateFile::scanned(&path, &convert_metadata(std::fs::metadata(&path).unwrap()))
+        };
+        let plan = Plan {
+            actions: vec![Action::Replace {
+                source: scanned("photo.jpg", "original"),
+                target: scanned("photo-resized.jpg", "resized"),
+            }],
+        };
+        let path = root.join("plan.d2fn");
+        plan.save(&path).unwrap();
+
+        let run = |args: &[&str]| {
+            let cli =
+                Cli::try_parse_from([&["d2fn", "apply", "--plan", path.to_str().unwrap(), "--dry-run"], args].concat())
+                    .unwrap();
+            match cli.command {
+                Commands::Apply(arg) => apply(arg),
+                _ => unreachable!(),
+            }
+        };
+        let e = run(&[]).err().unwrap();
+        assert!(e.to_string().contains("see --allow-lossy"), "{e}");
+        run(&["--allow-lossy"]).unwrap();
+        std::fs::remove_dir_all(root).unwrap();
     }
-    println!("Done.");
 }
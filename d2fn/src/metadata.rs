@@ -1,5 +1,7 @@
 #[derive(Clone)]
 pub struct FileMetadata {
+    /// ID of device containing file
+    pub dev: u64,
     /// Inode number
     pub ino: u64,
     /// Number of hard links to file
@@ -8,38 +10,167 @@ pub struct FileMetadata {
     pub size: u64,
     /// Allocated blocks, in 512-byte units
     pub blocks: u64,
+    /// Last modification time, in seconds since epoch
+    pub mtime: i64,
+    /// Nanoseconds part of the last modification time
+    pub mtime_nsec: i64,
+    /// File type and permission bits, as in `st_mode`
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
 }
 
-#[cfg(target_os = "freebsd")]
+/// Which size of files their waste is measured in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WasteMetric {
+    /// Size of the content, `st_size`
+    #[default]
+    Apparent,
+    /// Blocks allocated on disk, less than the size on file systems with compression
+    Allocated,
+}
+
+/// Allocation may fall short of the size by this fraction, for metadata rounding or filesystem compression, before a
+/// file is considered sparse.
+const SPARSE_TOLERANCE: u64 = 8;
+
+impl FileMetadata {
+    /// Bytes allocated on disk.
+    pub fn allocated_bytes(&self) -> u64 {
+        self.blocks * 512
+    }
+
+    /// Bytes the file takes on disk, its apparent size if the file system does not count blocks.
+    pub fn disk_usage(&self) -> u64 {
+        match self.blocks {
+            0 => self.size,
+            _ => self.allocated_bytes(),
+        }
+    }
+
+    /// Size of the file measured by `metric`.
+    pub fn size_by(&self, metric: WasteMetric) -> u64 {
+        match metric {
+            WasteMetric::Apparent => self.size,
+            WasteMetric::Allocated => self.disk_usage(),
+        }
+    }
+
+    /// Whether the file has holes: much less space is allocated than its apparent size.
+    pub fn is_sparse(&self) -> bool {
+        let tolerance = (self.size / SPARSE_TOLERANCE).max(64 * 1024);
+        self.allocated_bytes() + tolerance < self.size
+    }
+
+    /// Whether the file looks modified compared to `other`, a newer metadata of the same path.
+    pub fn is_changed(&self, other: &FileMetadata) -> bool {
+        self.size != other.size || self.mtime != other.mtime || self.mtime_nsec != other.mtime_nsec
+    }
+}
+
+#[cfg(unix)]
 pub fn convert_metadata(metadata: std::fs::Metadata) -> FileMetadata {
     use std::os::unix::fs::MetadataExt;
 
+    let dev = metadata.dev();
     let ino = metadata.ino();
     let link_count = metadata.nlink();
     let size = metadata.size();
     let blocks = metadata.blocks();
+    let mtime = metadata.mtime();
+    let mtime_nsec = metadata.mtime_nsec();
 
     FileMetadata {
+        dev,
         ino,
         link_count,
         size,
         blocks,
+        mtime,
+        mtime_nsec,
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
     }
 }
 
-#[cfg(target_os = "linux")]
+/// Without inode numbers, every file gets a distinct made-up one, so no file is mistaken for a hardlink of another.
+#[cfg(not(unix))]
 pub fn convert_metadata(metadata: std::fs::Metadata) -> FileMetadata {
-    use std::os::linux::fs::MetadataExt;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::UNIX_EPOCH;
 
-    let ino = metadata.st_ino();
-    let link_count = metadata.st_nlink();
-    let size = metadata.st_size();
-    let blocks = metadata.st_blocks();
+    static NEXT_INO: AtomicU64 = AtomicU64::new(1);
+
+    let size = metadata.len();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
 
     FileMetadata {
-        ino,
-        link_count,
+        dev: 0,
+        ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+        link_count: 1,
         size,
-        blocks,
+        blocks: size.div_ceil(512),
+        mtime: modified.as_secs() as i64,
+        mtime_nsec: modified.subsec_nanos() as i64,
+        mode: if metadata.permissions().readonly() { 0o444 } else { 0o644 },
+        uid: 0,
+        gid: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert_metadata, FileMetadata, WasteMetric};
+
+    // 在每个 unix 平台上运行, 包括 FreeBSD 与 macOS.
+    #[cfg(unix)]
+    #[test]
+    fn test_convert_metadata() {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = std::env::temp_dir().join(format!("d2fn-metadata-{}", std::process::id()));
+        std::fs::write(&path, "some content").unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let converted = convert_metadata(metadata.clone());
+
+        assert_eq!(converted.size, "some content".len() as u64);
+        assert_eq!((converted.dev, converted.ino), (metadata.dev(), metadata.ino()));
+        assert_eq!(converted.link_count, 1);
+        assert!(!converted.is_changed(&convert_metadata(std::fs::metadata(&path).unwrap())));
+        assert_eq!(converted.mode & 0o170000, 0o100000);
+        assert_eq!((converted.uid, converted.gid), (metadata.uid(), metadata.gid()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_is_sparse() {
+        let metadata = |size, blocks| FileMetadata {
+            dev: 0,
+            ino: 0,
+            link_count: 1,
+            size,
+            blocks,
+            mtime: 0,
+            mtime_nsec: 0,
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+        };
+        // 小文件按块对齐, 不算稀疏
+        assert!(!metadata(100, 8).is_sparse());
+        assert!(!metadata(1 << 30, (1 << 30) / 512).is_sparse());
+        assert!(!metadata(1 << 30, (1 << 30) / 512 * 9 / 10).is_sparse());
+        assert!(metadata(1 << 30, 2048).is_sparse());
+
+        // 压缩后分配的块少于表观大小; 不计块数的文件系统按表观大小
+        assert_eq!(metadata(10 << 20, 6144).size_by(WasteMetric::Allocated), 3 << 20);
+        assert_eq!(metadata(10 << 20, 6144).size_by(WasteMetric::Apparent), 10 << 20);
+        assert_eq!(metadata(100, 0).disk_usage(), 100);
     }
 }
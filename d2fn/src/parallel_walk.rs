@@ -0,0 +1,768 @@
+//! Walk a directory tree on several threads. On wide trees over NFS, `readdir` latency rather than hashing is the
+//! bottleneck, and a single walker keeps only one request in flight.
+//!
+//! Entries are produced in no particular order, unless sorted on a single thread, see [`ParallelWalker::sorted`].
+
+use common::since::Since;
+use std::fs::{DirEntry, FileType, Metadata};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+
+/// Entries buffered between walker threads and the consumer.
+const CHANNEL_SIZE: usize = 4096;
+
+/// Symbolic links followed at most on the way from the root to an entry, see [`SymlinkPolicy::Follow`].
+const MAX_FOLLOW: usize = 8;
+
+/// What to do with symbolic links met during the walk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Leave them out.
+    #[default]
+    Skip,
+    /// Yield and enter their targets. A link to a directory containing it, by device and inode, is not entered, neither
+    /// is a link found below 8 other followed links.
+    Follow,
+    /// Yield links themselves without following, their metadata being the one of the link.
+    Yield,
+}
+
+/// When to yield directories, see [`ParallelWalker::yield_dirs`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirOrder {
+    /// Files only
+    None,
+    /// Before anything in it
+    #[default]
+    PreOrder,
+    /// After everything in it, including subdirectories
+    PostOrder,
+}
+
+pub enum WalkItem {
+    Dir(PathBuf),
+    File(WalkEntry),
+}
+
+/// An entry the walker failed to read. Files under it are missing from the result if `subtree_skipped` is set.
+#[derive(Debug)]
+pub struct WalkError {
+    pub path: PathBuf,
+    pub error: io::Error,
+    pub subtree_skipped: bool,
+}
+
+impl std::fmt::Display for WalkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.subtree_skipped {
+            write!(f, "{} (subtree skipped): {}", self.path.display(), self.error)
+        } else {
+            write!(f, "{}: {}", self.path.display(), self.error)
+        }
+    }
+}
+
+/// A file found by [`ParallelWalker`].
+pub struct WalkEntry {
+    entry: DirEntry,
+    /// The entry is a link which was followed.
+    followed: bool,
+    /// The entry is reached through a followed link, itself or one of its ancestors.
+    via_link: bool,
+}
+
+impl WalkEntry {
+    pub fn path(&self) -> PathBuf {
+        self.entry.path()
+    }
+
+    /// Type of the link target if the entry is a followed link.
+    pub fn file_type(&self) -> io::Result<FileType> {
+        if self.followed {
+            Ok(self.metadata()?.file_type())
+        } else {
+            self.entry.file_type()
+        }
+    }
+
+    /// Metadata of the link target if the entry is a followed link.
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        if self.followed {
+            std::fs::metadata(self.entry.path())
+        } else {
+            self.entry.metadata()
+        }
+    }
+
+    /// Treat the entry, a link to a file, as followed. For walkers which do not follow links themselves.
+    pub fn follow(self) -> Self {
+        Self {
+            followed: true,
+            via_link: true,
+            ..self
+        }
+    }
+
+    /// Whether the path goes through a followed symbolic link, so it's not the canonical path of the file.
+    pub fn via_link(&self) -> bool {
+        self.via_link
+    }
+}
+
+/// Decides whether an entry is skipped, given its path, type and depth below the root, 0 for entries directly in it.
+/// Directories are checked before being entered.
+pub type Prune = dyn Fn(&Path, &FileType, usize) -> bool + Send + Sync;
+
+pub struct ParallelWalker {
+    root: PathBuf,
+    threads: usize,
+    dirs: DirOrder,
+    /// An entry is skipped if any of them returns true.
+    prune: Vec<Arc<Prune>>,
+    symlinks: SymlinkPolicy,
+    since: Option<Since>,
+    sorted: bool,
+}
+
+/// A directory to read.
+struct DirJob {
+    path: PathBuf,
+    /// Depth of its entries
+    depth: usize,
+    via_link: bool,
+    /// The directory itself and those on the way here from the root. `None` unless following links.
+    ancestors: Option<Arc<Ancestor>>,
+    /// Links followed on the way here
+    links: usize,
+    /// Set in post-order mode
+    node: Option<Arc<DirNode>>,
+}
+
+/// A directory entered when following links, identified by device and inode, which bind mounts and links to it share.
+struct Ancestor {
+    dev: u64,
+    ino: u64,
+    parent: Option<Arc<Ancestor>>,
+}
+
+impl Ancestor {
+    fn of(metadata: &Metadata, parent: Option<Arc<Ancestor>>) -> Arc<Self> {
+        Arc::new(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            parent,
+        })
+    }
+
+    /// Whether the directory of `metadata` is this one or one of its ancestors.
+    fn contains(&self, metadata: &Metadata) -> bool {
+        let mut node = Some(self);
+        while let Some(ancestor) = node {
+            if (ancestor.dev, ancestor.ino) == (metadata.dev(), metadata.ino()) {
+                return true;
+            }
+            node = ancestor.parent.as_deref();
+        }
+        false
+    }
+}
+
+/// A directory not completely walked, in post-order mode.
+struct DirNode {
+    path: PathBuf,
+    /// Reads not finished yet: of the directory itself, and of its subdirectories
+    remaining: AtomicUsize,
+    /// `None` for the root
+    parent: Option<Arc<DirNode>>,
+}
+
+impl DirNode {
+    /// Count one read under `node` as finished, and yield every directory completed by it, deepest first. The root is
+    /// not yielded.
+    fn finish(mut node: Arc<DirNode>, sender: &SyncSender<Result<WalkItem, WalkError>>) -> Result<(), ()> {
+        loop {
+            if node.remaining.fetch_sub(1, Ordering::AcqRel) != 1 {
+                return Ok(());
+            }
+            let Some(parent) = node.parent.clone() else {
+                return Ok(());
+            };
+            sender.send(Ok(WalkItem::Dir(node.path.clone()))).map_err(|_| ())?;
+            node = parent;
+        }
+    }
+}
+
+fn is_hidden(path: &Path, _: &FileType, _: usize) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+struct Pending {
+    /// Directories not read yet
+    dirs: Vec<DirJob>,
+    /// Threads reading a directory, which may push more
+    busy: usize,
+    /// Set once the consumer is gone
+    stopped: bool,
+}
+
+/// Directories shared by walker threads. A thread takes one whenever it's idle, the walk ends when there is none
+/// left and no thread is busy.
+struct Queue {
+    pending: Mutex<Pending>,
+    ready: Condvar,
+}
+
+impl Queue {
+    fn take(&self) -> Option<DirJob> {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            if pending.stopped {
+                return None;
+            }
+            if let Some(dir) = pending.dirs.pop() {
+                pending.busy += 1;
+                return Some(dir);
+            }
+            if pending.busy == 0 {
+                self.ready.notify_all();
+                return None;
+            }
+            pending = self.ready.wait(pending).unwrap();
+        }
+    }
+
+    fn push(&self, dir: DirJob) {
+        self.pending.lock().unwrap().dirs.push(dir);
+        self.ready.notify_one();
+    }
+
+    fn done(&self, stop: bool) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.busy -= 1;
+        pending.stopped |= stop;
+        if pending.stopped || (pending.busy == 0 && pending.dirs.is_empty()) {
+            self.ready.notify_all();
+        }
+    }
+}
+
+impl ParallelWalker {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let root = path.as_ref().to_path_buf();
+        if !root.metadata()?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a directory"));
+        }
+        let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+        Ok(Self {
+            root,
+            threads,
+            dirs: DirOrder::PreOrder,
+            prune: Vec::new(),
+            symlinks: SymlinkPolicy::Skip,
+            since: None,
+            sorted: false,
+        })
+    }
+
+    /// Count of walker threads, the count of CPUs by default.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Yield directories before or after their content, in pre-order by default. Except for this, the order of
+    /// entries is unspecified. The root itself is not yielded.
+    pub fn yield_dirs(mut self, order: DirOrder) -> Self {
+        self.dirs = order;
+        self
+    }
+
+    /// Skip files and directories whose name starts with a dot.
+    pub fn filter_hidden_items(self, enable: bool) -> Self {
+        if enable {
+            self.prune_if(is_hidden)
+        } else {
+            self
+        }
+    }
+
+    /// Skip entries for which `prune` returns true, and do not enter such directories. It's called from walker
+    /// threads concurrently.
+    pub fn prune_if(mut self, prune: impl Fn(&Path, &FileType, usize) -> bool + Send + Sync + 'static) -> Self {
+        self.prune.push(Arc::new(prune));
+        self
+    }
+
+    /// How to treat symbolic links, skipped by default.
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Yield the entries of each directory sorted by name bytes, and enter its subdirectories in the same order. With
+    /// one thread, two walks of the same tree then yield the same sequence. Each directory is read whole before its
+    /// entries are yielded, holding them in memory meanwhile: about a hundred bytes plus the name per entry.
+    pub fn sorted(mut self, enable: bool) -> Self {
+        self.sorted = enable;
+        self
+    }
+
+    /// Yield only files changed at or after the cutoff of `since`, as told by a stat of each file. Directories are
+    /// entered regardless of their own times, which do not change with files deeper down.
+    pub fn modified_since(mut self, since: Since) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    fn read_dir(&self, job: DirJob, queue: &Queue, sender: &SyncSender<Result<WalkItem, WalkError>>) -> Result<(), ()> {
+        let DirJob {
+            path: dir,
+            depth,
+            via_link,
+            ancestors,
+            links,
+            node,
+        } = job;
+        // 各线程的条目交错到达, 出错的路径随错误一起发送.
+        let failed = |path: &Path, error, subtree_skipped| {
+            let error = WalkError {
+                path: path.to_path_buf(),
+                error,
+                subtree_skipped,
+            };
+            sender.send(Err(error)).map_err(|_| ())
+        };
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => return failed(&dir, e, true),
+        };
+        let entries: Box<dyn Iterator<Item = io::Result<DirEntry>>> = if self.sorted {
+            let mut entries = entries.collect::<Vec<_>>();
+            // 出错的条目排在最前
+            entries.sort_by_key(|entry| entry.as_ref().ok().map(DirEntry::file_name));
+            Box::new(entries.into_iter())
+        } else {
+            Box::new(entries)
+        };
+        // 排序时子目录读完本目录后再入队, 按名称顺序出栈
+        let mut subdirs = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    failed(&dir, e, false)?;
+                    continue;
+                }
+            };
+            // file_type() 不跟随符号链接
+            let mut file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    failed(&entry.path(), e, false)?;
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let mut link_followed = false;
+            let mut next_ancestors = None;
+            if file_type.is_symlink() {
+                match self.symlinks {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Yield => (),
+                    SymlinkPolicy::Follow => {
+                        // 悬空的链接原样返回
+                        if let Ok(target) = std::fs::metadata(&path) {
+                            if target.is_dir() {
+                                // 目标是已经走过的某个目录, 继续跟随会绕回来.
+                                if links >= MAX_FOLLOW || ancestors.as_ref().is_some_and(|a| a.contains(&target)) {
+                                    continue;
+                                }
+                                next_ancestors = Some(Ancestor::of(&target, ancestors.clone()));
+                            }
+                            file_type = target.file_type();
+                            link_followed = true;
+                        }
+                    }
+                }
+            } else if let (true, Some(ancestors)) = (file_type.is_dir(), &ancestors) {
+                match entry.metadata() {
+                    Ok(metadata) => next_ancestors = Some(Ancestor::of(&metadata, Some(ancestors.clone()))),
+                    Err(e) => {
+                        failed(&path, e, true)?;
+                        continue;
+                    }
+                }
+            }
+            if self.prune.iter().any(|prune| prune(&path, &file_type, depth)) {
+                continue;
+            }
+            let via_link = via_link || link_followed;
+            if file_type.is_dir() {
+                if self.dirs == DirOrder::PreOrder {
+                    sender.send(Ok(WalkItem::Dir(path.clone()))).map_err(|_| ())?;
+                }
+                // 子目录读完之前, 当前目录不算完成
+                let node = node.as_ref().map(|parent| {
+                    parent.remaining.fetch_add(1, Ordering::AcqRel);
+                    Arc::new(DirNode {
+                        path: path.clone(),
+                        remaining: AtomicUsize::new(1),
+                        parent: Some(parent.clone()),
+                    })
+                });
+                let job = DirJob {
+                    path,
+                    depth: depth + 1,
+                    via_link,
+                    ancestors: next_ancestors,
+                    links: links + link_followed as usize,
+                    node,
+                };
+                if self.sorted {
+                    subdirs.push(job);
+                } else {
+                    queue.push(job);
+                }
+                continue;
+            }
+            let entry = WalkEntry {
+                entry,
+                followed: link_followed,
+                via_link,
+            };
+            // 无法 stat 的文件照常返回, 由调用方报告错误. 未跟随的链接交给调用方判断.
+            if let (Some(since), false) = (&self.since, file_type.is_symlink()) {
+                if entry.metadata().is_ok_and(|metadata| !since.includes(&metadata)) {
+                    continue;
+                }
+            }
+            sender.send(Ok(WalkItem::File(entry))).map_err(|_| ())?;
+        }
+        for job in subdirs.into_iter().rev() {
+            queue.push(job);
+        }
+        Ok(())
+    }
+}
+
+impl IntoIterator for ParallelWalker {
+    type Item = Result<WalkItem, WalkError>;
+    type IntoIter = ParallelWalk;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_SIZE);
+        let ancestors = match self.symlinks {
+            SymlinkPolicy::Follow => std::fs::metadata(&self.root).ok().map(|m| Ancestor::of(&m, None)),
+            _ => None,
+        };
+        let root = DirJob {
+            path: self.root.clone(),
+            depth: 0,
+            via_link: false,
+            ancestors,
+            links: 0,
+            node: (self.dirs == DirOrder::PostOrder).then(|| {
+                Arc::new(DirNode {
+                    path: self.root.clone(),
+                    remaining: AtomicUsize::new(1),
+                    parent: None,
+                })
+            }),
+        };
+        let queue = Arc::new(Queue {
+            pending: Mutex::new(Pending {
+                dirs: vec![root],
+                busy: 0,
+                stopped: false,
+            }),
+            ready: Condvar::new(),
+        });
+        let walker = Arc::new(self);
+
+        for _ in 0..walker.threads {
+            let (walker, queue, sender) = (walker.clone(), queue.clone(), sender.clone());
+            std::thread::spawn(move || {
+                while let Some(dir) = queue.take() {
+                    let node = dir.node.clone();
+                    // 发送失败说明消费者已经不需要了
+                    let mut stop = walker.read_dir(dir, &queue, &sender).is_err();
+                    if let (false, Some(node)) = (stop, node) {
+                        stop = DirNode::finish(node, &sender).is_err();
+                    }
+                    queue.done(stop);
+                }
+            });
+        }
+        ParallelWalk { receiver }
+    }
+}
+
+/// Entries of a [`ParallelWalker`]. The iterator ends once every thread is done, dropping it stops the threads.
+pub struct ParallelWalk {
+    receiver: Receiver<Result<WalkItem, WalkError>>,
+}
+
+impl Iterator for ParallelWalk {
+    type Item = Result<WalkItem, WalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DirOrder, ParallelWalker, SymlinkPolicy, WalkEntry, WalkError, WalkItem};
+    use common::since::{Since, TimeField};
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    fn file(item: Result<WalkItem, WalkError>) -> Option<WalkEntry> {
+        match item.unwrap() {
+            WalkItem::File(entry) => Some(entry),
+            WalkItem::Dir(_) => None,
+        }
+    }
+
+    #[test]
+    fn test_parallel_walk() {
+        let root = std::env::temp_dir().join(format!("d2fn-test-{}-parallel-walk", std::process::id()));
+        let mut expected = BTreeSet::new();
+        for i in 0..20 {
+            for j in 0..5 {
+                let path = root.join(format!("d{i}/e{j}/f.txt"));
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, "content").unwrap();
+                expected.insert(path);
+            }
+        }
+        std::fs::create_dir_all(root.join(".hidden")).unwrap();
+        std::fs::write(root.join(".hidden/f.txt"), "content").unwrap();
+        std::fs::write(root.join(".f.txt"), "content").unwrap();
+
+        let walk = |threads| {
+            ParallelWalker::open(&root)
+                .unwrap()
+                .threads(threads)
+                .yield_dirs(DirOrder::None)
+                .filter_hidden_items(true)
+                .into_iter()
+                .filter_map(file)
+                .map(|entry| entry.path())
+                .collect::<BTreeSet<PathBuf>>()
+        };
+        assert_eq!(walk(1), expected);
+        assert_eq!(walk(8), expected);
+
+        // d0 不进入, d1 下只跳过第二层的 e0
+        let d1 = root.join("d1");
+        let pruned = ParallelWalker::open(&root)
+            .unwrap()
+            .yield_dirs(DirOrder::None)
+            .filter_hidden_items(true)
+            .prune_if(move |path, file_type, depth| {
+                let name = path.file_name().unwrap();
+                file_type.is_dir() && (name == "d0" || (depth == 1 && name == "e0" && path.starts_with(&d1)))
+            })
+            .into_iter()
+            .filter_map(file)
+            .map(|entry| entry.path())
+            .collect::<BTreeSet<PathBuf>>();
+        assert_eq!(pruned.len(), expected.len() - 5 - 1);
+        assert!(pruned
+            .iter()
+            .all(|p| !p.starts_with(root.join("d0")) && !p.starts_with(root.join("d1/e0"))));
+
+        // 提前结束遍历, 线程应当自行退出
+        let first = ParallelWalker::open(&root).unwrap().threads(4).into_iter().next();
+        assert!(first.is_some());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_sorted() {
+        let root = std::env::temp_dir().join(format!("d2fn-test-{}-sorted", std::process::id()));
+        for path in ["c/z.txt", "b.txt", "a/y.txt", "a/x.txt", "a/b/w.txt"] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "content").unwrap();
+        }
+
+        let walk = || {
+            ParallelWalker::open(&root)
+                .unwrap()
+                .threads(1)
+                .sorted(true)
+                .into_iter()
+                .map(|item| match item.unwrap() {
+                    WalkItem::Dir(path) => path,
+                    WalkItem::File(entry) => entry.path(),
+                })
+                .map(|path| path.strip_prefix(&root).unwrap().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        let first = walk();
+        assert_eq!(
+            first,
+            ["a", "b.txt", "c", "a/b", "a/x.txt", "a/y.txt", "a/b/w.txt", "c/z.txt"]
+        );
+        assert_eq!(walk(), first);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_modified_since() {
+        let root = std::env::temp_dir().join(format!("d2fn-test-{}-modified-since", std::process::id()));
+        let old = SystemTime::now() - Duration::from_secs(30 * 86400);
+        for path in ["a/old.txt", "a/b/new.txt", "new.txt", "old.txt"] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let file = std::fs::File::create(&path).unwrap();
+            if path.ends_with("old.txt") {
+                file.set_modified(old).unwrap();
+            }
+        }
+        // 目录的修改时间早于截止时间, 仍会进入
+        for dir in ["a/b", "a"] {
+            std::fs::File::open(root.join(dir)).unwrap().set_modified(old).unwrap();
+        }
+
+        let walk = |since| {
+            ParallelWalker::open(&root)
+                .unwrap()
+                .yield_dirs(DirOrder::None)
+                .modified_since(since)
+                .into_iter()
+                .filter_map(file)
+                .map(|entry| entry.path().strip_prefix(&root).unwrap().to_path_buf())
+                .collect::<BTreeSet<PathBuf>>()
+        };
+        let cutoff = SystemTime::now() - Duration::from_secs(86400);
+        let since = Since {
+            cutoff,
+            field: TimeField::Mtime,
+        };
+        assert_eq!(walk(since), BTreeSet::from(["a/b/new.txt", "new.txt"].map(PathBuf::from)));
+        // 修改时间被改回过去, 但 ctime 仍是刚才
+        let since = Since {
+            cutoff,
+            field: TimeField::Ctime,
+        };
+        assert_eq!(walk(since).len(), 4);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_dir_order() {
+        let root = std::env::temp_dir().join(format!("d2fn-test-{}-dir-order", std::process::id()));
+        for path in ["a/b/c/f.txt", "a/b/f.txt", "a/skip/f.txt", "a/f.txt", "d/f.txt"] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "content").unwrap();
+        }
+        std::fs::create_dir_all(root.join("a/b/empty")).unwrap();
+
+        let walk = |order| {
+            ParallelWalker::open(&root)
+                .unwrap()
+                .threads(4)
+                .yield_dirs(order)
+                .prune_if(|path, _, _| path.ends_with("skip"))
+                .into_iter()
+                .map(|item| match item.unwrap() {
+                    WalkItem::Dir(path) => (path, true),
+                    WalkItem::File(entry) => (entry.path(), false),
+                })
+                .collect::<Vec<_>>()
+        };
+        let dirs = |items: &[(PathBuf, bool)]| {
+            let mut dirs = items
+                .iter()
+                .filter(|(_, is_dir)| *is_dir)
+                .map(|(p, _)| p.clone())
+                .collect::<Vec<_>>();
+            dirs.sort();
+            dirs
+        };
+        let expected_dirs = ["a", "a/b", "a/b/c", "a/b/empty", "d"].map(|p| root.join(p)).to_vec();
+
+        // 每个目录都在其下所有条目之前, 或之后
+        let pre = walk(DirOrder::PreOrder);
+        assert_eq!(dirs(&pre), expected_dirs);
+        for (i, (path, _)) in pre.iter().enumerate() {
+            assert!(pre[..i].iter().all(|(other, _)| !other.starts_with(path)));
+        }
+        let post = walk(DirOrder::PostOrder);
+        assert_eq!(dirs(&post), expected_dirs);
+        assert_eq!(post.len(), pre.len());
+        for (i, (path, _)) in post.iter().enumerate() {
+            assert!(post[i + 1..].iter().all(|(other, _)| !other.starts_with(path)));
+        }
+        assert!(walk(DirOrder::None).iter().all(|(_, is_dir)| !is_dir));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let root = std::env::temp_dir().join(format!("d2fn-test-{}-symlinks", std::process::id()));
+        std::fs::create_dir_all(root.join("data")).unwrap();
+        std::fs::write(root.join("data/f.txt"), "content").unwrap();
+        symlink(root.join("data/f.txt"), root.join("file-link")).unwrap();
+        symlink(root.join("data"), root.join("dir-link")).unwrap();
+        // 指向根目录, 跟随会无限循环
+        symlink(&root, root.join("data/loop")).unwrap();
+        symlink(root.join("missing"), root.join("dangling")).unwrap();
+
+        let walk = |policy| {
+            let mut entries = ParallelWalker::open(&root)
+                .unwrap()
+                .yield_dirs(DirOrder::None)
+                .symlinks(policy)
+                .into_iter()
+                .filter_map(file)
+                .map(|entry| {
+                    let path = entry.path().strip_prefix(&root).unwrap().to_path_buf();
+                    (path.to_string_lossy().to_string(), entry.via_link())
+                })
+                .collect::<Vec<_>>();
+            entries.sort();
+            entries
+        };
+        let entry = |path: &str, via_link| (path.to_string(), via_link);
+
+        assert_eq!(walk(SymlinkPolicy::Skip), vec![entry("data/f.txt", false)]);
+        assert_eq!(
+            walk(SymlinkPolicy::Yield),
+            vec![
+                entry("dangling", false),
+                entry("data/f.txt", false),
+                entry("data/loop", false),
+                entry("dir-link", false),
+                entry("file-link", false),
+            ]
+        );
+        assert_eq!(
+            walk(SymlinkPolicy::Follow),
+            vec![
+                entry("dangling", false),
+                entry("data/f.txt", false),
+                entry("dir-link/f.txt", true),
+                entry("file-link", true),
+            ]
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+//! Action plan: decisions made in review, executed by `d2fn apply --plan`.
+
+use anyhow::{bail, Context, Result};
+use bincode::{Decode, Encode};
+use std::fs::{File, Metadata};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::hash::HashKey;
+use crate::inventory::{DuplicateFile, InventoryReader};
+
+const PLAN_MAGIC: &[u8; 8] = b"D2FNPLAN";
+/// Version 2 records metadata of files, see [`DuplicateFile`]. Version 3 adds the root of files, always `None`.
+/// Version 4 records the file kept when deleting a copy, version 5 nanoseconds of modification times.
+const PLAN_VERSION: u8 = 0x05;
+
+#[derive(Encode, Decode, Clone)]
+pub enum Action {
+    /// Replace `target` with a hardlink to `source`.
+    Hardlink { source: DuplicateFile, target: DuplicateFile },
+    /// Remove `target`, a copy of `source` which is kept.
+    Delete { source: DuplicateFile, target: DuplicateFile },
+    /// Replace `target` with a hardlink to `source`, though their content differs. See `--allow-lossy`.
+    Replace { source: DuplicateFile, target: DuplicateFile },
+}
+
+/// What to do with the extra copies, when a plan is made from an inventory directly.
+#[derive(Clone, Copy)]
+pub enum Resolution {
+    Hardlink,
+    Delete,
+}
+
+#[derive(Encode, Decode, Default)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+}
+
+impl Plan {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = File::create(path)?;
+        let mut writer = BufWriter::new(file);
+
+        writer.write_all(PLAN_MAGIC)?;
+        writer.write_all(&[PLAN_VERSION])?;
+        bincode::encode_into_std_write(self, &mut writer, bincode::config::standard())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Keep the first file of each group in an inventory, and resolve the others. Unreadable groups are skipped, so
+    /// are groups of similar but not identical files unless `allow_lossy`, and groups whose files were compared only
+    /// by a prefix. The inventory must be hashed with `key`. Relative paths are anchored to `roots` if given.
+    pub fn from_inventory<P: AsRef<Path>>(
+        inventory: P,
+        resolution: Resolution,
+        allow_lossy: bool,
+        key: Option<&HashKey>,
+        roots: &[PathBuf],
+    ) -> Result<Self> {
+        let reader = InventoryReader::open(inventory)?.anchor_to(roots)?;
+        reader.check_key(key)?;
+        let mut actions = Vec::new();
+        let mut lossy_groups = 0;
+        let mut prefix_groups = 0;
+
+        for group in reader {
+            let mut group = match group {
+                Ok(g) => g,
+                Err(e) => {
+                    tracing::error!("unable to read duplicate group: {e}");
+                    continue;
+                }
+            };
+            if group.files.len() < 2 {
+                continue;
+            }
+            if group.similar && !allow_lossy {
+                lossy_groups += 1;
+                continue;
+            }
+            // 只有前缀相同的文件未必相同, 不能据此删除或替换.
+            if !group.similar && !group.whole {
+                prefix_groups += 1;
+                continue;
+            }
+
+            let source = group.files.swap_remove(0);
+            // 与保留文件同一 inode 的路径已是硬链接, 无需处理.
+            let linked = |f: &DuplicateFile| f.ino == source.ino && f.dev == source.dev;
+            for target in group.files.into_iter().filter(|f| !linked(f)) {
+                let action = match resolution {
+                    Resolution::Hardlink if group.similar => Action::Replace {
+                        source: source.clone(),
+                        target,
+                    },
+                    Resolution::Hardlink => Action::Hardlink {
+                        source: source.clone(),
+                        target,
+                    },
+                    Resolution::Delete => Action::Delete {
+                        source: source.clone(),
+                        target,
+                    },
+                };
+                actions.push(action);
+            }
+        }
+        if lossy_groups > 0 {
+            tracing::warn!("{lossy_groups} groups of similar, not identical files are skipped, see --allow-lossy.");
+        }
+        if prefix_groups > 0 {
+            tracing::warn!("{prefix_groups} groups compared only by a prefix are skipped, scan again with --verify.");
+        }
+        Ok(Self { actions })
+    }
+
+    /// Count of actions replacing a file with another of different content, see [`Action::Replace`].
+    pub fn lossy_actions(&self) -> usize {
+        self.actions
+            .iter()
+            .filter(|action| matches!(action, Action::Replace { .. }))
+            .count()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0u8; PLAN_MAGIC.len() + 1];
+        reader
+            .read_exact(&mut header)
+            .with_context(|| "reading header.".to_string())?;
+        if &header[..PLAN_MAGIC.len()] != PLAN_MAGIC {
+            bail!("not a plan file.");
+        }
+        if header[PLAN_MAGIC.len()] != PLAN_VERSION {
+            bail!(
+                "unsupported plan version {}, please review the inventory again.",
+                header[PLAN_MAGIC.len()]
+            );
+        }
+        let plan = bincode::decode_from_std_read(&mut reader, bincode::config::standard())?;
+        Ok(plan)
+    }
+}
+
+/// Check the file is still the one recorded, and return its metadata.
+fn check_unchanged(file: &DuplicateFile) -> Result<(PathBuf, Metadata)> {
+    let path = PathBuf::from(&file.path);
+    let metadata = std::fs::metadata(&path).with_context(|| format!("unable to stat {}", path.display()))?;
+
+    // 清单生成后, 文件可能已被改写或替换. 修改时间比较到纳秒, 同一秒内原地改写为同样大小也能发现.
+    if file.is_changed(&metadata) {
+        bail!("{} changed since scan.", path.display());
+    }
+    Ok((path, metadata))
+}
+
+fn same_inode(a: &Metadata, b: &Metadata) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/// Replace `target` with a hardlink to `source`. The link is made under a temporary name in the directory of `target`
+/// and renamed over it, so `target` is left as it is if linking fails.
+fn replace_with_link(source: &Path, target: &Path) -> std::io::Result<()> {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(target.file_name().unwrap_or_default());
+    name.push(format!(".d2fn-{}", std::process::id()));
+    let temporary = target.with_file_name(name);
+
+    std::fs::hard_link(source, &temporary)?;
+    std::fs::rename(&temporary, target).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temporary);
+    })
+}
+
+impl Action {
+    pub fn target(&self) -> &DuplicateFile {
+        match self {
+            Action::Hardlink { target, .. } => target,
+            Action::Delete { target, .. } => target,
+            Action::Replace { target, .. } => target,
+        }
+    }
+
+    /// Execute the action, after checking the files involved are unchanged. Nothing is modified if `dry_run`.
+    pub fn execute(&self, dry_run: bool) -> Result<()> {
+        match self {
+            Action::Hardlink { source, target } => {
+                let (src_path, src_meta) = check_unchanged(source)?;
+                let (dst_path, dst_meta) = check_unchanged(target)?;
+                if same_inode(&src_meta, &dst_meta) {
+                    return Ok(());
+                }
+                if src_meta.len() != dst_meta.len() {
+                    bail!("{} changed since scan.", dst_path.display());
+                }
+                if !dry_run {
+                    replace_with_link(&src_path, &dst_path)?;
+                }
+            }
+            Action::Delete { source, target } => {
+                // 保留的文件被改写或删除后, 不能再删除其副本
+                let (src_path, _) = check_unchanged(source)?;
+                let (dst_path, _) = check_unchanged(target)?;
+                if src_path == dst_path {
+                    bail!("{} is the file kept.", dst_path.display());
+                }
+                if !dry_run {
+                    std::fs::remove_file(dst_path)?;
+                }
+            }
+            Action::Replace { source, target } => {
+                let (src_path, src_meta) = check_unchanged(source)?;
+                let (dst_path, dst_meta) = check_unchanged(target)?;
+                if same_inode(&src_meta, &dst_meta) {
+                    return Ok(());
+                }
+                if !dry_run {
+                    replace_with_link(&src_path, &dst_path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_unchanged, replace_with_link, Action, Plan, Resolution};
+    use crate::inventory::{DuplicateFile, DuplicateGroup, InventoryWriter};
+    use crate::metadata::convert_metadata;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn scanned(path: &Path) -> DuplicateFile {
+        DuplicateFile::scanned(path, &convert_metadata(std::fs::metadata(path).unwrap()))
+    }
+
+    #[test]
+    fn test_from_inventory() {
+        let file = |ino, dev, path: &str| {
+            let mut file = DuplicateFile::new(ino, Path::new(path));
+            file.dev = Some(dev);
+            file
+        };
+        let group = |files, whole| DuplicateGroup {
+            files,
+            similar: false,
+            hash: None,
+            whole,
+        };
+        let path = std::env::temp_dir().join(format!("d2fn-plan-inventory-{}", std::process::id()));
+        InventoryWriter::create(&path)
+            .unwrap()
+            .export(
+                [
+                    // 另一文件系统上的文件可能恰好有相同的 inode 号
+                    group(vec![file(1, 1, "/a"), file(1, 1, "/b"), file(1, 2, "/c")], true),
+                    group(vec![file(2, 1, "/d"), file(3, 1, "/e")], false),
+                ]
+                .into_iter(),
+            )
+            .unwrap();
+
+        let plan = Plan::from_inventory(&path, Resolution::Delete, false, None, &[]).unwrap();
+        assert_eq!(plan.actions.len(), 1);
+        assert_eq!(PathBuf::from(&plan.actions[0].target().path), Path::new("/c"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_check_unchanged() {
+        let path = std::env::temp_dir().join(format!("d2fn-plan-unchanged-{}", std::process::id()));
+        std::fs::write(&path, "content").unwrap();
+        let modified = UNIX_EPOCH + Duration::new(1_690_000_000, 100);
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+        let recorded = scanned(&path);
+        check_unchanged(&recorded).unwrap();
+
+        // 原地改写为同样大小, 修改时间在同一秒内
+        std::fs::write(&path, "CONTENT").unwrap();
+        file.set_modified(modified + Duration::from_nanos(100)).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().mtime(), 1_690_000_000);
+        assert!(check_unchanged(&recorded).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_delete() {
+        let root = std::env::temp_dir().join(format!("d2fn-plan-delete-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let (kept, copy) = (root.join("kept"), root.join("copy"));
+        std::fs::write(&kept, "content").unwrap();
+        std::fs::write(&copy, "content").unwrap();
+        let action = Action::Delete {
+            source: scanned(&kept),
+            target: scanned(&copy),
+        };
+
+        // 保留的文件已被删除, 副本不能再删
+        std::fs::rename(&kept, root.join("moved")).unwrap();
+        assert!(action.execute(false).is_err());
+        assert!(copy.exists());
+        std::fs::rename(root.join("moved"), &kept).unwrap();
+        action.execute(false).unwrap();
+        assert!(!copy.exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_replace_with_link() {
+        let root = std::env::temp_dir().join(format!("d2fn-plan-link-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let (kept, copy) = (root.join("kept"), root.join("copy"));
+        std::fs::write(&kept, "content").unwrap();
+        std::fs::write(&copy, "content").unwrap();
+
+        replace_with_link(&kept, &copy).unwrap();
+        assert_eq!(
+            std::fs::metadata(&copy).unwrap().ino(),
+            std::fs::metadata(&kept).unwrap().ino()
+        );
+        // 链接失败时, 目标保持原样
+        std::fs::write(&copy, "other").unwrap();
+        assert!(replace_with_link(&root.join("missing"), &copy).is_err());
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "other");
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 2);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! Dropping files of an inventory which changed since the scan, before it is applied.
+//!
+//! Each file is looked up again and compared with its recorded metadata, see [`DuplicateFile::is_changed`]. Files
+//! gone or changed are dropped, and so are groups left with less than two files. Survivors may be hashed again.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::check::majority;
+use crate::hash::{checksum_file_throttled, CachePolicy, CompareMode, Digest, HashAlgorithm, HashKey};
+use crate::inventory::{DuplicateFile, DuplicateGroup, InventoryReader, InventoryWriter};
+
+#[derive(Default, Debug)]
+pub struct RefreshStats {
+    pub groups_kept: usize,
+    pub groups_dropped: usize,
+    pub files_gone: usize,
+    /// Files of another size, modification time or inode, or another content if hashed again
+    pub files_changed: usize,
+    pub files_unreadable: usize,
+    /// Groups which could not be decoded
+    pub bad_groups: usize,
+    pub bytes_hashed: u64,
+}
+
+/// Files of `group` still as recorded, and hashed to the same content if `rehash`.
+fn refresh_group(
+    group: DuplicateGroup,
+    key: Option<&HashKey>,
+    rehash: bool,
+    cache: CachePolicy,
+    stats: &mut RefreshStats,
+) -> Vec<DuplicateFile> {
+    let mut files = Vec::with_capacity(group.files.len());
+    for file in group.files {
+        match std::fs::metadata(PathBuf::from(&file.path)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => stats.files_gone += 1,
+            Err(_) => stats.files_unreadable += 1,
+            Ok(metadata) if file.is_changed(&metadata) => stats.files_changed += 1,
+            Ok(_) => files.push(file),
+        }
+    }
+    // 相似文件的内容本就不同, 不再比较哈希
+    if !rehash || group.similar || files.len() < 2 {
+        return files;
+    }
+
+    let mode = match group.hash.and_then(|h| h.prefix) {
+        Some(prefix) => CompareMode::Part(prefix as usize),
+        None => CompareMode::Full,
+    };
+    let mut digests = Vec::with_capacity(files.len());
+    for file in &files {
+        let path = PathBuf::from(&file.path);
+        match checksum_file_throttled(&path, mode, HashAlgorithm::Blake3, key, cache, None, None) {
+            Ok(checksum) => {
+                stats.bytes_hashed += checksum.bytes_hashed;
+                digests.push(Some(checksum.hash));
+            }
+            Err(e) => {
+                tracing::warn!(path = %path.display(), "unable to hash: {e:#}");
+                stats.files_unreadable += 1;
+                digests.push(None);
+            }
+        }
+    }
+    let expected = match group.hash {
+        Some(hash) => Some(Digest::Blake3(blake3::Hash::from(hash.blake3))),
+        None => majority(&digests),
+    };
+    files
+        .into_iter()
+        .zip(digests)
+        .filter(|(_, digest)| match digest {
+            Some(digest) if Some(*digest) != expected => {
+                stats.files_changed += 1;
+                false
+            }
+            Some(_) => true,
+            // 已计入无法读取
+            None => false,
+        })
+        .map(|(file, _)| file)
+        .collect()
+}
+
+/// Write the groups of `input` still valid to `output`, which may be `input` itself: the inventory is written to a
+/// temporary file next to `output` first, and renamed over it once complete. Relative paths are anchored to `roots`
+/// if given, see [`InventoryReader::anchor_to`], and recorded relative to them again.
+pub fn refresh_inventory(
+    input: &Path,
+    output: &Path,
+    key: Option<&HashKey>,
+    roots: &[PathBuf],
+    rehash: bool,
+    cache: CachePolicy,
+) -> Result<RefreshStats> {
+    let _span = tracing::info_span!("refresh").entered();
+    let reader = InventoryReader::open(input)?.read_only_sequential().anchor_to(roots)?;
+    reader.check_key(key)?;
+    let key_id = reader.key_id().unwrap_or(0);
+    let scan_roots = reader.roots().to_vec();
+    let relative_roots = scan_roots.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+
+    let mut temporary = output.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut writer = InventoryWriter::create(&temporary)
+        .with_context(|| format!("unable to create {}.", temporary.display()))?
+        .key_id(key_id)
+        .scan_roots(scan_roots.clone());
+
+    let mut stats = RefreshStats::default();
+    let groups = reader.filter_map(|group| {
+        let group = match group {
+            Ok(group) => group,
+            Err(e) => {
+                tracing::error!("unable to read duplicate group: {e}");
+                stats.bad_groups += 1;
+                return None;
+            }
+        };
+        let (similar, hash, whole) = (group.similar, group.hash, group.whole);
+        let files = refresh_group(group, key, rehash, cache, &mut stats);
+        if files.len() < 2 {
+            stats.groups_dropped += 1;
+            return None;
+        }
+        stats.groups_kept += 1;
+        let files = files.into_iter().map(|file| file.relative_to(&relative_roots)).collect();
+        Some(DuplicateGroup {
+            files,
+            similar,
+            hash,
+            whole,
+        })
+    });
+    writer.export(groups)?;
+    std::fs::rename(&temporary, output).with_context(|| format!("unable to replace {}.", output.display()))?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::refresh_inventory;
+    use crate::hash::CachePolicy;
+    use crate::inventory::{DuplicateFile, DuplicateGroup, InventoryReader, InventoryWriter};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_refresh_inventory() {
+        let dir = std::env::temp_dir().join(format!("d2fn-refresh-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = ["a", "b", "c", "d", "e"].map(|name| dir.join(name));
+        for path in &paths {
+            std::fs::write(path, "same content").unwrap();
+        }
+        let group = |paths: &[PathBuf]| DuplicateGroup {
+            files: paths
+                .iter()
+                .map(|path| {
+                    let metadata = crate::metadata::convert_metadata(std::fs::metadata(path).unwrap());
+                    DuplicateFile::scanned(path, &metadata)
+                })
+                .collect(),
+            similar: false,
+            hash: None,
+            whole: false,
+        };
+        let inventory = dir.join("inventory");
+        InventoryWriter::create(&inventory)
+            .unwrap()
+            .export([group(&paths[..3]), group(&paths[3..])].into_iter())
+            .unwrap();
+        // c 被删除, d 大小改变; 第二组只剩一个文件
+        std::fs::remove_file(&paths[2]).unwrap();
+        std::fs::write(&paths[3], "other content").unwrap();
+
+        let output = dir.join("refreshed");
+        let stats = refresh_inventory(&inventory, &output, None, &[], false, CachePolicy::Keep).unwrap();
+        assert_eq!((stats.groups_kept, stats.groups_dropped), (1, 1));
+        assert_eq!((stats.files_gone, stats.files_changed, stats.files_unreadable), (1, 1, 0));
+        let groups = InventoryReader::open(&output)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(groups.len(), 1);
+        let kept = groups[0]
+            .files
+            .iter()
+            .map(|file| PathBuf::from(&file.path))
+            .collect::<Vec<_>>();
+        assert_eq!(kept, paths[..2]);
+        assert!(!dir.join("refreshed.tmp").exists());
+
+        // 内容被改写但大小和修改时间不变, 仅重新哈希时发现
+        let mtime = std::fs::metadata(&paths[1]).unwrap().modified().unwrap();
+        std::fs::write(&paths[1], "SAME CONTENT").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&paths[1])
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let stats = refresh_inventory(&output, &output, None, &[], false, CachePolicy::Keep).unwrap();
+        assert_eq!(stats.groups_kept, 1);
+        let stats = refresh_inventory(&output, &output, None, &[], true, CachePolicy::Keep).unwrap();
+        assert_eq!((stats.groups_kept, stats.files_changed), (0, 1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
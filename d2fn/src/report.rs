@@ -0,0 +1,424 @@
+//! Summarize an inventory, for people or for other programs.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::display_file_size;
+use crate::inventory::{DuplicateGroup, InventoryReader};
+use crate::metadata::{convert_metadata, FileMetadata, WasteMetric};
+
+#[derive(Clone, Copy)]
+pub enum ReportFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Serialize)]
+pub struct FileEntry {
+    pub ino: u64,
+    pub path: String,
+    /// `None` if the file is gone
+    pub size: Option<u64>,
+    /// Bytes allocated on disk, `None` if the file is gone
+    pub allocated: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct GroupEntry {
+    /// Rank by wasted bytes, starting from 1.
+    pub index: usize,
+    pub size: u64,
+    /// Bytes taken by extra copies.
+    pub wasted: u64,
+    /// Bytes allocated on disk to extra copies, less than `wasted` on file systems with compression.
+    pub wasted_allocated: u64,
+    /// Similar, not identical
+    pub similar: bool,
+    pub files: Vec<FileEntry>,
+}
+
+impl GroupEntry {
+    fn from_inventory(group: DuplicateGroup) -> Self {
+        Self::from_group(group, |path| std::fs::metadata(path).ok().map(convert_metadata))
+    }
+
+    /// Build the entry of `group`, looking up files with `metadata`, which gives `None` for files gone.
+    fn from_group(group: DuplicateGroup, metadata: impl Fn(&Path) -> Option<FileMetadata>) -> Self {
+        let similar = group.similar;
+        let files = group
+            .files
+            .into_iter()
+            .map(|file| {
+                let path = PathBuf::from(&file.path);
+                let metadata = metadata(&path);
+                FileEntry {
+                    ino: file.ino,
+                    path: path.to_string_lossy().to_string(),
+                    size: metadata.as_ref().map(|m| m.size),
+                    allocated: metadata.as_ref().map(FileMetadata::disk_usage),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // 清单中没有记录文件大小, 以现存的文件为准.
+        let size = files.iter().find_map(|f| f.size).unwrap_or(0);
+        // 已经硬链接在一起的路径共用 ino, 只算一份; 第一份不算浪费.
+        let mut inodes = HashSet::new();
+        let wasted_allocated = files
+            .iter()
+            .filter_map(|f| f.allocated.filter(|_| inodes.insert(f.ino)))
+            .skip(1)
+            .sum();
+        Self {
+            index: 0,
+            size,
+            wasted: size * (inodes.len() as u64).saturating_sub(1),
+            wasted_allocated,
+            similar,
+            files,
+        }
+    }
+
+    /// Bytes taken by extra copies, measured by `metric`.
+    pub fn wasted_by(&self, metric: WasteMetric) -> u64 {
+        match metric {
+            WasteMetric::Apparent => self.wasted,
+            WasteMetric::Allocated => self.wasted_allocated,
+        }
+    }
+}
+
+/// Load groups from an inventory, the most wasteful by `metric` first. Only the first `top` groups are kept if given.
+pub fn load<P: AsRef<Path>>(inventory: P, top: Option<usize>, metric: WasteMetric) -> Result<Vec<GroupEntry>> {
+    let reader = InventoryReader::open(inventory)?;
+    let mut groups = Vec::with_capacity(reader.total().unwrap_or(0));
+    for group in reader {
+        groups.push(GroupEntry::from_inventory(group?));
+    }
+
+    groups.sort_by_key(|g| std::cmp::Reverse(g.wasted_by(metric)));
+    if let Some(top) = top {
+        groups.truncate(top);
+    }
+    for (i, group) in groups.iter_mut().enumerate() {
+        group.index = i + 1;
+    }
+    Ok(groups)
+}
+
+/// Load the `n`-th group of an inventory, counting from 1 in the order groups are written.
+pub fn load_group<P: AsRef<Path>>(inventory: P, n: usize) -> Result<GroupEntry> {
+    let mut reader = InventoryReader::open(inventory)?;
+    if let Some(total) = reader.total().filter(|&total| n > total) {
+        bail!("no group {n}, the inventory has {total} groups.");
+    }
+    let mut group = GroupEntry::from_inventory(reader.get_group(n - 1)?);
+    group.index = n;
+    Ok(group)
+}
+
+/// Quote a CSV field if needed, see RFC 4180.
+fn csv_field(text: &str) -> Cow<'_, str> {
+    if text.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", text.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+pub fn write<W: Write>(groups: &[GroupEntry], format: ReportFormat, mut writer: W) -> Result<()> {
+    match format {
+        ReportFormat::Text => {
+            let (mut total_wasted, mut total_allocated) = (0, 0);
+            for group in groups {
+                let kind = if group.similar { " similar, not identical," } else { "" };
+                writeln!(
+                    writer,
+                    "# group {},{kind} {} * {}, {} ({} on disk) can be saved.",
+                    group.index,
+                    group.files.len(),
+                    display_file_size(group.size),
+                    display_file_size(group.wasted),
+                    display_file_size(group.wasted_allocated)
+                )?;
+                for file in &group.files {
+                    let mark = if file.size.is_some() { ' ' } else { '?' };
+                    writeln!(writer, "{mark} {}", file.path)?;
+                }
+                total_wasted += group.wasted;
+                total_allocated += group.wasted_allocated;
+            }
+            writeln!(
+                writer,
+                "{} groups, {} ({} on disk) can be saved.",
+                groups.len(),
+                display_file_size(total_wasted),
+                display_file_size(total_allocated)
+            )?;
+        }
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, groups)?;
+            writeln!(writer)?;
+        }
+        ReportFormat::Csv => {
+            writeln!(writer, "group,size,wasted,wasted_allocated,similar,ino,path")?;
+            for group in groups {
+                for file in &group.files {
+                    writeln!(
+                        writer,
+                        "{},{},{},{},{},{},{}",
+                        group.index,
+                        group.size,
+                        group.wasted,
+                        group.wasted_allocated,
+                        group.similar,
+                        file.ino,
+                        csv_field(&file.path)
+                    )?;
+                }
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Escape text to be put in an HTML element or a quoted attribute.
+fn html_escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len() + 16);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// A JPEG thumbnail of the image at `path` as a data URI. Files larger than `limit` are not decoded.
+#[cfg(feature = "thumbnails")]
+fn thumbnail(path: &Path, limit: u64) -> Option<String> {
+    use base64::Engine;
+
+    image::ImageFormat::from_path(path).ok()?;
+    if std::fs::metadata(path).ok()?.len() > limit {
+        return None;
+    }
+    let image = image::open(path).ok()?.thumbnail(96, 96).to_rgb8();
+    let mut jpeg = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(80)).ok()?;
+
+    let data = base64::engine::general_purpose::STANDARD.encode(jpeg.into_inner());
+    Some(format!("data:image/jpeg;base64,{data}"))
+}
+
+#[cfg(not(feature = "thumbnails"))]
+fn thumbnail(_path: &Path, _limit: u64) -> Option<String> {
+    None
+}
+
+fn write_html_group<W: Write>(group: &GroupEntry, thumbnail: Option<&str>, writer: &mut W) -> Result<()> {
+    write!(
+        writer,
+        "<tr data-index=\"{}\" data-size=\"{}\" data-count=\"{}\" data-wasted=\"{}\" data-wasted-allocated=\"{}\">",
+        group.index,
+        group.size,
+        group.files.len(),
+        group.wasted,
+        group.wasted_allocated
+    )?;
+    write!(writer, "<td>{}</td><td>", group.index)?;
+    if let Some(src) = thumbnail {
+        write!(writer, "<img src=\"{src}\" alt=\"\">")?;
+    }
+    write!(
+        writer,
+        "</td><td class=\"number\">{}</td><td class=\"number\">{}</td><td class=\"number\">{}</td>",
+        display_file_size(group.size),
+        group.files.len(),
+        display_file_size(group.wasted)
+    )?;
+
+    let first = group.files.first().map(|f| f.path.as_str()).unwrap_or_default();
+    write!(writer, "<td><details><summary>{}", html_escape(first))?;
+    if group.similar {
+        write!(writer, " <span class=\"similar\">(similar, not identical)</span>")?;
+    }
+    write!(writer, "</summary><ul>")?;
+    for file in &group.files {
+        let class = if file.size.is_some() { "" } else { " class=\"missing\"" };
+        write!(writer, "<li{class}>{}</li>", html_escape(&file.path))?;
+    }
+    writeln!(writer, "</ul></details></td></tr>")?;
+    Ok(())
+}
+
+/// Write a self-contained HTML page for `inventory`, and return the count of groups. Groups are streamed, only one
+/// is held in memory at a time; the page sorts them by wasted bytes itself.
+///
+/// With feature `thumbnails`, image groups show a thumbnail of their first image if `thumbnail_limit` is given,
+/// images larger than the limit are skipped.
+pub fn write_html<P: AsRef<Path>, W: Write>(inventory: P, mut writer: W, thumbnail_limit: Option<u64>) -> Result<usize> {
+    const TEMPLATE: &str = include_str!("../template/inventory.html");
+    const MARKER: &str = "<!-- groups -->";
+    let (head, tail) = TEMPLATE.split_once(MARKER).expect("marker in html template");
+
+    let title = inventory.as_ref().to_string_lossy();
+    writer.write_all(head.replace("{title}", &html_escape(&title)).as_bytes())?;
+
+    let reader = InventoryReader::open(&inventory)?.read_only_sequential();
+    let (mut count, mut total_wasted) = (0, 0);
+    for group in reader {
+        let mut group = GroupEntry::from_inventory(group?);
+        count += 1;
+        group.index = count;
+        total_wasted += group.wasted;
+
+        let thumbnail = thumbnail_limit.and_then(|limit| {
+            let first = group.files.iter().find(|f| f.size.is_some())?;
+            thumbnail(Path::new(&first.path), limit)
+        });
+        write_html_group(&group, thumbnail.as_deref(), &mut writer)?;
+    }
+
+    let summary = format!("共 {count} 组, 可节省 {}", display_file_size(total_wasted));
+    writer.write_all(tail.replace("{summary}", &summary).as_bytes())?;
+    writer.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{csv_field, html_escape, write, write_html_group, FileEntry, GroupEntry, ReportFormat};
+    use crate::inventory::{DuplicateFile, DuplicateGroup};
+    use crate::metadata::{FileMetadata, WasteMetric};
+    use std::path::Path;
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("a/b.pdf"), "a/b.pdf");
+        assert_eq!(csv_field("a,b.pdf"), "\"a,b.pdf\"");
+        assert_eq!(csv_field("say \"hi\".txt"), "\"say \"\"hi\"\".txt\"");
+    }
+
+    #[test]
+    fn test_csv_rows() {
+        let groups = vec![GroupEntry {
+            index: 1,
+            size: 10,
+            wasted: 10,
+            wasted_allocated: 4096,
+            similar: false,
+            files: vec![
+                FileEntry {
+                    ino: 1,
+                    path: "a.pdf".to_string(),
+                    size: Some(10),
+                    allocated: Some(4096),
+                },
+                FileEntry {
+                    ino: 2,
+                    path: "b,c.pdf".to_string(),
+                    size: Some(10),
+                    allocated: Some(4096),
+                },
+            ],
+        }];
+        let mut output = Vec::new();
+        write(&groups, ReportFormat::Csv, &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "group,size,wasted,wasted_allocated,similar,ino,path\n1,10,10,4096,false,1,a.pdf\n1,10,10,4096,false,2,\"b,c.pdf\"\n"
+        );
+    }
+
+    #[test]
+    fn test_from_group() {
+        let metadata = |ino, size, blocks| FileMetadata {
+            dev: 1,
+            ino,
+            link_count: 1,
+            size,
+            blocks,
+            mtime: 0,
+            mtime_nsec: 0,
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+        };
+        // 压缩后 a 占 2M, b 占 1M; c 与 b 硬链接, d 已删除
+        let group = DuplicateGroup {
+            files: [(1, "a"), (2, "b"), (2, "c"), (3, "d")]
+                .iter()
+                .map(|(ino, name)| DuplicateFile::new(*ino, Path::new(name)))
+                .collect(),
+            similar: false,
+            hash: None,
+            whole: false,
+        };
+        let entry = GroupEntry::from_group(group, |path| match path.to_str() {
+            Some("a") => Some(metadata(1, 10 << 20, 4096)),
+            Some("b" | "c") => Some(metadata(2, 10 << 20, 2048)),
+            _ => None,
+        });
+        assert_eq!(entry.size, 10 << 20);
+        assert_eq!(entry.wasted_by(WasteMetric::Apparent), 10 << 20);
+        assert_eq!(entry.wasted_by(WasteMetric::Allocated), 1 << 20);
+        assert_eq!(entry.files[0].allocated, Some(2 << 20));
+        assert_eq!(entry.files[3].allocated, None);
+
+        let mut output = Vec::new();
+        write(&[entry], ReportFormat::Text, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("# group 0, 4 * 10MB, 10MB (1MB on disk) can be saved.\n"));
+    }
+
+    #[test]
+    fn test_html_group() {
+        let group = GroupEntry {
+            index: 3,
+            size: 2048,
+            wasted: 2048,
+            wasted_allocated: 512,
+            similar: false,
+            files: vec![
+                FileEntry {
+                    ino: 1,
+                    path: "<a&b>.pdf".to_string(),
+                    size: Some(2048),
+                    allocated: Some(512),
+                },
+                FileEntry {
+                    ino: 2,
+                    path: "c.pdf".to_string(),
+                    size: None,
+                    allocated: None,
+                },
+            ],
+        };
+        let mut output = Vec::new();
+        write_html_group(&group, None, &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(
+            "<tr data-index=\"3\" data-size=\"2048\" data-count=\"2\" data-wasted=\"2048\" data-wasted-allocated=\"512\">"
+        ));
+        assert!(output.contains("<summary>&lt;a&amp;b&gt;.pdf</summary>"));
+        assert!(output.contains("<li class=\"missing\">c.pdf</li>"));
+        assert_eq!(html_escape("it's"), "it&#39;s");
+    }
+}
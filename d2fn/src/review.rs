@@ -0,0 +1,462 @@
+//! Terminal UI to review duplicate groups and decide what to do with each file.
+
+use anyhow::Result;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::display_file_size;
+use crate::inventory::{DuplicateFile, DuplicateGroup, InventoryReader};
+use crate::plan::{Action, Plan};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileAction {
+    Keep,
+    Hardlink,
+    Delete,
+    /// Leave the file alone
+    Ignore,
+}
+
+impl FileAction {
+    fn label(&self) -> &'static str {
+        match self {
+            FileAction::Keep => "KEEP",
+            FileAction::Hardlink => "LINK",
+            FileAction::Delete => "DEL ",
+            FileAction::Ignore => "    ",
+        }
+    }
+}
+
+pub struct ReviewFile {
+    pub file: DuplicateFile,
+    pub path: PathBuf,
+    /// `None` if the file is gone
+    pub size: Option<u64>,
+    pub mtime: Option<i64>,
+    pub action: FileAction,
+}
+
+pub struct ReviewGroup {
+    pub files: Vec<ReviewFile>,
+    pub reviewed: bool,
+    /// Similar, not identical. Nothing is queued by default.
+    pub similar: bool,
+}
+
+impl ReviewGroup {
+    fn from_inventory(group: DuplicateGroup) -> Self {
+        let similar = group.similar;
+        let keeper_ino = group.files.first().map(|f| f.ino);
+        let files = group
+            .files
+            .into_iter()
+            .enumerate()
+            .map(|(i, file)| {
+                let path = PathBuf::from(&file.path);
+                let metadata = std::fs::metadata(&path).ok();
+                let action = match (&metadata, i) {
+                    (None, _) => FileAction::Ignore,
+                    (Some(_), 0) => FileAction::Keep,
+                    // 已是保留文件的硬链接
+                    (Some(_), _) if Some(file.ino) == keeper_ino => FileAction::Ignore,
+                    (Some(_), _) if similar => FileAction::Ignore,
+                    (Some(_), _) => FileAction::Hardlink,
+                };
+
+                ReviewFile {
+                    file,
+                    path,
+                    size: metadata.as_ref().map(|m| m.len()),
+                    mtime: metadata.as_ref().map(|m| m.mtime()),
+                    action,
+                }
+            })
+            .collect();
+        Self {
+            files,
+            reviewed: false,
+            similar,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.files.iter().find_map(|f| f.size).unwrap_or(0)
+    }
+
+    /// Bytes wasted by extra copies. Paths hardlinked together count once.
+    pub fn wasted(&self) -> u64 {
+        let inodes = self
+            .files
+            .iter()
+            .filter(|f| f.size.is_some())
+            .map(|f| f.file.ino)
+            .collect::<HashSet<_>>();
+        self.size() * (inodes.len() as u64).saturating_sub(1)
+    }
+
+    fn keeper(&self) -> Option<&ReviewFile> {
+        self.files.iter().find(|f| f.action == FileAction::Keep)
+    }
+
+    /// Bytes reclaimed by the actions chosen. Nothing is done to a group without a keeper.
+    pub fn queued_bytes(&self) -> u64 {
+        let Some(keeper) = self.keeper() else {
+            return 0;
+        };
+        // 同一 inode 的多个路径, 空间只释放一次.
+        let mut inodes = HashSet::from([keeper.file.ino]);
+        self.files
+            .iter()
+            .filter(|f| matches!(f.action, FileAction::Hardlink | FileAction::Delete))
+            .filter(|f| inodes.insert(f.file.ino))
+            .filter_map(|f| f.size)
+            .sum()
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        // 没有保留的文件时, 不执行任何操作, 避免删光所有副本.
+        let Some(keeper) = self.keeper() else {
+            return Vec::new();
+        };
+        self.files
+            .iter()
+            .filter_map(|f| match f.action {
+                // 相似的文件内容不同, 替换是用户明确选择的
+                FileAction::Hardlink if self.similar => Some(Action::Replace {
+                    source: keeper.file.clone(),
+                    target: f.file.clone(),
+                }),
+                FileAction::Hardlink => Some(Action::Hardlink {
+                    source: keeper.file.clone(),
+                    target: f.file.clone(),
+                }),
+                FileAction::Delete => Some(Action::Delete {
+                    source: keeper.file.clone(),
+                    target: f.file.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub struct Review {
+    pub groups: Vec<ReviewGroup>,
+    group_state: ListState,
+    file_state: ListState,
+    /// Index of the group being expanded
+    opened: Option<usize>,
+    plan_path: PathBuf,
+    message: String,
+}
+
+enum Control {
+    Continue,
+    Quit,
+}
+
+impl Review {
+    pub fn load<P: AsRef<Path>>(inventory: P, plan_path: PathBuf) -> Result<Self> {
+        let reader = InventoryReader::open(inventory)?;
+        let mut groups = Vec::with_capacity(reader.total().unwrap_or(0));
+        for group in reader {
+            groups.push(ReviewGroup::from_inventory(group?));
+        }
+        Ok(Self::new(groups, plan_path))
+    }
+
+    fn new(mut groups: Vec<ReviewGroup>, plan_path: PathBuf) -> Self {
+        groups.sort_by_key(|g| std::cmp::Reverse(g.wasted()));
+
+        let mut group_state = ListState::default();
+        if !groups.is_empty() {
+            group_state.select(Some(0));
+        }
+        Self {
+            groups,
+            group_state,
+            file_state: ListState::default(),
+            opened: None,
+            plan_path,
+            message: String::new(),
+        }
+    }
+
+    pub fn plan(&self) -> Plan {
+        let actions = self.groups.iter().filter(|g| g.reviewed).flat_map(|g| g.actions()).collect();
+        Plan { actions }
+    }
+
+    fn move_cursor(state: &mut ListState, len: usize, down: bool) {
+        if len == 0 {
+            return;
+        }
+        let current = state.selected().unwrap_or(0);
+        let next = if down {
+            (current + 1).min(len - 1)
+        } else {
+            current.saturating_sub(1)
+        };
+        state.select(Some(next));
+    }
+
+    fn set_action(&mut self, action: FileAction) {
+        let (Some(group), Some(file)) = (self.opened, self.file_state.selected()) else {
+            return;
+        };
+        let group = &mut self.groups[group];
+        let file = &mut group.files[file];
+        if file.size.is_none() {
+            self.message = "file is gone.".to_string();
+            return;
+        }
+        // 再按一次则取消
+        file.action = if file.action == action { FileAction::Ignore } else { action };
+        group.reviewed = true;
+    }
+
+    fn handle_key(&mut self, code: KeyCode) -> Control {
+        self.message.clear();
+        match (self.opened, code) {
+            (_, KeyCode::Char('q')) => return Control::Quit,
+            (_, KeyCode::Char('w')) => {
+                self.message = match self.plan().save(&self.plan_path) {
+                    Ok(_) => format!("plan saved to {}.", self.plan_path.display()),
+                    Err(e) => format!("unable to save plan: {e}"),
+                };
+            }
+            (None, KeyCode::Up | KeyCode::Char('k')) => Self::move_cursor(&mut self.group_state, self.groups.len(), false),
+            (None, KeyCode::Down | KeyCode::Char('j')) => Self::move_cursor(&mut self.group_state, self.groups.len(), true),
+            (None, KeyCode::Enter | KeyCode::Right) => {
+                if let Some(selected) = self.group_state.selected() {
+                    self.opened = Some(selected);
+                    self.groups[selected].reviewed = true;
+                    self.file_state.select(Some(0));
+                }
+            }
+            (Some(group), KeyCode::Up | KeyCode::Char('k')) => {
+                Self::move_cursor(&mut self.file_state, self.groups[group].files.len(), false)
+            }
+            (Some(group), KeyCode::Down | KeyCode::Char('j')) => {
+                Self::move_cursor(&mut self.file_state, self.groups[group].files.len(), true)
+            }
+            (Some(_), KeyCode::Esc | KeyCode::Left | KeyCode::Backspace) => self.opened = None,
+            (Some(_), KeyCode::Char('K')) => self.set_action(FileAction::Keep),
+            (Some(_), KeyCode::Char('h')) => self.set_action(FileAction::Hardlink),
+            (Some(_), KeyCode::Char('d')) => self.set_action(FileAction::Delete),
+            (Some(_), KeyCode::Char('i')) => self.set_action(FileAction::Ignore),
+            _ => {}
+        }
+        Control::Continue
+    }
+
+    fn summary(&self) -> String {
+        let reviewed = self.groups.iter().filter(|g| g.reviewed).count();
+        let queued: u64 = self.groups.iter().filter(|g| g.reviewed).map(|g| g.queued_bytes()).sum();
+        let keys = match self.opened {
+            None => "j/k: move  Enter: open  w: save plan  q: quit",
+            Some(_) => "j/k: move  K: keep  h: hardlink  d: delete  i: ignore  Esc: back  w: save plan",
+        };
+        format!(
+            "reviewed {reviewed}/{} groups, {} queued | {keys} {}",
+            self.groups.len(),
+            display_file_size(queued),
+            self.message
+        )
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)])
+            .split(frame.size());
+        let highlight = Style::default().add_modifier(Modifier::REVERSED);
+
+        match self.opened {
+            None => {
+                let items = self
+                    .groups
+                    .iter()
+                    .enumerate()
+                    .map(|(i, g)| {
+                        let mark = if g.reviewed { "*" } else { " " };
+                        let kind = if g.similar { " (similar, not identical)" } else { "" };
+                        let first = g.files.first().map(|f| f.path.display().to_string()).unwrap_or_default();
+                        ListItem::new(format!(
+                            "{mark} #{:<6} {:>8} wasted, {} files{kind}  {first}",
+                            i + 1,
+                            display_file_size(g.wasted()),
+                            g.files.len()
+                        ))
+                    })
+                    .collect::<Vec<_>>();
+                let list = List::new(items)
+                    .block(Block::default().borders(Borders::ALL).title("Duplicate groups"))
+                    .highlight_style(highlight);
+                frame.render_stateful_widget(list, chunks[0], &mut self.group_state);
+            }
+            Some(group) => {
+                let items = self.groups[group]
+                    .files
+                    .iter()
+                    .map(|f| {
+                        let size = f.size.map(display_file_size).unwrap_or_else(|| "gone".to_string());
+                        let mtime = f.mtime.map(display_timestamp).unwrap_or_default();
+                        ListItem::new(format!("[{}] {:>8} {mtime:16} {}", f.action.label(), size, f.path.display()))
+                    })
+                    .collect::<Vec<_>>();
+                let title = if self.groups[group].similar {
+                    format!("Group #{}, similar, not identical", group + 1)
+                } else {
+                    format!("Group #{}", group + 1)
+                };
+                let list = List::new(items)
+                    .block(Block::default().borders(Borders::ALL).title(title))
+                    .highlight_style(highlight);
+                frame.render_stateful_widget(list, chunks[0], &mut self.file_state);
+            }
+        }
+        frame.render_widget(Paragraph::new(self.summary()), chunks[1]);
+    }
+
+    pub fn run(mut self) -> Result<()> {
+        enable_raw_mode()?;
+        crossterm::execute!(std::io::stdout(), EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+
+        let result = (|| -> Result<()> {
+            loop {
+                terminal.draw(|frame| self.draw(frame))?;
+                if let Event::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    if let Control::Quit = self.handle_key(key.code) {
+                        return Ok(());
+                    }
+                }
+            }
+        })();
+
+        disable_raw_mode()?;
+        crossterm::execute!(std::io::stdout(), LeaveAlternateScreen)?;
+        result
+    }
+}
+
+/// Format seconds since epoch as "YYYY-MM-DD HH:MM" in UTC.
+fn display_timestamp(secs: i64) -> String {
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}", rem / 3600, rem % 3600 / 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{display_timestamp, FileAction, Review, ReviewFile, ReviewGroup};
+    use crate::inventory::DuplicateFile;
+    use crossterm::event::KeyCode;
+    use std::path::{Path, PathBuf};
+
+    fn group(size: u64, count: usize) -> ReviewGroup {
+        let files = (0..count)
+            .map(|i| {
+                let path = PathBuf::from(format!("/{size}/{i}"));
+                ReviewFile {
+                    file: DuplicateFile::new(i as u64, &path),
+                    path,
+                    size: Some(size),
+                    mtime: Some(0),
+                    action: if i == 0 { FileAction::Keep } else { FileAction::Hardlink },
+                }
+            })
+            .collect();
+        ReviewGroup {
+            files,
+            reviewed: false,
+            similar: false,
+        }
+    }
+
+    #[test]
+    fn test_sorted_by_waste() {
+        let review = Review::new(vec![group(10, 2), group(100, 3), group(50, 2)], PathBuf::new());
+        let wasted = review.groups.iter().map(|g| g.wasted()).collect::<Vec<_>>();
+
+        assert_eq!(wasted, vec![200, 50, 10]);
+    }
+
+    #[test]
+    fn test_cursor_kept_when_returning() {
+        let mut review = Review::new(vec![group(10, 2), group(20, 2), group(30, 2)], PathBuf::new());
+
+        review.handle_key(KeyCode::Down);
+        review.handle_key(KeyCode::Enter);
+        assert_eq!(review.opened, Some(1));
+        review.handle_key(KeyCode::Down);
+        review.handle_key(KeyCode::Esc);
+        assert_eq!(review.opened, None);
+        assert_eq!(review.group_state.selected(), Some(1));
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut review = Review::new(vec![group(30, 3), group(10, 2)], PathBuf::new());
+
+        review.handle_key(KeyCode::Enter);
+        review.handle_key(KeyCode::Down);
+        review.handle_key(KeyCode::Char('d'));
+        review.handle_key(KeyCode::Down);
+        review.handle_key(KeyCode::Char('h'));
+        review.handle_key(KeyCode::Esc);
+
+        // 只有查看过的组会被写入; 第三个文件的 hardlink 被取消
+        let plan = review.plan();
+        assert_eq!(plan.actions.len(), 1);
+        let target = PathBuf::from(&plan.actions[0].target().path);
+        assert_eq!(target, Path::new("/30/1"));
+        assert_eq!(review.groups[0].queued_bytes(), 30);
+
+        // 没有保留的文件时, 整组不执行
+        review.handle_key(KeyCode::Enter);
+        review.handle_key(KeyCode::Char('K'));
+        assert!(review.plan().actions.is_empty());
+    }
+
+    #[test]
+    fn test_linked_paths_counted_once() {
+        let mut linked = group(10, 4);
+        // 第二个路径是保留文件的硬链接, 第四个与第三个共用 inode.
+        linked.files[1].file.ino = 0;
+        linked.files[3].file.ino = 2;
+        linked.files[1].action = FileAction::Ignore;
+
+        assert_eq!(linked.wasted(), 10);
+        assert_eq!(linked.queued_bytes(), 10);
+    }
+
+    #[test]
+    fn test_display_timestamp() {
+        assert_eq!(display_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(display_timestamp(1692835200 + 3600 + 120), "2023-08-24 01:02");
+    }
+}
@@ -0,0 +1,161 @@
+//! Fingerprint images with a difference hash (dHash), to find re-encoded or resized copies of the same picture.
+//!
+//! Such files are similar, not identical: replacing one with another loses data, so their groups are kept apart
+//! from exact duplicates.
+
+use anyhow::{Context, Result};
+use image::imageops::FilterType;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::duplicate::{File, InodeKey};
+use common::throttle::Throttle;
+
+/// Default maximum Hamming distance between fingerprints of similar images, out of 64 bits.
+pub const DEFAULT_THRESHOLD: u32 = 6;
+
+const IMAGE_EXT: [&str; 8] = ["jpg", "jpeg", "png", "gif", "bmp", "webp", "tiff", "tif"];
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| IMAGE_EXT.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// 64-bit dHash: shrink to 9x8 grayscale, and set a bit when a pixel is brighter than its right neighbour.
+pub fn dhash(path: &Path) -> Result<u64> {
+    let image = image::open(path).with_context(|| format!("unable to decode {}", path.display()))?;
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | (left > right) as u64;
+        }
+    }
+    Ok(hash)
+}
+
+pub fn hamming(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+pub struct Fingerprint {
+    pub path: PathBuf,
+    pub ino: u64,
+    pub size: u64,
+    pub hash: u64,
+}
+
+/// Collect fingerprints during a scan, and group them by Hamming distance.
+pub struct SimilarImages {
+    threshold: u32,
+    inode_set: HashSet<InodeKey>,
+    fingerprints: Vec<Fingerprint>,
+    /// Images which could not be decoded, and were skipped
+    pub failed: Vec<PathBuf>,
+}
+
+impl SimilarImages {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            inode_set: HashSet::new(),
+            fingerprints: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+
+    /// Fingerprint `file` if it is an image. A file failing to decode is only recorded.
+    pub fn add(&mut self, file: &File, throttle: &Throttle) {
+        if !is_image(&file.path) || !self.inode_set.insert((file.metadata.dev, file.metadata.ino)) {
+            return;
+        }
+
+        throttle.consume(file.metadata.size as usize);
+        match dhash(&file.path) {
+            Ok(hash) => self.fingerprints.push(Fingerprint {
+                path: file.path.clone(),
+                ino: file.metadata.ino,
+                size: file.metadata.size,
+                hash,
+            }),
+            Err(_) => self.failed.push(file.path.clone()),
+        }
+        throttle.idle();
+    }
+
+    /// Groups of images within the threshold of each other, directly or through other members.
+    pub fn groups(&self) -> Vec<Vec<&Fingerprint>> {
+        // 并查集. 两两比较, 对几万张图片而言仍可接受.
+        let mut parent = (0..self.fingerprints.len()).collect::<Vec<_>>();
+        fn find(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+
+        for i in 0..self.fingerprints.len() {
+            for j in i + 1..self.fingerprints.len() {
+                if hamming(self.fingerprints[i].hash, self.fingerprints[j].hash) <= self.threshold {
+                    let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                    parent[a] = b;
+                }
+            }
+        }
+
+        let mut groups = vec![Vec::new(); self.fingerprints.len()];
+        for i in 0..self.fingerprints.len() {
+            let root = find(&mut parent, i);
+            groups[root].push(&self.fingerprints[i]);
+        }
+        groups.into_iter().filter(|group| group.len() > 1).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dhash, hamming, is_image};
+    use image::{GrayImage, Luma};
+    use std::path::Path;
+
+    fn gradient(width: u32, height: u32, reverse: bool) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| {
+            let value = ((x * 7 + y * 3) % 256) as u8;
+            Luma([if reverse { 255 - value } else { value }])
+        })
+    }
+
+    #[test]
+    fn test_resized_copy_is_similar() {
+        let dir = std::env::temp_dir().join(format!("d2fn-similar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (original, resized, other, broken) =
+            (dir.join("a.png"), dir.join("b.png"), dir.join("c.png"), dir.join("d.png"));
+        gradient(64, 48, false).save(&original).unwrap();
+        image::imageops::resize(&gradient(64, 48, false), 32, 24, image::imageops::FilterType::Nearest)
+            .save(&resized)
+            .unwrap();
+        gradient(64, 48, true).save(&other).unwrap();
+        std::fs::write(&broken, "not an image").unwrap();
+
+        let (a, b, c) = (dhash(&original).unwrap(), dhash(&resized).unwrap(), dhash(&other).unwrap());
+        assert!(hamming(a, b) <= super::DEFAULT_THRESHOLD);
+        assert!(hamming(a, c) > super::DEFAULT_THRESHOLD);
+        assert!(dhash(&broken).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_image() {
+        assert!(is_image(Path::new("a/b.JPG")));
+        assert!(!is_image(Path::new("a/b.mp4")));
+        assert!(!is_image(Path::new("a/jpg")));
+    }
+}
@@ -0,0 +1,193 @@
+//! Progress of a scan written to a small JSON document, for monitors which poll files.
+//!
+//! The document is rewritten as the scan makes progress, at most once per interval, by writing a temporary file next
+//! to it and renaming it over. A scan which hangs stops rewriting it, and the `updated` timestamp goes stale.
+
+use crate::duplicate::StatusReport;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Results of a finished scan.
+#[derive(Serialize, Debug, Default)]
+pub struct Totals {
+    pub files: usize,
+    pub groups: usize,
+    pub duplicates: usize,
+    pub reclaimable_bytes: u64,
+    pub walk_errors: usize,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    /// `running` or `done`
+    state: &'a str,
+    phase: &'a str,
+    /// Unix timestamps, in seconds
+    started: u64,
+    updated: u64,
+    /// Estimated part of the work done, from 0 to 1, `null` while it is unknown
+    fraction: Option<f64>,
+    scanned: usize,
+    duplicated: usize,
+    ignored: usize,
+    excluded: usize,
+    stale_files: usize,
+    read_rate: u64,
+    bytes_read: u64,
+    last_file: &'a str,
+    hashing_current_file: Option<&'a str>,
+    hashing_progress: (u64, u64),
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totals: Option<&'a Totals>,
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+pub struct StatusFile {
+    path: PathBuf,
+    interval: Duration,
+    started: SystemTime,
+    phase: &'static str,
+    last_write: Option<Instant>,
+    /// Whether a failed write was already reported
+    warned: bool,
+}
+
+impl StatusFile {
+    pub fn new(path: PathBuf, interval: Duration) -> Self {
+        Self {
+            path,
+            interval,
+            started: SystemTime::now(),
+            phase: "discover",
+            last_write: None,
+            warned: false,
+        }
+    }
+
+    /// Start `phase`, and rewrite the document at the next update.
+    pub fn set_phase(&mut self, phase: &'static str) {
+        self.phase = phase;
+        self.last_write = None;
+    }
+
+    /// Whether the interval passed since the document was written.
+    pub fn due(&self) -> bool {
+        self.last_write.is_none_or(|last| last.elapsed() >= self.interval)
+    }
+
+    /// Rewrite the document if it is due. A failure is logged once, the scan goes on.
+    pub fn update(&mut self, status: &StatusReport, bytes_read: u64, fraction: Option<f64>) {
+        if !self.due() {
+            return;
+        }
+        if let Err(e) = self.write("running", status, bytes_read, fraction, None) {
+            if !self.warned {
+                tracing::warn!("{e:#}");
+                self.warned = true;
+            }
+        }
+    }
+
+    /// Write the document a last time, with the results of the scan.
+    pub fn finish(&mut self, status: &StatusReport, bytes_read: u64, totals: &Totals) -> Result<()> {
+        self.phase = "done";
+        self.write("done", status, bytes_read, Some(1.0), Some(totals))
+    }
+
+    fn write(
+        &mut self,
+        state: &str,
+        status: &StatusReport,
+        bytes_read: u64,
+        fraction: Option<f64>,
+        totals: Option<&Totals>,
+    ) -> Result<()> {
+        let document = Document {
+            state,
+            phase: self.phase,
+            started: unix_seconds(self.started),
+            updated: unix_seconds(SystemTime::now()),
+            fraction,
+            scanned: status.scanned,
+            duplicated: status.duplicated,
+            ignored: status.ignored,
+            excluded: status.excluded,
+            stale_files: status.stale_files,
+            read_rate: status.read_rate,
+            bytes_read,
+            last_file: &status.last_file,
+            hashing_current_file: status.hashing_current_file.as_deref(),
+            hashing_progress: status.hashing_progress,
+            totals,
+        };
+        // 先写临时文件再改名, 读取方不会看到写了一半的文档
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, serde_json::to_vec(&document)?)
+            .and_then(|_| std::fs::rename(&temporary, &self.path))
+            .with_context(|| format!("unable to write the status file {}.", self.path.display()))?;
+        self.last_write = Some(Instant::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StatusFile, Totals};
+    use crate::duplicate::StatusReport;
+    use std::time::Duration;
+
+    #[test]
+    fn test_status_file() {
+        let path = std::env::temp_dir().join(format!("d2fn-status-{}.json", std::process::id()));
+        let mut file = StatusFile::new(path.clone(), Duration::from_secs(3600));
+        let status = StatusReport {
+            scanned: 3,
+            last_file: "/tank/a.pdf".to_string(),
+            ..Default::default()
+        };
+        file.update(&status, 10, None);
+        let document: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(document["state"], "running");
+        assert_eq!(document["phase"], "discover");
+        assert_eq!(document["scanned"], 3);
+        assert!(document["fraction"].is_null());
+        assert!(document.get("totals").is_none());
+
+        // 间隔未到时不重写, 换阶段后立即重写
+        file.update(
+            &StatusReport {
+                scanned: 4,
+                ..Default::default()
+            },
+            10,
+            None,
+        );
+        assert!(std::fs::read_to_string(&path).unwrap().contains("\"scanned\":3"));
+        file.set_phase("verify");
+        file.update(&status, 10, Some(0.5));
+        let document: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            (document["phase"].as_str(), document["fraction"].as_f64()),
+            (Some("verify"), Some(0.5))
+        );
+
+        let totals = Totals {
+            files: 3,
+            groups: 1,
+            duplicates: 1,
+            ..Default::default()
+        };
+        file.finish(&status, 10, &totals).unwrap();
+        let document: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(document["state"], "done");
+        assert_eq!(document["totals"]["groups"], 1);
+        assert!(!path.with_extension("json.tmp").exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
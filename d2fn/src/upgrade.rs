@@ -0,0 +1,230 @@
+//! Rewriting an inventory of an earlier version in the current format, without scanning again.
+//!
+//! Inventories before version 3 only record inode numbers and paths. Each file is looked up again to fill in its
+//! size, modification time and device. Groups are read and written one at a time, memory use does not grow with the
+//! size of the inventory.
+
+use anyhow::{bail, Context, Result};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::check::majority;
+use crate::hash::{checksum_file_throttled, CachePolicy, CompareMode, HashAlgorithm, HashKey};
+use crate::inventory::{DuplicateFile, DuplicateGroup, GroupHash, InventoryReader, InventoryWriter};
+
+#[derive(Default, Debug)]
+pub struct UpgradeStats {
+    /// Version of the input
+    pub version: u8,
+    pub groups: usize,
+    pub files: usize,
+    /// Files whose metadata was filled in
+    pub filled: usize,
+    /// Files gone, kept without metadata
+    pub missing: usize,
+    /// Files replaced by another inode, kept without metadata
+    pub replaced: usize,
+    /// Groups given a hash with `rehash`
+    pub hashed: usize,
+    /// Groups left without a hash, as their files differ or could not be read
+    pub unhashed: usize,
+    /// Groups which could not be decoded
+    pub bad_groups: usize,
+}
+
+/// What happened to a file without metadata.
+pub enum UpgradeEvent<'a> {
+    Missing(&'a Path),
+    Replaced(&'a Path),
+}
+
+/// Fill in the metadata of `file` if it lacks it and is still the file recorded.
+fn fill(file: &mut DuplicateFile, stats: &mut UpgradeStats, report: &mut impl FnMut(UpgradeEvent)) {
+    if file.size.is_some() {
+        return;
+    }
+    let path = PathBuf::from(&file.path);
+    match std::fs::metadata(&path) {
+        Ok(metadata) if metadata.ino() == file.ino => {
+            file.dev = Some(metadata.dev());
+            file.size = Some(metadata.size());
+            file.mtime = Some(metadata.mtime());
+            file.mtime_nsec = Some(metadata.mtime_nsec());
+            stats.filled += 1;
+        }
+        Ok(_) => {
+            stats.replaced += 1;
+            report(UpgradeEvent::Replaced(&path));
+        }
+        Err(_) => {
+            stats.missing += 1;
+            report(UpgradeEvent::Missing(&path));
+        }
+    }
+}
+
+/// The hash of the whole content of files of `group`, `None` if they differ, or less than two can be read.
+fn full_hash(group: &DuplicateGroup, key: Option<&HashKey>, cache: CachePolicy) -> Option<GroupHash> {
+    let digests = group
+        .files
+        .iter()
+        .map(|file| {
+            let path = PathBuf::from(&file.path);
+            checksum_file_throttled(&path, CompareMode::Full, HashAlgorithm::Blake3, key, cache, None, None)
+                .map(|checksum| checksum.hash)
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    let expected = majority(&digests)?;
+    // 缺失的文件不影响其余文件的哈希
+    let mut read = digests.iter().flatten();
+    if read.clone().count() < 2 || read.any(|digest| *digest != expected) {
+        return None;
+    }
+    Some(GroupHash {
+        blake3: *expected.blake3()?.as_bytes(),
+        prefix: None,
+    })
+}
+
+/// Write the groups of `input` to `output` in the current format, and fill in metadata of files recorded without it.
+/// Files gone or replaced since the scan are kept as they are, and reported to `report`. With `rehash`, groups
+/// without a hash get the one of their whole content, if all their files still share it. `input` is only read, and
+/// `output` written to a temporary file first, renamed once complete.
+pub fn upgrade_inventory(
+    input: &Path,
+    output: &Path,
+    key: Option<&HashKey>,
+    rehash: bool,
+    cache: CachePolicy,
+    mut report: impl FnMut(UpgradeEvent),
+) -> Result<UpgradeStats> {
+    let _span = tracing::info_span!("upgrade").entered();
+    if output.exists() && std::fs::canonicalize(input)? == std::fs::canonicalize(output)? {
+        bail!("the output is the input, which is never modified.");
+    }
+    let reader = InventoryReader::open(input)?.read_only_sequential();
+    reader.check_key(key)?;
+    let mut stats = UpgradeStats {
+        version: reader.version(),
+        ..Default::default()
+    };
+    let roots = reader.roots().to_vec();
+    let relative_roots = roots.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+
+    let mut temporary = output.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut writer = InventoryWriter::create(&temporary)
+        .with_context(|| format!("unable to create {}.", temporary.display()))?
+        .key_id(reader.key_id().unwrap_or(0))
+        .scan_roots(roots.clone());
+
+    let groups = reader.filter_map(|group| {
+        let mut group = match group {
+            Ok(group) => group,
+            Err(e) => {
+                tracing::error!("unable to read duplicate group: {e}");
+                stats.bad_groups += 1;
+                return None;
+            }
+        };
+        stats.groups += 1;
+        stats.files += group.files.len();
+        for file in &mut group.files {
+            fill(file, &mut stats, &mut report);
+        }
+        // 相似文件的内容本就不同
+        if rehash && !group.similar && group.hash.is_none() {
+            group.hash = full_hash(&group, key, cache);
+            group.whole |= group.hash.is_some();
+            match group.hash {
+                Some(_) => stats.hashed += 1,
+                None => stats.unhashed += 1,
+            }
+        }
+        group.files = group
+            .files
+            .into_iter()
+            .map(|file| file.relative_to(&relative_roots))
+            .collect();
+        Some(group)
+    });
+    writer.export(groups)?;
+    std::fs::rename(&temporary, output).with_context(|| format!("unable to write {}.", output.display()))?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{upgrade_inventory, UpgradeEvent};
+    use crate::hash::CachePolicy;
+    use crate::inventory::{InventoryReader, CURRENT_VERSION};
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+
+    /// A version 1 inventory: header, then length-prefixed records of `(ino, path)`, without footer.
+    fn write_v1(path: &Path, groups: &[Vec<(u64, PathBuf)>]) {
+        let mut file = std::fs::File::create(path).unwrap();
+        file.write_all(&[1, 10]).unwrap();
+        file.write_all(&(groups.len() as u32).to_le_bytes()).unwrap();
+        for group in groups {
+            let files = group
+                .iter()
+                .map(|(ino, path)| (*ino, path.as_os_str().as_encoded_bytes().to_vec()))
+                .collect::<Vec<_>>();
+            let record = bincode::encode_to_vec(files, bincode::config::standard()).unwrap();
+            file.write_all(&(record.len() as u32).to_le_bytes()).unwrap();
+            file.write_all(&record).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_upgrade_inventory() {
+        let dir = std::env::temp_dir().join(format!("d2fn-upgrade-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let paths = ["a", "b", "c", "d"].map(|name| dir.join(name));
+        for path in &paths {
+            std::fs::write(path, "same content").unwrap();
+        }
+        let ino = |path: &Path| std::fs::metadata(path).unwrap().ino();
+        let first = paths[..3].iter().map(|path| (ino(path), path.clone())).collect();
+        // d 记录的 inode 已不是现在的文件
+        let second = vec![(ino(&paths[0]), paths[0].clone()), (ino(&paths[3]) + 1, paths[3].clone())];
+        let input = dir.join("v1.d2fn");
+        write_v1(&input, &[first, second]);
+        std::fs::remove_file(&paths[2]).unwrap();
+        let original = std::fs::read(&input).unwrap();
+
+        let output = dir.join("upgraded.d2fn");
+        let mut events = Vec::new();
+        let stats = upgrade_inventory(&input, &output, None, true, CachePolicy::Keep, |event| {
+            events.push(match event {
+                UpgradeEvent::Missing(path) => format!("missing {}", path.file_name().unwrap().to_string_lossy()),
+                UpgradeEvent::Replaced(path) => format!("replaced {}", path.file_name().unwrap().to_string_lossy()),
+            })
+        })
+        .unwrap();
+        assert_eq!(events, ["missing c", "replaced d"]);
+        assert_eq!((stats.version, stats.groups, stats.files), (1, 2, 5));
+        assert_eq!((stats.filled, stats.missing, stats.replaced), (3, 1, 1));
+        // 缺失的文件不影响哈希; d 虽不是原来的文件, 内容仍相同
+        assert_eq!((stats.hashed, stats.unhashed), (2, 0));
+        assert_eq!(std::fs::read(&input).unwrap(), original);
+
+        let mut reader = InventoryReader::open(&output).unwrap();
+        assert_eq!(reader.version(), CURRENT_VERSION);
+        let groups = reader.by_ref().collect::<anyhow::Result<Vec<_>>>().unwrap();
+        let first = &groups[0];
+        assert_eq!(first.files.len(), 3);
+        assert_eq!(first.files[0].size, Some(12));
+        assert!(first.files[2].size.is_none());
+        assert_eq!(first.hash.unwrap().blake3, *blake3::hash(b"same content").as_bytes());
+        assert!(groups[1].files[1].size.is_none());
+        assert_eq!(groups[1].hash.unwrap().blake3, *blake3::hash(b"same content").as_bytes());
+
+        assert!(upgrade_inventory(&output, &output, None, false, CachePolicy::Keep, |_| {}).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,653 @@
+//! `tape`, a command line tool like mt(1) built on the tape crate.
+
+mod watch;
+
+use anyhow::{bail, Context, Result};
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use common::completion::{self, CompletionsArg};
+use common::config::Config;
+use common::exit::{self, ErrorKind, EXIT_END_OF_DATA};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use tape::device::{Compression, Density, DumpEnd, DumpReport, ScsiTapeErrors, TapeStatus};
+use tape::{LocationBuilder, TapeDevice, TapeStatusEx};
+
+/// Device used if neither given nor configured
+const DEFAULT_DEVICE: &str = "/dev/nsa0";
+
+#[derive(Parser)]
+#[command(author, version, about = "Control magnetic tape drives, like mt(1)")]
+#[command(
+    after_help = "Examples:\n  tape status\n  tape -f /dev/nsa1 --json status -x\n\nShell completion: source <(tape completions bash), also zsh and fish."
+)]
+struct Cli {
+    /// Tape device, /dev/nsa0 if not configured. Use the non-rewinding node, or the position is lost when the device
+    /// is closed
+    #[arg(short = 'f', long = "device", global = true, env = "TAPE")]
+    device: Option<String>,
+    /// Print output as JSON on stdout, and errors as JSON on stderr
+    #[arg(long, global = true, default_value_t = false)]
+    json: bool,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// Output of a successful command in JSON mode. Fields are a contract with scripts, do not rename them.
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    ok: bool,
+    /// For `status`, and `status -x`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<JsonStatus<'a>>,
+    /// For `errstat`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a ScsiTapeErrors>,
+    /// For `dump`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dump: Option<JsonDump>,
+    /// For other commands, position after the operation if the drive reports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<JsonPosition>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonStatus<'a> {
+    Basic(&'a TapeStatus),
+    Extended(&'a TapeStatusEx),
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct JsonPosition {
+    file: usize,
+    block: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct JsonDump {
+    bytes: u64,
+    blocks: u64,
+    blake3: String,
+    /// `filemark` or `end_of_data`
+    end: &'static str,
+}
+
+impl JsonDump {
+    fn new(report: &DumpReport, hash: &blake3::Hash) -> Self {
+        Self {
+            bytes: report.bytes,
+            blocks: report.blocks,
+            blake3: hash.to_hex().to_string(),
+            end: match report.end {
+                DumpEnd::Filemark => "filemark",
+                DumpEnd::EndOfData => "end_of_data",
+            },
+        }
+    }
+}
+
+/// What a command gives to print.
+enum Output {
+    Status(TapeStatus),
+    StatusEx(TapeStatusEx),
+    Errors(ScsiTapeErrors),
+    Dump(DumpReport, blake3::Hash),
+    Done,
+}
+
+impl Output {
+    /// A dump stopped by the end of data exits with its own code, so scripts know there is no file after it.
+    fn exit_code(&self) -> ExitCode {
+        match self {
+            Output::Dump(report, _) if report.end == DumpEnd::EndOfData => ExitCode::from(EXIT_END_OF_DATA),
+            _ => ExitCode::SUCCESS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum CompressionMode {
+    On,
+    Off,
+    /// IDRC algorithm
+    Idrc,
+    /// DCLZ algorithm
+    Dclz,
+}
+
+impl From<CompressionMode> for Compression {
+    fn from(mode: CompressionMode) -> Self {
+        match mode {
+            CompressionMode::On => Compression::On,
+            CompressionMode::Off => Compression::Off,
+            CompressionMode::Idrc => Compression::Idrc,
+            CompressionMode::Dclz => Compression::Dclz,
+        }
+    }
+}
+
+#[derive(Args)]
+struct StatusArg {
+    /// Print the extended status reported by the driver
+    #[arg(short = 'x', default_value_t = false)]
+    extended: bool,
+}
+
+#[derive(Args)]
+struct WatchArg {
+    /// Time between polls, such as 2s or 500ms
+    #[arg(long, value_name = "TIME", default_value = "2s", value_parser = parse_interval)]
+    interval: Duration,
+}
+
+#[derive(Args)]
+struct CountArg {
+    /// How many times
+    #[arg(default_value_t = 1)]
+    count: u32,
+}
+
+#[derive(Args)]
+struct EraseArg {
+    /// Quick erase, only write an end-of-data mark at the current position
+    #[arg(short, default_value_t = false)]
+    quick: bool,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("target").required(true).args(["file", "block"])))]
+struct LocateArg {
+    /// Locate to the beginning of file N
+    #[arg(long, value_name = "N")]
+    file: Option<u64>,
+    /// Locate to logical block N
+    #[arg(long, value_name = "N")]
+    block: Option<u64>,
+    /// Change to partition P first
+    #[arg(long, value_name = "P")]
+    partition: Option<i64>,
+}
+
+#[derive(Args)]
+struct DumpArg {
+    /// Locate to the beginning of file N first, instead of dumping from the current position
+    #[arg(long, value_name = "N")]
+    file: Option<u64>,
+    /// File to write to, replaced if it exists
+    #[arg(short, long, value_name = "PATH")]
+    out: PathBuf,
+}
+
+#[derive(Args)]
+struct BlockSizeArg {
+    /// Block size in bytes, or "variable"
+    #[arg(value_parser = parse_block_size)]
+    size: u32,
+}
+
+#[derive(Args)]
+struct DensityArg {
+    /// Density code such as 0x58, or name such as LTO-5
+    #[arg(value_parser = parse_density)]
+    density: u32,
+}
+
+#[derive(Args)]
+struct CompressionArg {
+    #[arg(value_enum)]
+    state: CompressionMode,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print the status of the drive
+    #[command(after_help = "Examples:\n  tape status\n  tape status -x")]
+    Status(StatusArg),
+    /// Poll the status of the drive until q or Ctrl-C, redrawn in place on a terminal
+    #[command(after_help = "Examples:\n  tape watch\n  tape -f /dev/nsa1 watch --interval 500ms")]
+    Watch(WatchArg),
+    /// Rewind the tape
+    #[command(after_help = "Examples:\n  tape rewind")]
+    Rewind,
+    /// Rewind the tape and put the drive offline
+    #[command(after_help = "Examples:\n  tape offline")]
+    Offline,
+    /// Lock the cartridge in the drive, its eject button does nothing until unlocked. The driver may unlock it when the
+    /// device is closed
+    #[command(after_help = "Examples:\n  tape lock")]
+    Lock,
+    /// Unlock the cartridge, so it can be ejected
+    #[command(after_help = "Examples:\n  tape unlock")]
+    Unlock,
+    /// Forward space files
+    #[command(after_help = "Examples:\n  tape fsf\n  tape fsf 3")]
+    Fsf(CountArg),
+    /// Backward space files
+    #[command(after_help = "Examples:\n  tape bsf 2")]
+    Bsf(CountArg),
+    /// Forward space records
+    #[command(after_help = "Examples:\n  tape fsr 10")]
+    Fsr(CountArg),
+    /// Backward space records
+    #[command(after_help = "Examples:\n  tape bsr 10")]
+    Bsr(CountArg),
+    /// Write end-of-file marks
+    #[command(after_help = "Examples:\n  tape weof\n  tape weof 2")]
+    Weof(CountArg),
+    /// Erase the tape from the current position
+    #[command(after_help = "Examples:\n  tape erase\n  tape erase -q")]
+    Erase(EraseArg),
+    /// Locate to a file or block
+    #[command(after_help = "Examples:\n  tape locate --file 17\n  tape locate --block 1024 --partition 1")]
+    Locate(LocateArg),
+    /// Copy a tape file to a file on disk. Exits with 5 if it ends at the end of data instead of a filemark
+    #[command(after_help = "Examples:\n  tape dump --file 12 --out dump.bin\n  tape --json dump -o rest.bin")]
+    Dump(DumpArg),
+    /// Set the block size
+    #[command(after_help = "Examples:\n  tape blocksize 65536\n  tape blocksize variable")]
+    Blocksize(BlockSizeArg),
+    /// Set the density
+    #[command(after_help = "Examples:\n  tape density LTO-5\n  tape density 0x58")]
+    Density(DensityArg),
+    /// Turn compression on or off
+    #[command(after_help = "Examples:\n  tape comp on")]
+    Comp(CompressionArg),
+    /// Space to the end of recorded data
+    #[command(after_help = "Examples:\n  tape eod")]
+    Eod,
+    /// Re-tension the tape
+    #[command(after_help = "Examples:\n  tape retension")]
+    Retension,
+    /// Print and clear the error status of the last commands
+    #[command(after_help = "Examples:\n  tape errstat\n  tape --json errstat")]
+    Errstat,
+    /// Print the completion script of a shell
+    #[command(hide = true)]
+    Completions(CompletionsArg),
+}
+
+fn parse_block_size(value: &str) -> Result<u32, String> {
+    if value.eq_ignore_ascii_case("variable") {
+        // 0 表示可变块大小
+        return Ok(0);
+    }
+    value
+        .parse()
+        .map_err(|_| format!("expect a size in bytes or \"variable\", got {value}"))
+}
+
+fn parse_interval(value: &str) -> Result<Duration, String> {
+    let unit_start = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (count, unit) = value.split_at(unit_start);
+    let count = count
+        .parse::<u64>()
+        .map_err(|_| format!("expect a time such as 2s or 500ms, got {value}"))?;
+    let interval = match unit {
+        "ms" => Duration::from_millis(count),
+        "s" | "" => Duration::from_secs(count),
+        "m" => Duration::from_secs(count * 60),
+        _ => return Err(format!("expect a time such as 2s or 500ms, got {value}")),
+    };
+    if interval.is_zero() {
+        return Err("the interval can not be zero".to_string());
+    }
+    Ok(interval)
+}
+
+fn parse_density(value: &str) -> Result<u32, String> {
+    if let Some(density) = Density::by_name(value) {
+        return Ok(density.code);
+    }
+    let code = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    match code {
+        Ok(code) => Ok(code),
+        Err(_) => Density::find(value).map(|density| density.code).map_err(|e| e.to_string()),
+    }
+}
+
+fn status(tape: &TapeDevice, arg: StatusArg) -> Result<Output> {
+    if arg.extended {
+        match tape.status_ex()? {
+            Some(status) => Ok(Output::StatusEx(status)),
+            None => bail!(Unsupported("extended status")),
+        }
+    } else {
+        Ok(Output::Status(tape.status()?))
+    }
+}
+
+/// Error of something the drive or driver does not report.
+#[derive(Debug)]
+struct Unsupported(&'static str);
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the drive does not report {}.", self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+fn error_kind(e: &anyhow::Error) -> ErrorKind {
+    if e.is::<Unsupported>() {
+        return ErrorKind::Unsupported;
+    }
+    exit::error_kind(e)
+}
+
+/// Hash what goes through it.
+struct HashWriter<W: Write> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn dump(tape: &TapeDevice, arg: DumpArg) -> Result<Output> {
+    if let Some(file) = arg.file {
+        tape.locate_to(&LocationBuilder::new().file(file))?;
+    }
+    let out = File::create(&arg.out).with_context(|| format!("unable to create {}.", arg.out.display()))?;
+    let mut writer = HashWriter {
+        inner: BufWriter::new(out),
+        hasher: blake3::Hasher::new(),
+    };
+    let report = tape
+        .dump_file(&mut writer)
+        .with_context(|| format!("unable to dump to {}.", arg.out.display()))?;
+    Ok(Output::Dump(report, writer.hasher.finalize()))
+}
+
+fn run(tape: &TapeDevice, command: Commands) -> Result<Output> {
+    match command {
+        Commands::Status(arg) => return status(tape, arg),
+        Commands::Watch(arg) => watch::watch(tape, arg.interval)?,
+        Commands::Rewind => tape.rewind()?,
+        Commands::Offline => tape.rewind_and_offline()?,
+        Commands::Lock => tape.prevent_removal()?,
+        Commands::Unlock => tape.allow_removal()?,
+        Commands::Fsf(arg) => tape.forward_space_file(arg.count)?,
+        Commands::Bsf(arg) => tape.backward_space_file(arg.count)?,
+        Commands::Fsr(arg) => tape.forward_space_record(arg.count)?,
+        Commands::Bsr(arg) => tape.backward_space_record(arg.count)?,
+        Commands::Weof(arg) => tape.write_eof(arg.count)?,
+        // 计数为 0 时快速擦除
+        Commands::Erase(arg) => tape.erase(if arg.quick { 0 } else { 1 })?,
+        Commands::Locate(arg) => {
+            let mut builder = LocationBuilder::new();
+            if let Some(partition) = arg.partition {
+                builder = builder.change_partition(partition);
+            }
+            let location = match (arg.file, arg.block) {
+                (Some(file), _) => builder.file(file),
+                (None, Some(block)) => builder.block(block),
+                (None, None) => unreachable!("a target is required by clap."),
+            };
+            tape.locate_to(&location)?;
+        }
+        Commands::Dump(arg) => return dump(tape, arg),
+        Commands::Blocksize(arg) => tape.set_block_size(arg.size)?,
+        Commands::Density(arg) => tape.set_density(arg.density)?,
+        Commands::Comp(arg) => {
+            tape.set_compression(arg.state.into())?;
+        }
+        Commands::Eod => tape.jump_to_eom()?,
+        Commands::Retension => tape.retension()?,
+        Commands::Errstat => return Ok(Output::Errors(tape.get_last_error()?)),
+        Commands::Completions(_) => unreachable!("completions do not open the device."),
+    }
+    Ok(Output::Done)
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string(value).expect("output is always serializable."));
+}
+
+fn print_output(tape: &TapeDevice, output: Output, json: bool) {
+    if !json {
+        match output {
+            Output::Status(status) => println!("{status}"),
+            Output::StatusEx(status) => println!("{status}"),
+            Output::Errors(errors) => println!("{errors}"),
+            Output::Dump(report, hash) => {
+                let end = match report.end {
+                    DumpEnd::Filemark => "a filemark",
+                    DumpEnd::EndOfData => "the end of data",
+                };
+                println!("{} bytes in {} blocks, stopped at {end}.", report.bytes, report.blocks);
+                println!("blake3: {}", hash.to_hex());
+            }
+            Output::Done => {}
+        }
+        return;
+    }
+    let mut result = JsonOutput {
+        ok: true,
+        status: None,
+        errors: None,
+        dump: None,
+        position: None,
+    };
+    match &output {
+        Output::Status(status) => result.status = Some(JsonStatus::Basic(status)),
+        Output::StatusEx(status) => result.status = Some(JsonStatus::Extended(status)),
+        Output::Errors(errors) => result.errors = Some(errors),
+        Output::Dump(report, hash) => result.dump = Some(JsonDump::new(report, hash)),
+        Output::Done => {
+            result.position = tape.status().ok().map(|status| JsonPosition {
+                file: status.file_no,
+                block: status.block_no,
+            })
+        }
+    }
+    print_json(&result);
+}
+
+fn main() -> ExitCode {
+    let cli: Cli = match exit::parse_args() {
+        Ok(cli) => cli,
+        Err(code) => return code,
+    };
+
+    if let Commands::Completions(arg) = &cli.command {
+        completion::print_completions(arg.shell, Cli::command(), &["device"]);
+        return ExitCode::SUCCESS;
+    }
+
+    let machine = exit::machine_errors(cli.json);
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => return exit::report(ErrorKind::Config, &format!("{e:#}"), machine),
+    };
+    let device = cli
+        .device
+        .or(config.tape.device)
+        .unwrap_or_else(|| DEFAULT_DEVICE.to_string());
+    let tape = match TapeDevice::open(device.as_str()) {
+        Ok(tape) => tape,
+        Err(e) => return exit::report(error_kind(&e), &format!("{e:#}"), machine),
+    };
+    match run(&tape, cli.command) {
+        Ok(output) => {
+            let code = output.exit_code();
+            print_output(&tape, output, cli.json);
+            code
+        }
+        Err(e) => exit::report(error_kind(&e), &format!("{e:#}"), machine),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{error_kind, Cli, Commands, CompressionMode, JsonDump, JsonOutput, JsonPosition, JsonStatus, Unsupported};
+    use clap::{CommandFactory, Parser};
+    use common::exit::ErrorKind;
+    use serde_json::json;
+    use std::time::Duration;
+    use tape::device::{BlockSize, Compression, Density, DriverState, DumpEnd, DumpReport, TapeStatus};
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("tape").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse(&["-f", "/dev/nsa1", "rewind"]).device.as_deref(), Some("/dev/nsa1"));
+        assert_eq!(parse(&["rewind", "-f", "/dev/nsa1"]).device.as_deref(), Some("/dev/nsa1"));
+
+        assert!(matches!(parse(&["status"]).command, Commands::Status(arg) if !arg.extended));
+        assert!(matches!(parse(&["status", "-x"]).command, Commands::Status(arg) if arg.extended));
+        assert!(matches!(parse(&["offline"]).command, Commands::Offline));
+        assert!(matches!(parse(&["lock"]).command, Commands::Lock));
+        assert!(matches!(parse(&["unlock"]).command, Commands::Unlock));
+        assert!(matches!(parse(&["watch"]).command, Commands::Watch(arg) if arg.interval == Duration::from_secs(2)));
+        assert!(matches!(
+            parse(&["watch", "--interval", "500ms"]).command,
+            Commands::Watch(arg) if arg.interval == Duration::from_millis(500)
+        ));
+        assert!(matches!(parse(&["fsf"]).command, Commands::Fsf(arg) if arg.count == 1));
+        assert!(matches!(parse(&["fsf", "3"]).command, Commands::Fsf(arg) if arg.count == 3));
+        assert!(matches!(parse(&["bsf", "2"]).command, Commands::Bsf(arg) if arg.count == 2));
+        assert!(matches!(parse(&["fsr", "4"]).command, Commands::Fsr(arg) if arg.count == 4));
+        assert!(matches!(parse(&["bsr", "5"]).command, Commands::Bsr(arg) if arg.count == 5));
+        assert!(matches!(parse(&["weof", "2"]).command, Commands::Weof(arg) if arg.count == 2));
+        assert!(matches!(parse(&["erase"]).command, Commands::Erase(arg) if !arg.quick));
+        assert!(matches!(parse(&["erase", "-q"]).command, Commands::Erase(arg) if arg.quick));
+        assert!(matches!(
+            parse(&["locate", "--file", "17", "--partition", "1"]).command,
+            Commands::Locate(arg) if arg.file == Some(17) && arg.partition == Some(1)
+        ));
+        assert!(matches!(
+            parse(&["locate", "--block", "1024"]).command,
+            Commands::Locate(arg) if arg.block == Some(1024) && arg.file.is_none()
+        ));
+        assert!(matches!(parse(&["blocksize", "variable"]).command, Commands::Blocksize(arg) if arg.size == 0));
+        assert!(matches!(parse(&["blocksize", "65536"]).command, Commands::Blocksize(arg) if arg.size == 65536));
+        assert!(matches!(parse(&["density", "0x58"]).command, Commands::Density(arg) if arg.density == 0x58));
+        assert!(matches!(parse(&["density", "lto-5"]).command, Commands::Density(arg) if arg.density == 0x58));
+        assert!(matches!(parse(&["density", "96"]).command, Commands::Density(arg) if arg.density == 96));
+        assert!(matches!(parse(&["density", "t10000c"]).command, Commands::Density(arg) if arg.density == 0x4c));
+        assert!(matches!(parse(&["comp", "on"]).command, Commands::Comp(arg) if arg.state == CompressionMode::On));
+        assert!(matches!(parse(&["comp", "off"]).command, Commands::Comp(arg) if arg.state == CompressionMode::Off));
+        assert!(matches!(parse(&["comp", "dclz"]).command, Commands::Comp(arg) if arg.state == CompressionMode::Dclz));
+        assert!(matches!(parse(&["eod"]).command, Commands::Eod));
+        assert!(matches!(parse(&["retension"]).command, Commands::Retension));
+        assert!(matches!(parse(&["errstat"]).command, Commands::Errstat));
+        assert!(matches!(
+            parse(&["dump", "--file", "12", "--out", "dump.bin"]).command,
+            Commands::Dump(arg) if arg.file == Some(12) && arg.out.to_str() == Some("dump.bin")
+        ));
+        assert!(matches!(parse(&["dump", "-o", "-"]).command, Commands::Dump(arg) if arg.file.is_none()));
+        assert!(matches!(parse(&["completions", "zsh"]).command, Commands::Completions(_)));
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let output = JsonOutput {
+            ok: true,
+            status: None,
+            errors: None,
+            dump: None,
+            position: Some(JsonPosition { file: 2, block: 10 }),
+        };
+        let value = serde_json::to_value(&output).unwrap();
+        assert_eq!(value, json!({"ok": true, "position": {"file": 2, "block": 10}}));
+
+        let status = TapeStatus {
+            state: DriverState::Rest,
+            block_size: BlockSize::Fixed(512),
+            density: Density::by_name("LTO-5").unwrap(),
+            compression: Compression::On,
+            file_no: 1,
+            block_no: 0,
+            residual: 0,
+        };
+        let output = JsonOutput {
+            ok: true,
+            status: Some(JsonStatus::Basic(&status)),
+            errors: None,
+            dump: None,
+            position: None,
+        };
+        let value = serde_json::to_value(&output).unwrap();
+        assert_eq!(
+            value,
+            json!({"ok": true, "status": {
+                "state": "rest",
+                "block_size": 512,
+                "density": {"code": 0x58, "description": "LTO-5"},
+                "compression": "on",
+                "file_no": 1,
+                "block_no": 0,
+                "residual": 0,
+            }})
+        );
+        assert!(parse(&["--json", "rewind"]).json);
+
+        let report = DumpReport {
+            bytes: 3,
+            blocks: 1,
+            end: DumpEnd::EndOfData,
+        };
+        let hash = blake3::hash(b"abc");
+        let value = serde_json::to_value(JsonDump::new(&report, &hash)).unwrap();
+        assert_eq!(
+            value,
+            json!({"bytes": 3, "blocks": 1, "blake3": hash.to_hex().as_str(), "end": "end_of_data"})
+        );
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let e = anyhow::Error::new(Unsupported("extended status"));
+        assert_eq!(error_kind(&e), ErrorKind::Unsupported);
+        assert_eq!(error_kind(&e).exit_code(), 4);
+
+        let e = tape::TapeDevice::open("/dev/no-such-tape").err().unwrap();
+        assert_eq!(error_kind(&e), ErrorKind::DeviceNotReady);
+        assert_eq!(error_kind(&e).exit_code(), 10);
+
+        assert_eq!(error_kind(&anyhow::anyhow!("unexpected")).exit_code(), 1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for args in [
+            &["locate"][..],
+            &["locate", "--file", "1", "--block", "2"],
+            &["blocksize", "large"],
+            &["density", "LTO-99"],
+            &["density", "3592A1"],
+            &["comp", "maybe"],
+            &["fsf", "-1"],
+            &["dump", "--file", "1"],
+            &["watch", "--interval", "0s"],
+            &["watch", "--interval", "2h"],
+        ] {
+            let args = std::iter::once("tape").chain(args.iter().copied());
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! `tape watch`, the status of the drive polled at an interval and redrawn in place.
+//!
+//! The status is polled through a clone of the handle, see [`TapeDevice::try_clone`]. When stdout is not a terminal,
+//! a line is printed for each poll instead.
+
+use anyhow::Result;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+use tape::device::{BlockSize, TapeStatus};
+use tape::TapeDevice;
+
+/// What a poll of the drive gives.
+struct Sample {
+    at: Instant,
+    status: TapeStatus,
+    /// Logical block from the beginning of the partition, `None` if the drive does not report it
+    position: Option<u32>,
+    /// Whether the tape is past the early warning, `None` without extended status
+    early_warning: Option<bool>,
+}
+
+/// Transfer rate between two polls, in bytes if the block size is fixed.
+#[derive(Debug, PartialEq)]
+enum Rate {
+    Bytes(f64),
+    Blocks(f64),
+}
+
+impl std::fmt::Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rate::Bytes(rate) => write!(f, "{:.1} MB/s", rate / (1024.0 * 1024.0)),
+            Rate::Blocks(rate) => write!(f, "{rate:.0} blocks/s"),
+        }
+    }
+}
+
+/// Rate of the drive moving from block `before` to block `after` in `elapsed`, `None` if it moved backwards.
+fn rate(before: u32, after: u32, elapsed: Duration, block_size: &BlockSize) -> Option<Rate> {
+    let blocks = after.checked_sub(before)? as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    Some(match block_size {
+        BlockSize::Fixed(size) => Rate::Bytes(blocks * *size as f64),
+        BlockSize::Variable => Rate::Blocks(blocks),
+    })
+}
+
+fn poll(tape: &TapeDevice) -> Result<Sample> {
+    // NOP 使驱动刷新状态
+    tape.nop()?;
+    let status = tape.status()?;
+    let position = tape.read_scsi_pos().ok();
+    let early_warning = tape
+        .status_ex()
+        .ok()
+        .flatten()
+        .map(|status| status.eop != 0 || status.bpew != 0);
+    Ok(Sample {
+        at: Instant::now(),
+        status,
+        position,
+        early_warning,
+    })
+}
+
+/// Lines describing `sample`, with the rate since `previous`.
+fn describe(sample: &Sample, previous: Option<&Sample>) -> Vec<String> {
+    let status = &sample.status;
+    let rate = previous
+        .zip(sample.position)
+        .and_then(|(previous, after)| {
+            let before = previous.position?;
+            rate(before, after, sample.at - previous.at, &status.block_size)
+        })
+        .map_or_else(|| "-".to_string(), |rate| rate.to_string());
+    let position = sample
+        .position
+        .map_or_else(|| "-".to_string(), |position| position.to_string());
+    let early_warning = match sample.early_warning {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "-",
+    };
+    vec![
+        format!("State: {}", status.state),
+        format!(
+            "File: {}  Record: {}  Logical block: {position}",
+            status.file_no, status.block_no
+        ),
+        format!("Block size: {}  Density: {}", status.block_size, status.density),
+        format!("Compression: {}  Early warning: {early_warning}", status.compression),
+        format!("Rate: {rate}"),
+    ]
+}
+
+/// Whether `q` or Ctrl-C is pressed before `deadline`.
+fn quit_before(deadline: Instant) -> Result<bool> {
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if !event::poll(timeout)? {
+            return Ok(false);
+        }
+        if let Event::Key(key) = event::read()? {
+            let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.kind == KeyEventKind::Press && (key.code == KeyCode::Char('q') || ctrl_c) {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Poll `tape` every `interval` until interrupted, redrawing the status in place on a terminal.
+pub fn watch(tape: &TapeDevice, interval: Duration) -> Result<()> {
+    let tape = tape.try_clone()?;
+    let mut previous: Option<Sample> = None;
+    let mut stdout = std::io::stdout();
+    if !stdout.is_terminal() {
+        loop {
+            // 轮询失败时照常输出, 换带或驱动忙时仍继续观察
+            let line = match poll(&tape) {
+                Ok(sample) => {
+                    let line = describe(&sample, previous.as_ref()).join("  ");
+                    previous = Some(sample);
+                    line
+                }
+                Err(e) => format!("error: {e:#}"),
+            };
+            match writeln!(stdout, "{line}") {
+                Ok(()) => {}
+                // 读取端已关闭, 例如接到 head
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+            std::thread::sleep(interval);
+        }
+    }
+
+    enable_raw_mode()?;
+    crossterm::execute!(stdout, EnterAlternateScreen, crossterm::cursor::Hide)?;
+    let result = (|| -> Result<()> {
+        loop {
+            let deadline = Instant::now() + interval;
+            let lines = match poll(&tape) {
+                Ok(sample) => {
+                    let lines = describe(&sample, previous.as_ref());
+                    previous = Some(sample);
+                    lines
+                }
+                Err(e) => vec![format!("error: {e:#}")],
+            };
+            crossterm::queue!(stdout, crossterm::cursor::MoveTo(0, 0), Clear(ClearType::All))?;
+            write!(stdout, "{} every {:?}, q to quit\r\n\r\n", tape.path(), interval)?;
+            for line in lines {
+                write!(stdout, "{line}\r\n")?;
+            }
+            stdout.flush()?;
+            if quit_before(deadline)? {
+                return Ok(());
+            }
+        }
+    })();
+
+    disable_raw_mode()?;
+    crossterm::execute!(stdout, crossterm::cursor::Show, LeaveAlternateScreen)?;
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rate, Rate};
+    use std::time::Duration;
+    use tape::device::BlockSize;
+
+    #[test]
+    fn test_rate() {
+        let elapsed = Duration::from_secs(2);
+        assert_eq!(
+            rate(100, 300, elapsed, &BlockSize::Fixed(65536)),
+            Some(Rate::Bytes(6553600.0))
+        );
+        assert_eq!(rate(100, 300, elapsed, &BlockSize::Variable), Some(Rate::Blocks(100.0)));
+        assert_eq!(rate(100, 100, elapsed, &BlockSize::Variable), Some(Rate::Blocks(0.0)));
+        // 倒带后位置变小
+        assert_eq!(rate(300, 0, elapsed, &BlockSize::Variable), None);
+        assert_eq!(Rate::Bytes(6553600.0).to_string(), "6.2 MB/s");
+        assert_eq!(Rate::Blocks(99.6).to_string(), "100 blocks/s");
+    }
+}
@@ -1,38 +1,158 @@
 #![allow(dead_code)]
 
+mod capability;
+mod compare;
+#[cfg(feature = "copy")]
+mod copy;
+mod drive;
+mod dump;
 mod eot;
 mod err;
+mod error;
 mod limit;
 mod locate;
+mod log_sense;
+mod node;
 mod operate;
+#[cfg(feature = "status-ex")]
+mod params;
+mod position;
+mod read;
+mod ready;
+mod removal;
 mod status;
+#[cfg(feature = "status-ex")]
 mod status_ex;
+mod write;
 
 use anyhow::Result;
-use std::os::fd::RawFd;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::OnceLock;
 
+pub use capability::DriveCapabilities;
+pub use compare::CompareOutcome;
+#[cfg(feature = "copy")]
+pub use copy::{copy_files, CopyOptions, CopyReport, FileCopy, FileCount};
+pub use dump::{DumpEnd, DumpReport};
 pub use eot::EotModel;
 pub use err::{ErrorCounter, ScsiTapeErrors};
+pub use error::TapeError;
 pub use limit::BlockLimit;
 pub use locate::{Location, LocationBuilder};
+pub use log_sense::CompressionCounters;
+pub use node::{CloseBehavior, DeviceVariant};
 pub use operate::Operation;
-pub use status::{Density, DriverState, TapeStatus};
-pub use status_ex::TapeStatusEx;
+#[cfg(feature = "status-ex")]
+pub use params::SaParameters;
+pub use position::{FileMove, TapePosition};
+pub use read::{EventReader, PositionedEvent, ReadBlock, TapeEvent};
+pub use status::{BlockSize, Compression, Density, DriverState, TapeStatus};
+#[cfg(feature = "status-ex")]
+pub use status_ex::{DensityEntry, DensityFlags, DensityReport, MtDensity, Protection, TapeStatusEx};
 
 pub struct TapeDevice {
     fd: RawFd,
+    /// Path the device was opened with, for errors
+    path: String,
+    capabilities: OnceLock<DriveCapabilities>,
 }
 
 impl TapeDevice {
     pub fn open<P: nix::NixPath + ?Sized>(path: &P) -> Result<Self> {
+        Self::open_with(path, nix::fcntl::OFlag::O_RDWR)
+    }
+
+    /// Open the device without waiting for the drive, which [`TapeDevice::open`] does while it has no tape, or is
+    /// still loading one. Call [`TapeDevice::wait_ready`] before reading or writing.
+    pub fn open_nonblocking<P: nix::NixPath + ?Sized>(path: &P) -> Result<Self> {
         use nix::fcntl::OFlag;
+
+        Self::open_with(path, OFlag::O_RDWR | OFlag::O_NONBLOCK)
+    }
+
+    fn open_with<P: nix::NixPath + ?Sized>(path: &P, flags: nix::fcntl::OFlag) -> Result<Self> {
         use nix::sys::stat::Mode;
 
-        let fd = nix::fcntl::open(path, OFlag::O_RDWR, Mode::all())?;
-        Ok(Self { fd })
+        let path_str = path.with_nix_path(|p| p.to_string_lossy().into_owned())?;
+        let fd = nix::fcntl::open(path, flags, Mode::all()).map_err(|errno| TapeError::Ioctl {
+            device: path_str.clone(),
+            operation: "open()".to_string(),
+            errno,
+        })?;
+        Ok(Self {
+            fd,
+            path: path_str,
+            capabilities: OnceLock::new(),
+        })
     }
 
     pub fn fd(&self) -> RawFd {
         self.fd
     }
+
+    /// Close the device, and return the error of close, which [`Drop`] ignores. Closing a rewinding node such as
+    /// `/dev/sa0` rewinds the tape, and writes the filemarks still pending.
+    pub fn close(mut self) -> Result<()> {
+        // 置为 -1, drop 时不再关闭
+        let fd = std::mem::replace(&mut self.fd, -1);
+        nix::unistd::close(fd).map_err(|errno| self.ioctl_error("close()", errno))?;
+        Ok(())
+    }
+
+    /// Path the device was opened with.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Another handle on the device opened, sharing its position. A watcher polls the status through it while the
+    /// original handle does I/O, the device can not be opened twice.
+    pub fn try_clone(&self) -> Result<Self> {
+        let fd = nix::unistd::dup(self.fd).map_err(|errno| self.ioctl_error("dup()", errno))?;
+        Ok(Self {
+            fd,
+            path: self.path.clone(),
+            capabilities: self.capabilities.clone(),
+        })
+    }
+}
+
+/// Borrow the descriptor for I/O outside this crate, `File::from(tape.as_fd().try_clone_to_owned()?)` gives a handle
+/// closed on its own, without closing the device under `TapeDevice`.
+impl AsFd for TapeDevice {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // fd 在 TapeDevice 存续期间有效
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl AsRawFd for TapeDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// The caller owns the descriptor returned, the device is not closed on drop.
+impl IntoRawFd for TapeDevice {
+    fn into_raw_fd(mut self) -> RawFd {
+        std::mem::replace(&mut self.fd, -1)
+    }
+}
+
+/// Take ownership of `fd`, opened on a tape device, closed when the device is dropped. Errors name it `/dev/fd/N`.
+impl FromRawFd for TapeDevice {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self {
+            fd,
+            path: format!("/dev/fd/{fd}"),
+            capabilities: OnceLock::new(),
+        }
+    }
+}
+
+impl Drop for TapeDevice {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            let _ = nix::unistd::close(self.fd);
+        }
+    }
 }
@@ -0,0 +1,132 @@
+//! What a drive supports, so that operations it lacks fail up front instead of with an errno deep in a workflow.
+//!
+//! sa(4) does not report most of these, they are inferred from the extended status: the product name, the density
+//! of the medium loaded and the medium types the drive lists.
+
+use super::{TapeDevice, TapeError};
+use anyhow::Result;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// Density codes of DDS and DAT-72, the formats with setmarks
+const SETMARK_DENSITIES: [u32; 5] = [0x13, 0x24, 0x25, 0x26, 0x47];
+/// Density codes of formats with partitions: DDS-2 to DAT-72, and LTO-5 and later
+const PARTITION_DENSITIES: [u32; 10] = [0x24, 0x25, 0x26, 0x47, 0x58, 0x5A, 0x5C, 0x5D, 0x5E, 0x60];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DriveCapabilities {
+    pub setmarks: bool,
+    pub compression: bool,
+    /// Logical block protection
+    pub protection: bool,
+    pub partitions: bool,
+    /// Takes write-once media
+    pub worm: bool,
+    /// Whether the driver reported the status they are inferred from. If not, all but `worm` are assumed, and the
+    /// drive refuses what it lacks.
+    pub probed: bool,
+}
+
+impl DriveCapabilities {
+    fn unprobed() -> Self {
+        Self {
+            setmarks: true,
+            compression: true,
+            protection: true,
+            partitions: true,
+            worm: false,
+            probed: false,
+        }
+    }
+
+    #[cfg(feature = "status-ex")]
+    fn from_status(status: &super::TapeStatusEx) -> Self {
+        let density = status.mtdensity.media_density;
+        let product = status.product.to_ascii_uppercase();
+        let worm = status
+            .mtdensity
+            .density_report
+            .iter()
+            .flat_map(|report| &report.density_entry)
+            .filter_map(|entry| entry.medium_type_name.as_deref())
+            .any(|name| name.to_ascii_uppercase().contains("WORM"));
+        Self {
+            // 未装入磁带时密度为 0, 由产品名判断
+            setmarks: SETMARK_DENSITIES.contains(&density) || product.contains("DDS") || product.contains("DAT"),
+            compression: status.compression_supported != 0,
+            protection: status.protection.protection_supported != 0,
+            partitions: PARTITION_DENSITIES.contains(&density),
+            worm,
+            probed: true,
+        }
+    }
+}
+
+impl TapeDevice {
+    /// What the drive supports, probed on first use and kept for the life of the device.
+    pub fn capabilities(&self) -> DriveCapabilities {
+        *self.capabilities.get_or_init(|| {
+            #[cfg(feature = "status-ex")]
+            if let Ok(Some(status)) = self.status_ex() {
+                return DriveCapabilities::from_status(&status);
+            }
+            DriveCapabilities::unprobed()
+        })
+    }
+
+    /// Fail with [`TapeError::Unsupported`] if the drive lacks `capability`, named for the error.
+    pub(crate) fn require(&self, supported: fn(&DriveCapabilities) -> bool, capability: &'static str) -> Result<()> {
+        if supported(&self.capabilities()) {
+            return Ok(());
+        }
+        Err(TapeError::Unsupported {
+            device: self.path.clone(),
+            capability,
+        }
+        .into())
+    }
+}
+
+#[cfg(all(test, feature = "status-ex"))]
+mod test {
+    use super::DriveCapabilities;
+    use crate::device::{DensityEntry, DensityReport, TapeStatusEx};
+
+    #[test]
+    fn test_from_status() {
+        let mut status = TapeStatusEx {
+            product: "ULTRIUM-HH8".to_string(),
+            compression_supported: 1,
+            ..Default::default()
+        };
+        status.mtdensity.media_density = 0x5E;
+        status.mtdensity.density_report.push(DensityReport {
+            density_entry: vec![DensityEntry {
+                medium_type_name: Some("LTO-8 WORM".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        let capabilities = DriveCapabilities::from_status(&status);
+        assert_eq!(
+            capabilities,
+            DriveCapabilities {
+                setmarks: false,
+                compression: true,
+                protection: false,
+                partitions: true,
+                worm: true,
+                probed: true,
+            }
+        );
+
+        // 未装入磁带的 DDS 驱动器
+        let status = TapeStatusEx {
+            product: "DAT72".to_string(),
+            ..Default::default()
+        };
+        let capabilities = DriveCapabilities::from_status(&status);
+        assert!(capabilities.setmarks && !capabilities.partitions && !capabilities.worm);
+    }
+}
@@ -0,0 +1,183 @@
+//! Compare a tape file with a local copy, without extracting it.
+
+use super::drive::Drive;
+use super::read::ReadBlock;
+use super::TapeDevice;
+use anyhow::{Context, Result};
+use std::fmt;
+use std::io::{ErrorKind, Read};
+
+/// Result of a comparison. Offsets are in bytes from the beginning of the comparison.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOutcome {
+    Identical {
+        bytes: u64,
+    },
+    /// First byte that differs
+    Differs {
+        offset: u64,
+    },
+    /// The tape file ends at `offset`, the other one goes on
+    TapeShorter {
+        offset: u64,
+    },
+    /// The other one ends at `offset`, the tape file goes on
+    TapeLonger {
+        offset: u64,
+    },
+}
+
+impl CompareOutcome {
+    pub fn is_identical(&self) -> bool {
+        matches!(self, CompareOutcome::Identical { .. })
+    }
+}
+
+impl fmt::Display for CompareOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompareOutcome::Identical { bytes } => write!(f, "identical, {bytes} bytes"),
+            CompareOutcome::Differs { offset } => write!(f, "differs at byte {offset}"),
+            CompareOutcome::TapeShorter { offset } => write!(f, "the tape file is shorter, it ends at byte {offset}"),
+            CompareOutcome::TapeLonger { offset } => write!(f, "the tape file is longer, the other ends at byte {offset}"),
+        }
+    }
+}
+
+/// Read into `buf` until it is full or the reader ends, and return how many bytes were read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+pub(crate) fn compare<D: Drive, R: Read>(drive: &D, mut other: R) -> Result<CompareOutcome> {
+    let size = drive.max_block_size()?;
+    let mut block = vec![0u8; size];
+    let mut expected = vec![0u8; size];
+    let mut offset = 0u64;
+    loop {
+        let count = match drive.read_block(&mut block)? {
+            ReadBlock::Data(count) => count,
+            ReadBlock::Setmark => continue,
+            ReadBlock::Filemark | ReadBlock::EndOfData => {
+                // 磁带文件已结束, 另一侧也应结束
+                let rest = read_full(&mut other, &mut expected[..1]).context("unable to read the other file.")?;
+                return Ok(match rest {
+                    0 => CompareOutcome::Identical { bytes: offset },
+                    _ => CompareOutcome::TapeShorter { offset },
+                });
+            }
+        };
+        // 按磁带块读取另一侧, 与其分块方式无关
+        let read = read_full(&mut other, &mut expected[..count]).context("unable to read the other file.")?;
+        if let Some(i) = (0..read).find(|i| block[*i] != expected[*i]) {
+            return Ok(CompareOutcome::Differs {
+                offset: offset + i as u64,
+            });
+        }
+        if read < count {
+            return Ok(CompareOutcome::TapeLonger {
+                offset: offset + read as u64,
+            });
+        }
+        offset += count as u64;
+    }
+}
+
+impl TapeDevice {
+    /// Compare the rest of the current file with `other`, stopping at the first difference.
+    ///
+    /// The tape is left at the beginning of the next file if both are identical or the tape file is shorter, otherwise
+    /// just after the block where they differ.
+    pub fn compare_file<R: Read>(&self, other: R) -> Result<CompareOutcome> {
+        compare(self, other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare, CompareOutcome};
+    use crate::device::drive::mock::MockTape;
+    use std::io::{Cursor, Read};
+
+    fn tape() -> MockTape {
+        MockTape::with_files(&[vec![vec![1; 100], vec![2; 100], vec![3; 50]], vec![vec![4; 10]]])
+    }
+
+    fn content() -> Vec<u8> {
+        [vec![1; 100], vec![2; 100], vec![3; 50]].concat()
+    }
+
+    /// Gives at most 7 bytes by read.
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(7);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(
+            compare(&tape(), Cursor::new(content())).unwrap(),
+            CompareOutcome::Identical { bytes: 250 }
+        );
+        assert_eq!(
+            compare(&tape(), Trickle(Cursor::new(content()))).unwrap(),
+            CompareOutcome::Identical { bytes: 250 }
+        );
+
+        let mut other = content();
+        other[150] = 0;
+        assert_eq!(
+            compare(&tape(), Cursor::new(other)).unwrap(),
+            CompareOutcome::Differs { offset: 150 }
+        );
+    }
+
+    #[test]
+    fn test_compare_lengths() {
+        let mut other = content();
+        other.push(3);
+        let tape = tape();
+        assert_eq!(
+            compare(&tape, Cursor::new(other)).unwrap(),
+            CompareOutcome::TapeShorter { offset: 250 }
+        );
+        // 停在下一个文件开头
+        assert_eq!(
+            compare(&tape, Cursor::new(vec![4; 10])).unwrap(),
+            CompareOutcome::Identical { bytes: 10 }
+        );
+
+        assert_eq!(
+            compare(&self::tape(), Cursor::new(&content()[..120])).unwrap(),
+            CompareOutcome::TapeLonger { offset: 120 }
+        );
+        // 较短一侧的末尾字节不同时, 报告差异
+        let mut other = content()[..120].to_vec();
+        other[119] = 0;
+        assert_eq!(
+            compare(&self::tape(), Cursor::new(other)).unwrap(),
+            CompareOutcome::Differs { offset: 119 }
+        );
+        assert_eq!(
+            compare(&self::tape(), Cursor::new(Vec::new())).unwrap(),
+            CompareOutcome::TapeLonger { offset: 0 }
+        );
+        assert_eq!(
+            CompareOutcome::TapeLonger { offset: 0 }.to_string(),
+            "the tape file is longer, the other ends at byte 0"
+        );
+    }
+}
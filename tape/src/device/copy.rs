@@ -0,0 +1,328 @@
+//! Copy files from one tape to another, without staging them on disk.
+//!
+//! Each file is read block by block and written as it is read, re-blocked when the destination takes other block
+//! sizes. Once its filemark is written, the copy is read back and hashed, and compared to the hash of what was read.
+
+use super::drive::Drive;
+use super::position::{prev_file, FileMove};
+use super::read::ReadBlock;
+use super::TapeDevice;
+use anyhow::{bail, ensure, Context, Result};
+
+/// How many files to copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileCount {
+    Files(u32),
+    /// Every file up to the end of data, to clone a whole tape
+    ToEndOfData,
+}
+
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    /// Size of the blocks written, `None` to keep the source blocks when the destination takes them
+    block_size: Option<usize>,
+    verify: bool,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            block_size: None,
+            verify: true,
+        }
+    }
+}
+
+impl CopyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write blocks of `size` bytes, the last block of each file being shorter.
+    pub fn block_size(mut self, size: usize) -> Self {
+        self.block_size = Some(size);
+        self
+    }
+
+    /// Read back and hash each file copied, on by default.
+    pub fn verify(mut self, val: bool) -> Self {
+        self.verify = val;
+        self
+    }
+}
+
+/// One file copied.
+#[derive(Debug, Clone)]
+pub struct FileCopy {
+    /// File number on the source tape
+    pub source_file: u64,
+    /// File number on the destination tape
+    pub destination_file: u64,
+    pub bytes: u64,
+    pub source_blocks: u64,
+    pub destination_blocks: u64,
+    /// Hash of what was read from the source
+    pub hash: blake3::Hash,
+    /// Hash of the copy read back, `None` without verification
+    pub destination_hash: Option<blake3::Hash>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CopyReport {
+    pub files: Vec<FileCopy>,
+}
+
+impl CopyReport {
+    pub fn bytes(&self) -> u64 {
+        self.files.iter().map(|file| file.bytes).sum()
+    }
+}
+
+/// Write `data` in blocks of `size` bytes, keeping what does not fill a block in `pending`.
+struct Reblocker<'a, D: Drive> {
+    drive: &'a D,
+    size: Option<usize>,
+    max: usize,
+    pending: Vec<u8>,
+    blocks: u64,
+}
+
+impl<D: Drive> Reblocker<'_, D> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        match self.size {
+            Some(size) => {
+                self.pending.extend_from_slice(data);
+                let full = self.pending.len() / size * size;
+                for block in self.pending[..full].chunks(size) {
+                    self.drive.write_block(block)?;
+                    self.blocks += 1;
+                }
+                self.pending.drain(..full);
+            }
+            // 源块大于目标驱动器的上限时拆分
+            None => {
+                for block in data.chunks(self.max) {
+                    self.drive.write_block(block)?;
+                    self.blocks += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            self.drive.write_block(&self.pending)?;
+            self.blocks += 1;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Hash the file under the head up to its filemark, and the number of blocks in it.
+fn hash_file<D: Drive>(drive: &D, buffer: &mut [u8]) -> Result<(blake3::Hash, u64)> {
+    let mut hasher = blake3::Hasher::new();
+    let mut blocks = 0;
+    loop {
+        match drive.read_block(buffer)? {
+            ReadBlock::Data(count) => {
+                hasher.update(&buffer[..count]);
+                blocks += 1;
+            }
+            ReadBlock::Filemark => return Ok((hasher.finalize(), blocks)),
+            ReadBlock::Setmark | ReadBlock::EndOfData => bail!("the file ends without a filemark."),
+        }
+    }
+}
+
+pub(crate) fn copy<S: Drive, D: Drive>(src: &S, dst: &D, count: FileCount, opts: &CopyOptions) -> Result<CopyReport> {
+    let dst_max = dst.max_block_size()?;
+    if let Some(size) = opts.block_size {
+        ensure!(
+            size > 0 && size <= dst_max,
+            "block size {size} is out of the destination limit of {dst_max} bytes."
+        );
+    }
+    let mut buffer = vec![0u8; src.max_block_size()?];
+    let mut verify_buffer = vec![0u8; dst_max];
+    let mut report = CopyReport::default();
+
+    loop {
+        if let FileCount::Files(n) = count {
+            if report.files.len() == n as usize {
+                break;
+            }
+        }
+        let source_file = src.position()?.0.context("the source lost track of the file number.")?;
+        let destination_file = dst.position()?.0.context("the destination lost track of the file number.")?;
+        let mut hasher = blake3::Hasher::new();
+        let mut bytes = 0;
+        let mut source_blocks = 0;
+        let mut writer = Reblocker {
+            drive: dst,
+            size: opts.block_size,
+            max: dst_max,
+            pending: Vec::new(),
+            blocks: 0,
+        };
+
+        let ended = loop {
+            match src.read_block(&mut buffer)? {
+                ReadBlock::Data(count) => {
+                    let data = &buffer[..count];
+                    hasher.update(data);
+                    writer.write(data)?;
+                    bytes += count as u64;
+                    source_blocks += 1;
+                }
+                ReadBlock::Filemark => break false,
+                ReadBlock::Setmark => bail!("file {source_file} of the source has a setmark, which is not copied."),
+                ReadBlock::EndOfData => break true,
+            }
+        };
+        // 在文件开头遇到 EOD, 没有更多文件
+        if ended && source_blocks == 0 {
+            if let FileCount::Files(n) = count {
+                bail!("the source ends after {} of {n} files.", report.files.len());
+            }
+            break;
+        }
+        writer.flush()?;
+        let destination_blocks = writer.blocks;
+        dst.write_filemarks(1)?;
+
+        let hash = hasher.finalize();
+        let destination_hash = if opts.verify {
+            // 回到刚写入的文件开头, 读完后停在下一个文件开头
+            match prev_file(dst)? {
+                FileMove::Moved(_) => {}
+                _ => bail!("unable to go back to file {destination_file} of the destination."),
+            }
+            let (destination_hash, _) = hash_file(dst, &mut verify_buffer)?;
+            ensure!(
+                destination_hash == hash,
+                "file {destination_file} of the destination does not match file {source_file} of the source, {} != {}.",
+                destination_hash.to_hex(),
+                hash.to_hex()
+            );
+            Some(destination_hash)
+        } else {
+            None
+        };
+        report.files.push(FileCopy {
+            source_file,
+            destination_file,
+            bytes,
+            source_blocks,
+            destination_blocks,
+            hash,
+            destination_hash,
+        });
+        // 最后一个文件没有文件标记
+        if ended {
+            if let FileCount::Files(n) = count {
+                ensure!(
+                    report.files.len() == n as usize,
+                    "the source ends after {} of {n} files.",
+                    report.files.len()
+                );
+            }
+            break;
+        }
+    }
+    Ok(report)
+}
+
+/// Copy `count` files from the current position of `src` to the current position of `dst`, which should be in
+/// variable block mode. Each file copied is followed by a filemark on the destination, what follows it there is lost.
+pub fn copy_files(src: &TapeDevice, dst: &TapeDevice, count: FileCount, opts: CopyOptions) -> Result<CopyReport> {
+    copy(src, dst, count, &opts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{copy, CopyOptions, FileCount};
+    use crate::device::drive::mock::MockTape;
+
+    #[test]
+    fn test_copy() {
+        let src = MockTape::with_blocks(&[3, 5, 4]);
+        let dst = MockTape::blank();
+        let report = copy(&src, &dst, FileCount::Files(2), &CopyOptions::new()).unwrap();
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(report.bytes(), 8 * 512);
+        assert_eq!(report.files[1].source_file, 1);
+        assert_eq!(report.files[1].destination_blocks, 5);
+        assert_eq!(report.files[0].destination_hash, Some(report.files[0].hash));
+        // 目标带上的文件与源相同, 之后为 EOD
+        assert_eq!(dst.files()[..2], src.files()[..2]);
+        assert!(dst.files()[2].is_empty());
+        assert_eq!(dst.files().len(), 3);
+    }
+
+    #[test]
+    fn test_copy_to_end_of_data() {
+        let src = MockTape::with_blocks(&[3, 5, 4]);
+        let dst = MockTape::blank();
+        let report = copy(&src, &dst, FileCount::ToEndOfData, &CopyOptions::new()).unwrap();
+        assert_eq!(report.files.len(), 3);
+        assert_eq!(dst.files(), src.files());
+
+        // 文件数多于源带上的
+        let src = MockTape::with_blocks(&[3]);
+        let e = copy(&src, &MockTape::blank(), FileCount::Files(2), &CopyOptions::new()).unwrap_err();
+        assert_eq!(e.to_string(), "the source ends after 1 of 2 files.");
+
+        // 最后一个文件没有文件标记
+        let src = MockTape::with_blocks(&[2]);
+        src.records
+            .borrow_mut()
+            .push(super::super::drive::mock::Record::Block(vec![7; 100]));
+        let dst = MockTape::blank();
+        let report = copy(&src, &dst, FileCount::ToEndOfData, &CopyOptions::new()).unwrap();
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(dst.files()[1], [vec![7; 100]]);
+    }
+
+    #[test]
+    fn test_reblock() {
+        let src = MockTape::with_files(&[vec![vec![1; 1000], vec![2; 1000], vec![3; 500]]]);
+        let dst = MockTape::blank();
+        let report = copy(&src, &dst, FileCount::Files(1), &CopyOptions::new().block_size(1024)).unwrap();
+        let blocks = &dst.files()[0];
+        assert_eq!(blocks.iter().map(Vec::len).collect::<Vec<_>>(), [1024, 1024, 452]);
+        assert_eq!(report.files[0].source_blocks, 3);
+        assert_eq!(report.files[0].destination_blocks, 3);
+        assert_eq!(report.files[0].hash, blake3::hash(&blocks.concat()));
+
+        // 目标驱动器的块上限较小
+        let src = MockTape::with_files(&[vec![vec![1; 1000]]]);
+        let dst = MockTape::blank().max_block(256);
+        copy(&src, &dst, FileCount::Files(1), &CopyOptions::new()).unwrap();
+        assert_eq!(dst.files()[0].len(), 4);
+        assert!(copy(&src, &dst, FileCount::Files(1), &CopyOptions::new().block_size(512)).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        // 从目标带中间开始, 校验后继续追加
+        let src = MockTape::with_blocks(&[3, 5]);
+        let dst = MockTape::with_blocks(&[1, 1]).at(2, Some(0));
+        let report = copy(&src, &dst, FileCount::ToEndOfData, &CopyOptions::new()).unwrap();
+        assert_eq!(report.files[0].destination_file, 2);
+        assert_eq!(report.files[1].destination_file, 3);
+        assert_eq!(dst.files()[2..4], src.files()[..2]);
+
+        let dst = MockTape::blank();
+        let report = copy(
+            &src.at(0, Some(0)),
+            &dst,
+            FileCount::Files(1),
+            &CopyOptions::new().verify(false),
+        )
+        .unwrap();
+        assert!(report.files[0].destination_hash.is_none());
+        assert!(dst.ops.borrow().is_empty());
+    }
+}
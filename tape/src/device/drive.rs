@@ -0,0 +1,286 @@
+//! Primitive operations that higher level moves are built on, so that they are tested on a mock tape.
+
+use super::read::{mark_of, ReadBlock};
+use super::{TapeDevice, TapeError};
+use anyhow::Result;
+use nix::errno::Errno;
+
+/// Where spacing stopped before the count was done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Boundary {
+    EndOfData,
+    BeginningOfTape,
+}
+
+pub(crate) trait Drive {
+    /// File and block numbers, `None` for the ones the driver lost track of.
+    fn position(&self) -> Result<(Option<u64>, Option<u64>)>;
+    /// Space over `count` filemarks, backward if negative. Running into EOD or BOT is reported, not an error.
+    fn space_files(&self, count: i32) -> Result<Option<Boundary>>;
+    fn rewind(&self) -> Result<()>;
+    fn read_block(&self, buf: &mut [u8]) -> Result<ReadBlock>;
+    fn write_block(&self, buf: &[u8]) -> Result<()>;
+    fn write_filemarks(&self, count: u32) -> Result<()>;
+    /// Largest block the drive reads or writes.
+    fn max_block_size(&self) -> Result<usize>;
+}
+
+/// The driver reports -1 for an unknown file or block number.
+fn known(value: usize) -> Option<u64> {
+    (value as i32 >= 0).then_some(value as u64)
+}
+
+impl Drive for TapeDevice {
+    fn position(&self) -> Result<(Option<u64>, Option<u64>)> {
+        let status = self.status()?;
+        Ok((known(status.file_no), known(status.block_no)))
+    }
+
+    fn space_files(&self, count: i32) -> Result<Option<Boundary>> {
+        let result = match count {
+            0 => Ok(()),
+            1.. => self.forward_space_file(count as u32),
+            _ => self.backward_space_file(count.unsigned_abs()),
+        };
+        let Err(e) = result else {
+            return Ok(None);
+        };
+        // 两种边界都以 EIO 结束
+        if e.downcast_ref::<TapeError>().map(TapeError::errno) != Some(Errno::EIO) {
+            return Err(e);
+        }
+        if count > 0 {
+            let sense = self.get_last_error().map(|errors| errors.ctl_sense).unwrap_or_default();
+            if mark_of(&sense) == ReadBlock::EndOfData {
+                return Ok(Some(Boundary::EndOfData));
+            }
+        } else if self.position()? == (Some(0), Some(0)) {
+            return Ok(Some(Boundary::BeginningOfTape));
+        }
+        Err(e)
+    }
+
+    fn rewind(&self) -> Result<()> {
+        TapeDevice::rewind(self)
+    }
+
+    fn read_block(&self, buf: &mut [u8]) -> Result<ReadBlock> {
+        TapeDevice::read_block(self, buf)
+    }
+
+    fn write_block(&self, buf: &[u8]) -> Result<()> {
+        TapeDevice::write_block(self, buf)
+    }
+
+    fn write_filemarks(&self, count: u32) -> Result<()> {
+        self.write_eof(count)
+    }
+
+    fn max_block_size(&self) -> Result<usize> {
+        Ok(self.read_block_limit()?.max_block_length as usize)
+    }
+}
+
+/// A tape in memory, moving like sa(4) does.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::{Boundary, Drive};
+    use crate::device::read::ReadBlock;
+    use anyhow::{bail, ensure, Result};
+    use std::cell::{Cell, RefCell};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) enum Record {
+        Block(Vec<u8>),
+        Filemark,
+    }
+
+    pub(crate) struct MockTape {
+        /// Records up to the end of data
+        pub records: RefCell<Vec<Record>>,
+        /// Index of the record under the head
+        pub index: Cell<usize>,
+        /// The driver forgets the block number after spacing backward
+        pub block_known: Cell<bool>,
+        pub max_block: usize,
+        /// Operations issued, such as `bsf 1`
+        pub ops: RefCell<Vec<String>>,
+    }
+
+    impl MockTape {
+        /// A blank tape, positioned at BOT.
+        pub fn blank() -> Self {
+            Self {
+                records: RefCell::new(Vec::new()),
+                index: Cell::new(0),
+                block_known: Cell::new(true),
+                max_block: 1 << 20,
+                ops: RefCell::new(Vec::new()),
+            }
+        }
+
+        /// A tape of files made of `blocks[i]` blocks of 512 bytes, each followed by a filemark, positioned at BOT.
+        pub fn with_blocks(blocks: &[usize]) -> Self {
+            let files: Vec<Vec<Vec<u8>>> = blocks
+                .iter()
+                .enumerate()
+                .map(|(file, count)| (0..*count).map(|block| vec![(file * 16 + block) as u8; 512]).collect())
+                .collect();
+            Self::with_files(&files)
+        }
+
+        /// A tape of files made of the given blocks, each followed by a filemark, positioned at BOT.
+        pub fn with_files(files: &[Vec<Vec<u8>>]) -> Self {
+            let tape = Self::blank();
+            for blocks in files {
+                let mut records = tape.records.borrow_mut();
+                records.extend(blocks.iter().cloned().map(Record::Block));
+                records.push(Record::Filemark);
+            }
+            tape
+        }
+
+        pub fn max_block(mut self, size: usize) -> Self {
+            self.max_block = size;
+            self
+        }
+
+        /// Move to `block` of `file`, or to the end of the file without a block number, as after spacing backward.
+        pub fn at(self, file: u64, block: Option<u64>) -> Self {
+            let records = self.records.borrow();
+            let start = match file {
+                0 => 0,
+                _ => {
+                    records
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, record)| **record == Record::Filemark)
+                        .nth(file as usize - 1)
+                        .expect("no such file")
+                        .0
+                        + 1
+                }
+            };
+            let index = match block {
+                Some(block) => start + block as usize,
+                None => (start..records.len())
+                    .find(|i| records[*i] == Record::Filemark)
+                    .unwrap_or(records.len()),
+            };
+            drop(records);
+            self.index.set(index);
+            self.block_known.set(block.is_some());
+            self
+        }
+
+        /// Blocks of each file, the last one being what follows the last filemark.
+        pub fn files(&self) -> Vec<Vec<Vec<u8>>> {
+            let mut files = vec![Vec::new()];
+            for record in self.records.borrow().iter() {
+                match record {
+                    Record::Block(data) => files.last_mut().unwrap().push(data.clone()),
+                    Record::Filemark => files.push(Vec::new()),
+                }
+            }
+            files
+        }
+
+        /// Drop what follows the head, as writing does.
+        fn truncate(&self) {
+            self.records.borrow_mut().truncate(self.index.get());
+            self.block_known.set(true);
+        }
+    }
+
+    impl Drive for MockTape {
+        fn position(&self) -> Result<(Option<u64>, Option<u64>)> {
+            let records = self.records.borrow();
+            let passed = &records[..self.index.get()];
+            let file = passed.iter().filter(|record| **record == Record::Filemark).count();
+            let block = passed.iter().rev().take_while(|record| **record != Record::Filemark).count();
+            Ok((Some(file as u64), self.block_known.get().then_some(block as u64)))
+        }
+
+        fn space_files(&self, count: i32) -> Result<Option<Boundary>> {
+            self.ops
+                .borrow_mut()
+                .push(format!("{} {}", if count < 0 { "bsf" } else { "fsf" }, count.abs()));
+            let records = self.records.borrow();
+            for _ in 0..count.unsigned_abs() {
+                if count > 0 {
+                    match (self.index.get()..records.len()).find(|i| records[*i] == Record::Filemark) {
+                        Some(mark) => self.index.set(mark + 1),
+                        None => {
+                            self.index.set(records.len());
+                            self.block_known.set(true);
+                            return Ok(Some(Boundary::EndOfData));
+                        }
+                    }
+                    self.block_known.set(true);
+                } else {
+                    match (0..self.index.get()).rev().find(|i| records[*i] == Record::Filemark) {
+                        // 停在文件标记靠近 BOT 的一侧, 块号未知
+                        Some(mark) => {
+                            self.index.set(mark);
+                            self.block_known.set(false);
+                        }
+                        None => {
+                            self.index.set(0);
+                            self.block_known.set(true);
+                            return Ok(Some(Boundary::BeginningOfTape));
+                        }
+                    }
+                }
+            }
+            Ok(None)
+        }
+
+        fn rewind(&self) -> Result<()> {
+            self.ops.borrow_mut().push("rewind".to_string());
+            self.index.set(0);
+            self.block_known.set(true);
+            Ok(())
+        }
+
+        fn read_block(&self, buf: &mut [u8]) -> Result<ReadBlock> {
+            let records = self.records.borrow();
+            let Some(record) = records.get(self.index.get()) else {
+                return Ok(ReadBlock::EndOfData);
+            };
+            let read = match record {
+                Record::Filemark => ReadBlock::Filemark,
+                Record::Block(data) => {
+                    // 可变块模式下缓冲区小于块时读取失败
+                    ensure!(buf.len() >= data.len(), "EINVAL: block of {} bytes", data.len());
+                    buf[..data.len()].copy_from_slice(data);
+                    ReadBlock::Data(data.len())
+                }
+            };
+            self.index.set(self.index.get() + 1);
+            Ok(read)
+        }
+
+        fn write_block(&self, buf: &[u8]) -> Result<()> {
+            if buf.len() > self.max_block {
+                bail!("EINVAL: block of {} bytes", buf.len());
+            }
+            self.truncate();
+            self.records.borrow_mut().push(Record::Block(buf.to_vec()));
+            self.index.set(self.index.get() + 1);
+            Ok(())
+        }
+
+        fn write_filemarks(&self, count: u32) -> Result<()> {
+            self.truncate();
+            for _ in 0..count {
+                self.records.borrow_mut().push(Record::Filemark);
+            }
+            self.index.set(self.index.get() + count as usize);
+            Ok(())
+        }
+
+        fn max_block_size(&self) -> Result<usize> {
+            Ok(self.max_block)
+        }
+    }
+}
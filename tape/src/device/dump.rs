@@ -0,0 +1,116 @@
+//! Copy a tape file to a writer, for recovery without the program that wrote it.
+
+use super::drive::Drive;
+use super::read::ReadBlock;
+use super::TapeDevice;
+use anyhow::{Context, Result};
+use std::io::Write;
+
+/// What stopped a dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpEnd {
+    /// The filemark of the file, the tape is at the beginning of the next file.
+    Filemark,
+    /// The file is the last one and has no filemark, the tape is at the end of data.
+    EndOfData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpReport {
+    pub bytes: u64,
+    pub blocks: u64,
+    pub end: DumpEnd,
+}
+
+pub(crate) fn dump<D: Drive, W: Write>(drive: &D, mut out: W) -> Result<DumpReport> {
+    let mut buffer = vec![0u8; drive.max_block_size()?];
+    let mut bytes = 0;
+    let mut blocks = 0;
+    let end = loop {
+        match drive.read_block(&mut buffer)? {
+            ReadBlock::Data(count) => {
+                // 写入失败时立即停止, 磁带停在该块之后
+                out.write_all(&buffer[..count])
+                    .with_context(|| format!("unable to write block {blocks} of the file, after {bytes} bytes."))?;
+                bytes += count as u64;
+                blocks += 1;
+            }
+            ReadBlock::Setmark => {}
+            ReadBlock::Filemark => break DumpEnd::Filemark,
+            ReadBlock::EndOfData => break DumpEnd::EndOfData,
+        }
+    };
+    out.flush().context("unable to flush the output.")?;
+    Ok(DumpReport { bytes, blocks, end })
+}
+
+impl TapeDevice {
+    /// Write the rest of the current file to `out`, block by block, skipping setmarks.
+    ///
+    /// If writing fails, the tape is left just after the block that was not written. Call
+    /// [`beginning_of_file`](Self::beginning_of_file) to dump the file again.
+    pub fn dump_file<W: Write>(&self, out: W) -> Result<DumpReport> {
+        dump(self, out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dump, DumpEnd, DumpReport};
+    use crate::device::drive::mock::{MockTape, Record};
+    use crate::device::drive::Drive;
+    use std::io::{self, Write};
+
+    #[test]
+    fn test_dump() {
+        let tape = MockTape::with_files(&[vec![vec![1; 100], vec![2; 50]], vec![vec![3; 10]]]);
+        let mut out = Vec::new();
+        let report = dump(&tape, &mut out).unwrap();
+        assert_eq!(
+            report,
+            DumpReport {
+                bytes: 150,
+                blocks: 2,
+                end: DumpEnd::Filemark
+            }
+        );
+        assert_eq!(out, [vec![1; 100], vec![2; 50]].concat());
+        assert_eq!(tape.position().unwrap(), (Some(1), Some(0)));
+
+        // 最后一个文件没有文件标记
+        let tape = MockTape::with_blocks(&[1]).at(1, Some(0));
+        tape.records.borrow_mut().push(Record::Block(vec![4; 20]));
+        let report = dump(&tape, io::sink()).unwrap();
+        assert_eq!((report.bytes, report.end), (20, DumpEnd::EndOfData));
+        let report = dump(&tape, io::sink()).unwrap();
+        assert_eq!((report.bytes, report.end), (0, DumpEnd::EndOfData));
+    }
+
+    /// Accepts `limit` bytes, then fails.
+    struct FullDisk {
+        limit: usize,
+    }
+
+    impl Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.limit < buf.len() {
+                return Err(io::Error::other("no space left"));
+            }
+            self.limit -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dump_write_error() {
+        let tape = MockTape::with_blocks(&[4, 2]);
+        let e = dump(&tape, FullDisk { limit: 1024 }).unwrap_err();
+        assert_eq!(e.to_string(), "unable to write block 2 of the file, after 1024 bytes.");
+        // 停在未写出的块之后
+        assert_eq!(tape.position().unwrap(), (Some(0), Some(3)));
+    }
+}
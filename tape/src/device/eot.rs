@@ -20,7 +20,7 @@ impl TapeDevice {
         let mut model = 0u32;
 
         unsafe {
-            ioctl_func::get_eot_model(self.fd, &mut model)?;
+            ioctl_func::get_eot_model(self.fd, &mut model).map_err(|e| self.ioctl_error("get_eot_model()", e))?;
         }
         let result = match model {
             1 => EotModel::OneSetmark,
@@ -43,7 +43,10 @@ impl TapeDevice {
             }
         };
 
-        unsafe { ioctl_func::set_eot_model(self.fd, &eot_model)? };
+        unsafe {
+            ioctl_func::set_eot_model(self.fd, &eot_model)
+                .map_err(|e| self.ioctl_error(format!("set_eot_model(model={eot_model})"), e))?
+        };
         Ok(())
     }
 }
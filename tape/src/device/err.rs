@@ -1,9 +1,13 @@
 use super::TapeDevice;
 use anyhow::Result;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::fmt;
 
 /// structure for MTIOCERRSTAT - tape get error status command
 /// really only supported for SCSI tapes right now
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct ScsiTapeErrors {
     // These are latched from the last command that had a SCSI
     // Check Condition noted for these operations. The act
@@ -24,11 +28,14 @@ pub struct ScsiTapeErrors {
     // These are the read and write cumulative error counters.
     // (how to reset cumulative error counters is not yet defined).
     // (not implemented as yet but space is being reserved for them)
+    #[cfg_attr(feature = "serde", serde(skip))]
     _wterr: ErrorCounter,
+    #[cfg_attr(feature = "serde", serde(skip))]
     _rderr: ErrorCounter,
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct ErrorCounter {
     /// total # retries performed
     retries: u32,
@@ -42,6 +49,32 @@ pub struct ErrorCounter {
     nbytes: u64,
 }
 
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{byte:02x}")?;
+    }
+    writeln!(f)
+}
+
+/// Formatted like `mt errstat`.
+impl fmt::Display for ScsiTapeErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Last I/O Command:     ")?;
+        write_hex(f, &self.io_cdb)?;
+        write!(f, "Last I/O Sense:       ")?;
+        write_hex(f, &self.io_sense)?;
+        writeln!(f, "Last I/O Residual:    {}", self.io_resid)?;
+        write!(f, "Last Control Command: ")?;
+        write_hex(f, &self.ctl_cdb)?;
+        write!(f, "Last Control Sense:   ")?;
+        write_hex(f, &self.ctl_sense)?;
+        write!(f, "Last Control Residual: {}", self.ctl_resid)
+    }
+}
+
 #[repr(C)]
 pub union MtErrStat {
     scsi_err_stat: ScsiTapeErrors,
@@ -65,7 +98,7 @@ impl TapeDevice {
     pub fn get_last_error(&self) -> Result<ScsiTapeErrors> {
         let result = unsafe {
             let mut err_stat: MtErrStat = std::mem::zeroed();
-            ioctl_func::read_error_status(self.fd, &mut err_stat)?;
+            ioctl_func::read_error_status(self.fd, &mut err_stat).map_err(|e| self.ioctl_error("get_last_error()", e))?;
 
             err_stat.scsi_err_stat
         };
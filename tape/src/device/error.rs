@@ -0,0 +1,194 @@
+use super::{DriverState, TapeDevice};
+use nix::errno::Errno;
+use std::fmt;
+use std::time::Duration;
+
+/// Error of an operation on a tape device. Functions return it inside `anyhow::Error`, get it back with
+/// `downcast_ref::<TapeError>()` to match on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TapeError {
+    /// An ioctl, a read, or opening the device failed.
+    Ioctl {
+        /// Path the device was opened with
+        device: String,
+        /// Operation attempted, such as `write_eof(count=2)`
+        operation: String,
+        errno: Errno,
+    },
+    /// The drive lacks what the operation needs, nothing was sent to it.
+    Unsupported {
+        device: String,
+        /// Such as `setmarks`
+        capability: &'static str,
+    },
+    /// Fewer bytes were written than given, as at the end of the tape in fixed block mode.
+    ShortWrite {
+        device: String,
+        written: usize,
+        requested: usize,
+        /// Residual count the driver reported for the write, `None` if it could not be read
+        residual: Option<i32>,
+    },
+    /// No tape was loaded in the drive while waited for.
+    NotReady { device: String, waited: Duration },
+    /// The drive had a tape but was still busy, such as loading or rewinding it, when the wait expired.
+    Timeout {
+        device: String,
+        waited: Duration,
+        /// State last reported by the driver
+        state: DriverState,
+    },
+}
+
+impl fmt::Display for TapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TapeError::Ioctl {
+                device,
+                operation,
+                errno,
+            } => write!(f, "{device}: {operation} failed: {errno}"),
+            TapeError::Unsupported { device, capability } => write!(f, "{device}: the drive does not support {capability}"),
+            TapeError::ShortWrite {
+                device,
+                written,
+                requested,
+                residual,
+            } => {
+                write!(f, "{device}: only {written} of {requested} bytes are written")?;
+                match residual {
+                    Some(residual) => write!(f, ", residual {residual}"),
+                    None => Ok(()),
+                }
+            }
+            TapeError::NotReady { device, waited } => {
+                write!(f, "{device}: no tape is loaded after waiting {}s", waited.as_secs())
+            }
+            TapeError::Timeout { device, waited, state } => {
+                write!(
+                    f,
+                    "{device}: the drive is not ready after waiting {}s: {state}",
+                    waited.as_secs()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TapeError {}
+
+impl TapeError {
+    /// Errno the call failed with, `EOPNOTSUPP` for an operation not sent to the drive, `ENOSPC` for a short write,
+    /// `ENXIO` and `ETIMEDOUT` for a drive not ready after a wait.
+    pub fn errno(&self) -> Errno {
+        match self {
+            TapeError::Ioctl { errno, .. } => *errno,
+            TapeError::Unsupported { .. } => Errno::EOPNOTSUPP,
+            TapeError::ShortWrite { .. } => Errno::ENOSPC,
+            TapeError::NotReady { .. } => Errno::ENXIO,
+            TapeError::Timeout { .. } => Errno::ETIMEDOUT,
+        }
+    }
+
+    /// The drive lacks a capability the operation needs.
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self, TapeError::Unsupported { .. })
+    }
+
+    /// The device node is absent, the drive has no tape loaded, or is not ready after a wait.
+    pub fn is_not_ready(&self) -> bool {
+        matches!(self.errno(), Errno::ENOENT | Errno::ENXIO | Errno::ENODEV) || self.is_timeout()
+    }
+
+    /// The drive was waited for, and is still busy with the tape.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, TapeError::Timeout { .. })
+    }
+
+    /// Another process holds the device open.
+    pub fn is_busy(&self) -> bool {
+        self.errno() == Errno::EBUSY
+    }
+
+    /// The end of the tape is reached while writing.
+    pub fn is_end_of_medium(&self) -> bool {
+        self.errno() == Errno::ENOSPC
+    }
+}
+
+impl TapeDevice {
+    /// Wrap `errno` of `operation` on this device.
+    pub(crate) fn ioctl_error(&self, operation: impl Into<String>, errno: Errno) -> TapeError {
+        TapeError::Ioctl {
+            device: self.path.clone(),
+            operation: operation.into(),
+            errno,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TapeError;
+    use crate::device::DriverState;
+    use crate::TapeDevice;
+    use nix::errno::Errno;
+    use std::time::Duration;
+
+    #[test]
+    fn test_open_error() {
+        let e = TapeDevice::open("/dev/no-such-tape").err().unwrap();
+        let error = e.downcast_ref::<TapeError>().unwrap();
+        assert_eq!(
+            error,
+            &TapeError::Ioctl {
+                device: "/dev/no-such-tape".to_string(),
+                operation: "open()".to_string(),
+                errno: Errno::ENOENT,
+            }
+        );
+        assert_eq!(
+            e.to_string(),
+            "/dev/no-such-tape: open() failed: ENOENT: No such file or directory"
+        );
+        assert!(error.is_not_ready());
+        assert!(!error.is_busy() && !error.is_end_of_medium() && !error.is_unsupported());
+
+        let error = TapeError::Unsupported {
+            device: "/dev/nsa0".to_string(),
+            capability: "setmarks",
+        };
+        assert_eq!(error.to_string(), "/dev/nsa0: the drive does not support setmarks");
+        assert!(error.is_unsupported() && !error.is_not_ready());
+
+        let error = TapeError::ShortWrite {
+            device: "/dev/nsa0".to_string(),
+            written: 32768,
+            requested: 65536,
+            residual: Some(32768),
+        };
+        assert_eq!(
+            error.to_string(),
+            "/dev/nsa0: only 32768 of 65536 bytes are written, residual 32768"
+        );
+        assert!(error.is_end_of_medium());
+
+        let error = TapeError::NotReady {
+            device: "/dev/nsa0".to_string(),
+            waited: Duration::from_secs(60),
+        };
+        assert_eq!(error.to_string(), "/dev/nsa0: no tape is loaded after waiting 60s");
+        assert!(error.is_not_ready() && !error.is_timeout());
+
+        let error = TapeError::Timeout {
+            device: "/dev/nsa0".to_string(),
+            waited: Duration::from_secs(60),
+            state: DriverState::Loading,
+        };
+        assert_eq!(
+            error.to_string(),
+            "/dev/nsa0: the drive is not ready after waiting 60s: Loading"
+        );
+        assert!(error.is_not_ready() && error.is_timeout() && !error.is_busy());
+    }
+}
@@ -1,8 +1,11 @@
 use super::TapeDevice;
 use anyhow::Result;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BlockLimit {
     /// The actual granularity is 2 raised to the power of the value.
     ///
@@ -32,10 +35,30 @@ impl TapeDevice {
         let result = unsafe {
             let mut limit: BlockLimit = std::mem::zeroed();
 
-            ioctl_func::read_block_limit(self.fd, &mut limit)?;
+            ioctl_func::read_block_limit(self.fd, &mut limit).map_err(|e| self.ioctl_error("read_block_limit()", e))?;
             limit
         };
 
         Ok(result)
     }
 }
+
+#[cfg(all(test, feature = "serde"))]
+mod test {
+    use super::BlockLimit;
+
+    #[test]
+    fn test_serde() {
+        let limit = BlockLimit {
+            granularity: 0,
+            min_block_length: 1,
+            max_block_length: 8388608,
+        };
+        let value = serde_json::to_value(&limit).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"granularity": 0, "min_block_length": 1, "max_block_length": 8388608})
+        );
+        assert_eq!(serde_json::from_value::<BlockLimit>(value).unwrap(), limit);
+    }
+}
@@ -1,5 +1,6 @@
 use super::TapeDevice;
 use anyhow::Result;
+use std::fmt;
 
 enum MtLocateDestType {
     Object = 0x00,
@@ -94,6 +95,21 @@ pub struct Location {
     to_partition: Option<i64>,
 }
 
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.target {
+            Target::File(file) => write!(f, "file={file}")?,
+            Target::Block(block) => write!(f, "block={block}")?,
+            Target::Setmark(setmark) => write!(f, "setmark={setmark}")?,
+            Target::Eod => write!(f, "eod")?,
+        }
+        if let Some(partition) = self.to_partition {
+            write!(f, ", partition={partition}")?;
+        }
+        Ok(())
+    }
+}
+
 mod ioctl_func {
     use super::MtLocate;
 
@@ -111,6 +127,7 @@ impl TapeDevice {
             param.flags |= MtLocateFlags::Immediately as u32;
         }
         if let Some(partition) = location.to_partition {
+            self.require(|c| c.partitions, "partitions")?;
             param.partition = partition;
             param.flags |= MtLocateFlags::ChangePartition as u32;
         }
@@ -126,6 +143,7 @@ impl TapeDevice {
                 param.logical_id = block;
             }
             Target::Setmark(setmark) => {
+                self.require(|c| c.setmarks, "setmarks")?;
                 param.dest_type = MtLocateDestType::Setmark as u32;
                 param.logical_id = setmark;
             }
@@ -134,14 +152,25 @@ impl TapeDevice {
             }
         }
         // Note: `/dev/nsa0` is needed, while operation on `/dev/sa0` leads always leads to status BOP.
-        let ret = unsafe { ioctl_func::locate(self.fd, &param)? };
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+        let ret = unsafe {
+            ioctl_func::locate(self.fd, &param).map_err(|e| self.ioctl_error(format!("locate_to({location})"), e))?
+        };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            dest_type = param.dest_type,
+            logical_id = param.logical_id,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "tape located"
+        );
         Ok(ret as u32)
     }
 
     pub fn read_scsi_pos(&self) -> Result<u32> {
         let mut result = 0u32;
         unsafe {
-            ioctl_func::rdspos(self.fd, &mut result)?;
+            ioctl_func::rdspos(self.fd, &mut result).map_err(|e| self.ioctl_error("read_scsi_pos()", e))?;
         }
         Ok(result)
     }
@@ -149,8 +178,20 @@ impl TapeDevice {
     pub fn write_scsi_pos(&self, pos: u32) -> Result<()> {
         let mut _result = pos;
         unsafe {
-            ioctl_func::slocate(self.fd, &_result)?;
+            ioctl_func::slocate(self.fd, &_result).map_err(|e| self.ioctl_error(format!("write_scsi_pos(pos={pos})"), e))?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::LocationBuilder;
+
+    #[test]
+    fn test_display() {
+        let location = LocationBuilder::new().change_partition(1).file(17);
+        assert_eq!(location.to_string(), "file=17, partition=1");
+        assert_eq!(LocationBuilder::new().end_of_data().to_string(), "eod");
+    }
+}
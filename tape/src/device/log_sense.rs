@@ -0,0 +1,170 @@
+//! Counters of the data compression log page, to tell how well the drive compressed what was written.
+//!
+//! sa(4) has no ioctl for LOG SENSE, so with the `passthrough` feature the command is sent to the pass(4) device of
+//! the drive by camcontrol(8). Both the SSC page and the older HP page use the same parameter codes.
+
+/// Data compression log page of SSC
+const PAGE_DATA_COMPRESSION: u8 = 0x1b;
+/// Compression log page of HP DDS and older LTO drives
+const PAGE_HP_COMPRESSION: u8 = 0x32;
+/// Parameters counting megabytes, then the remainder in bytes, which may be negative
+const PARAM_MB_FROM_HOST: u16 = 0x0006;
+const PARAM_BYTES_FROM_HOST: u16 = 0x0007;
+const PARAM_MB_TO_MEDIUM: u16 = 0x0008;
+const PARAM_BYTES_TO_MEDIUM: u16 = 0x0009;
+const MEGABYTE: i64 = 1 << 20;
+
+/// Bytes received from the host and written to the medium, since the cartridge was loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionCounters {
+    pub bytes_from_host: u64,
+    pub bytes_to_medium: u64,
+}
+
+impl CompressionCounters {
+    /// Ratio of what was received to what was written since `before`, `None` if nothing reached the medium or the
+    /// counters were reset in between.
+    pub fn ratio_since(&self, before: &CompressionCounters) -> Option<f64> {
+        let from_host = self.bytes_from_host.checked_sub(before.bytes_from_host)?;
+        let to_medium = self.bytes_to_medium.checked_sub(before.bytes_to_medium)?;
+        (from_host > 0 && to_medium > 0).then(|| from_host as f64 / to_medium as f64)
+    }
+}
+
+/// Big endian value, negative if its first bit is set.
+fn signed(bytes: &[u8]) -> i64 {
+    let initial = if bytes.first().is_some_and(|b| b & 0x80 != 0) { -1 } else { 0 };
+    bytes.iter().fold(initial, |value, b| (value << 8) | *b as i64)
+}
+
+/// Counters of log page `code`, `None` if it is another page or lacks a counter.
+pub(crate) fn parse_compression_page(page: &[u8], code: u8) -> Option<CompressionCounters> {
+    if page.len() < 4 || page[0] & 0x3f != code {
+        return None;
+    }
+    let length = u16::from_be_bytes([page[2], page[3]]) as usize;
+    // 分配长度不足时页面被截断
+    let mut rest = &page[4..page.len().min(4 + length)];
+    let (mut mb_from_host, mut bytes_from_host, mut mb_to_medium, mut bytes_to_medium) = (None, None, None, None);
+    while rest.len() >= 4 {
+        let parameter = u16::from_be_bytes([rest[0], rest[1]]);
+        let end = 4 + rest[3] as usize;
+        let Some(value) = rest.get(4..end).map(signed) else {
+            break;
+        };
+        match parameter {
+            PARAM_MB_FROM_HOST => mb_from_host = Some(value),
+            PARAM_BYTES_FROM_HOST => bytes_from_host = Some(value),
+            PARAM_MB_TO_MEDIUM => mb_to_medium = Some(value),
+            PARAM_BYTES_TO_MEDIUM => bytes_to_medium = Some(value),
+            _ => {}
+        }
+        rest = &rest[end..];
+    }
+    let from_host = mb_from_host? * MEGABYTE + bytes_from_host?;
+    let to_medium = mb_to_medium? * MEGABYTE + bytes_to_medium?;
+    Some(CompressionCounters {
+        bytes_from_host: u64::try_from(from_host).ok()?,
+        bytes_to_medium: u64::try_from(to_medium).ok()?,
+    })
+}
+
+#[cfg(feature = "passthrough")]
+mod passthrough {
+    use super::{parse_compression_page, CompressionCounters, PAGE_DATA_COMPRESSION, PAGE_HP_COMPRESSION};
+    use crate::TapeDevice;
+    use anyhow::{Context, Result};
+    use std::process::Command;
+
+    /// Allocation length of LOG SENSE, enough for the compression pages
+    const ALLOCATION_LENGTH: u16 = 256;
+
+    /// Cumulative values of log page `code`, `None` if the drive rejects it or camcontrol is absent.
+    fn log_sense(periph: &str, code: u8) -> Result<Option<Vec<u8>>> {
+        let [high, low] = ALLOCATION_LENGTH.to_be_bytes();
+        // PC = 01, 累计值
+        let cdb = format!("4d 00 {:02x} 00 00 00 00 {high:02x} {low:02x} 00", 0x40 | code);
+        let length = ALLOCATION_LENGTH.to_string();
+        let output = match Command::new("camcontrol")
+            .args(["cmd", periph, "-c", &cdb, "-i", &length, "-"])
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("unable to run camcontrol."),
+        };
+        Ok(output.status.success().then_some(output.stdout))
+    }
+
+    impl TapeDevice {
+        /// Compression counters of the drive, `None` if it does not report them or the device is not a sa(4) node.
+        pub fn compression_counters(&self) -> Result<Option<CompressionCounters>> {
+            let Some(variant) = self.device_variant() else {
+                return Ok(None);
+            };
+            if !self.capabilities().compression {
+                return Ok(None);
+            }
+            let periph = format!("sa{}", variant.unit);
+            for code in [PAGE_DATA_COMPRESSION, PAGE_HP_COMPRESSION] {
+                if let Some(counters) = log_sense(&periph, code)?.and_then(|page| parse_compression_page(&page, code)) {
+                    return Ok(Some(counters));
+                }
+            }
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_compression_page, CompressionCounters, PAGE_DATA_COMPRESSION, PAGE_HP_COMPRESSION};
+
+    /// A log page with 4-byte parameters
+    fn page(code: u8, parameters: &[(u16, i32)]) -> Vec<u8> {
+        let mut page = vec![code, 0, 0, (parameters.len() * 8) as u8];
+        for (parameter, value) in parameters {
+            page.extend(parameter.to_be_bytes());
+            page.extend([0x00, 4]);
+            page.extend(value.to_be_bytes());
+        }
+        page
+    }
+
+    #[test]
+    fn test_parse_compression_page() {
+        let data = page(
+            PAGE_DATA_COMPRESSION,
+            &[(0x0001, 200), (6, 3), (7, -1024), (8, 1), (9, 512), (0x0100, 1)],
+        );
+        let counters = parse_compression_page(&data, PAGE_DATA_COMPRESSION).unwrap();
+        assert_eq!(
+            counters,
+            CompressionCounters {
+                bytes_from_host: 3 * 1048576 - 1024,
+                bytes_to_medium: 1048576 + 512,
+            }
+        );
+        // 其他页, 或缺少计数
+        assert_eq!(parse_compression_page(&data, PAGE_HP_COMPRESSION), None);
+        assert_eq!(parse_compression_page(&page(0x32, &[(6, 3), (7, 0), (8, 1)]), 0x32), None);
+        // 截断的参数被忽略
+        assert_eq!(
+            parse_compression_page(&data[..data.len() - 2], PAGE_DATA_COMPRESSION),
+            Some(counters)
+        );
+        assert_eq!(parse_compression_page(&[], PAGE_DATA_COMPRESSION), None);
+    }
+
+    #[test]
+    fn test_ratio_since() {
+        let counters = |from_host, to_medium| CompressionCounters {
+            bytes_from_host: from_host,
+            bytes_to_medium: to_medium,
+        };
+        assert_eq!(counters(3000, 1500).ratio_since(&counters(1000, 500)), Some(2.0));
+        // 没有数据写入介质, 或计数在换带后清零
+        assert_eq!(counters(3000, 500).ratio_since(&counters(1000, 500)), None);
+        assert_eq!(counters(100, 50).ratio_since(&counters(1000, 500)), None);
+    }
+}
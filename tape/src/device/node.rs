@@ -0,0 +1,158 @@
+//! Device nodes of a drive. sa(4) creates several nodes for each unit, which differ in what happens when the device
+//! is closed, and optionally in the mode page set used (`.0` to `.3` suffix).
+
+use super::{TapeDevice, TapeError};
+use anyhow::{ensure, Result};
+use nix::errno::Errno;
+use std::fmt;
+use std::path::Path;
+
+/// Highest mode number of a node, as in `/dev/nsa0.3`
+const MAX_MODE: u8 = 3;
+
+/// What the driver does with the tape when the device is closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseBehavior {
+    /// `/dev/saN`, the tape is rewound on close. The position is lost after every command, so a second `open` writes
+    /// over the first file. Only fit for a single archive per tape.
+    RewindOnClose,
+    /// `/dev/nsaN`, the position is kept on close. Use it to write or read several files in a row.
+    NoRewind,
+    /// `/dev/esaN`, the tape is rewound and ejected on close, to hand the cartridge over when a job ends.
+    EjectOnClose,
+}
+
+impl CloseBehavior {
+    fn prefix(self) -> &'static str {
+        match self {
+            CloseBehavior::RewindOnClose => "sa",
+            CloseBehavior::NoRewind => "nsa",
+            CloseBehavior::EjectOnClose => "esa",
+        }
+    }
+}
+
+/// Which node of a drive a path names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceVariant {
+    pub unit: u32,
+    pub behavior: CloseBehavior,
+    /// Mode number, `None` for the node without suffix
+    pub mode: Option<u8>,
+}
+
+impl DeviceVariant {
+    /// Recognize a node by its file name, such as `/dev/nsa0` or `esa1.2`. Control nodes `saN.ctl` and other files
+    /// give `None`.
+    pub fn parse(path: &str) -> Option<Self> {
+        let name = Path::new(path).file_name()?.to_str()?;
+        let behavior = [
+            CloseBehavior::NoRewind,
+            CloseBehavior::EjectOnClose,
+            CloseBehavior::RewindOnClose,
+        ]
+        .into_iter()
+        .find(|behavior| name.starts_with(behavior.prefix()))?;
+        let rest = &name[behavior.prefix().len()..];
+        let (unit, mode) = match rest.split_once('.') {
+            Some((unit, mode)) => (unit, Some(mode.parse::<u8>().ok().filter(|mode| *mode <= MAX_MODE)?)),
+            None => (rest, None),
+        };
+        if unit.is_empty() || !unit.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        Some(Self {
+            unit: unit.parse().ok()?,
+            behavior,
+            mode,
+        })
+    }
+
+    /// Path of the node under `/dev`.
+    pub fn path(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for DeviceVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/dev/{}{}", self.behavior.prefix(), self.unit)?;
+        if let Some(mode) = self.mode {
+            write!(f, ".{mode}")?;
+        }
+        Ok(())
+    }
+}
+
+impl TapeDevice {
+    /// Open drive `unit` through the node of `behavior`, and of `mode` (0 to 3) if given.
+    pub fn open_unit(unit: u32, behavior: CloseBehavior, mode: Option<u8>) -> Result<Self> {
+        if let Some(mode) = mode {
+            ensure!(mode <= MAX_MODE, "mode {mode} is out of 0 to {MAX_MODE}.");
+        }
+        let path = DeviceVariant { unit, behavior, mode }.path();
+        if !Path::new(&path).exists() {
+            return Err(TapeError::Ioctl {
+                device: path,
+                operation: "open_unit()".to_string(),
+                errno: Errno::ENOENT,
+            }
+            .into());
+        }
+        Self::open(path.as_str())
+    }
+
+    /// Node the device was opened on, `None` if the path is not a sa(4) node.
+    pub fn device_variant(&self) -> Option<DeviceVariant> {
+        DeviceVariant::parse(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CloseBehavior, DeviceVariant};
+    use crate::device::TapeError;
+    use crate::TapeDevice;
+
+    #[test]
+    fn test_parse() {
+        let variant = |unit, behavior, mode| Some(DeviceVariant { unit, behavior, mode });
+        assert_eq!(
+            DeviceVariant::parse("/dev/sa0"),
+            variant(0, CloseBehavior::RewindOnClose, None)
+        );
+        assert_eq!(DeviceVariant::parse("/dev/nsa1"), variant(1, CloseBehavior::NoRewind, None));
+        assert_eq!(
+            DeviceVariant::parse("esa12.3"),
+            variant(12, CloseBehavior::EjectOnClose, Some(3))
+        );
+        for path in [
+            "/dev/sa0.ctl",
+            "/dev/nsa",
+            "/dev/sa0.4",
+            "/dev/sata0",
+            "/dev/da0",
+            "/dev/nsa0.",
+            "",
+        ] {
+            assert_eq!(DeviceVariant::parse(path), None, "{path}");
+        }
+
+        for path in ["/dev/sa0", "/dev/nsa1", "/dev/esa2.1"] {
+            assert_eq!(DeviceVariant::parse(path).unwrap().path(), path);
+        }
+    }
+
+    #[test]
+    fn test_open_unit() {
+        let e = TapeDevice::open_unit(999, CloseBehavior::NoRewind, None).err().unwrap();
+        let error = e.downcast_ref::<TapeError>().unwrap();
+        assert!(error.is_not_ready());
+        assert_eq!(
+            e.to_string(),
+            "/dev/nsa999: open_unit() failed: ENOENT: No such file or directory"
+        );
+
+        assert!(TapeDevice::open_unit(0, CloseBehavior::NoRewind, Some(4)).is_err());
+    }
+}
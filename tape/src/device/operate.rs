@@ -1,7 +1,7 @@
-use super::TapeDevice;
-use anyhow::Result;
+use super::{Compression, Density, TapeDevice};
+use anyhow::{bail, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Operation {
     /// Write an end-of-file record
     WriteEof = 0,
@@ -47,6 +47,41 @@ pub enum Operation {
     WriteEofImmediately = 20,
 }
 
+impl Operation {
+    /// Call of the method doing this operation, such as `write_eof(count=2)`.
+    fn describe(self, count: u32) -> String {
+        let (method, argument) = match self {
+            Operation::WriteEof => ("write_eof", "count"),
+            Operation::ForwardSpaceFile => ("forward_space_file", "count"),
+            Operation::BackwardSpaceFile => ("backward_space_file", "count"),
+            Operation::ForwardSpaceRecord => ("forward_space_record", "count"),
+            Operation::BackwardSpaceRecord => ("backward_space_record", "count"),
+            Operation::Rewind => ("rewind", ""),
+            Operation::Offline => ("rewind_and_offline", ""),
+            Operation::NOP => ("nop", ""),
+            Operation::EnableCache => ("enable_cache", ""),
+            Operation::DisableCache => ("disable_cache", ""),
+            Operation::SetBlockSize => ("set_block_size", "size"),
+            Operation::SetDensity => ("set_density", "code"),
+            Operation::EraseToEnd => ("erase", "count"),
+            Operation::JumpToEnd => ("jump_to_eom", ""),
+            Operation::SetCompression => ("set_compression", "enable"),
+            Operation::Retension => ("retension", ""),
+            Operation::WriteSetmark => ("write_setmark", "count"),
+            Operation::ForwardSpaceSetmark => ("forward_space_setmark", "count"),
+            Operation::BackwardSpaceSetmark => ("backward_space_setmark", "count"),
+            Operation::Load => ("load", ""),
+            Operation::WriteEofImmediately => ("write_eof_immediately", "count"),
+        };
+        match (self, argument) {
+            (_, "") => format!("{method}()"),
+            (Operation::SetCompression, _) if count <= 1 => format!("{method}({argument}={})", count != 0),
+            (Operation::SetCompression, _) => format!("{method}(mode={})", Compression::from(count)),
+            _ => format!("{method}({argument}={count})"),
+        }
+    }
+}
+
 #[repr(C)]
 pub struct MtOp {
     /// Operations defined above
@@ -64,12 +99,22 @@ mod ioctl_func {
 
 impl TapeDevice {
     fn do_tape_op(&self, op: Operation, count: u32) -> Result<i32> {
+        // 倒带、擦除等操作可能耗时数分钟
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
         let ret = unsafe {
             let mut mt_op: MtOp = std::mem::zeroed();
             mt_op.op = op as u16;
             mt_op.count = count as i32;
-            ioctl_func::tape_op(self.fd, &mt_op)?
+            ioctl_func::tape_op(self.fd, &mt_op).map_err(|e| self.ioctl_error(op.describe(count), e))?
         };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            ?op,
+            count,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "tape operation finished"
+        );
 
         Ok(ret)
     }
@@ -82,8 +127,9 @@ impl TapeDevice {
         self.do_tape_op(Operation::WriteEofImmediately, count).map(|_| ())
     }
 
-    /// DDS drive only
+    /// DDS drive only, fails with [`TapeError::Unsupported`](super::TapeError::Unsupported) on others.
     pub fn write_setmark(&self, count: u32) -> Result<()> {
+        self.require(|c| c.setmarks, "setmarks")?;
         self.do_tape_op(Operation::WriteSetmark, count).map(|_| ())
     }
 
@@ -103,13 +149,15 @@ impl TapeDevice {
         self.do_tape_op(Operation::BackwardSpaceRecord, count).map(|_| ())
     }
 
-    /// DDS drive only
+    /// DDS drive only, fails with [`TapeError::Unsupported`](super::TapeError::Unsupported) on others.
     pub fn forward_space_setmark(&self, count: u32) -> Result<()> {
+        self.require(|c| c.setmarks, "setmarks")?;
         self.do_tape_op(Operation::ForwardSpaceSetmark, count).map(|_| ())
     }
 
-    /// DDS drive only
+    /// DDS drive only, fails with [`TapeError::Unsupported`](super::TapeError::Unsupported) on others.
     pub fn backward_space_setmark(&self, count: u32) -> Result<()> {
+        self.require(|c| c.setmarks, "setmarks")?;
         self.do_tape_op(Operation::BackwardSpaceSetmark, count).map(|_| ())
     }
 
@@ -133,8 +181,42 @@ impl TapeDevice {
         self.do_tape_op(Operation::SetDensity, code).map(|_| ())
     }
 
-    pub fn set_compression(&self, enable: bool) -> Result<()> {
-        self.do_tape_op(Operation::SetCompression, enable as u32).map(|_| ())
+    /// Set the density by its description, such as `LTO-6`, see [`Density::find`].
+    pub fn set_density_by_name(&self, name: &str) -> Result<()> {
+        self.set_density(Density::find(name)?.code)
+    }
+
+    /// Set the compression to `mode`, and return the one the drive reports then. With [`Compression::On`] the drive
+    /// chooses the algorithm; fails if the drive does not take the mode.
+    pub fn set_compression(&self, mode: Compression) -> Result<Compression> {
+        let code = match mode {
+            Compression::Off => 0,
+            Compression::On => 1,
+            Compression::Idrc => 0x10,
+            Compression::Dclz => 0x20,
+            Compression::Unknown => bail!("{}: unable to set an unknown compression.", self.path),
+        };
+        self.require(|c| c.compression, "compression")?;
+        self.do_tape_op(Operation::SetCompression, code)?;
+
+        let effective = self.status()?.compression;
+        let accepted = match mode {
+            Compression::On => effective != Compression::Off,
+            _ => effective == mode,
+        };
+        if !accepted {
+            bail!(
+                "{}: compression {mode} is requested, but the drive reports {effective}.",
+                self.path
+            );
+        }
+        Ok(effective)
+    }
+
+    /// Turn the compression on or off, see [`TapeDevice::set_compression`].
+    pub fn set_compression_enabled(&self, enable: bool) -> Result<()> {
+        let mode = if enable { Compression::On } else { Compression::Off };
+        self.set_compression(mode).map(|_| ())
     }
 
     /// Zero represents doing quickly
@@ -146,7 +228,28 @@ impl TapeDevice {
         self.do_tape_op(Operation::JumpToEnd, 0).map(|_| ())
     }
 
+    /// Do nothing, the driver refreshes the status it reports.
+    pub fn nop(&self) -> Result<()> {
+        self.do_tape_op(Operation::NOP, 1).map(|_| ())
+    }
+
     pub fn retension(&self) -> Result<()> {
         self.do_tape_op(Operation::Retension, 0).map(|_| ())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Operation;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(Operation::WriteEof.describe(2), "write_eof(count=2)");
+        assert_eq!(Operation::Rewind.describe(0), "rewind()");
+        assert_eq!(Operation::SetCompression.describe(1), "set_compression(enable=true)");
+        assert_eq!(
+            Operation::SetCompression.describe(0x20),
+            "set_compression(mode=DCLZ Algorithm)"
+        );
+    }
+}
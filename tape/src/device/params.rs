@@ -0,0 +1,152 @@
+//! Parameters of the sa(4) driver, as `mt param` lists and sets them with MTIOCPARAMGET and MTIOCPARAMSET.
+//!
+//! The driver lists them in XML, the same way as the extended status. A parameter is set by its name, such as `sili`
+//! or `protection.lbp_w` for one inside a node.
+
+use super::status_ex::{c_string, ExtGetIoctl, RawStatusEx};
+use super::{Protection, TapeDevice};
+use anyhow::{bail, ensure, Result};
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// Length of the name of a parameter, with its terminating NUL
+const NAME_LEN: usize = 64;
+/// Length of the error the driver reports
+const ERROR_LEN: usize = 128;
+
+#[derive(Debug, Deserialize, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[serde(default)]
+pub struct SaParameters {
+    /// Set to 1 to report an error when a block read is not of the size requested
+    pub sili: u32,
+    /// Set to 1 to report an error when the early warning is reached while writing
+    pub eot_warn: u32,
+    /// Protection information of each block
+    pub protection: Protection,
+}
+
+/// `sa_param_type`
+#[repr(C)]
+#[allow(dead_code)]
+#[derive(Debug)]
+enum ParamType {
+    None,
+    Signed,
+    Unsigned,
+    String,
+    Opaque,
+}
+
+/// `sa_param_status`
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+enum ParamStatus {
+    None,
+    Ok,
+    Error,
+}
+
+/// `struct mtparamset`
+#[repr(C)]
+struct RawParamSet {
+    value_name: [u8; NAME_LEN],
+    value_type: ParamType,
+    value_len: i32,
+    /// Union of the values, `value_unsigned` first
+    value: [u64; 64],
+    status: ParamStatus,
+    error_str: [u8; ERROR_LEN],
+}
+
+impl RawParamSet {
+    /// Set `name` to the unsigned `value`.
+    fn unsigned(name: &str, value: u64) -> Result<Self> {
+        ensure!(
+            !name.is_empty() && name.len() < NAME_LEN && !name.contains('\0'),
+            "invalid parameter name: {name:?}"
+        );
+        let mut value_name = [0u8; NAME_LEN];
+        value_name[..name.len()].copy_from_slice(name.as_bytes());
+        let mut values = [0u64; 64];
+        values[0] = value;
+        Ok(Self {
+            value_name,
+            value_type: ParamType::Unsigned,
+            value_len: std::mem::size_of::<u64>() as i32,
+            value: values,
+            status: ParamStatus::None,
+            error_str: [0; ERROR_LEN],
+        })
+    }
+}
+
+mod ioctl_func {
+    use super::{RawParamSet, RawStatusEx};
+
+    nix::ioctl_readwrite!(get_params, b'm', 12u8, RawStatusEx);
+    nix::ioctl_readwrite!(set_param, b'm', 13u8, RawParamSet);
+}
+
+impl TapeDevice {
+    /// Parameters of the driver, `None` if it has nothing to report.
+    pub fn get_params(&self) -> Result<Option<SaParameters>> {
+        let get_params: ExtGetIoctl = ioctl_func::get_params;
+        let xml = match unsafe { self.ext_get_xml(get_params, "get_params()")? } {
+            Some(content) => content,
+            None => return Ok(None),
+        };
+        Ok(Some(serde_xml_rs::from_str(&xml)?))
+    }
+
+    /// Set the parameter `name` of the driver, such as `sili` or `protection.lbp_w`, to `value`.
+    pub fn set_param(&self, name: &str, value: u64) -> Result<()> {
+        assert_eq!(std::mem::size_of::<RawParamSet>(), 720);
+
+        let mut raw = RawParamSet::unsigned(name, value)?;
+        let operation = format!("set_param({name}={value})");
+        unsafe {
+            ioctl_func::set_param(self.fd, &mut raw).map_err(|e| self.ioctl_error(operation.as_str(), e))?;
+        }
+        if raw.status == ParamStatus::Error {
+            bail!("{}: {operation} failed: {}", self.path, c_string(&raw.error_str));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RawParamSet, SaParameters};
+
+    #[test]
+    fn test_parse() {
+        let xml = r#"<mtparamget>
+            <sili type="int" size="4" fmt="%d" desc="Set Illegal Length Indication">1</sili>
+            <eot_warn type="int" size="4" fmt="%d" desc="Report errors at EOT">0</eot_warn>
+            <protection>
+                <protection_supported type="int" size="4" fmt="%d" desc="Set to 1 if protection information is supported">1</protection_supported>
+                <prot_method type="uint" size="1" fmt="%u" desc="Current Protection Method">1</prot_method>
+                <pi_length type="uint" size="1" fmt="%u" desc="Length of Protection Information">4</pi_length>
+                <lbp_w type="uint" size="1" fmt="%u" desc="Check Protection on Writes">1</lbp_w>
+                <lbp_r type="uint" size="1" fmt="%u" desc="Check and Include Protection on Reads">1</lbp_r>
+                <rbdp type="uint" size="1" fmt="%u" desc="Transfer Protection Information for RBD">0</rbdp>
+            </protection>
+        </mtparamget>"#;
+        let params: SaParameters = serde_xml_rs::from_str(xml).unwrap();
+        assert_eq!((params.sili, params.eot_warn), (1, 0));
+        assert_eq!(params.protection.protection_supported, 1);
+        assert_eq!((params.protection.pi_length, params.protection.lbp_w), (4, 1));
+    }
+
+    #[test]
+    fn test_raw_param_set() {
+        let raw = RawParamSet::unsigned("protection.lbp_w", 1).unwrap();
+        assert_eq!(&raw.value_name[..17], b"protection.lbp_w\0");
+        assert_eq!((raw.value_len, raw.value[0]), (8, 1));
+        assert!(RawParamSet::unsigned("", 1).is_err());
+        assert!(RawParamSet::unsigned(&"x".repeat(64), 1).is_err());
+        assert!(RawParamSet::unsigned("sili\0", 1).is_err());
+    }
+}
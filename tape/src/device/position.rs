@@ -0,0 +1,238 @@
+//! Positions on the tape, and moves built on spacing over filemarks.
+//!
+//! Quirks of sa(4) handled here:
+//! - BSF stops on the BOT side of the filemark, that is at the end of the previous file, and the driver forgets the
+//!   block number. A FSF over the same mark is needed to reach the beginning of a file.
+//! - BSF in the first file fails with EIO at BOT instead of stopping there, so the first file is reached by rewinding.
+//! - Once a filemark is read or written, the position is already block 0 of the next file. Spacing back from there
+//!   would go to the previous file.
+
+use super::drive::{Boundary, Drive};
+use super::read::ReadBlock;
+use super::{TapeDevice, TapeStatus};
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// File number, and block number in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TapePosition {
+    pub file: u64,
+    pub block: u64,
+}
+
+impl TapePosition {
+    /// Move past what was read.
+    pub(crate) fn advance(&mut self, read: ReadBlock) {
+        match read {
+            ReadBlock::Data(_) | ReadBlock::Setmark => self.block += 1,
+            ReadBlock::Filemark => {
+                self.file += 1;
+                self.block = 0;
+            }
+            ReadBlock::EndOfData => {}
+        }
+    }
+}
+
+impl fmt::Display for TapePosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "file {}, block {}", self.file, self.block)
+    }
+}
+
+/// Outcome of moving to the next or previous file.
+#[derive(Debug)]
+pub enum FileMove<T = TapeStatus> {
+    /// At the beginning of the target file, with the status read there
+    Moved(T),
+    /// There is no next file, the tape is left at the end of data.
+    AtEndOfData,
+    /// There is no previous file, the tape is not moved.
+    AtBeginningOfTape,
+}
+
+impl<T> FileMove<T> {
+    fn try_map<U>(self, f: impl FnOnce(T) -> Result<U>) -> Result<FileMove<U>> {
+        Ok(match self {
+            FileMove::Moved(value) => FileMove::Moved(f(value)?),
+            FileMove::AtEndOfData => FileMove::AtEndOfData,
+            FileMove::AtBeginningOfTape => FileMove::AtBeginningOfTape,
+        })
+    }
+}
+
+/// Read the position, failing if the driver lost track of it even after the moves made.
+fn known_position<D: Drive>(drive: &D) -> Result<TapePosition> {
+    match drive.position()? {
+        (Some(file), Some(block)) => Ok(TapePosition { file, block }),
+        _ => bail!("the driver lost track of the position, rewind or locate first."),
+    }
+}
+
+/// Check the drive is at the beginning of `file` after a move.
+fn ensure_at<D: Drive>(drive: &D, file: u64) -> Result<TapePosition> {
+    let position = known_position(drive)?;
+    if position != (TapePosition { file, block: 0 }) {
+        bail!("expected to be at the beginning of file {file}, the drive reports {position}.");
+    }
+    Ok(position)
+}
+
+fn current_file<D: Drive>(drive: &D) -> Result<u64> {
+    match drive.position()? {
+        (Some(file), _) => Ok(file),
+        (None, _) => bail!("the driver lost track of the file number, rewind or locate first."),
+    }
+}
+
+pub(crate) fn beginning_of_file<D: Drive>(drive: &D) -> Result<TapePosition> {
+    let file = match drive.position()? {
+        // 已在文件开头, 包括 BOT 和刚越过文件标记
+        (Some(file), Some(0)) => return Ok(TapePosition { file, block: 0 }),
+        (Some(file), _) => file,
+        (None, _) => bail!("the driver lost track of the file number, rewind or locate first."),
+    };
+    if file == 0 {
+        drive.rewind()?;
+    } else {
+        // 停在前一个文件标记之前, 再越过它
+        drive.space_files(-1)?;
+        drive.space_files(1)?;
+    }
+    ensure_at(drive, file)
+}
+
+pub(crate) fn next_file<D: Drive>(drive: &D) -> Result<FileMove<TapePosition>> {
+    let file = current_file(drive)?;
+    match drive.space_files(1)? {
+        Some(Boundary::EndOfData) => Ok(FileMove::AtEndOfData),
+        Some(Boundary::BeginningOfTape) => bail!("the drive reports BOT after spacing forward."),
+        // 越过文件标记后即在下一个文件开头
+        None => Ok(FileMove::Moved(ensure_at(drive, file + 1)?)),
+    }
+}
+
+pub(crate) fn prev_file<D: Drive>(drive: &D) -> Result<FileMove<TapePosition>> {
+    let file = current_file(drive)?;
+    if file == 0 {
+        return Ok(FileMove::AtBeginningOfTape);
+    }
+    let target = file - 1;
+    if target == 0 {
+        drive.rewind()?;
+    } else {
+        // 越过当前文件与目标文件之前的两个文件标记, 再回到目标文件一侧
+        if drive.space_files(-2)?.is_some() {
+            bail!("the drive reached BOT before file {target}.");
+        }
+        drive.space_files(1)?;
+    }
+    Ok(FileMove::Moved(ensure_at(drive, target)?))
+}
+
+impl TapeDevice {
+    /// Go back to the beginning of the current file, for example to read it again after an error. Nothing is done if
+    /// the tape is already there, just after a filemark or at BOT.
+    pub fn beginning_of_file(&self) -> Result<TapePosition> {
+        beginning_of_file(self)
+    }
+
+    /// Move to the beginning of the next file.
+    pub fn next_file(&self) -> Result<FileMove> {
+        next_file(self)?.try_map(|_| self.status())
+    }
+
+    /// Move to the beginning of the previous file, the one before the file the tape is in.
+    pub fn prev_file(&self) -> Result<FileMove> {
+        prev_file(self)?.try_map(|_| self.status())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{beginning_of_file, next_file, prev_file, FileMove, TapePosition};
+    use crate::device::drive::mock::MockTape;
+    use crate::device::read::ReadBlock;
+
+    #[test]
+    fn test_advance() {
+        let mut position = TapePosition { file: 2, block: 5 };
+        position.advance(ReadBlock::Data(512));
+        assert_eq!(position, TapePosition { file: 2, block: 6 });
+        position.advance(ReadBlock::Filemark);
+        assert_eq!(position, TapePosition { file: 3, block: 0 });
+        position.advance(ReadBlock::Setmark);
+        position.advance(ReadBlock::EndOfData);
+        assert_eq!(position, TapePosition { file: 3, block: 1 });
+        assert_eq!(position.to_string(), "file 3, block 1");
+    }
+
+    #[test]
+    fn test_beginning_of_file() {
+        // 文件中间
+        let tape = MockTape::with_blocks(&[3, 5, 4]).at(1, Some(3));
+        assert_eq!(beginning_of_file(&tape).unwrap(), TapePosition { file: 1, block: 0 });
+        assert_eq!(*tape.ops.borrow(), ["bsf 1", "fsf 1"]);
+
+        // 刚越过文件标记
+        let tape = MockTape::with_blocks(&[3, 5, 4]).at(2, Some(0));
+        assert_eq!(beginning_of_file(&tape).unwrap(), TapePosition { file: 2, block: 0 });
+        assert!(tape.ops.borrow().is_empty());
+
+        // BOT
+        let tape = MockTape::with_blocks(&[3, 5]);
+        assert_eq!(beginning_of_file(&tape).unwrap(), TapePosition { file: 0, block: 0 });
+        assert!(tape.ops.borrow().is_empty());
+
+        // 第一个文件中间, BSF 会在 BOT 失败
+        let tape = MockTape::with_blocks(&[3, 5]).at(0, Some(2));
+        assert_eq!(beginning_of_file(&tape).unwrap(), TapePosition { file: 0, block: 0 });
+        assert_eq!(*tape.ops.borrow(), ["rewind"]);
+
+        // 之前的 BSF 使块号未知
+        let tape = MockTape::with_blocks(&[3, 5, 4]).at(1, None);
+        assert_eq!(beginning_of_file(&tape).unwrap(), TapePosition { file: 1, block: 0 });
+        let tape = MockTape::with_blocks(&[3]).at(0, None);
+        assert_eq!(beginning_of_file(&tape).unwrap(), TapePosition { file: 0, block: 0 });
+    }
+
+    fn moved_to(result: FileMove<TapePosition>) -> Option<(u64, u64)> {
+        match result {
+            FileMove::Moved(position) => Some((position.file, position.block)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_next_file() {
+        let tape = MockTape::with_blocks(&[3, 5]).at(0, Some(2));
+        assert_eq!(moved_to(next_file(&tape).unwrap()), Some((1, 0)));
+        assert_eq!(moved_to(next_file(&tape).unwrap()), Some((2, 0)));
+        // 已在最后一个文件标记之后
+        assert!(matches!(next_file(&tape).unwrap(), FileMove::AtEndOfData));
+        assert_eq!(*tape.ops.borrow(), ["fsf 1", "fsf 1", "fsf 1"]);
+
+        let tape = MockTape::with_blocks(&[3]).at(0, None);
+        assert_eq!(moved_to(next_file(&tape).unwrap()), Some((1, 0)));
+    }
+
+    #[test]
+    fn test_prev_file() {
+        // 文件中间与文件开头都回到前一个文件开头
+        let tape = MockTape::with_blocks(&[3, 5, 4, 2]).at(3, Some(1));
+        assert_eq!(moved_to(prev_file(&tape).unwrap()), Some((2, 0)));
+        assert_eq!(*tape.ops.borrow(), ["bsf 2", "fsf 1"]);
+        assert_eq!(moved_to(prev_file(&tape).unwrap()), Some((1, 0)));
+
+        // 第一个文件经由回卷到达
+        tape.ops.borrow_mut().clear();
+        assert_eq!(moved_to(prev_file(&tape).unwrap()), Some((0, 0)));
+        assert_eq!(*tape.ops.borrow(), ["rewind"]);
+
+        tape.ops.borrow_mut().clear();
+        assert!(matches!(prev_file(&tape).unwrap(), FileMove::AtBeginningOfTape));
+        let tape = tape.at(0, Some(2));
+        assert!(matches!(prev_file(&tape).unwrap(), FileMove::AtBeginningOfTape));
+        assert!(tape.ops.borrow().is_empty());
+    }
+}
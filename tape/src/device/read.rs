@@ -0,0 +1,174 @@
+//! Reading blocks, with filemarks, setmarks and the end of data told apart.
+//!
+//! A read returns 0 bytes both on a filemark and at the end of recorded data, sa(4) only tells which in the sense data
+//! latched for the last data command, so it is looked up after every short read.
+
+use super::{TapeDevice, TapePosition};
+use anyhow::Result;
+use nix::errno::Errno;
+use std::io::Read;
+
+/// Sense key of a read over blank medium, in fixed format sense data
+const SENSE_BLANK_CHECK: u8 = 0x08;
+/// Additional sense code and qualifier of a setmark
+const ASC_SETMARK: (u8, u8) = (0x00, 0x03);
+/// Additional sense code and qualifier of the end of data
+const ASC_END_OF_DATA: (u8, u8) = (0x00, 0x05);
+
+/// What one read gave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadBlock {
+    /// A block of this many bytes, at the beginning of the buffer
+    Data(usize),
+    Filemark,
+    Setmark,
+    EndOfData,
+}
+
+/// Which mark stopped a read, from fixed format sense data. Without sense data, it was a filemark.
+pub(super) fn mark_of(sense: &[u8]) -> ReadBlock {
+    // 0x70 与 0x71 为固定格式
+    if sense.len() < 14 || !matches!(sense[0] & 0x7f, 0x70 | 0x71) {
+        return ReadBlock::Filemark;
+    }
+    let asc = (sense[12], sense[13]);
+    if sense[2] & 0x0f == SENSE_BLANK_CHECK || asc == ASC_END_OF_DATA {
+        ReadBlock::EndOfData
+    } else if asc == ASC_SETMARK {
+        ReadBlock::Setmark
+    } else {
+        ReadBlock::Filemark
+    }
+}
+
+impl TapeDevice {
+    /// Read one block into `buf`, which has to be as large as the block, or the read fails with `EINVAL` in
+    /// variable block mode.
+    pub fn read_block(&self, buf: &mut [u8]) -> Result<ReadBlock> {
+        match nix::unistd::read(self.fd, buf) {
+            Ok(0) => {
+                // 读取错误状态同时清除锁存的 sense 数据
+                let sense = self.get_last_error().map(|errors| errors.io_sense).unwrap_or_default();
+                Ok(mark_of(&sense))
+            }
+            Ok(count) => Ok(ReadBlock::Data(count)),
+            Err(Errno::EIO) => {
+                // 部分驱动器在数据末尾报告 BLANK CHECK 错误, 而不是返回 0
+                let sense = self.get_last_error().map(|errors| errors.io_sense).unwrap_or_default();
+                match mark_of(&sense) {
+                    ReadBlock::EndOfData => Ok(ReadBlock::EndOfData),
+                    _ => Err(self.ioctl_error(format!("read_block(size={})", buf.len()), Errno::EIO).into()),
+                }
+            }
+            Err(errno) => Err(self.ioctl_error(format!("read_block(size={})", buf.len()), errno).into()),
+        }
+    }
+
+    /// Read the tape from the current position as events, with blocks of at most `buf_size` bytes. Positions start
+    /// from the ones the drive reports, or 0 if it does not.
+    pub fn events(&self, buf_size: usize) -> EventReader<'_> {
+        let (file, block) = self
+            .status()
+            .map(|status| (status.file_no as u64, status.block_no as u64))
+            .unwrap_or_default();
+        EventReader {
+            tape: self,
+            buffer: vec![0u8; buf_size],
+            position: TapePosition { file, block },
+            done: false,
+        }
+    }
+}
+
+/// Each call reads one block, and returns 0 on a filemark or at the end of data. In variable block mode, `buf` has to
+/// be as large as the block, see [`TapeDevice::read_block`] to tell marks apart.
+impl Read for &TapeDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(nix::unistd::read(self.fd, buf)?)
+    }
+}
+
+impl Read for TapeDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+/// Something met while reading the tape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TapeEvent {
+    Data(Vec<u8>),
+    Filemark,
+    Setmark,
+    EndOfData,
+}
+
+/// An event, and the file and block numbers at which it occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PositionedEvent {
+    pub file: u64,
+    pub block: u64,
+    pub event: TapeEvent,
+}
+
+/// Iterator of the events of a tape, ending after `EndOfData` or the first error.
+pub struct EventReader<'a> {
+    tape: &'a TapeDevice,
+    buffer: Vec<u8>,
+    position: TapePosition,
+    done: bool,
+}
+
+impl Iterator for EventReader<'_> {
+    type Item = Result<PositionedEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let read = match self.tape.read_block(&mut self.buffer) {
+            Ok(read) => read,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        let event = match read {
+            ReadBlock::Data(count) => TapeEvent::Data(self.buffer[..count].to_vec()),
+            ReadBlock::Filemark => TapeEvent::Filemark,
+            ReadBlock::Setmark => TapeEvent::Setmark,
+            ReadBlock::EndOfData => {
+                self.done = true;
+                TapeEvent::EndOfData
+            }
+        };
+        let TapePosition { file, block } = self.position;
+        self.position.advance(read);
+        Some(Ok(PositionedEvent { file, block, event }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{mark_of, ReadBlock};
+
+    fn sense(key: u8, asc: u8, ascq: u8) -> [u8; 32] {
+        let mut sense = [0u8; 32];
+        sense[0] = 0x70;
+        sense[2] = key;
+        sense[12] = asc;
+        sense[13] = ascq;
+        sense
+    }
+
+    #[test]
+    fn test_mark_of() {
+        assert_eq!(mark_of(&sense(0x80, 0x00, 0x01)), ReadBlock::Filemark);
+        assert_eq!(mark_of(&sense(0x80, 0x00, 0x03)), ReadBlock::Setmark);
+        assert_eq!(mark_of(&sense(0x08, 0x00, 0x05)), ReadBlock::EndOfData);
+        assert_eq!(mark_of(&sense(0x48, 0x00, 0x00)), ReadBlock::EndOfData);
+        // 没有 sense 数据
+        assert_eq!(mark_of(&[0u8; 32]), ReadBlock::Filemark);
+        assert_eq!(mark_of(&[]), ReadBlock::Filemark);
+    }
+}
@@ -0,0 +1,98 @@
+//! Waiting for a tape, after opening the drive with [`TapeDevice::open_nonblocking`].
+//!
+//! The drive is polled until the driver is at rest. While no tape is loaded, the ioctls fail with `ENXIO`, or with
+//! `EIO` on some drives; while one is being loaded the driver reports it busy.
+
+use super::{DriverState, TapeError, TapeStatus};
+use crate::TapeDevice;
+use anyhow::Result;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use std::time::{Duration, Instant};
+
+/// How often the drive is polled
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether `error` means the drive has no tape loaded.
+fn is_no_tape(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<TapeError>()
+        .is_some_and(|error| error.is_not_ready() || error.errno() == Errno::EIO)
+}
+
+impl TapeDevice {
+    /// Poll the drive until it has a tape loaded and is at rest, for up to `timeout`, and return its status then.
+    /// Fails with [`TapeError::NotReady`] if no tape was loaded, or [`TapeError::Timeout`] if the drive was still
+    /// busy with it. Reads and writes wait for the drive again once it is ready.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<TapeStatus> {
+        let started = Instant::now();
+        loop {
+            // NOP 使驱动向磁带机查询, 刷新状态
+            let state = match self.nop().and_then(|_| self.status()) {
+                Ok(status) if status.state == DriverState::Rest => {
+                    self.set_blocking()?;
+                    return Ok(status);
+                }
+                Ok(status) => Some(status.state),
+                Err(e) if is_no_tape(&e) => None,
+                Err(e) => return Err(e),
+            };
+            let waited = started.elapsed();
+            if waited >= timeout {
+                let device = self.path.clone();
+                return Err(match state {
+                    Some(state) => TapeError::Timeout { device, waited, state },
+                    None => TapeError::NotReady { device, waited },
+                }
+                .into());
+            }
+            std::thread::sleep(POLL_INTERVAL.min(timeout - waited));
+        }
+    }
+
+    /// Clear `O_NONBLOCK` the device may be opened with.
+    fn set_blocking(&self) -> Result<()> {
+        let flags = fcntl(self.fd, FcntlArg::F_GETFL).map_err(|e| self.ioctl_error("fcntl(F_GETFL)", e))?;
+        let flags = OFlag::from_bits_truncate(flags) - OFlag::O_NONBLOCK;
+        fcntl(self.fd, FcntlArg::F_SETFL(flags)).map_err(|e| self.ioctl_error("fcntl(F_SETFL)", e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_no_tape;
+    use crate::device::TapeError;
+    use crate::TapeDevice;
+    use nix::errno::Errno;
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_is_no_tape() {
+        let error = |errno| {
+            anyhow::Error::from(TapeError::Ioctl {
+                device: "/dev/nsa0".to_string(),
+                operation: "nop()".to_string(),
+                errno,
+            })
+        };
+        assert!(is_no_tape(&error(Errno::ENXIO)));
+        assert!(is_no_tape(&error(Errno::EIO)));
+        assert!(!is_no_tape(&error(Errno::EBUSY)));
+        assert!(!is_no_tape(&error(Errno::ENOTTY)));
+        assert!(!is_no_tape(&anyhow::anyhow!("Your tape lib is not of SCSI.")));
+    }
+
+    #[test]
+    fn test_wait_ready() {
+        let tape = TapeDevice::open_nonblocking("/dev/null").unwrap();
+        let flags = OFlag::from_bits_truncate(fcntl(tape.fd(), FcntlArg::F_GETFL).unwrap());
+        assert!(flags.contains(OFlag::O_NONBLOCK));
+        // 不是磁带机, 立即失败而不等待
+        let started = Instant::now();
+        let e = tape.wait_ready(Duration::from_secs(30)).err().unwrap();
+        assert_eq!(e.downcast_ref::<TapeError>().unwrap().errno(), Errno::ENOTTY);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
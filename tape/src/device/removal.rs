@@ -0,0 +1,84 @@
+//! PREVENT ALLOW MEDIUM REMOVAL, locking the cartridge in the drive so its eject button does nothing.
+//!
+//! sa(4) has no ioctl for it, so with the `passthrough` feature the command is sent to the pass(4) device of the
+//! drive by camcontrol(8). The drive allows removal again on a reset, or when the driver releases it on close.
+
+/// Operation code of PREVENT ALLOW MEDIUM REMOVAL
+const OPCODE: u8 = 0x1e;
+
+/// CDB of the command, preventing removal if `prevent`.
+fn cdb(prevent: bool) -> String {
+    format!("{OPCODE:02x} 00 00 00 {:02x} 00", prevent as u8)
+}
+
+/// Whether camcontrol reports the command was rejected as an illegal request, that is the drive does not support it.
+fn is_illegal_request(stderr: &str) -> bool {
+    stderr.to_ascii_uppercase().contains("ILLEGAL REQUEST")
+}
+
+#[cfg(feature = "passthrough")]
+mod passthrough {
+    use super::{cdb, is_illegal_request};
+    use crate::device::TapeError;
+    use crate::TapeDevice;
+    use anyhow::{bail, Context, Result};
+    use std::process::Command;
+
+    impl TapeDevice {
+        fn medium_removal(&self, prevent: bool) -> Result<()> {
+            let Some(variant) = self.device_variant() else {
+                bail!("{}: not a sa(4) node, unable to lock the medium.", self.path);
+            };
+            let periph = format!("sa{}", variant.unit);
+            let output = Command::new("camcontrol")
+                .args(["cmd", &periph, "-v", "-c", &cdb(prevent)])
+                .output()
+                .context("unable to run camcontrol, which sends the command to the drive.")?;
+            if output.status.success() {
+                return Ok(());
+            }
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if is_illegal_request(&stderr) {
+                return Err(TapeError::Unsupported {
+                    device: self.path.clone(),
+                    capability: "preventing medium removal",
+                }
+                .into());
+            }
+            let action = if prevent { "prevent" } else { "allow" };
+            bail!("{}: unable to {action} medium removal: {}", self.path, stderr.trim());
+        }
+
+        /// Lock the cartridge in the drive, the eject button does nothing until [`TapeDevice::allow_removal`].
+        pub fn prevent_removal(&self) -> Result<()> {
+            self.medium_removal(true)
+        }
+
+        /// Unlock the cartridge locked by [`TapeDevice::prevent_removal`].
+        pub fn allow_removal(&self) -> Result<()> {
+            self.medium_removal(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cdb, is_illegal_request};
+
+    #[test]
+    fn test_cdb() {
+        assert_eq!(cdb(true), "1e 00 00 00 01 00");
+        assert_eq!(cdb(false), "1e 00 00 00 00 00");
+    }
+
+    #[test]
+    fn test_is_illegal_request() {
+        let stderr = "camcontrol: error sending command\n\
+                      (pass0:ahd0:0:4:0): PREVENT ALLOW MEDIUM REMOVAL. CDB: 1e 00 00 00 01 00\n\
+                      (pass0:ahd0:0:4:0): SCSI sense: ILLEGAL REQUEST asc:20,0 (Invalid command operation code)\n";
+        assert!(is_illegal_request(stderr));
+        assert!(!is_illegal_request(
+            "camcontrol: error sending command\n(pass0): SCSI sense: NOT READY asc:3a,0\n"
+        ));
+    }
+}
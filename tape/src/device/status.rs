@@ -1,7 +1,11 @@
 use crate::TapeDevice;
-use anyhow::{bail, Context, Result};
-use strum::{EnumIter, EnumString, FromRepr};
+use anyhow::{anyhow, bail, Context, Result};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
+/// Serialized by code and description, and looked up by code when deserialized.
 #[derive(Debug)]
 pub struct Density {
     pub code: u32,
@@ -11,121 +15,171 @@ pub struct Density {
     pub bpi: u32,
     /// Description
     pub description: &'static str,
+    /// Bytes a medium of this density holds without compression, `None` if not known
+    pub native_capacity: Option<u64>,
 }
 
-/// Copied from `freebsd-src/lib/libmt/mtlib.c`,
-/// which are originally from T10 Project 997D
-static DENSITIES: [Density; 14] = [
-    Density {
-        code: 0x40,
-        bpmm: 4880,
-        bpi: 123952,
-        description: "LTO-1",
-    },
-    Density {
-        code: 0x42,
-        bpmm: 7398,
-        bpi: 187909,
-        description: "LTO-2",
-    },
-    Density {
-        code: 0x44,
-        bpmm: 9638,
-        bpi: 244805,
-        description: "LTO-3",
-    },
-    Density {
-        code: 0x46,
-        bpmm: 12725,
-        bpi: 323215,
-        description: "LTO-4",
-    },
-    Density {
-        code: 0x40,
-        bpmm: 4880,
-        bpi: 123952,
-        description: "LTO-1",
-    },
-    Density {
-        code: 0x42,
-        bpmm: 7398,
-        bpi: 187909,
-        description: "LTO-2",
-    },
-    Density {
-        code: 0x44,
-        bpmm: 9638,
-        bpi: 244805,
-        description: "LTO-3",
-    },
-    Density {
-        code: 0x46,
-        bpmm: 12725,
-        bpi: 323215,
-        description: "LTO-4",
-    },
-    Density {
-        code: 0x58,
-        bpmm: 15142,
-        bpi: 384607,
-        description: "LTO-5",
-    },
-    Density {
-        code: 0x5A,
-        bpmm: 15142,
-        bpi: 384607,
-        description: "LTO-6",
-    },
-    Density {
-        code: 0x5C,
-        bpmm: 19107,
-        bpi: 485318,
-        description: "LTO-7",
-    },
-    Density {
-        code: 0x5D,
-        bpmm: 19107,
-        bpi: 485318,
-        description: "LTO-M8",
-    },
-    Density {
-        code: 0x5E,
-        bpmm: 20669,
-        bpi: 524993,
-        description: "LTO-8",
-    },
+/// Bytes in a GB, as capacities of media are given
+const GB: u64 = 1_000_000_000;
+
+const fn density(code: u32, bpmm: u32, bpi: u32, description: &'static str, native_capacity: Option<u64>) -> Density {
     Density {
-        code: 0x60,
-        bpmm: 23031,
-        bpi: 584987,
-        description: "LTO-9",
-    },
+        code,
+        bpmm,
+        bpi,
+        description,
+        native_capacity,
+    }
+}
+
+/// Copied from `freebsd-src/lib/libmt/mtlib.c`,
+/// which are originally from T10 Project 997D.
+/// 0x48 is also the code of DAT-160, which mtlib.c reports as SDLT. Capacities are the uncompressed ones of the
+/// vendors.
+static DENSITIES: [Density; 72] = [
+    density(0x01, 32, 800, "X3.22-1983", None),
+    density(0x02, 63, 1600, "X3.39-1986", None),
+    density(0x03, 246, 6250, "X3.54-1986", None),
+    density(0x05, 315, 8000, "X3.136-1986", None),
+    density(0x06, 126, 3200, "X3.157-1987", None),
+    density(0x07, 252, 6400, "X3.116-1986", None),
+    density(0x08, 315, 8000, "X3.158-1987", None),
+    density(0x09, 491, 37871, "X3.180", None),
+    density(0x0A, 262, 6667, "X3B5/86-199", None),
+    density(0x0B, 63, 1600, "X3.56-1986", None),
+    density(0x0C, 500, 12690, "HI-TC1", None),
+    density(0x0D, 999, 25380, "HI-TC2", None),
+    density(0x0F, 394, 10000, "QIC-120", None),
+    density(0x10, 394, 10000, "QIC-150", None),
+    density(0x11, 630, 16000, "QIC-320", None),
+    density(0x12, 2034, 51667, "QIC-1350", None),
+    density(0x13, 2400, 61000, "X3B5/88-185A", Some(2 * GB)),
+    density(0x14, 1703, 43245, "X3.202-1991", None),
+    density(0x15, 1789, 45434, "ECMA TC17", None),
+    density(0x16, 394, 10000, "X3.193-1990", None),
+    density(0x17, 1673, 42500, "X3B5/91-174", None),
+    density(0x18, 1673, 42500, "X3B5/92-50", None),
+    density(0x19, 2460, 62500, "DLTapeIII", Some(10 * GB)),
+    density(0x1A, 3214, 81633, "DLTapeIV(20GB)", Some(20 * GB)),
+    density(0x1B, 3383, 85937, "DLTapeIV(35GB)", Some(35 * GB)),
+    density(0x1C, 1654, 42000, "QIC-385M", None),
+    density(0x1D, 1512, 38400, "QIC-410M", None),
+    density(0x1E, 1385, 36000, "QIC-1000C", None),
+    density(0x1F, 2666, 67733, "QIC-2100C", None),
+    density(0x20, 2666, 67733, "QIC-6GB(M)", Some(6 * GB)),
+    density(0x21, 2666, 67733, "QIC-20GB(C)", Some(20 * GB)),
+    density(0x22, 1600, 40640, "QIC-2GB(C)", Some(2 * GB)),
+    density(0x23, 2666, 67733, "QIC-875M", None),
+    density(0x24, 2400, 61000, "DDS-2", Some(4 * GB)),
+    density(0x25, 3816, 97000, "DDS-3", Some(12 * GB)),
+    density(0x26, 3816, 97000, "DDS-4", Some(20 * GB)),
+    density(0x27, 3056, 77611, "Mammoth", None),
+    density(0x28, 1491, 37871, "X3.224", None),
+    density(0x40, 4880, 123952, "LTO-1", Some(100 * GB)),
+    density(0x41, 3868, 98250, "DLTapeIV(40GB)", Some(40 * GB)),
+    density(0x42, 7398, 187909, "LTO-2", Some(200 * GB)),
+    density(0x44, 9638, 244805, "LTO-3", Some(400 * GB)),
+    density(0x46, 12725, 323215, "LTO-4", Some(800 * GB)),
+    density(0x47, 6417, 163000, "DAT-72", Some(36 * GB)),
+    density(0x48, 5236, 133000, "SDLTapeI(110)", Some(110 * GB)),
+    density(0x49, 7598, 193000, "SDLTapeI(160)", Some(160 * GB)),
+    density(0x4A, 0, 0, "T10000A", Some(500 * GB)),
+    density(0x4B, 0, 0, "T10000B", Some(1000 * GB)),
+    density(0x4C, 0, 0, "T10000C", Some(5000 * GB)),
+    density(0x4D, 0, 0, "T10000D", Some(8500 * GB)),
+    density(0x51, 11800, 299720, "3592A1 (unencrypted)", Some(300 * GB)),
+    density(0x52, 11800, 299720, "3592A2 (unencrypted)", Some(700 * GB)),
+    density(0x53, 13452, 341681, "3592A3 (unencrypted)", Some(1000 * GB)),
+    density(0x54, 19686, 500024, "3592A4 (unencrypted)", Some(4000 * GB)),
+    density(0x55, 20670, 525018, "3592A5 (unencrypted)", Some(10000 * GB)),
+    density(0x56, 20670, 525018, "3592B5 (unencrypted)", Some(15000 * GB)),
+    density(0x57, 21850, 554990, "3592A6 (unencrypted)", Some(20000 * GB)),
+    density(0x58, 15142, 384607, "LTO-5", Some(1500 * GB)),
+    density(0x5A, 15142, 384607, "LTO-6", Some(2500 * GB)),
+    density(0x5C, 19107, 485318, "LTO-7", Some(6000 * GB)),
+    density(0x5D, 19107, 485318, "LTO-M8", Some(9000 * GB)),
+    density(0x5E, 20669, 524993, "LTO-8", Some(12000 * GB)),
+    density(0x60, 23031, 584987, "LTO-9", Some(18000 * GB)),
+    density(0x71, 11800, 299720, "3592A1 (encrypted)", Some(300 * GB)),
+    density(0x72, 11800, 299720, "3592A2 (encrypted)", Some(700 * GB)),
+    density(0x73, 13452, 341681, "3592A3 (encrypted)", Some(1000 * GB)),
+    density(0x74, 19686, 500024, "3592A4 (encrypted)", Some(4000 * GB)),
+    density(0x75, 20670, 525018, "3592A5 (encrypted)", Some(10000 * GB)),
+    density(0x76, 20670, 525018, "3592B5 (encrypted)", Some(15000 * GB)),
+    density(0x77, 21850, 554990, "3592A6 (encrypted)", Some(20000 * GB)),
+    density(0x8C, 1789, 45434, "EXB-8500c", None),
+    density(0x90, 1703, 43245, "EXB-8200c", None),
 ];
 
-static UNKNOWN_DENSITY: Density = Density {
-    code: 0,
-    bpmm: 0,
-    bpi: 0,
-    description: "Unknown",
+static UNKNOWN_DENSITY: Density = density(0, 0, 0, "Unknown", None);
+
+/// Index in [`DENSITIES`] of each code, `u8::MAX` for codes not in it.
+static DENSITY_INDEX: [u8; 256] = {
+    let mut index = [u8::MAX; 256];
+    let mut i = 0;
+    while i < DENSITIES.len() {
+        index[DENSITIES[i].code as usize] = i as u8;
+        i += 1;
+    }
+    index
 };
 
 impl Density {
-    fn get(code: u32) -> &'static Self {
-        for predefined in &DENSITIES {
-            if predefined.code == code {
-                return predefined;
-            }
+    pub(super) fn get(code: u32) -> &'static Self {
+        let index = DENSITY_INDEX.get(code as usize).copied().unwrap_or(u8::MAX);
+        DENSITIES.get(index as usize).unwrap_or(&UNKNOWN_DENSITY)
+    }
+
+    /// Look up a density by its description, such as `LTO-5`, ignoring case.
+    pub fn by_name(name: &str) -> Option<&'static Self> {
+        DENSITIES.iter().find(|d| d.description.eq_ignore_ascii_case(name))
+    }
+
+    /// Look up a density by its description, or the start of only one description such as `T10000C` or `3592A4 (e`,
+    /// ignoring case. The error lists the descriptions that may be meant, or all of them.
+    pub fn find(name: &str) -> Result<&'static Self> {
+        if let Some(density) = Self::by_name(name) {
+            return Ok(density);
+        }
+        let prefix = name.to_ascii_lowercase();
+        let matches = DENSITIES
+            .iter()
+            .filter(|d| d.description.to_ascii_lowercase().starts_with(&prefix))
+            .collect::<Vec<_>>();
+        let names = |densities: &[&Density]| densities.iter().map(|d| d.description).collect::<Vec<_>>().join(", ");
+        match matches.as_slice() {
+            [density] => Ok(density),
+            [] => bail!(
+                "unknown density {name}, expect one of: {}",
+                names(&DENSITIES.iter().collect::<Vec<_>>())
+            ),
+            _ => bail!("density {name} is ambiguous, expect one of: {}", names(&matches)),
         }
-        &UNKNOWN_DENSITY
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for Density {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (0x{:02x})", self.description, self.code)
+    }
+}
+
+/// Serialized as `"variable"` or the size of fixed blocks.
+#[derive(Debug, PartialEq, Eq)]
 pub enum BlockSize {
     Variable,
     Fixed(u32),
 }
 
+impl fmt::Display for BlockSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockSize::Variable => write!(f, "variable"),
+            BlockSize::Fixed(size) => write!(f, "{size}"),
+        }
+    }
+}
+
 impl From<i32> for BlockSize {
     fn from(value: i32) -> Self {
         if value == 0 {
@@ -136,6 +190,63 @@ impl From<i32> for BlockSize {
     }
 }
 
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::{BlockSize, Density};
+    use serde::ser::SerializeStruct;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    impl Serialize for Density {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut density = serializer.serialize_struct("Density", 2)?;
+            density.serialize_field("code", &self.code)?;
+            density.serialize_field("description", self.description)?;
+            density.end()
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct DensityCode {
+        code: u32,
+    }
+
+    impl<'de> Deserialize<'de> for &'static Density {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let DensityCode { code } = DensityCode::deserialize(deserializer)?;
+            Ok(Density::get(code))
+        }
+    }
+
+    impl Serialize for BlockSize {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                BlockSize::Variable => serializer.serialize_str("variable"),
+                BlockSize::Fixed(size) => serializer.serialize_u32(*size),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SerializedBlockSize {
+        Fixed(u32),
+        Named(String),
+    }
+
+    impl<'de> Deserialize<'de> for BlockSize {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            match SerializedBlockSize::deserialize(deserializer)? {
+                SerializedBlockSize::Fixed(0) => Ok(BlockSize::Variable),
+                SerializedBlockSize::Fixed(size) => Ok(BlockSize::Fixed(size)),
+                SerializedBlockSize::Named(name) if name == "variable" => Ok(BlockSize::Variable),
+                SerializedBlockSize::Named(name) => Err(de::Error::custom(format!(
+                    "expect \"variable\" or a block size, got {name:?}"
+                ))),
+            }
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Default)]
 pub struct RawStatus {
@@ -183,66 +294,147 @@ pub struct RawStatus {
     pub blkno: i32,
 }
 
-#[derive(Debug, EnumString, FromRepr)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
 pub enum DriverState {
     /// Unknown
-    #[strum(serialize = "Unknown")]
     Nil = 0,
     /// Doing Nothing
-    #[strum(serialize = "Doing Nothing")]
     Rest = 1,
     /// Communicating with tape (but no motion)
-    #[strum(serialize = "Communicating with tape (but no motion)")]
     Busy = 2,
     /// Writing
-    #[strum(serialize = "Writing")]
     Writing = 20,
     /// Writing Filemarks
-    #[strum(serialize = "Writing Filemarks")]
     WritingFilemarks = 21,
     /// Erasing
-    #[strum(serialize = "Erasing")]
     Erasing = 22,
     /// Reading
-    #[strum(serialize = "Reading")]
     Reading = 30,
     /// Spacing Forward
-    #[strum(serialize = "Spacing Forward")]
     SpacingForward = 40,
     /// Spacing Reverse
-    #[strum(serialize = "Spacing Reverse")]
     SpacingReverse = 41,
     /// Hardware Positioning (direction unknown)
-    #[strum(serialize = "Hardware Positioning (direction unknown)")]
     Pos = 42,
     /// Rewinding
-    #[strum(serialize = "Rewinding")]
     Rewinding = 43,
     /// Retensioning
-    #[strum(serialize = "Retensioning")]
     Retensioning = 44,
     /// Unloading
-    #[strum(serialize = "Unloading")]
     Unloading = 45,
     /// Loading
-    #[strum(serialize = "Loading")]
     Loading = 46,
 }
 
-#[derive(EnumString, EnumIter, Clone, Copy, Debug)]
+impl DriverState {
+    const ALL: [DriverState; 14] = [
+        DriverState::Nil,
+        DriverState::Rest,
+        DriverState::Busy,
+        DriverState::Writing,
+        DriverState::WritingFilemarks,
+        DriverState::Erasing,
+        DriverState::Reading,
+        DriverState::SpacingForward,
+        DriverState::SpacingReverse,
+        DriverState::Pos,
+        DriverState::Rewinding,
+        DriverState::Retensioning,
+        DriverState::Unloading,
+        DriverState::Loading,
+    ];
+
+    /// State of a `dsreg` register value.
+    pub fn from_repr(value: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|&state| state as usize == value)
+    }
+
+    /// Description as printed by mt(1).
+    pub fn description(&self) -> &'static str {
+        match self {
+            DriverState::Nil => "Unknown",
+            DriverState::Rest => "Doing Nothing",
+            DriverState::Busy => "Communicating with tape (but no motion)",
+            DriverState::Writing => "Writing",
+            DriverState::WritingFilemarks => "Writing Filemarks",
+            DriverState::Erasing => "Erasing",
+            DriverState::Reading => "Reading",
+            DriverState::SpacingForward => "Spacing Forward",
+            DriverState::SpacingReverse => "Spacing Reverse",
+            DriverState::Pos => "Hardware Positioning (direction unknown)",
+            DriverState::Rewinding => "Rewinding",
+            DriverState::Retensioning => "Retensioning",
+            DriverState::Unloading => "Unloading",
+            DriverState::Loading => "Loading",
+        }
+    }
+}
+
+impl fmt::Display for DriverState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl FromStr for DriverState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|state| state.description() == s)
+            .ok_or_else(|| anyhow!("unknown driver state {s}"))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
 pub enum Compression {
-    #[strum(serialize = "Off")]
     Off,
-    #[strum(serialize = "On")]
     On,
-    #[strum(serialize = "IDRC Algorithm")]
     Idrc,
-    #[strum(serialize = "DCLZ Algorithm")]
     Dclz,
 
     Unknown,
 }
 
+impl Compression {
+    /// Description as printed by mt(1).
+    pub fn description(&self) -> &'static str {
+        match self {
+            Compression::Off => "Off",
+            Compression::On => "On",
+            Compression::Idrc => "IDRC Algorithm",
+            Compression::Dclz => "DCLZ Algorithm",
+            Compression::Unknown => "Unknown",
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [
+            Compression::Off,
+            Compression::On,
+            Compression::Idrc,
+            Compression::Dclz,
+            Compression::Unknown,
+        ]
+        .into_iter()
+        .find(|compression| compression.description() == s)
+        .ok_or_else(|| anyhow!("unknown compression {s}"))
+    }
+}
+
 impl From<u32> for Compression {
     fn from(value: u32) -> Self {
         match value {
@@ -256,6 +448,7 @@ impl From<u32> for Compression {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TapeStatus {
     pub state: DriverState,
     pub block_size: BlockSize,
@@ -266,10 +459,95 @@ pub struct TapeStatus {
     pub file_no: usize,
     /// relative block number of current position
     pub block_no: usize,
-    /// Residual count
+    /// Residual count. The driver reports only its low 16 bits, [`TapeDevice::status`] reads it from the extended
+    /// status when it may be larger
     pub residual: usize,
 }
 
+/// Whether the residual count `resid` the driver reports may be the low 16 bits of a larger one.
+fn residual_may_be_truncated(resid: i16) -> bool {
+    resid < 0 || resid == i16::MAX
+}
+
+/// Density as mt(1) prints it, such as `0x58:LTO-5`.
+fn mt_density(density: &Density) -> String {
+    match density.code {
+        0 => "0".to_string(),
+        code => format!("0x{code:02x}:{}", density.description),
+    }
+}
+
+/// Block size as mt(1) prints it.
+fn mt_block_size(block_size: &BlockSize) -> String {
+    match block_size {
+        BlockSize::Variable => "variable".to_string(),
+        BlockSize::Fixed(size) => format!("{size} bytes"),
+    }
+}
+
+/// Compression as mt(1) prints it.
+fn mt_compression(compression: Compression) -> &'static str {
+    match compression {
+        Compression::Off => "disabled",
+        Compression::On => "enabled",
+        Compression::Idrc => "IDRC",
+        Compression::Dclz => "DCLZ",
+        Compression::Unknown => "unknown",
+    }
+}
+
+/// Driver state as mt(1) prints it.
+pub(super) fn mt_state(state: Option<DriverState>) -> &'static str {
+    match state {
+        None | Some(DriverState::Nil) => "unknown",
+        Some(DriverState::Rest) => "at rest",
+        Some(DriverState::Busy) => "communicating",
+        Some(DriverState::Writing) => "writing",
+        Some(DriverState::WritingFilemarks) => "writing filemarks",
+        Some(DriverState::Erasing) => "erasing",
+        Some(DriverState::Reading) => "reading",
+        Some(DriverState::SpacingForward) => "spacing forward",
+        Some(DriverState::SpacingReverse) => "spacing reverse",
+        Some(DriverState::Pos) => "hardware positioning (direction unknown)",
+        Some(DriverState::Rewinding) => "rewinding",
+        Some(DriverState::Retensioning) => "retensioning",
+        Some(DriverState::Unloading) => "unloading",
+        Some(DriverState::Loading) => "loading",
+    }
+}
+
+/// Header of the table of modes, then the line of the current one.
+pub(super) fn write_current_mode(
+    f: &mut fmt::Formatter<'_>,
+    density: &Density,
+    block_size: &BlockSize,
+    compression: &str,
+) -> fmt::Result {
+    writeln!(f, "Mode      Density              Blocksize      bpi      Compression")?;
+    writeln!(
+        f,
+        "Current:  {:<17}    {:<12}   {:<7}  {compression}",
+        mt_density(density),
+        mt_block_size(block_size),
+        density.bpi
+    )
+}
+
+/// Formatted like `mt status`.
+impl fmt::Display for TapeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_current_mode(f, self.density, &self.block_size, mt_compression(self.compression))?;
+        writeln!(f, "---------------------------------")?;
+        writeln!(f, "Current Driver State: {}.", mt_state(Some(self.state)))?;
+        writeln!(f, "---------------------------------")?;
+        write!(
+            f,
+            "File Number: {}\tRecord Number: {}\tResidual Count {}",
+            self.file_no, self.block_no, self.residual
+        )
+    }
+}
+
 impl TryFrom<RawStatus> for TapeStatus {
     type Error = anyhow::Error;
 
@@ -287,7 +565,8 @@ impl TryFrom<RawStatus> for TapeStatus {
             block_size: BlockSize::from(raw.blksiz),
             file_no: raw.fileno as usize,
             block_no: raw.blkno as usize,
-            residual: raw.resid as usize,
+            // 驱动把残差截断为 short, 负数是 32768 及以上的低 16 位
+            residual: raw.resid as u16 as usize,
         };
         Ok(result)
     }
@@ -305,13 +584,168 @@ impl TapeDevice {
 
         let mut raw_status = RawStatus::default();
         unsafe {
-            ioctl_func::get_status(self.fd, &mut raw_status)?;
+            ioctl_func::get_status(self.fd, &mut raw_status).map_err(|e| self.ioctl_error("status()", e))?;
         }
 
         /* #define MT_ISAR  0x07, scsi lib */
         if raw_status._type != 0x07 {
             bail!("Your tape lib is not of SCSI.");
         }
-        TapeStatus::try_from(raw_status)
+        #[cfg(feature = "status-ex")]
+        let truncated = residual_may_be_truncated(raw_status.resid);
+        let status = TapeStatus::try_from(raw_status)?;
+        #[cfg(feature = "status-ex")]
+        if truncated {
+            return Ok(self.with_extended_residual(status));
+        }
+        Ok(status)
+    }
+
+    /// `status` with the residual count of the extended status, which is not truncated, if the driver reports it.
+    #[cfg(feature = "status-ex")]
+    fn with_extended_residual(&self, mut status: TapeStatus) -> TapeStatus {
+        let residual = self.status_ex().ok().flatten().map(|status_ex| status_ex.residual);
+        if let Some(Ok(residual)) = residual.map(usize::try_from) {
+            status.residual = residual;
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{residual_may_be_truncated, Compression, Density, RawStatus, TapeStatus, DENSITIES, GB};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_residual() {
+        let status = |resid| {
+            let raw = RawStatus {
+                _type: 0x07,
+                dsreg: 1,
+                resid,
+                ..Default::default()
+            };
+            TapeStatus::try_from(raw).unwrap()
+        };
+        assert_eq!(status(512).residual, 512);
+        // 49152 字节的残差被驱动截断为 -16384
+        assert_eq!(status(-16384).residual, 49152);
+        assert_eq!(status(-1).residual, 65535);
+
+        assert!(!residual_may_be_truncated(0));
+        assert!(!residual_may_be_truncated(32766));
+        assert!(residual_may_be_truncated(i16::MAX));
+        assert!(residual_may_be_truncated(-1));
+        assert!(residual_may_be_truncated(i16::MIN));
+    }
+
+    #[test]
+    fn test_compression_from_raw() {
+        assert_eq!(Compression::from(0), Compression::Off);
+        // 驱动以 1 或 MT_COMP_ENABLE 表示启用
+        assert_eq!(Compression::from(1), Compression::On);
+        assert_eq!(Compression::from(0xffffffff), Compression::On);
+        assert_eq!(Compression::from(0x10), Compression::Idrc);
+        assert_eq!(Compression::from(0x20), Compression::Dclz);
+        for other in [2, 0x11, 0x30, 0xfffffffd] {
+            assert_eq!(Compression::from(other), Compression::Unknown);
+        }
+    }
+
+    #[test]
+    fn test_densities() {
+        let mut codes = HashSet::new();
+        for density in &DENSITIES {
+            assert!(codes.insert(density.code), "0x{:02x} is listed twice", density.code);
+            assert!(std::ptr::eq(Density::get(density.code), density));
+        }
+        assert_eq!(Density::get(0x26).description, "DDS-4");
+        assert_eq!(Density::get(0x4c).description, "T10000C");
+        assert_eq!(Density::get(0x58).native_capacity, Some(1500 * GB));
+        assert_eq!(Density::get(0x47).native_capacity, Some(36 * GB));
+        for unknown in [0, 0x04, 0xff, 0x100, u32::MAX] {
+            assert_eq!(Density::get(unknown).description, "Unknown");
+        }
+        assert_eq!(Density::by_name("lto-9").unwrap().code, 0x60);
+
+        assert_eq!(Density::find("lto-6").unwrap().code, 0x5a);
+        assert_eq!(Density::find("3592a4 (e").unwrap().code, 0x74);
+        let e = Density::find("3592A1").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "density 3592A1 is ambiguous, expect one of: 3592A1 (unencrypted), 3592A1 (encrypted)"
+        );
+        let e = Density::find("LTO-10").unwrap_err().to_string();
+        assert!(e.starts_with("unknown density LTO-10, expect one of: X3.22-1983, "), "{e}");
+        assert!(e.ends_with(", EXB-8200c"), "{e}");
+    }
+
+    #[test]
+    fn test_display() {
+        let raw = RawStatus {
+            _type: 0x07,
+            dsreg: 1,
+            resid: 0,
+            blksiz: 0,
+            density: 0x58,
+            comp: 1,
+            fileno: 3,
+            blkno: 12,
+            ..Default::default()
+        };
+        let status = TapeStatus::try_from(raw).unwrap();
+        let expected = "\
+Mode      Density              Blocksize      bpi      Compression
+Current:  0x58:LTO-5           variable       384607   enabled
+---------------------------------
+Current Driver State: at rest.
+---------------------------------
+File Number: 3\tRecord Number: 12\tResidual Count 0";
+        assert_eq!(status.to_string(), expected);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        use super::{BlockSize, Compression, Density, DriverState};
+        use serde_json::json;
+
+        let status = TapeStatus {
+            state: DriverState::Writing,
+            block_size: BlockSize::Fixed(65536),
+            density: Density::by_name("LTO-6").unwrap(),
+            compression: Compression::On,
+            file_no: 3,
+            block_no: 12,
+            residual: 0,
+        };
+        let value = serde_json::to_value(&status).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "state": "writing",
+                "block_size": 65536,
+                "density": {"code": 0x5a, "description": "LTO-6"},
+                "compression": "on",
+                "file_no": 3,
+                "block_no": 12,
+                "residual": 0,
+            })
+        );
+        let parsed: TapeStatus = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), value);
+        assert!(std::ptr::eq(parsed.density, status.density));
+
+        assert_eq!(serde_json::to_value(BlockSize::Variable).unwrap(), json!("variable"));
+        assert_eq!(
+            serde_json::from_value::<BlockSize>(json!("variable")).unwrap(),
+            BlockSize::Variable
+        );
+        assert_eq!(serde_json::from_value::<BlockSize>(json!(0)).unwrap(), BlockSize::Variable);
+        assert!(serde_json::from_value::<BlockSize>(json!("fixed")).is_err());
+        // 未知的密度码
+        let density: &Density = serde_json::from_value(json!({"code": 0x99, "description": "LTO-99"})).unwrap();
+        assert_eq!(density.description, "Unknown");
     }
 }
@@ -1,9 +1,14 @@
-use super::{DriverState, TapeDevice};
+use super::status::{mt_state, write_current_mode};
+use super::{BlockSize, Density, DriverState, TapeDevice};
 use anyhow::{anyhow, bail, Result};
 use serde::Deserialize;
-use std::ffi::CStr;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
 
 #[derive(Debug, Deserialize, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 #[serde(default)]
 pub struct TapeStatusEx {
     /// Device driver name, such as `sa(8)`.
@@ -67,7 +72,65 @@ pub struct TapeStatusEx {
     pub mtdensity: MtDensity,
 }
 
+/// A number the driver reports as -1 when unknown.
+fn known(value: i64) -> String {
+    if value < 0 {
+        "Unknown".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Formatted like `mt status` of a driver with extended status.
+impl fmt::Display for TapeStatusEx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Drive: {}{}: <{} {} {}> Serial Number: {}",
+            self.periph_name, self.unit_number, self.vendor, self.product, self.revision, self.serial_num
+        )?;
+        writeln!(f, "---------------------------------")?;
+        let compression = match (self.compression_supported, self.compression_enabled) {
+            (0, _) => "unsupported".to_string(),
+            (_, 0) => "disabled".to_string(),
+            _ => format!("enabled (0x{:x})", self.compression_algorithm),
+        };
+        let density = Density::get(self.mtdensity.media_density);
+        let block_size = BlockSize::from(self.media_blocksize as i32);
+        write_current_mode(f, density, &block_size, &compression)?;
+        writeln!(f, "---------------------------------")?;
+        let state = DriverState::from_repr(self.dsreg as usize);
+        writeln!(f, "Current Driver State: {}.", mt_state(state))?;
+        writeln!(f, "---------------------------------")?;
+        writeln!(
+            f,
+            "Partition: {:>3}      Calc File Number: {:>3}     Calc Record Number: {}",
+            known(self.partition),
+            known(self.calculated_fileno),
+            known(self.calculated_rel_blkno)
+        )?;
+        writeln!(
+            f,
+            "Residual:  {:>3}  Reported File Number: {:>3} Reported Record Number: {}",
+            self.residual,
+            known(self.reported_fileno),
+            known(self.reported_blkno)
+        )?;
+        let flags = [(self.bop, "BOP"), (self.eop, "EOP"), (self.bpew, "BPEW")]
+            .into_iter()
+            .filter(|(flag, _)| *flag == 1)
+            .map(|(_, name)| name)
+            .collect::<Vec<_>>();
+        if flags.is_empty() {
+            write!(f, "Flags: None")
+        } else {
+            write!(f, "Flags: {}", flags.join(","))
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 #[serde(default)]
 pub struct Protection {
     /// Set to 1 if protection information is supported
@@ -85,6 +148,7 @@ pub struct Protection {
 }
 
 #[derive(Debug, Deserialize, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 #[serde(default)]
 pub struct MtDensity {
     /// Current Medium Density Code
@@ -93,6 +157,7 @@ pub struct MtDensity {
 }
 
 #[derive(Debug, Deserialize, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 #[serde(default)]
 pub struct DensityReport {
     /// Medium type report
@@ -103,6 +168,7 @@ pub struct DensityReport {
 }
 
 #[derive(Debug, Deserialize, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 #[serde(default)]
 pub struct DensityEntry {
     /// Primary Density Code
@@ -110,7 +176,7 @@ pub struct DensityEntry {
     /// Secondary Density Code
     pub secondary_density_code: u8,
     /// Density Flags
-    pub density_flags: String,
+    pub density_flags: DensityFlags,
     /// Bits per mm
     pub bits_per_mm: u32,
     /// Media width
@@ -139,7 +205,62 @@ pub struct DensityEntry {
     pub medium_type_name: Option<String>,
 }
 
+bitflags::bitflags! {
+    /// Flags of a density, `SDD_*` of scsi_sa.h. Bits not named here are kept.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct DensityFlags: u32 {
+        /// Descriptor length valid
+        const DLV = 0x01;
+        /// Default density of the drive
+        const DEFLT = 0x20;
+        /// Duplicate of another density code
+        const DUP = 0x40;
+        /// The drive can write in this density
+        const WRTOK = 0x80;
+    }
+}
+
+impl FromStr for DensityFlags {
+    type Err = String;
+
+    /// Parse `0x`-prefixed hex, as the driver prints it, or decimal. Empty is no flag.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let bits = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            _ if s.is_empty() => Ok(0),
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        bits.map(Self::from_bits_retain)
+            .map_err(|_| format!("expect density flags in hex or decimal, got {s:?}"))
+    }
+}
+
+/// Formatted like the driver with `%#x`, such as `0xa0`, and `0` without flags.
+impl fmt::Display for DensityFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bits() {
+            0 => f.write_str("0"),
+            bits => write!(f, "{bits:#x}"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DensityFlags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for DensityFlags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 #[derive(Debug, Deserialize, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct DensityCodeList {
     /// Density Code
     pub density_code: Vec<u8>,
@@ -171,45 +292,68 @@ mod ioctl_func {
     nix::ioctl_readwrite!(get_status_ex, b'm', 11u8, RawStatusEx);
 }
 
-impl TapeDevice {
-    unsafe fn status_ex_get_xml(&self) -> Result<Option<String>> {
-        assert_eq!(std::mem::size_of::<RawStatusEx>(), 216);
-
-        const ALLOC_LEN: usize = 32768;
+/// Text of `bytes` up to the first NUL, all of them without one.
+pub(super) fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
 
-        let mut buffer = [0u8; ALLOC_LEN];
+/// XML the driver put in `buffer`, the first `fill_len` bytes with the terminating NUL.
+fn filled_xml(buffer: &[u8], fill_len: u32) -> String {
+    let filled = &buffer[..(fill_len as usize).min(buffer.len())];
+    // 仅去掉结尾的 NUL; 中间的 NUL 留给 XML 解析报错, 而不是悄悄截断
+    let filled = filled.strip_suffix(&[0]).unwrap_or(filled);
+    String::from_utf8_lossy(filled).into_owned()
+}
 
-        let mut raw_status: RawStatusEx = std::mem::zeroed();
-        raw_status.alloc_len = ALLOC_LEN as u32;
-        raw_status.xml = buffer.as_mut_ptr();
-        ioctl_func::get_status_ex(self.fd, &mut raw_status)?;
+/// Size of the buffer the XML is first read into
+const ALLOC_LEN: usize = 32 * 1024;
+/// Largest buffer tried, when the driver reports the XML does not fit
+const MAX_ALLOC_LEN: usize = 1024 * 1024;
 
+/// Call `fetch` to fill a buffer with XML, again with a buffer twice as large while the driver needs more space.
+fn with_growing_buffer(mut fetch: impl FnMut(&mut [u8]) -> Result<RawStatusEx>) -> Result<Option<String>> {
+    let mut buffer = vec![0u8; ALLOC_LEN];
+    loop {
+        let raw_status = fetch(&mut buffer)?;
         match raw_status.result {
-            StatusExtResult::None => Ok(None),
-            StatusExtResult::Ok => {
-                let cstr = CStr::from_ptr(buffer.as_ptr() as *const i8);
-                let xml_content = cstr.to_string_lossy().to_string();
-                Ok(Some(xml_content))
-            }
+            StatusExtResult::None => return Ok(None),
+            StatusExtResult::Ok => return Ok(Some(filled_xml(&buffer, raw_status.fill_len))),
+            StatusExtResult::NeedMoreSpace if buffer.len() < MAX_ALLOC_LEN => buffer.resize(buffer.len() * 2, 0),
             StatusExtResult::NeedMoreSpace => {
-                bail!("Buffer is too small, adjust ALLOC_LEN up and try again.")
-            }
-            StatusExtResult::GetError => {
-                let message = CStr::from_ptr(raw_status.err_str.as_mut_ptr() as *mut libc::c_char)
-                    .to_str()
-                    .unwrap();
-                bail!("{message}")
+                bail!("the XML the driver reports does not fit in {} bytes.", buffer.len())
             }
+            StatusExtResult::GetError => bail!("{}", c_string(&raw_status.err_str)),
         }
     }
+}
+
+/// An ioctl filling a `struct mtextget` with XML, such as MTIOCEXTGET.
+pub(super) type ExtGetIoctl = unsafe fn(libc::c_int, *mut RawStatusEx) -> nix::Result<libc::c_int>;
+
+impl TapeDevice {
+    unsafe fn status_ex_get_xml(&self) -> Result<Option<String>> {
+        self.ext_get_xml(ioctl_func::get_status_ex, "status_ex()")
+    }
+
+    /// XML `ioctl` fills in, `None` if the driver has nothing to report.
+    pub(super) unsafe fn ext_get_xml(&self, ioctl: ExtGetIoctl, operation: &str) -> Result<Option<String>> {
+        assert_eq!(std::mem::size_of::<RawStatusEx>(), 216);
+
+        with_growing_buffer(|buffer| {
+            let mut raw_status: RawStatusEx = std::mem::zeroed();
+            raw_status.alloc_len = buffer.len() as u32;
+            raw_status.xml = buffer.as_mut_ptr();
+            ioctl(self.fd, &mut raw_status).map_err(|e| self.ioctl_error(operation, e))?;
+            Ok(raw_status)
+        })
+    }
     pub fn status_ex(&self) -> Result<Option<TapeStatusEx>> {
         let xml = match unsafe { self.status_ex_get_xml()? } {
             Some(content) => content,
             None => return Ok(None),
         };
 
-        // TODO: We need a specified xml parser to deal with it
-        // DensityEntry::density_flags should be a integer, which represents in hex in xml.
         let result: TapeStatusEx = serde_xml_rs::from_str(&xml)?;
         Ok(Some(result))
     }
@@ -240,3 +384,192 @@ impl TapeDevice {
             .ok_or_else(|| anyhow!("Unexpected dsreg: {driver_state_register}"))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{c_string, filled_xml, with_growing_buffer, RawStatusEx, StatusExtResult, TapeStatusEx};
+    use super::{DensityFlags, DensityReport, ALLOC_LEN, MAX_ALLOC_LEN};
+
+    #[test]
+    fn test_density_flags() {
+        assert_eq!("0xa0".parse(), Ok(DensityFlags::WRTOK | DensityFlags::DEFLT));
+        assert_eq!("0X40".parse(), Ok(DensityFlags::DUP));
+        assert_eq!("128".parse(), Ok(DensityFlags::WRTOK));
+        assert_eq!(" ".parse(), Ok(DensityFlags::empty()));
+        // 未命名的位保留
+        let flags: DensityFlags = "0x102".parse().unwrap();
+        assert_eq!(flags.bits(), 0x102);
+        assert!("0xzz".parse::<DensityFlags>().is_err());
+        assert!("-1".parse::<DensityFlags>().is_err());
+
+        assert_eq!((DensityFlags::WRTOK | DensityFlags::DEFLT).to_string(), "0xa0");
+        assert_eq!(DensityFlags::empty().to_string(), "0");
+    }
+
+    #[test]
+    fn test_parse_density_report() {
+        // LTO-5 驱动器装入 LTO-5 磁带时的报告
+        let xml = r#"<density_report type="node">
+            <medium_type_report type="int" size="4" fmt="%d" desc="Medium type report">0</medium_type_report>
+            <media_report type="int" size="4" fmt="%d" desc="Media report">0</media_report>
+            <density_entry type="node" num="0">
+                <primary_density_code type="uint" size="1" fmt="%u" desc="Primary Density Code">70</primary_density_code>
+                <secondary_density_code type="uint" size="1" fmt="%u" desc="Secondary Density Code">70</secondary_density_code>
+                <density_flags type="uint" size="4" fmt="%#x" desc="Density Flags">0</density_flags>
+                <bits_per_mm type="uint" size="4" fmt="%u" desc="Bits per mm">12725</bits_per_mm>
+                <media_width type="uint" size="4" fmt="%u" desc="Media width">127</media_width>
+                <tracks type="uint" size="4" fmt="%u" desc="Number of Tracks">896</tracks>
+                <capacity type="uint" size="4" fmt="%u" desc="Capacity">800000</capacity>
+                <assigning_org type="str" size="8" fmt="%s" desc="Assigning Organization">LTO-CVE</assigning_org>
+                <density_name type="str" size="6" fmt="%s" desc="Density Name">U-416</density_name>
+                <description type="str" size="14" fmt="%s" desc="Description">Ultrium 4/16T</description>
+            </density_entry>
+            <density_entry type="node" num="1">
+                <primary_density_code type="uint" size="1" fmt="%u" desc="Primary Density Code">88</primary_density_code>
+                <secondary_density_code type="uint" size="1" fmt="%u" desc="Secondary Density Code">88</secondary_density_code>
+                <density_flags type="uint" size="4" fmt="%#x" desc="Density Flags">0xa0</density_flags>
+                <bits_per_mm type="uint" size="4" fmt="%u" desc="Bits per mm">15142</bits_per_mm>
+                <media_width type="uint" size="4" fmt="%u" desc="Media width">127</media_width>
+                <tracks type="uint" size="4" fmt="%u" desc="Number of Tracks">1280</tracks>
+                <capacity type="uint" size="4" fmt="%u" desc="Capacity">1500000</capacity>
+                <assigning_org type="str" size="8" fmt="%s" desc="Assigning Organization">LTO-CVE</assigning_org>
+                <density_name type="str" size="6" fmt="%s" desc="Density Name">U-516</density_name>
+                <description type="str" size="14" fmt="%s" desc="Description">Ultrium 5/16T</description>
+            </density_entry>
+        </density_report>"#;
+        let report: DensityReport = serde_xml_rs::from_str(xml).unwrap();
+        let flags = report
+            .density_entry
+            .iter()
+            .map(|entry| entry.density_flags)
+            .collect::<Vec<_>>();
+        assert_eq!(flags, [DensityFlags::empty(), DensityFlags::WRTOK | DensityFlags::DEFLT]);
+        assert_eq!(report.density_entry[1].density_name, "U-516");
+
+        // 介质类型报告的条目没有密度标志等字段
+        let xml = r#"<density_report type="node">
+            <medium_type_report type="int" size="4" fmt="%d" desc="Medium type report">1</medium_type_report>
+            <media_report type="int" size="4" fmt="%d" desc="Media report">0</media_report>
+            <density_entry type="node" num="0">
+                <medium_type type="uint" size="1" fmt="%u" desc="Medium Type">0</medium_type>
+                <num_density_codes type="int" size="1" fmt="%d" desc="Number of Density Codes">2</num_density_codes>
+                <density_code_list type="node">
+                    <density_code type="uint" size="1" fmt="%u" desc="Density Code">70</density_code>
+                    <density_code type="uint" size="1" fmt="%u" desc="Density Code">88</density_code>
+                </density_code_list>
+                <media_width type="uint" size="4" fmt="%u" desc="Media width">127</media_width>
+                <medium_length type="uint" size="4" fmt="%u" desc="Medium length">846</medium_length>
+                <assigning_org type="str" size="8" fmt="%s" desc="Assigning Organization">LTO-CVE</assigning_org>
+                <medium_type_name type="str" size="9" fmt="%s" desc="Medium type name">LTO5Data</medium_type_name>
+                <description type="str" size="20" fmt="%s" desc="Description">Ultrium 5 Data Tape</description>
+            </density_entry>
+        </density_report>"#;
+        let report: DensityReport = serde_xml_rs::from_str(xml).unwrap();
+        let entry = &report.density_entry[0];
+        assert_eq!(entry.density_flags, DensityFlags::empty());
+        assert_eq!(entry.medium_type_name.as_deref(), Some("LTO5Data"));
+        assert_eq!(entry.density_code_list.as_ref().unwrap().density_code, [70, 88]);
+        assert_eq!(entry.medium_length, Some(846));
+    }
+
+    fn raw_status(result: StatusExtResult, fill_len: u32) -> RawStatusEx {
+        RawStatusEx {
+            alloc_len: 0,
+            xml: std::ptr::null(),
+            fill_len,
+            result,
+            err_str: [0; 128],
+            reserved: [0; 64],
+        }
+    }
+
+    #[test]
+    fn test_with_growing_buffer() {
+        // 驱动需要 100 KiB, 缓冲区翻倍两次后够用
+        let xml = b"<mtextget></mtextget>\0";
+        let mut sizes = Vec::new();
+        let result = with_growing_buffer(|buffer| {
+            sizes.push(buffer.len());
+            if buffer.len() < 100 * 1024 {
+                return Ok(raw_status(StatusExtResult::NeedMoreSpace, 0));
+            }
+            buffer[..xml.len()].copy_from_slice(xml);
+            Ok(raw_status(StatusExtResult::Ok, xml.len() as u32))
+        });
+        assert_eq!(result.unwrap().as_deref(), Some("<mtextget></mtextget>"));
+        assert_eq!(sizes, [ALLOC_LEN, ALLOC_LEN * 2, ALLOC_LEN * 4]);
+
+        let mut largest = 0;
+        let e = with_growing_buffer(|buffer| {
+            largest = buffer.len();
+            Ok(raw_status(StatusExtResult::NeedMoreSpace, 0))
+        })
+        .unwrap_err();
+        assert_eq!(largest, MAX_ALLOC_LEN);
+        assert_eq!(e.to_string(), "the XML the driver reports does not fit in 1048576 bytes.");
+
+        let none = with_growing_buffer(|_| Ok(raw_status(StatusExtResult::None, 0)));
+        assert!(none.unwrap().is_none());
+        let mut error = raw_status(StatusExtResult::GetError, 0);
+        error.err_str[..8].copy_from_slice(b"No media");
+        let mut error = Some(error);
+        let e = with_growing_buffer(|_| Ok(error.take().unwrap())).unwrap_err();
+        assert_eq!(e.to_string(), "No media");
+    }
+
+    #[test]
+    fn test_filled_xml() {
+        let mut buffer = [0xffu8; 64];
+        let xml = b"<mtextget><a>1</a>\0<b>2</b></mtextget>\0";
+        buffer[..xml.len()].copy_from_slice(xml);
+        // 中间的 NUL 不截断内容, fill_len 之后的字节不读
+        assert_eq!(
+            filled_xml(&buffer, xml.len() as u32),
+            "<mtextget><a>1</a>\0<b>2</b></mtextget>"
+        );
+        assert_eq!(filled_xml(&buffer, 10), "<mtextget>");
+        // fill_len 超出缓冲区时只读到缓冲区末尾
+        assert!(filled_xml(&buffer, 1000).ends_with('\u{fffd}'));
+        assert_eq!(filled_xml(&buffer, 0), "");
+
+        assert_eq!(c_string(b"No media\0garbage"), "No media");
+        assert_eq!(c_string(b"unterminated"), "unterminated");
+    }
+
+    #[test]
+    fn test_display() {
+        let xml = include_str!("../../status_ex.example.xml");
+        let mut status: TapeStatusEx = serde_xml_rs::from_str(xml).unwrap();
+        let expected = "\
+Drive: sa0: <HP Ultrium 6-SCSI J3LZ> Serial Number: HUJ4140531
+---------------------------------
+Mode      Density              Blocksize      bpi      Compression
+Current:  0x5a:LTO-6           512 bytes      384607   enabled (0x1)
+---------------------------------
+Current Driver State: at rest.
+---------------------------------
+Partition:   0      Calc File Number:   0     Calc Record Number: 0
+Residual:    0  Reported File Number:   0 Reported Record Number: 0
+Flags: BOP";
+        assert_eq!(status.to_string(), expected);
+
+        status.bop = 0;
+        status.reported_fileno = -1;
+        let text = status.to_string();
+        assert!(text.contains("Reported File Number: Unknown"), "{text}");
+        assert!(text.ends_with("Flags: None"), "{text}");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let xml = include_str!("../../status_ex.example.xml");
+        let status: TapeStatusEx = serde_xml_rs::from_str(xml).unwrap();
+        let value = serde_json::to_value(&status).unwrap();
+        assert_eq!(value["serial_num"], "HUJ4140531");
+        assert_eq!(value["protection"]["lbp_w"], status.protection.lbp_w);
+        assert_eq!(value["mtdensity"]["media_density"], 90);
+        let parsed: TapeStatusEx = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), value);
+    }
+}
@@ -0,0 +1,58 @@
+//! Writing blocks.
+
+use super::{BlockSize, TapeDevice, TapeError};
+use anyhow::Result;
+use std::io::Write;
+
+impl TapeDevice {
+    /// The error of a write of `requested` bytes stopped after `written`, with the residual count of the driver.
+    fn short_write(&self, written: usize, requested: usize) -> TapeError {
+        TapeError::ShortWrite {
+            device: self.path.clone(),
+            written,
+            requested,
+            residual: self.get_last_error().ok().map(|errors| errors.io_resid),
+        }
+    }
+
+    /// Write `buf` as one block. It can not be longer than the maximum block length of the drive, and in fixed block
+    /// mode it has to be a multiple of the block size. At the end of the tape, the error is
+    /// [`TapeError::is_end_of_medium`](super::TapeError::is_end_of_medium).
+    pub fn write_block(&self, buf: &[u8]) -> Result<()> {
+        match nix::unistd::write(self.fd, buf) {
+            Ok(count) if count == buf.len() => Ok(()),
+            Ok(count) => Err(self.short_write(count, buf.len()).into()),
+            Err(errno) => Err(self.ioctl_error(format!("write_block(size={})", buf.len()), errno).into()),
+        }
+    }
+}
+
+/// Each call writes one block. A short write in fixed block mode fails with [`TapeError::ShortWrite`], as the rest
+/// can not follow in another block; get it back with `get_ref()` and `downcast_ref`.
+impl Write for &TapeDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = nix::unistd::write(self.fd, buf)?;
+        if count < buf.len() && matches!(self.status().map(|status| status.block_size), Ok(BlockSize::Fixed(_))) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                self.short_write(count, buf.len()),
+            ));
+        }
+        Ok(count)
+    }
+
+    /// Blocks are written by the driver as they are given.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for TapeDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,3 +1,105 @@
 pub mod device;
 
+#[cfg(feature = "status-ex")]
+pub use device::TapeStatusEx;
 pub use device::{LocationBuilder, TapeDevice};
+
+/// Each feature combination is built by `cargo test -p tape --no-default-features --features ...`, these tests check
+/// that the API of every enabled feature is there.
+#[cfg(test)]
+mod test {
+    use crate::device::{DriverState, TapeStatus};
+    use crate::TapeDevice;
+
+    #[test]
+    fn test_core() {
+        // 核心 API 仅依赖 nix、libc 与 anyhow
+        let _open = TapeDevice::open::<str>;
+        let _open_unit = TapeDevice::open_unit;
+        let _open_nonblocking = TapeDevice::open_nonblocking::<str>;
+        let _wait_ready = TapeDevice::wait_ready;
+        let _status: fn(&TapeDevice) -> anyhow::Result<TapeStatus> = TapeDevice::status;
+        let _rewind = TapeDevice::rewind;
+        let _events = TapeDevice::events;
+        let _next_file = TapeDevice::next_file;
+        let _dump_file = TapeDevice::dump_file::<std::io::Sink>;
+        let _compare_file = TapeDevice::compare_file::<std::io::Empty>;
+        let _capabilities = TapeDevice::capabilities;
+        let _nop = TapeDevice::nop;
+        let tape = TapeDevice::open("/dev/null").unwrap();
+        let clone = tape.try_clone().unwrap();
+        assert_ne!(clone.fd(), tape.fd());
+        assert_eq!(clone.path(), "/dev/null");
+
+        clone.close().unwrap();
+        // 借出的描述符复制后单独关闭, 不影响原来的
+        let owned = std::os::fd::AsFd::as_fd(&tape).try_clone_to_owned().unwrap();
+        drop(std::fs::File::from(owned));
+        assert!(nix::fcntl::fcntl(tape.fd(), nix::fcntl::FcntlArg::F_GETFD).is_ok());
+
+        // 直接读写设备
+        use std::io::{Read, Write};
+        let mut buffer = [0u8; 16];
+        assert_eq!((&tape).read(&mut buffer).unwrap(), 0);
+        assert_eq!((&tape).write(&buffer).unwrap(), 16);
+        assert_eq!(DriverState::from_repr(1), Some(DriverState::Rest));
+        assert_eq!("Doing Nothing".parse::<DriverState>().unwrap(), DriverState::Rest);
+    }
+
+    #[test]
+    fn test_raw_fd() {
+        use std::io::{Read, Write};
+        use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        let mut tape = unsafe { TapeDevice::from_raw_fd(write_end) };
+        assert_eq!(tape.as_raw_fd(), write_end);
+        assert_eq!(tape.path(), format!("/dev/fd/{write_end}"));
+        tape.write_all(b"block").unwrap();
+        // 交出描述符后不再关闭, 仍可写入
+        assert_eq!(tape.into_raw_fd(), write_end);
+        assert_eq!(nix::unistd::write(write_end, b" again").unwrap(), 6);
+
+        // 写端关闭后读到末尾
+        drop(unsafe { TapeDevice::from_raw_fd(write_end) });
+        let mut content = String::new();
+        unsafe { std::fs::File::from_raw_fd(read_end) }
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "block again");
+    }
+
+    #[cfg(feature = "status-ex")]
+    #[test]
+    fn test_status_ex() {
+        let _status_ex: fn(&TapeDevice) -> anyhow::Result<Option<crate::TapeStatusEx>> = TapeDevice::status_ex;
+        let _density = TapeDevice::density;
+        let _get_params = TapeDevice::get_params;
+        let _set_param = TapeDevice::set_param;
+    }
+
+    #[cfg(feature = "copy")]
+    #[test]
+    fn test_copy() {
+        let _copy_files = crate::device::copy_files;
+        let _options = crate::device::CopyOptions::new().block_size(256 * 1024);
+    }
+
+    #[cfg(feature = "passthrough")]
+    #[test]
+    fn test_passthrough() {
+        let _compression_counters = TapeDevice::compression_counters;
+        let _prevent_removal = TapeDevice::prevent_removal;
+        let _allow_removal = TapeDevice::allow_removal;
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        fn serializable<T: serde::Serialize>() {}
+        serializable::<TapeStatus>();
+        serializable::<crate::device::ScsiTapeErrors>();
+        #[cfg(feature = "status-ex")]
+        serializable::<crate::device::TapeStatusEx>();
+    }
+}